@@ -0,0 +1,151 @@
+//! Measures how quickly the archive's data files can be loaded, saved and merged, and simulates
+//! scheduling its downloads with the given settings. Nothing is downloaded, each request is
+//! assumed to take `--latency-ms` (plus the time to transfer the file, if `--limit-rate` is set).
+
+use crate::download::{RateLimiter, Scheduler};
+use crate::model::{list_user_dirs, DataFile, Tweet};
+use crate::BenchArgs;
+use anyhow::{bail, Context};
+use futures::{stream, StreamExt};
+use std::time::{Duration, Instant};
+use tokio::fs;
+use url::Url;
+
+#[derive(Default)]
+struct Timings {
+    accounts: usize,
+    tweets: usize,
+    bytes: u64,
+    load: Duration,
+    save: Duration,
+    merge: Duration,
+}
+
+pub async fn bench(args: BenchArgs) -> anyhow::Result<()> {
+    if !args.dir.is_dir() {
+        bail!("expected a directory")
+    }
+    let user_dirs = list_user_dirs(&args.dir).await?;
+    // Saved within the archive, so that the same storage is measured
+    let temp = tempfile::tempdir_in(&args.dir).context("Unable to create temporary directory")?;
+    let mut timings = Timings::default();
+    let mut media = vec![];
+    for user_dir in user_dirs {
+        let start = Instant::now();
+        let mut data_file = DataFile::load_unchecked(&user_dir)
+            .await?
+            .context("Missing data file")?;
+        timings.load += start.elapsed();
+
+        let start = Instant::now();
+        data_file.save(temp.path()).await?;
+        timings.save += start.elapsed();
+        timings.bytes += fs::metadata(temp.path().join("tweets.json")).await?.len();
+
+        // Merging every tweet again is the same work as a `--rescan`
+        let tweets: Vec<Tweet> = serde_json::from_value(serde_json::to_value(&data_file.tweets)?)?;
+        timings.tweets += tweets.len();
+        let start = Instant::now();
+        data_file.merge_tweets(tweets, "bench");
+        timings.merge += start.elapsed();
+        timings.accounts += 1;
+
+        for m in data_file.tweets.iter().flat_map(|t| &t.media) {
+            if let Some(url) = &m.url {
+                let size = match &m.file_name {
+                    Some(file_name) => fs::metadata(user_dir.join(file_name))
+                        .await
+                        .map_or(0, |m| m.len()),
+                    None => 0,
+                };
+                media.push((url.clone(), size));
+            }
+        }
+    }
+    if timings.accounts == 0 {
+        bail!("No downloaded accounts found")
+    }
+
+    log::info!(
+        "{} accounts, {} tweets, {:.1} MB of data files",
+        timings.accounts,
+        timings.tweets,
+        timings.bytes as f64 / 1_000_000.0
+    );
+    log::info!(
+        "Load: {:.3}s ({:.1} MB/s)",
+        timings.load.as_secs_f64(),
+        per_sec(timings.bytes as f64 / 1_000_000.0, timings.load)
+    );
+    log::info!(
+        "Save: {:.3}s ({:.1} MB/s)",
+        timings.save.as_secs_f64(),
+        per_sec(timings.bytes as f64 / 1_000_000.0, timings.save)
+    );
+    log::info!(
+        "Merge: {:.3}s ({:.0} tweets/s)",
+        timings.merge.as_secs_f64(),
+        per_sec(timings.tweets as f64, timings.merge)
+    );
+
+    if media.is_empty() {
+        log::info!("No media to simulate downloading");
+        return Ok(());
+    }
+    // Spread the sample over the archive, to get a representative mix of hosts
+    let step = (media.len() / args.downloads.max(1)).max(1);
+    let sample = media
+        .iter()
+        .step_by(step)
+        .take(args.downloads)
+        .cloned()
+        .collect::<Vec<_>>();
+    let sampled = sample.len();
+    let elapsed = simulate(sample, &args).await;
+    let rate = per_sec(sampled as f64, elapsed);
+    log::info!(
+        "Simulated {} downloads in {:.2}s ({:.1} files/s) with a concurrency of {}",
+        sampled,
+        elapsed.as_secs_f64(),
+        rate,
+        args.media.concurrency
+    );
+    let estimate = Duration::from_secs_f64(media.len() as f64 / rate);
+    log::info!(
+        "Downloading all {} media would take about {}",
+        media.len(),
+        humantime::format_duration(Duration::from_secs(estimate.as_secs()))
+    );
+    Ok(())
+}
+
+/// Runs the downloads through the scheduler and rate limiter, returning how long they took
+async fn simulate(sample: Vec<(Url, u64)>, args: &BenchArgs) -> Duration {
+    let scheduler = Scheduler::new(
+        Duration::from_millis(args.media.delay_ms),
+        args.media.per_host_concurrency,
+    );
+    let throttle = args.media.limit_rate.map(|rate| RateLimiter::new(rate.0));
+    let latency = Duration::from_millis(args.latency_ms);
+    let start = Instant::now();
+    stream::iter(sample)
+        .map(|(url, size)| {
+            let scheduler = scheduler.clone();
+            let throttle = throttle.clone();
+            async move {
+                let _slot = scheduler.acquire(&url).await;
+                tokio::time::sleep(latency).await;
+                if let Some(throttle) = throttle {
+                    throttle.consume(size as usize).await;
+                }
+            }
+        })
+        .buffer_unordered(args.media.concurrency)
+        .collect::<Vec<_>>()
+        .await;
+    start.elapsed()
+}
+
+fn per_sec(amount: f64, elapsed: Duration) -> f64 {
+    amount / elapsed.as_secs_f64().max(f64::EPSILON)
+}
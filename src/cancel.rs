@@ -0,0 +1,57 @@
+//! Cancellation of the running command (e.g. on Ctrl-C). Cancelled work is dropped rather than
+//! killed with the process, so in-flight requests are aborted, temporary files are removed, and
+//! the data files are saved with whatever had completed.
+
+use std::future::Future;
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+
+/// Exit code when cancelled, as for a process interrupted by SIGINT
+pub const EXIT_CANCELLED: i32 = 130;
+
+#[derive(Error, Debug)]
+#[error("Cancelled")]
+pub struct Cancelled;
+
+/// Returns a token that is cancelled on Ctrl-C (or SIGTERM), a second signal exits immediately
+pub fn on_signal() -> CancellationToken {
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+    tokio::spawn(async move {
+        signal().await;
+        log::warn!(
+            "Stopping once the downloads in progress complete, press Ctrl-C again to exit immediately"
+        );
+        cancel.cancel();
+        signal().await;
+        std::process::exit(EXIT_CANCELLED);
+    });
+    token
+}
+
+#[cfg(unix)]
+async fn signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() {
+    tokio::signal::ctrl_c().await.ok();
+}
+
+/// Runs `future` to completion, unless cancelled first in which case it is dropped
+pub async fn cancellable<T>(
+    cancel: &CancellationToken,
+    future: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    tokio::select! {
+        biased;
+        _ = cancel.cancelled() => Err(Cancelled.into()),
+        result = future => result,
+    }
+}
@@ -0,0 +1,137 @@
+//! Detects media that has been posted more than once, so that only one copy of the file is stored.
+//! Duplicates are found by SHA-256, and optionally for photos by a perceptual (difference) hash.
+
+use crate::download::trash;
+use crate::model::DataFile;
+use crate::thumbnail::ThumbnailCache;
+use crate::verify::hash_file;
+use crate::DedupeMode;
+use anyhow::Context;
+use image::imageops::FilterType;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// Maximum number of differing bits for two perceptual hashes to be considered the same image
+const PERCEPTUAL_THRESHOLD: u32 = 4;
+
+/// The first copy of some content
+struct Original {
+    media_id: u64,
+    file_name: String,
+    sha256: String,
+    perceptual: Option<u64>,
+}
+
+/// Deduplicates the downloaded media, the earliest tweet's copy is the one that is kept
+pub async fn dedupe_media(
+    user_dir: &Path,
+    data_file: &mut DataFile,
+    mode: DedupeMode,
+    perceptual: bool,
+) -> anyhow::Result<()> {
+    let mut originals: Vec<Original> = Vec::new();
+    let mut by_hash: HashMap<String, usize> = HashMap::new();
+    let mut deduped = 0;
+    for media in data_file.tweets.iter_mut().flat_map(|t| &mut t.media) {
+        let file_name = match &media.file_name {
+            Some(file_name) if media.duplicate_of.is_none() => file_name.clone(),
+            _ => continue,
+        };
+        let path = user_dir.join(&file_name);
+        if !path.exists() {
+            continue;
+        }
+        let sha256 = match &media.sha256 {
+            Some(sha256) => sha256.clone(),
+            None => hash_file(&path)
+                .await
+                .with_context(|| format!("Unable to read {}", path.display()))?,
+        };
+        let perceptual = if perceptual && ThumbnailCache::supports(&path) {
+            match perceptual_hash(path.clone()).await {
+                Ok(hash) => Some(hash),
+                Err(e) => {
+                    log::warn!("Unable to hash {}: {:#}", path.display(), e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let exact = by_hash.get(&sha256).copied();
+        let similar = || {
+            let hash = perceptual?;
+            originals.iter().position(|o| {
+                o.perceptual
+                    .is_some_and(|p| (p ^ hash).count_ones() <= PERCEPTUAL_THRESHOLD)
+            })
+        };
+        let original = match exact.or_else(similar) {
+            Some(index) => &originals[index],
+            None => {
+                by_hash.insert(sha256.clone(), originals.len());
+                originals.push(Original {
+                    media_id: media.id,
+                    file_name,
+                    sha256: sha256.clone(),
+                    perceptual,
+                });
+                media.sha256 = Some(sha256);
+                continue;
+            }
+        };
+        if original.file_name == file_name {
+            continue;
+        }
+        let original_path = user_dir.join(&original.file_name);
+        if mode == DedupeMode::HardLink && exact.is_some() {
+            hard_link(&original_path, &path)
+                .await
+                .with_context(|| format!("Unable to link {}", path.display()))?;
+        } else {
+            trash(user_dir, &file_name, Some(media.id), Some(sha256)).await?;
+            media.file_name = Some(original.file_name.clone());
+        }
+        log::debug!(
+            "{} is a duplicate of {}",
+            path.display(),
+            original_path.display()
+        );
+        media.duplicate_of = Some(original.media_id);
+        media.sha256 = Some(original.sha256.clone());
+        deduped += 1;
+    }
+    if deduped > 0 {
+        log::info!("Deduplicated {} files", deduped);
+    }
+    data_file.save(user_dir).await
+}
+
+/// Replaces `duplicate` with a hard link to `original`
+async fn hard_link(original: &Path, duplicate: &Path) -> std::io::Result<()> {
+    // Link to a temporary name first, so the duplicate is only replaced if linking succeeds
+    let temp = duplicate.with_extension("dedupe");
+    fs::hard_link(original, &temp).await?;
+    fs::rename(&temp, duplicate).await
+}
+
+/// A 64 bit difference hash, comparing the brightness of adjacent pixels of a 9x8 thumbnail
+async fn perceptual_hash(path: PathBuf) -> anyhow::Result<u64> {
+    tokio::task::spawn_blocking(move || {
+        let image = image::open(&path).context("Unable to decode image")?;
+        let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+        let mut hash = 0u64;
+        for y in 0..8 {
+            for x in 0..8 {
+                hash <<= 1;
+                if small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0] {
+                    hash |= 1;
+                }
+            }
+        }
+        Ok(hash)
+    })
+    .await?
+}
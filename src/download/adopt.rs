@@ -0,0 +1,88 @@
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+/// Bytes read from the start of a file to recognise its format
+const HEADER_LENGTH: usize = 12;
+
+/// Checks that an existing file can be adopted (with `--file-exists-policy adopt`) rather than
+/// downloaded again, returning why it can't be. A file left truncated or corrupted by an earlier
+/// run must not be recorded as downloaded, as it would never be repaired.
+pub async fn adoption_problem(path: &Path, sha256: Option<&str>) -> Option<String> {
+    let mut file = match File::open(path).await {
+        Ok(file) => file,
+        Err(e) => return Some(format!("unable to open it ({e})")),
+    };
+    let mut header = Vec::with_capacity(HEADER_LENGTH);
+    if let Err(e) = (&mut file)
+        .take(HEADER_LENGTH as u64)
+        .read_to_end(&mut header)
+        .await
+    {
+        return Some(format!("unable to read it ({e})"));
+    }
+    if header.is_empty() {
+        return Some("it is empty".to_string());
+    }
+    if crate::encryption::is_encrypted(&header) {
+        header = match crate::encryption::read(path).await {
+            Ok(mut plaintext) => {
+                plaintext.truncate(HEADER_LENGTH);
+                plaintext
+            }
+            Err(e) => return Some(format!("unable to decrypt it ({e:#})")),
+        };
+    }
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if !matches_format(&ext, &header) {
+        return Some(format!("it isn't a valid {ext} file"));
+    }
+    // Recorded when the media was previously downloaded
+    if let Some(expected) = sha256 {
+        match crate::verify::hash_file(path).await {
+            Ok(hash) if hash == expected => {}
+            Ok(_) => return Some("it doesn't match the recorded hash".to_string()),
+            Err(e) => return Some(format!("unable to hash it ({e:#})")),
+        }
+    }
+    None
+}
+
+/// Whether the header matches the format's magic bytes, unknown formats are assumed to be valid
+fn matches_format(ext: &str, header: &[u8]) -> bool {
+    match ext {
+        "jpg" | "jpeg" => header.starts_with(&[0xFF, 0xD8, 0xFF]),
+        "png" => header.starts_with(b"\x89PNG"),
+        "gif" => header.starts_with(b"GIF8"),
+        "webp" => header.starts_with(b"RIFF") && header.get(8..12) == Some(b"WEBP"),
+        "mp4" | "m4v" | "mov" => header.get(4..8) == Some(b"ftyp"),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rejects_invalid_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, data: &[u8]| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, data).unwrap();
+            path
+        };
+        let empty = write("empty.jpg", b"");
+        let truncated_html = write("error.jpg", b"<html>");
+        let jpeg = write("photo.jpg", &[0xFF, 0xD8, 0xFF, 0xE0, 0, 0x10]);
+        let unknown = write("notes.txt", b"anything");
+        assert!(adoption_problem(&empty, None).await.is_some());
+        assert!(adoption_problem(&truncated_html, None).await.is_some());
+        assert_eq!(adoption_problem(&jpeg, None).await, None);
+        assert_eq!(adoption_problem(&unknown, None).await, None);
+        assert!(adoption_problem(&jpeg, Some("00")).await.is_some());
+    }
+}
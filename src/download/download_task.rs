@@ -1,19 +1,41 @@
+use crate::download::hls::{download_hls, is_hls};
+use crate::download::scheduler::Scheduler;
+use crate::download::sink::MediaSink;
+use crate::download::throttle::RateLimiter;
+use crate::events::{DownloadId, ProgressEvents};
+use crate::progress::Progress;
+use indicatif::ProgressBar;
 use reqwest::Client;
+use sha2::{Digest, Sha256};
 use std::fmt::Debug;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use tempfile::NamedTempFile;
 use thiserror::Error;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
+use tokio_util::sync::CancellationToken;
 use url::Url;
 
+/// The temporary files of downloads in progress are named `.partial-<random>`
+const PARTIAL_PREFIX: &str = ".partial-";
+
 pub struct CompletedDownload {
     /// The destination the file was saved at
     pub saved_at: PathBuf,
     /// Number of bytes written
     pub written: usize,
+    /// SHA-256 hash (hex) of the file
+    pub sha256: String,
+    /// The Wayback Machine snapshot the file was recovered from, if the original was unavailable
+    pub recovered_from: Option<Url>,
 }
 
+/// A download that still has to be stored, still in its temporary file
+pub type Downloaded = (NamedTempFile, CompletedDownload);
+
 #[derive(Debug, Error)]
 pub enum DownloadError {
     #[error("Destination already exists: {0}")]
@@ -30,6 +52,20 @@ pub enum DownloadError {
     ),
     #[error("Received unsuccessful response code: {0}")]
     BadResponse(u16, Url),
+    #[error("Unable to encrypt file: {0:#}")]
+    EncryptionError(anyhow::Error),
+    #[error("Unable to store file: {0:#}")]
+    StorageError(anyhow::Error),
+    #[error("Unable to download HLS playlist with ffmpeg: {0}")]
+    FfmpegError(String),
+    #[error("Connection closed after {0} of {1} bytes")]
+    Truncated(usize, u64),
+    #[error("Timed out after {}", humantime::format_duration(*.0))]
+    TimedOut(Duration),
+    #[error("Stalled, no data received for {}", humantime::format_duration(*.0))]
+    Stalled(Duration),
+    #[error("Cancelled")]
+    Cancelled,
 }
 
 pub struct DownloadTask<C> {
@@ -41,47 +77,359 @@ pub struct DownloadTask<C> {
     pub destination: PathBuf,
     /// Arbitrary data to pass through
     pub context: C,
+    /// Identifies the download in the progress events
+    pub id: DownloadId,
     /// Whether to overwrite an existing file (will return error otherwise)
     pub overwrite: bool,
+    /// Where the downloaded file is stored
+    pub sink: Arc<dyn MediaSink>,
+    /// Where to report the progress of the download
+    pub progress: Progress,
+    /// Limits the combined throughput of downloads
+    pub throttle: Option<RateLimiter>,
+    /// Decides when the download may start
+    pub scheduler: Scheduler,
+    /// Whether to fall back to the Wayback Machine if the media no longer exists
+    pub wayback_fallback: bool,
+    /// The ffmpeg binary, used to download HLS playlists
+    pub ffmpeg: PathBuf,
+    /// Stops the download, removing the partially downloaded file. Downloads that have already
+    /// started are given `shutdown_timeout` to complete first.
+    pub cancel: CancellationToken,
+    pub shutdown_timeout: Duration,
+    /// Limits each attempt at the download, so that a hung connection can't hold a slot forever
+    pub timeout: Option<Duration>,
+    /// How long the server may go without sending anything
+    pub stall_timeout: Duration,
+    /// Further attempts made after a timeout or stall
+    pub retries: usize,
 }
 
 impl<C> DownloadTask<C> {
     pub async fn download(self) -> (Result<CompletedDownload, DownloadError>, C) {
-        let result = download_impl(self.destination, self.url, self.client, self.overwrite).await;
+        let name = self
+            .destination
+            .file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let result = self.download_until_cancelled(&name).await;
+        self.progress.inc();
         (result, self.context)
     }
+
+    /// Downloads that have started when cancelled are given `shutdown_timeout` to complete
+    async fn download_until_cancelled(
+        &self,
+        name: &str,
+    ) -> Result<CompletedDownload, DownloadError> {
+        let started = AtomicBool::new(false);
+        let download = self.download_file(name, &started);
+        tokio::pin!(download);
+        let result = tokio::select! {
+            biased;
+            _ = self.cancel.cancelled() => None,
+            result = &mut download => Some(result),
+        };
+        match result {
+            Some(result) => result,
+            None if started.load(Ordering::Relaxed) => {
+                tokio::time::timeout(self.shutdown_timeout, download)
+                    .await
+                    .unwrap_or(Err(DownloadError::Cancelled))
+            }
+            None => Err(DownloadError::Cancelled),
+        }
+    }
+
+    async fn download_file(
+        &self,
+        name: &str,
+        started: &AtomicBool,
+    ) -> Result<CompletedDownload, DownloadError> {
+        let _slot = self.scheduler.acquire(&self.url).await;
+        started.store(true, Ordering::Relaxed);
+        let bar = self.progress.file(name);
+        let mut events = ProgressEvents::new(self.id.clone());
+        let mut attempts = 0;
+        let mut result = loop {
+            match self.attempt(&bar, &mut events).await {
+                Err(
+                    e @ (DownloadError::TimedOut(_)
+                    | DownloadError::Stalled(_)
+                    | DownloadError::Truncated(..)),
+                ) if attempts < self.retries => {
+                    attempts += 1;
+                    log::warn!(
+                        "{} ({}), retrying ({}/{})",
+                        e,
+                        self.url,
+                        attempts,
+                        self.retries
+                    );
+                    bar.reset();
+                    events.reset();
+                }
+                result => break result,
+            }
+        };
+        if let Err(DownloadError::BadResponse(404, _)) = result {
+            if self.wayback_fallback {
+                result = self.recover(&bar, &mut events).await.unwrap_or(result);
+            }
+        }
+        bar.finish_and_clear();
+        let (temp, completed) = result?;
+        self.store(temp).await?;
+        Ok(completed)
+    }
+
+    /// Downloads the file once, within the `timeout`
+    async fn attempt(
+        &self,
+        bar: &ProgressBar,
+        events: &mut ProgressEvents,
+    ) -> Result<Downloaded, DownloadError> {
+        let download = async {
+            if is_hls(&self.url) {
+                download_hls(self.destination.clone(), &self.url, &self.ffmpeg).await
+            } else {
+                download_impl(
+                    self.destination.clone(),
+                    self.url.clone(),
+                    self.client.clone(),
+                    bar,
+                    events,
+                    self.throttle.as_ref(),
+                    self.stall_timeout,
+                )
+                .await
+            }
+        };
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, download)
+                .await
+                .unwrap_or(Err(DownloadError::TimedOut(timeout))),
+            None => download.await,
+        }
+    }
+
+    /// Hands the completely downloaded file over to the sink
+    async fn store(&self, temp: NamedTempFile) -> Result<(), DownloadError> {
+        let key = format!("{}/{}", self.id.username, self.id.path);
+        if !self.overwrite && self.sink.exists(&self.destination, &key).await? {
+            return Err(DownloadError::DestinationExists(self.destination.clone()));
+        }
+        self.sink.persist(temp, &self.destination, &key).await
+    }
+
+    /// Downloads the media from the Wayback Machine instead, returns `None` if it wasn't archived
+    async fn recover(
+        &self,
+        bar: &ProgressBar,
+        events: &mut ProgressEvents,
+    ) -> Option<Result<Downloaded, DownloadError>> {
+        let snapshot = match crate::wayback::find_snapshot(&self.client, &self.url).await {
+            Ok(snapshot) => snapshot?,
+            Err(e) => {
+                log::warn!(
+                    "Unable to query the Wayback Machine for {}: {:#}",
+                    self.url,
+                    e
+                );
+                return None;
+            }
+        };
+        log::info!("Recovering {} from {}", self.url, snapshot);
+        bar.reset();
+        events.reset();
+        let result = download_impl(
+            self.destination.clone(),
+            snapshot.clone(),
+            self.client.clone(),
+            bar,
+            events,
+            self.throttle.as_ref(),
+            self.stall_timeout,
+        )
+        .await;
+        Some(result.map(|(temp, completed)| {
+            let completed = CompletedDownload {
+                recovered_from: Some(snapshot),
+                ..completed
+            };
+            (temp, completed)
+        }))
+    }
 }
 
 async fn download_impl(
     destination: PathBuf,
     url: Url,
     client: Client,
-    overwrite: bool,
-) -> Result<CompletedDownload, DownloadError> {
+    bar: &ProgressBar,
+    events: &mut ProgressEvents,
+    throttle: Option<&RateLimiter>,
+    stall_timeout: Duration,
+) -> Result<Downloaded, DownloadError> {
+    let stalled = || DownloadError::Stalled(stall_timeout);
     let parent = destination
         .parent()
         .ok_or_else(|| DownloadError::InvalidDestination(destination.clone()))?;
-    let temp = NamedTempFile::new_in(parent).map_err(DownloadError::FileError)?;
+    tokio::fs::create_dir_all(parent)
+        .await
+        .map_err(DownloadError::FileError)?;
+    let temp = partial_file(parent)?;
     let mut file = File::from_std(temp.reopen().map_err(DownloadError::FileError)?);
-    let mut request = client.get(url.clone()).send().await?;
+    let mut request = tokio::time::timeout(stall_timeout, client.get(url.clone()).send())
+        .await
+        .map_err(|_| stalled())??;
     if !request.status().is_success() {
         return Err(DownloadError::BadResponse(request.status().as_u16(), url));
     }
+    let expected = request.content_length();
+    if let Some(length) = expected {
+        bar.set_length(length);
+        events.set_length(length);
+    }
     let mut written = 0;
-    while let Some(chunk) = request.chunk().await? {
+    // Hashed as it is written, rather than reading the file back afterwards
+    let mut hasher = Sha256::new();
+    loop {
+        let chunk = tokio::time::timeout(stall_timeout, request.chunk())
+            .await
+            .map_err(|_| stalled())?;
+        let chunk = match (chunk, expected) {
+            (Ok(Some(chunk)), _) => chunk,
+            (Ok(None), _) => break,
+            // The connection was closed early
+            (Err(e), Some(expected)) if e.is_body() && (written as u64) < expected => {
+                return Err(DownloadError::Truncated(written, expected))
+            }
+            (Err(e), _) => return Err(e.into()),
+        };
         written += chunk.len();
-        file.write(chunk.as_ref())
+        hasher.update(&chunk);
+        bar.inc(chunk.len() as u64);
+        events.inc(chunk.len() as u64);
+        if let Some(throttle) = throttle {
+            throttle.consume(chunk.len()).await;
+        }
+        file.write_all(chunk.as_ref())
             .await
             .map_err(DownloadError::FileError)?;
     }
-    file.flush().await.map_err(DownloadError::FileError)?;
-    if !overwrite && destination.exists() {
-        return Err(DownloadError::DestinationExists(destination));
+    if let Some(expected) = expected.filter(|expected| *expected != written as u64) {
+        return Err(DownloadError::Truncated(written, expected));
     }
-    temp.persist(&destination)
-        .map_err(|e| DownloadError::FileError(e.error))?;
-    Ok(CompletedDownload {
+    file.flush().await.map_err(DownloadError::FileError)?;
+    crate::encryption::encrypt_file(temp.path())
+        .await
+        .map_err(DownloadError::EncryptionError)?;
+    let completed = CompletedDownload {
         saved_at: destination,
         written,
-    })
+        sha256: hex::encode(hasher.finalize()),
+        recovered_from: None,
+    };
+    Ok((temp, completed))
+}
+
+/// Creates the temporary file a download is written to, until it is complete
+pub fn partial_file(parent: &Path) -> Result<NamedTempFile, DownloadError> {
+    tempfile::Builder::new()
+        .prefix(PARTIAL_PREFIX)
+        .tempfile_in(parent)
+        .map_err(DownloadError::FileError)
+}
+
+/// Removes the partial downloads left behind in the user folder (and its sub folders) when the
+/// process was killed, returning how many were removed
+pub fn remove_partial_files(dir: &Path) -> std::io::Result<usize> {
+    let mut removed = 0;
+    if !dir.is_dir() {
+        return Ok(removed);
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            removed += remove_partial_files(&path)?;
+        } else if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(PARTIAL_PREFIX)
+        {
+            std::fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    /// Serves a single response that promises 10 bytes, sending only the `body` given
+    async fn serve(body: &'static [u8], hang: bool) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let url = Url::parse(&format!("http://{addr}/media.jpg")).unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = socket.read(&mut request).await;
+            let head = "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n";
+            socket.write_all(head.as_bytes()).await.unwrap();
+            socket.write_all(body).await.unwrap();
+            if hang {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+        });
+        url
+    }
+
+    async fn download(url: Url, dir: &Path) -> Result<Downloaded, DownloadError> {
+        let id = DownloadId {
+            username: "alice".to_string(),
+            tweet_id: 1,
+            media_id: 2,
+            path: "media.jpg".to_string(),
+        };
+        download_impl(
+            dir.join("media.jpg"),
+            url,
+            Client::builder().no_proxy().build().unwrap(),
+            &ProgressBar::hidden(),
+            &mut ProgressEvents::new(id),
+            None,
+            Duration::from_millis(200),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn detects_stalls() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = serve(b"abc", true).await;
+        let result = download(url, dir.path()).await;
+        assert!(matches!(result, Err(DownloadError::Stalled(_))));
+        // The partial file is removed
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn detects_truncation() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = serve(b"abc", false).await;
+        let result = download(url, dir.path()).await;
+        assert!(matches!(result, Err(DownloadError::Truncated(3, 10))));
+
+        let url = serve(b"0123456789", false).await;
+        let (_, completed) = download(url, dir.path()).await.unwrap();
+        assert_eq!(completed.written, 10);
+        assert_eq!(completed.sha256, hex::encode(Sha256::digest(b"0123456789")));
+    }
 }
@@ -0,0 +1,46 @@
+//! `--dry-run`: reports what a sync would download, without writing anything
+
+use crate::download::queue;
+use crate::download::quota::estimate;
+use crate::download::selection::MediaSelection;
+use crate::events::{self, Event};
+use crate::model::DataFile;
+use crate::MediaArgs;
+use reqwest::Client;
+
+/// Sizes the media that would be downloaded from the (merged, but unsaved) data file, using `HEAD`
+/// requests
+pub async fn report(
+    username: &str,
+    data_file: &DataFile,
+    new_tweets: usize,
+    args: &MediaArgs,
+    connection_pool: &Client,
+) -> anyhow::Result<()> {
+    let selection = MediaSelection::new(args)?;
+    let candidates = queue(&selection, &data_file.tweets, args).await?;
+    let urls = candidates.iter().map(|(_, _, url, _, _)| url);
+    let (bytes, unknown_sizes) = estimate(connection_pool, urls, args.concurrency).await;
+    for (_, _, url, filename, _) in &candidates {
+        log::debug!("Would download {} to {}", url, filename);
+    }
+    let unknown = match unknown_sizes {
+        0 => String::new(),
+        count => format!(", {count} of unknown size"),
+    };
+    log::info!(
+        "Would download {} files ({}{}) for {}",
+        candidates.len(),
+        indicatif::HumanBytes(bytes),
+        unknown,
+        username
+    );
+    events::emit(Event::DryRun {
+        username,
+        new_tweets,
+        files: candidates.len(),
+        bytes,
+        unknown_sizes,
+    });
+    Ok(())
+}
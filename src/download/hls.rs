@@ -0,0 +1,61 @@
+use crate::download::download_task::{partial_file, CompletedDownload, DownloadError, Downloaded};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::process::Command;
+use url::Url;
+
+/// Whether the URL is an HLS playlist, which can't be downloaded directly
+pub fn is_hls(url: &Url) -> bool {
+    url.path().ends_with(".m3u8")
+}
+
+/// Uses ffmpeg to download the segments of an HLS playlist, muxing them into an mp4
+pub async fn download_hls(
+    destination: PathBuf,
+    url: &Url,
+    ffmpeg: &Path,
+) -> Result<Downloaded, DownloadError> {
+    let parent = destination
+        .parent()
+        .ok_or_else(|| DownloadError::InvalidDestination(destination.clone()))?;
+    tokio::fs::create_dir_all(parent)
+        .await
+        .map_err(DownloadError::FileError)?;
+    // Refused before anything is requested, rather than bypassing the proxy
+    let proxy =
+        crate::proxy::ffmpeg_args().map_err(|e| DownloadError::FfmpegError(e.to_string()))?;
+    let temp = partial_file(parent)?;
+    let output = Command::new(ffmpeg)
+        .args(["-hide_banner", "-loglevel", "error", "-y"])
+        .args(proxy)
+        .args(["-i", url.as_str()])
+        // Copy the streams as is, only fixing up the AAC headers for the mp4 container
+        .args(["-c", "copy", "-bsf:a", "aac_adtstoasc", "-f", "mp4"])
+        .arg(temp.path())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        // So that ffmpeg is stopped if the download is cancelled
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| DownloadError::FfmpegError(format!("{}: {e}", ffmpeg.display())))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(DownloadError::FfmpegError(stderr.trim().to_string()));
+    }
+    let mut hasher = Sha256::new();
+    let mut file = temp.reopen().map_err(DownloadError::FileError)?;
+    let written = std::io::copy(&mut file, &mut hasher).map_err(DownloadError::FileError)? as usize;
+    crate::encryption::encrypt_file(temp.path())
+        .await
+        .map_err(DownloadError::EncryptionError)?;
+    let completed = CompletedDownload {
+        saved_at: destination,
+        written,
+        sha256: hex::encode(hasher.finalize()),
+        recovered_from: None,
+    };
+    Ok((temp, completed))
+}
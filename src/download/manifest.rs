@@ -0,0 +1,134 @@
+use crate::model::{DataFile, MediaType};
+use anyhow::Context;
+use chrono::{TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::Path;
+use tokio::fs;
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+const DESCRIPTION: &str = "This folder is a twitter-dl archive of a single Twitter account. \
+    `tweets.json` lists every archived tweet (id, unix timestamp, text) along with its media; \
+    each downloaded media item's `file_name` refers to a file within this folder.";
+
+/// A human readable summary of an account folder, so that archives remain self describing
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Manifest {
+    pub description: String,
+    pub user_id: u64,
+    /// Every username this account has been downloaded under
+    pub aliases: BTreeSet<String>,
+    pub first_tweet: Option<String>,
+    pub last_tweet: Option<String>,
+    pub tweet_count: usize,
+    pub media_count: usize,
+    pub downloaded_count: usize,
+    pub tool_version: String,
+    pub updated_at: String,
+    pub settings: ManifestSettings,
+    /// Whether the data file and media are encrypted (see `encryption.rs`)
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+/// The settings used for the most recent sync
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ManifestSettings {
+    pub api: String,
+    pub media_types: Vec<MediaType>,
+    pub threads: bool,
+}
+
+impl Manifest {
+    async fn load(user_dir: &Path) -> Option<(String, Manifest)> {
+        let text = fs::read_to_string(user_dir.join(MANIFEST_FILE))
+            .await
+            .ok()?;
+        let manifest = serde_json::from_str::<Manifest>(&text).ok()?;
+        Some((text, manifest))
+    }
+
+    pub async fn write(
+        user_dir: &Path,
+        username: &str,
+        data_file: &DataFile,
+        settings: ManifestSettings,
+    ) -> anyhow::Result<()> {
+        let previous = Self::load(user_dir).await;
+        let mut aliases = previous
+            .as_ref()
+            .map(|(_, m)| m.aliases.clone())
+            .unwrap_or_default();
+        aliases.insert(username.to_string());
+        let format_timestamp = |ts: i64| Utc.timestamp(ts, 0).to_rfc3339();
+        let media = data_file.tweets.iter().flat_map(|t| t.media.iter());
+        let mut manifest = Manifest {
+            description: DESCRIPTION.to_string(),
+            user_id: data_file.user_id,
+            aliases,
+            first_tweet: data_file
+                .tweets
+                .first()
+                .map(|t| format_timestamp(t.timestamp)),
+            last_tweet: data_file
+                .tweets
+                .last()
+                .map(|t| format_timestamp(t.timestamp)),
+            tweet_count: data_file.tweets.len(),
+            media_count: media.clone().count(),
+            downloaded_count: media.filter(|m| m.file_name.is_some()).count(),
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            updated_at: Utc::now().to_rfc3339(),
+            settings,
+            encrypted: crate::encryption::enabled(),
+        };
+        let text = serde_json::to_string_pretty(&manifest).unwrap();
+        // Only the time changes on a sync that found nothing new, which is left as it was so that
+        // the manifest only shows up in diffs of the archive when something has changed
+        if let Some((previous_text, previous)) = previous {
+            manifest.updated_at = previous.updated_at;
+            let unchanged = serde_json::to_string_pretty(&manifest).unwrap();
+            if unchanged == previous_text {
+                return Ok(());
+            }
+        }
+        fs::write(user_dir.join(MANIFEST_FILE), &text)
+            .await
+            .context("Unable to write manifest file")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::{data_file, USERNAME};
+
+    fn settings() -> ManifestSettings {
+        ManifestSettings {
+            api: "v1".to_string(),
+            media_types: vec![MediaType::Photo, MediaType::Video],
+            threads: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn unchanged_manifest_is_not_rewritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_file = data_file();
+        Manifest::write(dir.path(), USERNAME, &data_file, settings())
+            .await
+            .unwrap();
+        let first = fs::read_to_string(dir.path().join(MANIFEST_FILE))
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        Manifest::write(dir.path(), USERNAME, &data_file, settings())
+            .await
+            .unwrap();
+        let second = fs::read_to_string(dir.path().join(MANIFEST_FILE))
+            .await
+            .unwrap();
+        assert_eq!(first, second);
+    }
+}
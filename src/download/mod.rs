@@ -1,79 +1,217 @@
-use crate::download::download_task::{DownloadError, DownloadTask};
-use crate::model::{DataFile, MediaType, MODEL_VERSION};
+//! The download pipeline: fetching an account's new tweets, merging them into its data file and
+//! downloading the selected media into its folder
+
+use crate::cancel::{cancellable, Cancelled};
+use crate::download::adopt::adoption_problem;
+use crate::download::download_task::{remove_partial_files, DownloadError, DownloadTask};
+use crate::download::manifest::{Manifest, ManifestSettings};
+use crate::download::profile::update_profile;
+pub use crate::download::quota::reset_disk_usage;
+pub use crate::download::scheduler::Scheduler;
+pub use crate::download::selection::MediaSelection;
+pub use crate::download::throttle::RateLimiter;
+pub use crate::download::trash::{new_trash_folder, trash, undo};
+use crate::events::{self, DownloadId, Event};
+use crate::filename::sanitize_username;
+use crate::lock;
+use crate::messages::{self, Message};
+use crate::model::{DataFile, MediaType, Profile, Tweet, MODEL_VERSION};
+use crate::progress::Progress;
+use crate::proxy;
+use crate::rclone::Remote;
+use crate::report::{self, FailedDownload, SkippedFile};
+use crate::telegram::{Summary, Telegram};
+use crate::twitter::extractor::ExtractorClient;
+use crate::twitter::graphql::TwitterClientGraphql;
+use crate::twitter::guest::TwitterClientGuest;
+use crate::twitter::nitter::NitterClient;
 use crate::twitter::v1::TwitterClientV1;
 use crate::twitter::v2::TwitterClientV2;
 use crate::twitter::Authentication;
-use crate::twitter::TwitterClient;
-use crate::{DownloadArgs, FileExistsPolicy};
+use crate::twitter::{TwitterClient, UserError};
+use crate::{Backend, DownloadArgs, DownloadOrder, FileExistsPolicy, MediaArgs};
 use anyhow::{bail, Context};
-use futures::{stream, StreamExt};
+use futures::{future, stream, Stream, StreamExt};
 use reqwest::Client;
 use std::collections::BTreeSet;
-use std::path::Path;
-use std::time::Duration;
+use std::fs::FileTimes;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use tokio::fs;
+use tokio_util::sync::CancellationToken;
+use url::Url;
 
+mod adopt;
 mod download_task;
+mod dry_run;
+mod hls;
+mod manifest;
+mod profile;
+mod prune;
+mod quota;
+mod scheduler;
+mod selection;
+mod sink;
+mod throttle;
+mod trash;
+mod tweet_list;
+
+/// Syncs every account given with `--users` or `--list`, as the `download` command does
+pub async fn download(args: DownloadArgs, cancel: &CancellationToken) -> anyhow::Result<()> {
+    let client = setup(&args).await?;
+    // The tweet list can be given on its own
+    let usernames = match (&args.users, &args.list, &args.tweet_list) {
+        (None, None, Some(_)) => Vec::new(),
+        _ => parse_usernames(&args).await?,
+    };
+
+    let connection_pool = connection_pool();
 
-pub async fn download(args: DownloadArgs) -> anyhow::Result<()> {
+    if !args.dry_run {
+        report::start();
+    }
+    let result = async {
+        let mut syncs = sync_accounts(&usernames, &args, client.as_ref(), &connection_pool, cancel);
+        while let Some((account, result)) = syncs.next().await {
+            if let Err(e) = result {
+                if args.continue_on_error && !e.is::<Cancelled>() {
+                    log::warn!("Error downloading tweets for: {}, ignoring...", account);
+                } else {
+                    return Err(e);
+                }
+            }
+        }
+        if let Some(list) = &args.tweet_list {
+            let sync = tweet_list::download_tweet_list(
+                list,
+                &args,
+                client.as_ref(),
+                &connection_pool,
+                cancel,
+            );
+            sync.await?;
+        }
+        Ok(())
+    }
+    .await;
+    // Also when the run stops early, as that's when the report is most useful
+    report::finish(&args.out).await?;
+    result
+}
+
+/// Syncs the accounts, `--account-concurrency` at a time, yielding each account's result as it
+/// finishes. Dropping the stream stops the syncs that are still running.
+pub fn sync_accounts<'a>(
+    usernames: &'a [String],
+    args: &'a DownloadArgs,
+    twitter: &'a dyn TwitterClient,
+    connection_pool: &'a Client,
+    cancel: &'a CancellationToken,
+) -> impl Stream<Item = (&'a str, anyhow::Result<()>)> + Unpin + 'a {
+    let syncs = usernames.iter().map(move |username| async move {
+        let result = download_account(username, args, twitter, connection_pool, cancel).await;
+        (username.as_str(), result)
+    });
+    Box::pin(stream::iter(syncs).buffer_unordered(args.account_concurrency.max(1)))
+}
+
+/// The ids to fetch the tweets between: those newer than the `latest` stored tweet, within
+/// `--since-date` and `--until-date` (older tweets aren't downloaded, so there's no need to page
+/// through them). When `--since-date` reaches back before the oldest stored tweet (e.g. an earlier
+/// run used a later date), the whole range is fetched so that the gap is filled. `None` when every
+/// tweet in the range is already known.
+fn fetch_range(
+    data_file: &DataFile,
+    latest: Option<u64>,
+    args: &MediaArgs,
+) -> Option<(Option<u64>, Option<u64>)> {
+    let first_id = |date| crate::twitter::first_id_at(args.timezone.start_of_day(date));
+    let since_date_id = args.since_date.and_then(first_id);
+    let until_id = args.until_date.and_then(first_id);
+    let since_id = match (latest, since_date_id) {
+        (Some(latest), Some(since_date_id)) => {
+            let covered = data_file
+                .tweets
+                .first()
+                .is_some_and(|oldest| oldest.id <= since_date_id);
+            Some(match covered {
+                true => latest.max(since_date_id),
+                false => since_date_id,
+            })
+        }
+        (latest, since_date_id) => latest.or(since_date_id),
+    };
+    match (since_id, until_id) {
+        (Some(since_id), Some(until_id)) if since_id >= until_id => None,
+        range => Some(range),
+    }
+}
+
+/// Validates the arguments, and creates the client for the selected backend
+pub async fn setup(args: &DownloadArgs) -> anyhow::Result<Box<dyn TwitterClient>> {
     if !args.out.is_dir() {
         bail!("Destination must be a directory");
     }
-    let auth = fs::read_to_string(&args.auth)
-        .await
-        .context("Unable to read auth file")?;
-    let auth =
-        serde_json::from_str::<Authentication>(&auth).context("Unable to deserialize auth file")?;
-    let usernames = parse_usernames(&args).await?;
-
-    let client: Box<dyn TwitterClient> = if args.api_v2 {
-        log::info!("Using Twitter API v2");
-        Box::new(TwitterClientV2::new(&auth)?)
+    let backend = if args.api_v2 {
+        Backend::V2
     } else {
-        log::info!("Using Twitter API v1.1");
-        Box::new(TwitterClientV1::new(&auth))
+        args.backend
     };
+    if args.threads && backend != Backend::V2 {
+        bail!("Downloading threads requires Twitter API v2");
+    }
+    create_client(args, backend).await
+}
 
-    let mut media_types = Vec::new();
-    if args.photos {
-        media_types.push(MediaType::Photo);
+async fn create_client(
+    args: &DownloadArgs,
+    backend: Backend,
+) -> anyhow::Result<Box<dyn TwitterClient>> {
+    if backend == Backend::Nitter {
+        let url = args
+            .nitter_url
+            .clone()
+            .context("A Nitter instance must be specified with `--nitter-url`")?;
+        log::info!("Using Nitter instance: {}", url);
+        return Ok(Box::new(NitterClient::new(url)?));
     }
-    if args.videos {
-        media_types.push(MediaType::Video);
+    if backend == Backend::Extractor {
+        let config = args
+            .extractor
+            .as_ref()
+            .context("An extractor config must be specified with `--extractor`")?;
+        return Ok(Box::new(ExtractorClient::load(config).await?));
     }
-    if args.gifs {
-        media_types.push(MediaType::Gif)
+    if backend == Backend::Guest {
+        log::info!("Using guest access");
+        return Ok(Box::new(TwitterClientGuest::new()?));
     }
-
-    let connection_pool = Client::builder()
-        .connect_timeout(Duration::from_secs(3))
-        .build()
-        .unwrap();
-
-    for account in usernames {
-        if let Err(e) = download_account(
-            &account,
-            args.concurrency,
-            &media_types,
-            &args.out,
-            args.rescan,
-            client.as_ref(),
-            &args.file_exists_policy,
-            &connection_pool,
-        )
-        .await
-        {
-            if args.continue_on_error {
-                log::warn!("Error downloading tweets for: {}, ignoring...", account);
-            } else {
-                return Err(e);
+    let auth = match &args.auth_data {
+        Some(text) => Authentication::parse(text)?,
+        None => Authentication::load(&args.auth).await?,
+    };
+    Ok(match backend {
+        Backend::V2 => {
+            log::info!("Using Twitter API v2");
+            Box::new(TwitterClientV2::new(&auth)?)
+        }
+        Backend::Graphql => {
+            log::info!("Using Twitter GraphQL API");
+            Box::new(TwitterClientGraphql::new(&auth)?.with_spaces(args.media.audio))
+        }
+        _ => {
+            // egg-mode makes its own connections
+            if proxy::api_enabled() {
+                bail!("Twitter API v1.1 can't be used with a proxy, use another `--backend`");
             }
+            log::info!("Using Twitter API v1.1");
+            Box::new(TwitterClientV1::new(&auth)?)
         }
-    }
-    Ok(())
+    })
 }
 
-async fn parse_usernames(args: &DownloadArgs) -> anyhow::Result<Vec<String>> {
+/// The accounts given with `--users` and `--list`, sorted and without duplicates
+pub async fn parse_usernames(args: &DownloadArgs) -> anyhow::Result<Vec<String>> {
     let mut account_names = BTreeSet::new();
     if let Some(users) = &args.users {
         users.split(',').for_each(|s| {
@@ -94,106 +232,992 @@ async fn parse_usernames(args: &DownloadArgs) -> anyhow::Result<Vec<String>> {
     Ok(account_names.into_iter().collect())
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn download_account(
+/// Creates the HTTP connection pool used for downloading media
+pub fn connection_pool() -> Client {
+    proxy::media(Client::builder())
+        .connect_timeout(Duration::from_secs(3))
+        .build()
+        .unwrap()
+}
+
+pub fn media_types(args: &MediaArgs) -> Vec<MediaType> {
+    let mut media_types = Vec::new();
+    if args.photos {
+        media_types.push(MediaType::Photo);
+    }
+    if args.videos {
+        media_types.push(MediaType::Video);
+    }
+    if args.gifs {
+        media_types.push(MediaType::Gif)
+    }
+    if args.audio {
+        media_types.push(MediaType::Audio)
+    }
+    media_types
+}
+
+/// Syncs one account into `<out>/<username>`: its profile, new tweets (and threads, with
+/// `--threads`) and the selected media, updating its data file and manifest as it goes. Returns
+/// an error wrapping [`Cancelled`] if `cancel` is triggered.
+pub async fn download_account(
     username: &str,
-    concurrency: usize,
-    media_types: &[MediaType],
-    out_dir: &Path,
-    rescan: bool,
+    args: &DownloadArgs,
     twitter: &'_ dyn TwitterClient,
-    file_exists_policy: &FileExistsPolicy,
     connection_pool: &Client,
+    cancel: &CancellationToken,
 ) -> anyhow::Result<()> {
-    let user_id = twitter
-        .get_id_for_username(username)
-        .await
-        .context("Unable to find user")?;
-    let user_dir = out_dir.join(username);
-    fs::create_dir_all(&user_dir)
-        .await
-        .context("Unable to create output directory")?;
+    events::emit(Event::AccountStarted { username });
+    let mut synced = AccountSync::default();
+    let result = download_account_impl(
+        username,
+        args,
+        twitter,
+        connection_pool,
+        cancel,
+        &mut synced,
+    )
+    .await;
+    let error = result.as_ref().err().map(|e| format!("{:#}", e));
+    report::account_finished(username, synced.new_tweets, error.clone());
+    let event = Event::AccountFinished {
+        username,
+        new_tweets: synced.new_tweets,
+        downloaded: synced.downloaded,
+        error: error.clone(),
+    };
+    // Only the real syncs are notified
+    let notify = !args.dry_run;
+    if let Some(url) = args.media.webhook_url.as_ref().filter(|_| notify) {
+        crate::webhook::post(connection_pool, url, &event).await;
+    }
+    events::emit(event);
+    if let (Some(token), Some(chat_id), true) =
+        (&args.telegram_token, &args.telegram_chat_id, notify)
+    {
+        let telegram = Telegram { token, chat_id };
+        let summary = Summary {
+            username,
+            new_tweets: synced.new_tweets,
+            downloaded: synced.downloaded,
+            error: error.as_deref(),
+        };
+        telegram.send(connection_pool, &summary).await;
+    }
+    result
+}
+
+/// What an account's sync did, as far as it got
+#[derive(Default)]
+struct AccountSync {
+    /// Including those from threads
+    new_tweets: usize,
+    /// Media files
+    downloaded: usize,
+}
+
+async fn download_account_impl(
+    username: &str,
+    args: &DownloadArgs,
+    twitter: &'_ dyn TwitterClient,
+    connection_pool: &Client,
+    cancel: &CancellationToken,
+    synced: &mut AccountSync,
+) -> anyhow::Result<()> {
+    let renamed;
+    let (username, profile) = match cancellable(cancel, twitter.get_user(username)).await {
+        Err(e)
+            if args.follow_renames && matches!(e.downcast_ref(), Some(UserError::NotFound(_))) =>
+        {
+            match follow_rename(username, args, twitter, cancel).await? {
+                Some((new_username, profile)) => {
+                    renamed = new_username;
+                    (renamed.as_str(), profile)
+                }
+                None => return Err(e).context("Unable to find user"),
+            }
+        }
+        profile => (username, profile.context("Unable to find user")?),
+    };
+    let user_id = profile.user_id;
+    let user_dir = account_dir(&args.out, username);
+    // Held until the sync completes, dry runs don't write anything
+    let _lock = if args.dry_run {
+        None
+    } else {
+        fs::create_dir_all(&user_dir)
+            .await
+            .context("Unable to create output directory")?;
+        Some(cancellable(cancel, lock::lock(&user_dir, args.wait_lock)).await?)
+    };
+    if !args.dry_run {
+        // Only for the viewer, so this shouldn't stop the sync
+        let update = update_profile(
+            &user_dir,
+            profile.clone(),
+            args.profile_media,
+            connection_pool,
+        );
+        match cancellable(cancel, update).await {
+            Err(e) if e.is::<Cancelled>() => return Err(e),
+            Err(e) => log::warn!("Unable to update profile for {}: {:#}", username, e),
+            Ok(()) => {}
+        }
+    }
     let mut data_file = DataFile::load(&user_dir, user_id)
         .await?
         .unwrap_or_else(|| DataFile::new(user_id));
-    let since_id = if rescan || data_file.version < MODEL_VERSION {
+    if !args.dry_run {
+        data_file.rotate_backups(&user_dir, args.backups).await?;
+    }
+    data_file.record_profile(&profile);
+    let latest = if args.rescan || data_file.version < MODEL_VERSION {
         log::info!("Refreshing all available tweets for {}", username);
         None
     } else {
         data_file.latest_tweet_id()
     };
-    let new_tweets = twitter.get_all_tweets_for_user(user_id, since_id).await?;
-    let new = data_file.merge_tweets(new_tweets);
-    log::info!("Got {:?} new tweets for {}", new, username);
+    // Threads and Wayback Machine captures want every tweet, not only those with media, and the
+    // tweets linking to Spaces aren't on the Media tab
+    let media_only = args.media_timeline
+        && !args.threads
+        && !args.submit_wayback
+        && !args.media.audio
+        && !media_types(&args.media).is_empty();
+    let new_tweets = match fetch_range(&data_file, latest, &args.media) {
+        Some((since_id, until_id)) => {
+            let fetch = if media_only {
+                twitter.get_media_tweets_for_user(
+                    username,
+                    user_id,
+                    since_id,
+                    until_id,
+                    args.max_tweets,
+                )
+            } else {
+                twitter.get_all_tweets_for_user(
+                    username,
+                    user_id,
+                    since_id,
+                    until_id,
+                    args.max_tweets,
+                )
+            };
+            cancellable(cancel, fetch).await?
+        }
+        None => Vec::new(),
+    };
+    let conversations = new_tweets
+        .iter()
+        .filter_map(|t| t.conversation_id)
+        .collect::<BTreeSet<_>>();
+    let new = data_file.merge_tweets(new_tweets, twitter.name());
+    synced.new_tweets += new;
+    log::info!(
+        "{}",
+        messages::format(
+            Message::NewTweets,
+            [("count", new.into()), ("username", username.into())]
+        )
+    );
+    events::emit(Event::TweetsFetched {
+        username,
+        new_tweets: new,
+        total_tweets: data_file.tweets.len(),
+    });
+    if args.threads {
+        let mut thread_tweets = Vec::new();
+        for conversation_id in conversations {
+            let mut tweets = cancellable(
+                cancel,
+                twitter.get_conversation_tweets(conversation_id, user_id),
+            )
+            .await
+            .context("Unable to fetch thread")?;
+            thread_tweets.append(&mut tweets);
+        }
+        let new = data_file.merge_tweets(thread_tweets, twitter.name());
+        synced.new_tweets += new;
+        log::info!(
+            "{}",
+            messages::format(
+                Message::NewThreadTweets,
+                [("count", new.into()), ("username", username.into())]
+            )
+        );
+    }
+    if args.dry_run {
+        let report = dry_run::report(
+            username,
+            &data_file,
+            synced.new_tweets,
+            &args.media,
+            connection_pool,
+        );
+        return cancellable(cancel, report).await;
+    }
     data_file.save(&user_dir).await?;
 
-    let mut downloads = vec![];
+    synced.downloaded = download_media(
+        username,
+        &user_dir,
+        &mut data_file,
+        &args.media,
+        connection_pool,
+        cancel,
+    )
+    .await?;
+    if args.submit_wayback {
+        let submit =
+            crate::wayback::submit_tweets(username, &user_dir, &mut data_file, connection_pool);
+        let result = cancellable(cancel, submit).await;
+        // Record the tweets that were submitted before being cancelled
+        data_file.save(&user_dir).await?;
+        result?;
+    }
 
-    for (tweet_index, tweet) in data_file.tweets.iter().enumerate() {
-        for (media_index, media) in tweet.media.iter().enumerate() {
-            if let Some((url, filename)) = media.is_download_candidate(tweet, media_types) {
-                downloads.push(DownloadTask {
-                    client: connection_pool.clone(),
-                    url,
-                    destination: user_dir.join(&filename),
-                    context: DownloadContext {
-                        tweet_index,
-                        media_index,
-                        filename,
-                    },
-                    overwrite: file_exists_policy == &FileExistsPolicy::Overwrite,
-                });
+    let settings = ManifestSettings {
+        api: twitter.name().to_string(),
+        media_types: media_types(&args.media),
+        threads: args.threads,
+    };
+    Manifest::write(&user_dir, username, &data_file, settings).await?;
+    if let Some(destination) = &args.rclone_remote {
+        let remote = Remote {
+            rclone: &args.rclone,
+            destination,
+            move_media: args.rclone_move,
+        };
+        cancellable(cancel, remote.upload(&user_dir, username)).await?;
+    }
+
+    Ok(())
+}
+
+/// `--follow-renames`: looks up the current username of an account previously synced as
+/// `username` (by the user id recorded in its folder), moving its folder to the new name. `None`
+/// when there is no previous sync, or the account wasn't renamed.
+async fn follow_rename(
+    username: &str,
+    args: &DownloadArgs,
+    twitter: &'_ dyn TwitterClient,
+    cancel: &CancellationToken,
+) -> anyhow::Result<Option<(String, Profile)>> {
+    let user_dir = account_dir(&args.out, username);
+    let user_id = match Profile::load(&user_dir).await? {
+        Some(profile) => profile.user_id,
+        None => match DataFile::load_unchecked(&user_dir).await? {
+            Some(data_file) => data_file.user_id,
+            None => return Ok(None),
+        },
+    };
+    let new_username = cancellable(cancel, twitter.get_username(user_id))
+        .await
+        .context("Unable to follow rename")?;
+    if new_username.eq_ignore_ascii_case(username) {
+        return Ok(None);
+    }
+    log::warn!(
+        "{} has been renamed to {}, update the accounts to sync",
+        username,
+        new_username
+    );
+    let new_dir = account_dir(&args.out, &new_username);
+    if args.dry_run {
+        log::info!("Would move {} to {}", user_dir.display(), new_dir.display());
+    } else if new_dir.exists() {
+        // e.g. the old name is already a symlink from a previous run
+        if !fs::symlink_metadata(&user_dir).await?.is_symlink() {
+            log::warn!(
+                "Not moving {} as {} already exists",
+                user_dir.display(),
+                new_dir.display()
+            );
+        }
+    } else {
+        fs::rename(&user_dir, &new_dir)
+            .await
+            .with_context(|| format!("Unable to move {}", user_dir.display()))?;
+        // Relative, so that the output folder can itself be moved
+        let target = new_dir.file_name().unwrap_or_default();
+        if let Err(e) = symlink_dir(Path::new(target), &user_dir) {
+            log::warn!("Unable to link {}: {}", user_dir.display(), e);
+        }
+    }
+    let profile = cancellable(cancel, twitter.get_user(&new_username))
+        .await
+        .context("Unable to find user")?;
+    Ok(Some((new_username, profile)))
+}
+
+/// The account's folder within the output folder, named after it as far as the file system allows
+pub fn account_dir(out: &Path, username: &str) -> PathBuf {
+    out.join(sanitize_username(username))
+}
+
+fn symlink_dir(target: &Path, link: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(target, link);
+    #[cfg(windows)]
+    return std::os::windows::fs::symlink_dir(target, link);
+}
+
+/// Downloads all the (not yet downloaded) media in a data file that match the selected types,
+/// returning the number of files downloaded
+pub async fn download_media(
+    username: &str,
+    user_dir: &Path,
+    data_file: &mut DataFile,
+    args: &MediaArgs,
+    connection_pool: &Client,
+    cancel: &CancellationToken,
+) -> anyhow::Result<usize> {
+    if crate::encryption::enabled()
+        && (args.ocr
+            || args.nsfw_command.is_some()
+            || args.render_html
+            || args.dedupe_perceptual
+            || args.embed_metadata
+            || args.objects.is_some())
+    {
+        bail!("`--ocr`, `--nsfw-command`, `--render-html`, `--dedupe-perceptual`, `--embed-metadata` and `--objects` aren't supported for encrypted archives")
+    }
+    let sink = sink::from_args(args, connection_pool)?;
+    match remove_partial_files(user_dir) {
+        Ok(0) => {}
+        Ok(removed) => log::info!(
+            "Removed {} partial downloads left by an earlier run for {}",
+            removed,
+            username
+        ),
+        Err(e) => log::warn!("Unable to remove partial downloads for {}: {}", username, e),
+    }
+    if args.repair {
+        let missing = data_file.clear_missing_files(user_dir);
+        if missing > 0 {
+            log::info!("Re-queued {} missing files for {}", missing, username);
+        }
+    }
+    if args.retry_unavailable {
+        let media = data_file.tweets.iter_mut().flat_map(|t| &mut t.media);
+        let retried = media.filter_map(|m| m.unavailable_since.take()).count();
+        if retried > 0 {
+            log::info!("Re-queued {} unavailable files for {}", retried, username);
+        }
+    }
+    let selection = MediaSelection::new(args)?;
+    if args.prune {
+        let pruned = prune::prune(user_dir, data_file, &selection).await?;
+        if pruned > 0 {
+            log::info!(
+                "Pruned {} files no longer selected for {}",
+                pruned,
+                username
+            );
+        }
+    }
+    let file_exists_policy = &args.file_exists_policy;
+    let candidates = queue(&selection, &data_file.tweets, args).await?;
+    // The media isn't stored on this disk otherwise
+    let mut reservation = None;
+    if quota::enabled(args) && sink.is_local() && !candidates.is_empty() {
+        let out = user_dir.parent().unwrap_or(user_dir);
+        let urls = candidates.iter().map(|(_, _, url, _, _)| url);
+        reservation = Some(quota::check(out, urls, args, connection_pool).await?);
+    }
+    if file_exists_policy == &FileExistsPolicy::Adopt {
+        for (tweet_index, media_index, _, filename, preview) in &candidates {
+            let path = user_dir.join(filename);
+            if !path.exists() {
+                continue;
+            }
+            let media = &data_file.tweets[*tweet_index].media[*media_index];
+            let sha256 = media.sha256.as_deref().filter(|_| !preview);
+            if let Some(problem) = adoption_problem(&path, sha256).await {
+                log::warn!(
+                    "Not adopting {} as {}, downloading it again",
+                    path.display(),
+                    problem
+                );
+                trash(user_dir, filename, None, None).await?;
             }
         }
     }
+    let progress = Progress::new(candidates.len(), username);
+    let throttle = args.limit_rate.map(|rate| RateLimiter::new(rate.0));
+    let scheduler = Scheduler::new(
+        Duration::from_millis(args.delay_ms),
+        args.per_host_concurrency,
+    );
+    let downloads = candidates
+        .into_iter()
+        .map(
+            |(tweet_index, media_index, url, filename, preview)| DownloadTask {
+                client: connection_pool.clone(),
+                url: url.clone(),
+                destination: user_dir.join(&filename),
+                id: DownloadId {
+                    username: username.to_string(),
+                    tweet_id: data_file.tweets[tweet_index].id,
+                    media_id: data_file.tweets[tweet_index].media[media_index].id,
+                    path: filename.clone(),
+                },
+                context: DownloadContext {
+                    url,
+                    tweet_index,
+                    media_index,
+                    filename,
+                    preview,
+                },
+                overwrite: file_exists_policy == &FileExistsPolicy::Overwrite,
+                sink: sink.clone(),
+                progress: progress.clone(),
+                throttle: throttle.clone(),
+                scheduler: scheduler.clone(),
+                wayback_fallback: args.wayback_fallback,
+                ffmpeg: args.ffmpeg.clone(),
+                cancel: cancel.clone(),
+                shutdown_timeout: args.shutdown_timeout.into(),
+                timeout: args.download_timeout.map(Into::into),
+                stall_timeout: args.stall_timeout.into(),
+                retries: args.download_retries,
+            },
+        )
+        .collect::<Vec<_>>();
 
     let mut counter = 0;
+    let mut unavailable = 0;
+    let mut cancelled = false;
+    let mut changed = BTreeSet::new();
+    // Webhook notifications are sent alongside the downloads, so a slow endpoint doesn't hold
+    // them up
+    let mut notifications = Vec::new();
     let mut buffered = stream::iter(downloads)
         .map(DownloadTask::download)
-        .buffer_unordered(concurrency);
+        .buffer_unordered(args.concurrency);
 
     while let Some((result, ctx)) = buffered.next().await {
+        let tweet_id = data_file.tweets[ctx.tweet_index].id;
+        let media_id = data_file.tweets[ctx.tweet_index].media[ctx.media_index].id;
+        let failed = |error: &dyn std::fmt::Display| {
+            events::emit(Event::DownloadFailed {
+                username,
+                tweet_id,
+                media_id,
+                error: error.to_string(),
+            });
+            report::failed(FailedDownload {
+                username: username.to_string(),
+                tweet_id,
+                media_id,
+                url: ctx.url.clone(),
+                path: ctx.filename.clone(),
+                error: error.to_string(),
+            });
+        };
         match result {
-            Ok(_completed) => {
-                data_file.tweets[ctx.tweet_index].media[ctx.media_index].file_name =
-                    Some(ctx.filename);
-                data_file.save(&user_dir).await.ok();
+            Ok(completed) => {
+                log::debug!(
+                    "Saved {} ({} bytes)",
+                    completed.saved_at.display(),
+                    completed.written
+                );
+                let event = Event::DownloadCompleted {
+                    username,
+                    tweet_id,
+                    media_id,
+                    path: &ctx.filename,
+                    media_type: data_file.tweets[ctx.tweet_index].media[ctx.media_index]
+                        .r#type
+                        .clone(),
+                    bytes: completed.written,
+                };
+                if let Some(url) = args.webhook_url.as_ref().filter(|_| !ctx.preview) {
+                    notifications.push(crate::webhook::post_in_background(
+                        connection_pool,
+                        url,
+                        &event,
+                    ));
+                }
+                events::emit(event);
+                report::downloaded(username);
+                if let Some(reservation) = reservation.as_mut() {
+                    reservation.downloaded(completed.written as u64);
+                }
+                if !args.no_mtime && sink.is_local() {
+                    let timestamp = data_file.tweets[ctx.tweet_index].timestamp;
+                    if let Err(e) = set_file_time(&completed.saved_at, timestamp) {
+                        log::warn!(
+                            "Unable to set the time of {}: {}",
+                            completed.saved_at.display(),
+                            e
+                        );
+                    }
+                }
+                let media = &mut data_file.tweets[ctx.tweet_index].media[ctx.media_index];
+                if ctx.preview {
+                    media.preview_file_name = Some(ctx.filename);
+                    data_file.save(user_dir).await.ok();
+                    counter += 1;
+                    continue;
+                }
+                media.file_name = Some(ctx.filename);
+                media.sha256 = Some(completed.sha256);
+                media.metadata_embedded = false;
+                media.recovered_from = completed.recovered_from;
+                // Restored (e.g. with `--retry-unavailable`) once none of its media is missing
+                let tweet = &mut data_file.tweets[ctx.tweet_index];
+                if tweet.media.iter().all(|m| m.unavailable_since.is_none()) {
+                    tweet.unavailable_since = None;
+                }
+                let tweet = &data_file.tweets[ctx.tweet_index];
+                // The file is downloaded either way, so a failing hook mustn't stop the others
+                if let Err(e) = selection.plugins().post_download(
+                    tweet,
+                    &tweet.media[ctx.media_index],
+                    &completed.saved_at,
+                ) {
+                    log::warn!(
+                        "Plugin failed after downloading {}: {:#}",
+                        completed.saved_at.display(),
+                        e
+                    );
+                }
+                data_file.save(user_dir).await.ok();
+                changed.insert(ctx.tweet_index);
                 counter += 1;
             }
             Err(e) => match e {
                 DownloadError::DestinationExists(..)
                     if file_exists_policy == &FileExistsPolicy::Adopt =>
                 {
-                    data_file.tweets[ctx.tweet_index].media[ctx.media_index].file_name =
-                        Some(ctx.filename);
-                    data_file.save(&user_dir).await.ok();
+                    let media = &mut data_file.tweets[ctx.tweet_index].media[ctx.media_index];
+                    if ctx.preview {
+                        media.preview_file_name = Some(ctx.filename);
+                    } else {
+                        media.sha256 = crate::verify::hash_file(&user_dir.join(&ctx.filename))
+                            .await
+                            .ok();
+                        media.file_name = Some(ctx.filename);
+                        media.metadata_embedded = false;
+                    }
+                    data_file.save(user_dir).await.ok();
                 }
-                DownloadError::DestinationExists(e)
+                DownloadError::DestinationExists(ref path)
                     if file_exists_policy == &FileExistsPolicy::Warn =>
                 {
-                    log::warn!("File: {} already exists, skipping", e.display());
+                    log::warn!("File: {} already exists, skipping", path.display());
+                    events::emit(Event::DownloadFailed {
+                        username,
+                        tweet_id,
+                        media_id,
+                        error: e.to_string(),
+                    });
+                    report::skipped(SkippedFile {
+                        username: username.to_string(),
+                        tweet_id,
+                        media_id,
+                        path: ctx.filename.clone(),
+                        reason: "already exists".to_string(),
+                    });
+                }
+                DownloadError::BadResponse(code @ (403 | 404), ref url) => {
+                    // Not much we can do, presumably the tweet has been deleted (or its media
+                    // withheld) at some point after we retrieved it. It's recorded so that it isn't
+                    // attempted again.
+                    log::warn!("File no longer available ({}): {}, skipping", code, url);
+                    events::emit(Event::DownloadFailed {
+                        username,
+                        tweet_id,
+                        media_id,
+                        error: e.to_string(),
+                    });
+                    report::unavailable(SkippedFile {
+                        username: username.to_string(),
+                        tweet_id,
+                        media_id,
+                        path: ctx.filename.clone(),
+                        reason: format!("no longer available ({code})"),
+                    });
+                    unavailable += 1;
+                    if !ctx.preview {
+                        let now = chrono::Utc::now().timestamp();
+                        let tweet = &mut data_file.tweets[ctx.tweet_index];
+                        tweet.unavailable_since.get_or_insert(now);
+                        tweet.media[ctx.media_index].unavailable_since = Some(now);
+                        data_file.save(user_dir).await.ok();
+                    }
                 }
-                DownloadError::BadResponse(c, url) if c == 404 => {
-                    // If there is a 404, not much we can do, presumably the tweet has
-                    // been deleted at some point after we retrieved it
-                    log::warn!("File no longer available (404): {}, skipping", url);
+                // The downloads that haven't started are cancelled straight away, those in progress
+                // are still recorded if they complete in time
+                DownloadError::Cancelled => cancelled = true,
+                _ => {
+                    failed(&e);
+                    return Err(e.into());
                 }
-                _ => return Err(e.into()),
             },
         }
     }
+    progress.finish();
+    future::join_all(notifications).await;
+    if cancelled {
+        data_file
+            .save(user_dir)
+            .await
+            .context("Error saving data file")?;
+        log::warn!(
+            "Stopped after downloading {} files for {}",
+            counter,
+            username
+        );
+        return Err(Cancelled.into());
+    }
+
+    if args.ocr {
+        crate::ocr::ocr_media(user_dir, data_file, &args.ocr_command, args.concurrency).await?;
+    }
+    if let Some(command) = &args.nsfw_command {
+        crate::nsfw::classify_media(user_dir, data_file, command, args).await?;
+    }
+    if let Some(mode) = args.dedupe {
+        crate::dedupe::dedupe_media(user_dir, data_file, mode, args.dedupe_perceptual).await?;
+    }
+    if args.embed_metadata {
+        crate::metadata::embed_metadata(username, user_dir, data_file, &args.ffmpeg).await?;
+    }
+    // After the steps that change the files, as the objects are shared
+    if let Some(link) = args.objects {
+        crate::objects::store_objects(user_dir, data_file, link).await?;
+    }
+    if args.write_metadata {
+        crate::sidecar::write_sidecars(username, user_dir, data_file).await?;
+    }
+    if args.render_html {
+        crate::render::render_tweets(username, user_dir, data_file, &selection, &changed).await?;
+    }
 
     data_file
-        .save(&user_dir)
+        .save(user_dir)
         .await
         .context("Error saving data file")?;
-    log::info!("Downloaded {} files for {}", counter, username);
+    log::info!(
+        "{}",
+        messages::format(
+            Message::Downloaded,
+            [("count", counter.into()), ("username", username.into())]
+        )
+    );
+    if unavailable > 0 {
+        log::info!(
+            "{} files are no longer available for {}",
+            unavailable,
+            username
+        );
+    }
+    Ok(counter)
+}
 
-    Ok(())
+/// Sets the file's modification time (and creation time, where it can be set) to the tweet's
+/// timestamp, so that sorting by date matches the order the tweets were posted in
+fn set_file_time(path: &Path, timestamp: i64) -> std::io::Result<()> {
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::FileTimesExt;
+    #[cfg(windows)]
+    use std::os::windows::fs::FileTimesExt;
+
+    let time = SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp.max(0) as u64);
+    let times = FileTimes::new().set_modified(time);
+    #[cfg(any(target_os = "macos", windows))]
+    let times = times.set_created(time);
+    std::fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_times(times)
 }
 
 struct DownloadContext {
+    pub url: Url,
     pub tweet_index: usize,
     pub media_index: usize,
     pub filename: String,
+    /// Whether this is the media's preview image, rather than the media itself
+    pub preview: bool,
+}
+
+/// The media to download, in `--order` and limited to `--max-files`, as (tweet index, media index,
+/// URL, path, whether it is a preview)
+async fn queue(
+    selection: &MediaSelection,
+    tweets: &[Tweet],
+    args: &MediaArgs,
+) -> anyhow::Result<Vec<(usize, usize, Url, String, bool)>> {
+    let mut candidates = selection
+        .candidates(tweets)
+        .await?
+        .into_iter()
+        .map(|(t, m, url, filename)| (t, m, url, filename, false))
+        .collect::<Vec<_>>();
+    let retried = |(t, m, ..): &(usize, usize, Url, String, bool)| {
+        args.retry
+            .as_ref()
+            .is_none_or(|retry| retry.contains(&(tweets[*t].id, tweets[*t].media[*m].id)))
+    };
+    candidates.retain(retried);
+    // The tweets are sorted oldest first, the sort is stable so each tweet's media stays in order
+    if args.order == DownloadOrder::Newest {
+        candidates.sort_by_key(|c| std::cmp::Reverse(c.0));
+    }
+    if let Some(max_files) = args.max_files {
+        candidates.truncate(max_files);
+    }
+    if args.thumbnails {
+        let previews = preview_candidates(tweets, &candidates);
+        candidates.extend(previews.into_iter().filter(retried));
+    }
+    Ok(candidates)
+}
+
+/// The preview images of the videos and gifs that have been, or are about to be, downloaded. Each
+/// is saved next to its video, e.g. `<tweet>_<media>_preview.jpg`
+fn preview_candidates(
+    tweets: &[Tweet],
+    candidates: &[(usize, usize, Url, String, bool)],
+) -> Vec<(usize, usize, Url, String, bool)> {
+    let mut previews = vec![];
+    for (tweet_index, tweet) in tweets.iter().enumerate() {
+        for (media_index, media) in tweet.media.iter().enumerate() {
+            let url = match (&media.preview_url, &media.preview_file_name) {
+                (Some(url), None) => url,
+                _ => continue,
+            };
+            let file_name = media.file_name.as_ref().or_else(|| {
+                candidates
+                    .iter()
+                    .find(|c| c.0 == tweet_index && c.1 == media_index)
+                    .map(|c| &c.3)
+            });
+            let file_name = match file_name {
+                Some(file_name) => file_name,
+                None => continue,
+            };
+            let stem = file_name
+                .rfind('.')
+                .map_or(file_name.as_str(), |idx| &file_name[..idx]);
+            let ext = url
+                .path()
+                .rfind('.')
+                .map_or("jpg", |idx| &url.path()[idx + 1..]);
+            let preview_name = format!("{stem}_preview.{ext}");
+            previews.push((tweet_index, media_index, url.clone(), preview_name, true));
+        }
+    }
+    previews
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::data_file;
+    use crate::model::{Media, Tweet};
+    use clap::Parser;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves `/gone.jpg` with `gone_status`, and any other path with a small body
+    async fn serve(gone_status: u16) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = [0; 1024];
+                let read = socket.read(&mut request).await.unwrap_or_default();
+                let request = String::from_utf8_lossy(&request[..read]);
+                let status = match request.starts_with("GET /gone.jpg") {
+                    true => gone_status,
+                    false => 200,
+                };
+                let response = format!(
+                    "HTTP/1.1 {status} X\r\nContent-Length: 3\r\nConnection: close\r\n\r\nabc"
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        Url::parse(&format!("http://{addr}/")).unwrap()
+    }
+
+    fn photo(id: u64, url: Url) -> Media {
+        Media::new(id, MediaType::Photo, Some(url))
+    }
+
+    async fn download(data_file: &mut DataFile, user_dir: &Path, extra: &[&str]) -> usize {
+        let args = ["twitter-dl", "--photos", "--no-mtime"]
+            .iter()
+            .chain(extra)
+            .collect::<Vec<_>>();
+        let args = MediaArgs::parse_from(args);
+        let client = Client::builder().no_proxy().build().unwrap();
+        download_media(
+            "alice",
+            user_dir,
+            data_file,
+            &args,
+            &client,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn records_unavailable_media() {
+        for status in [403, 404] {
+            let dir = tempfile::tempdir().unwrap();
+            let base = serve(status).await;
+            let mut data_file = DataFile::new(1);
+            let mut tweet = golden_tweet(30);
+            tweet.media = vec![
+                photo(1, base.join("ok.jpg").unwrap()),
+                photo(2, base.join("gone.jpg").unwrap()),
+            ];
+            data_file.tweets.push(tweet);
+            assert_eq!(download(&mut data_file, dir.path(), &[]).await, 1);
+            let tweet = &data_file.tweets[0];
+            assert!(tweet.unavailable_since.is_some());
+            assert!(tweet.media[0].unavailable_since.is_none());
+            assert!(tweet.media[0].file_name.is_some());
+            assert!(tweet.media[1].unavailable_since.is_some());
+            assert!(tweet.media[1].file_name.is_none());
+
+            // Not attempted again
+            assert_eq!(download(&mut data_file, dir.path(), &[]).await, 0);
+            assert!(data_file.tweets[0].unavailable_since.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn clears_restored_tweets() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = serve(404).await;
+        let mut data_file = DataFile::new(1);
+        let mut tweet = golden_tweet(30);
+        tweet.media = vec![photo(2, base.join("gone.jpg").unwrap())];
+        data_file.tweets.push(tweet);
+        download(&mut data_file, dir.path(), &[]).await;
+        assert!(data_file.tweets[0].unavailable_since.is_some());
+
+        let base = serve(200).await;
+        data_file.tweets[0].media[0].url = Some(base.join("gone.jpg").unwrap());
+        let downloaded = download(&mut data_file, dir.path(), &["--retry-unavailable"]).await;
+        assert_eq!(downloaded, 1);
+        let tweet = &data_file.tweets[0];
+        assert!(tweet.unavailable_since.is_none());
+        assert!(tweet.media[0].unavailable_since.is_none());
+    }
+
+    #[tokio::test]
+    async fn threads_require_v2() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().to_str().unwrap();
+        let args = ["twitter-dl", "--backend", "guest", "--threads", "-o", out];
+        let error = setup(&DownloadArgs::parse_from(args)).await.err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Downloading threads requires Twitter API v2"
+        );
+    }
+
+    #[test]
+    fn fetches_within_the_dates() {
+        let id_at = |date: &str| {
+            let date = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap();
+            let timestamp = date.and_hms_opt(0, 0, 0).unwrap().timestamp();
+            crate::twitter::first_id_at(timestamp).unwrap()
+        };
+        let (march, april, may) = (
+            id_at("2021-03-01"),
+            id_at("2021-04-01"),
+            id_at("2021-05-01"),
+        );
+        let range = |tweets: &[u64], args: &[&str]| {
+            let mut data_file = DataFile::new(1);
+            for id in tweets {
+                let mut tweet = golden_tweet(10);
+                tweet.id = *id;
+                data_file.tweets.push(tweet);
+            }
+            let args = MediaArgs::parse_from(
+                ["twitter-dl", "--photos", "--timezone", "UTC"]
+                    .into_iter()
+                    .chain(args.iter().copied()),
+            );
+            fetch_range(&data_file, data_file.latest_tweet_id(), &args)
+        };
+
+        assert_eq!(range(&[], &[]), Some((None, None)));
+        assert_eq!(range(&[april], &[]), Some((Some(april), None)));
+        assert_eq!(
+            range(
+                &[],
+                &["--since-date", "2021-03-01", "--until-date", "2021-05-01"]
+            ),
+            Some((Some(march), Some(may)))
+        );
+        // The stored tweets reach back to the date, so only the newer ones are fetched
+        assert_eq!(
+            range(&[march - 1, april], &["--since-date", "2021-03-01"]),
+            Some((Some(april), None))
+        );
+        // They don't, so the rest of the range is fetched too
+        assert_eq!(
+            range(&[april], &["--since-date", "2021-03-01"]),
+            Some((Some(march), None))
+        );
+        // A later date skips the stored tweets before it
+        assert_eq!(
+            range(&[march - 1, april], &["--since-date", "2021-05-01"]),
+            Some((Some(may), None))
+        );
+        // The tweets before `--until-date` are all known already
+        assert_eq!(range(&[may], &["--until-date", "2021-04-01"]), None);
+        assert_eq!(
+            range(&[march], &["--until-date", "2021-04-01"]),
+            Some((Some(march), Some(april)))
+        );
+    }
+
+    /// A tweet without media
+    fn golden_tweet(id: u64) -> Tweet {
+        let mut tweet = data_file().tweets.into_iter().find(|t| t.id == id).unwrap();
+        tweet.media.clear();
+        tweet
+    }
+
+    #[tokio::test]
+    async fn queues_newest_first() {
+        let mut data_file = data_file();
+        for media in data_file.tweets.iter_mut().flat_map(|t| &mut t.media) {
+            media.file_name = None;
+        }
+        let queued = |extra: &[&str]| {
+            let args = ["twitter-dl", "--photos", "--videos", "--gifs"]
+                .iter()
+                .chain(extra)
+                .collect::<Vec<_>>();
+            let args = MediaArgs::parse_from(args);
+            let tweets = &data_file.tweets;
+            async move {
+                let selection = MediaSelection::new(&args).unwrap();
+                let queue = queue(&selection, tweets, &args).await.unwrap();
+                queue.into_iter().map(|c| c.3).collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(
+            queued(&[]).await,
+            ["20_21.mp4", "30_11.jpg", "30_12.png", "40_41.mp4"]
+        );
+        assert_eq!(
+            queued(&["--order", "newest", "--max-files", "2"]).await,
+            ["40_41.mp4", "30_11.jpg"]
+        );
+    }
 }
@@ -0,0 +1,233 @@
+use crate::model::{Profile, ProfileImage, ProfileImageKind};
+use anyhow::{bail, Context};
+use chrono::Utc;
+use reqwest::Client;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use url::Url;
+
+/// Dated copies of the avatars and banners are kept in this sub folder
+const HISTORY_DIR: &str = "profile";
+
+/// Records the account's latest name, avatar and banner, with `profile_media` downloading the
+/// images each run. Each image that has changed is also kept as a dated copy, for `export
+/// timelapse`. The profile is only rewritten when something has changed.
+pub async fn update_profile(
+    user_dir: &Path,
+    mut profile: Profile,
+    profile_media: bool,
+    connection_pool: &Client,
+) -> anyhow::Result<()> {
+    let existing = Profile::load(user_dir).await.ok().flatten();
+    if let Some(existing) = &existing {
+        profile.history = existing.history.clone();
+    }
+    for kind in [ProfileImageKind::Avatar, ProfileImageKind::Banner] {
+        if !profile_media {
+            // Keep the images that were previously downloaded, so that their URLs still match
+            let (url, file) = existing.as_ref().map(|e| e.image(kind)).unwrap_or_default();
+            let (image_url, image_file) = profile.image_mut(kind);
+            *image_url = url.cloned();
+            *image_file = file.cloned();
+            continue;
+        }
+        update_image(
+            user_dir,
+            &mut profile,
+            existing.as_ref(),
+            kind,
+            connection_pool,
+        )
+        .await;
+    }
+    if existing.as_ref() != Some(&profile) {
+        profile.save(user_dir).await?;
+    }
+    Ok(())
+}
+
+async fn update_image(
+    user_dir: &Path,
+    profile: &mut Profile,
+    existing: Option<&Profile>,
+    kind: ProfileImageKind,
+    connection_pool: &Client,
+) {
+    let (previous_url, previous_file) = match existing {
+        Some(existing) => {
+            let (url, file) = existing.image(kind);
+            (url.cloned(), file.cloned())
+        }
+        None => (None, None),
+    };
+    let url = match profile.image(kind).0 {
+        Some(url) => url.clone(),
+        // Not every backend reports every image, keep what was previously recorded
+        None => {
+            let (url, file) = profile.image_mut(kind);
+            *url = previous_url;
+            *file = previous_file;
+            return;
+        }
+    };
+    let data = match fetch_image(kind, &url, connection_pool).await {
+        Ok(data) => data,
+        Err(e) => {
+            log::warn!("Unable to download {}: {:#}", kind.name(), e);
+            *profile.image_mut(kind).1 = previous_file;
+            return;
+        }
+    };
+    let sha256 = hex::encode(Sha256::digest(&data));
+    let current = previous_file.filter(|f| user_dir.join(f).exists());
+    // The image can change without its URL changing, so this compares their contents. The
+    // copies kept before the hashes were recorded are matched by URL instead.
+    let unchanged = match profile.history.iter_mut().rev().find(|i| i.kind == kind) {
+        Some(latest) => {
+            let unchanged = match &latest.sha256 {
+                Some(hash) => *hash == sha256,
+                None => latest.url == url,
+            };
+            if unchanged {
+                latest.sha256.get_or_insert_with(|| sha256.clone());
+            }
+            unchanged
+        }
+        None => false,
+    };
+    if let (true, Some(file_name)) = (unchanged, &current) {
+        *profile.image_mut(kind).1 = Some(file_name.clone());
+        return;
+    }
+
+    let file_name = format!("{}.{}", kind.name(), extension(&url));
+    if let Err(e) = crate::encryption::write(&user_dir.join(&file_name), data.clone()).await {
+        log::warn!("Unable to save {}: {:#}", kind.name(), e);
+        *profile.image_mut(kind).1 = current;
+        return;
+    }
+    *profile.image_mut(kind).1 = Some(file_name.clone());
+    if unchanged {
+        return;
+    }
+    match keep_copy(user_dir, kind, &file_name, data).await {
+        Ok(copy) => profile.history.push(ProfileImage {
+            kind,
+            url,
+            first_seen: Utc::now().timestamp(),
+            file_name: copy,
+            sha256: Some(sha256),
+        }),
+        Err(e) => log::warn!("Unable to keep a copy of the {}: {:#}", kind.name(), e),
+    }
+}
+
+async fn fetch_image(
+    kind: ProfileImageKind,
+    url: &Url,
+    connection_pool: &Client,
+) -> anyhow::Result<Vec<u8>> {
+    let response = connection_pool.get(url.clone()).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        bail!("Received unsuccessful response code {} for {}", status, url)
+    }
+    let data = response
+        .bytes()
+        .await
+        .with_context(|| format!("Unable to read {}", kind.name()))?;
+    Ok(data.to_vec())
+}
+
+/// Saves a date-stamped copy of the image into the history folder, returning the copy's path
+/// (relative to the user folder)
+async fn keep_copy(
+    user_dir: &Path,
+    kind: ProfileImageKind,
+    file_name: &str,
+    data: Vec<u8>,
+) -> anyhow::Result<String> {
+    let ext = file_name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext)
+        .unwrap_or("jpg");
+    let date = Utc::now().format("%Y-%m-%dT%H-%M-%S");
+    let copy = format!("{HISTORY_DIR}/{}-{date}.{ext}", kind.name());
+    tokio::fs::create_dir_all(user_dir.join(HISTORY_DIR)).await?;
+    crate::encryption::write(&user_dir.join(&copy), data).await?;
+    Ok(copy)
+}
+
+/// Banner URLs don't have an extension, they are served as JPEGs
+fn extension(url: &Url) -> String {
+    url.path()
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_lowercase())
+        .filter(|ext| ext.len() <= 4 && ext.chars().all(|c| c.is_ascii_alphanumeric()))
+        .unwrap_or_else(|| "jpg".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves each image with its file name (without the extension or query) as its contents
+    async fn serve() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = [0; 1024];
+                let read = socket.read(&mut request).await.unwrap_or_default();
+                let request = String::from_utf8_lossy(&request[..read]);
+                let path = request.split(' ').nth(1).unwrap_or_default();
+                let body = path.trim_start_matches('/').split(['.', '?']).next();
+                let body = body.unwrap_or_default();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        Url::parse(&format!("http://{addr}/")).unwrap()
+    }
+
+    async fn update(user_dir: &Path, avatar_url: Url) -> Profile {
+        let profile = Profile {
+            avatar_url: Some(avatar_url),
+            ..Profile::new(1)
+        };
+        let client = Client::builder().no_proxy().build().unwrap();
+        update_profile(user_dir, profile, true, &client)
+            .await
+            .unwrap();
+        Profile::load(user_dir).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn saves_changed_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let avatar = dir.path().join("avatar.jpg");
+        let base = serve().await;
+        let profile = update(dir.path(), base.join("first.jpg").unwrap()).await;
+        assert_eq!(profile.avatar_file.as_deref(), Some("avatar.jpg"));
+        assert_eq!(std::fs::read_to_string(&avatar).unwrap(), "first");
+        assert_eq!(profile.history.len(), 1);
+        let copy = dir.path().join(profile.history[0].file_name.as_str());
+        assert_eq!(std::fs::read_to_string(copy).unwrap(), "first");
+
+        // The same image, even at another URL, isn't saved again
+        std::fs::write(&avatar, "kept").unwrap();
+        let profile = update(dir.path(), base.join("first.jpg?v=2").unwrap()).await;
+        assert_eq!(std::fs::read_to_string(&avatar).unwrap(), "kept");
+        assert_eq!(profile.history.len(), 1);
+
+        let profile = update(dir.path(), base.join("second.jpg").unwrap()).await;
+        assert_eq!(std::fs::read_to_string(&avatar).unwrap(), "second");
+        assert_eq!(profile.history.len(), 2);
+        assert_eq!(profile.history[1].url.path(), "/second.jpg");
+    }
+}
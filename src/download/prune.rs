@@ -0,0 +1,105 @@
+//! `--prune`: shrinks an account folder to match the current selection, moving the files of media
+//! that would no longer be downloaded into the trash and forgetting them in the data file
+
+use crate::download::selection::MediaSelection;
+use crate::download::trash::trash;
+use crate::model::DataFile;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// Returns the number of media whose files were pruned
+pub async fn prune(
+    user_dir: &Path,
+    data_file: &mut DataFile,
+    selection: &MediaSelection,
+) -> anyhow::Result<usize> {
+    // With `--dedupe reference` media can share a file, which is kept while any of them is
+    let kept = data_file
+        .tweets
+        .iter()
+        .flat_map(|t| t.media.iter().map(move |m| (t, m)))
+        .filter(|(tweet, media)| selection.keeps(tweet, media))
+        .filter_map(|(_, media)| media.file_name.clone())
+        .collect::<BTreeSet<_>>();
+    let mut pruned = 0;
+    for tweet_index in 0..data_file.tweets.len() {
+        for media_index in 0..data_file.tweets[tweet_index].media.len() {
+            let tweet = &data_file.tweets[tweet_index];
+            let media = &tweet.media[media_index];
+            if media.file_name.is_none() || selection.keeps(tweet, media) {
+                continue;
+            }
+            let media = &mut data_file.tweets[tweet_index].media[media_index];
+            if let Some(file_name) = media.file_name.take() {
+                if !kept.contains(&file_name) {
+                    trash_if_exists(user_dir, &file_name, Some(media.id), media.sha256.clone())
+                        .await?;
+                    let sidecar = format!("{file_name}.json");
+                    trash_if_exists(user_dir, &sidecar, None, None).await?;
+                }
+            }
+            if let Some(preview) = media.preview_file_name.take() {
+                trash_if_exists(user_dir, &preview, None, None).await?;
+            }
+            media.sha256 = None;
+            media.recovered_from = None;
+            media.duplicate_of = None;
+            pruned += 1;
+        }
+    }
+    if pruned > 0 {
+        data_file.save(user_dir).await?;
+    }
+    Ok(pruned)
+}
+
+/// Files may already be gone, e.g. uploaded with `--rclone-move`
+async fn trash_if_exists(
+    user_dir: &Path,
+    file_name: &str,
+    media_id: Option<u64>,
+    sha256: Option<String>,
+) -> anyhow::Result<()> {
+    if user_dir.join(file_name).exists() {
+        trash(user_dir, file_name, media_id, sha256).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::data_file;
+    use crate::MediaArgs;
+    use clap::Parser;
+
+    #[tokio::test]
+    async fn prunes_unselected_media() {
+        let out = tempfile::tempdir().unwrap();
+        let user_dir = out.path().join("alice");
+        std::fs::create_dir(&user_dir).unwrap();
+        for file_name in ["30_11.jpg", "30_12.png", "20_21.mp4"] {
+            std::fs::write(user_dir.join(file_name), file_name).unwrap();
+        }
+        let mut data_file = data_file();
+        let args = MediaArgs::parse_from(["twitter-dl", "--photos"]);
+        let selection = MediaSelection::new(&args).unwrap();
+        assert_eq!(
+            prune(&user_dir, &mut data_file, &selection).await.unwrap(),
+            1
+        );
+
+        let video = &data_file.tweets[1].media[0];
+        assert_eq!(
+            (video.file_name.as_ref(), video.sha256.as_ref()),
+            (None, None)
+        );
+        assert!(!user_dir.join("20_21.mp4").exists());
+        assert!(user_dir.join("30_11.jpg").exists());
+        assert!(out.path().join(".trash").exists());
+        assert_eq!(
+            prune(&user_dir, &mut data_file, &selection).await.unwrap(),
+            0
+        );
+    }
+}
@@ -0,0 +1,298 @@
+//! Checks that the queued downloads fit within `--max-disk-usage` and the free space of the disk,
+//! before downloading anything
+
+use crate::download::hls::is_hls;
+use crate::{DiskFullAction, MediaArgs};
+use anyhow::bail;
+use futures::{stream, StreamExt};
+use indicatif::HumanBytes;
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::Client;
+use std::path::Path;
+use std::sync::Mutex;
+use url::Url;
+
+/// The size of the output folder, measured by the first check and then kept up to date as files
+/// are downloaded, rather than walking the whole folder for every account. Files removed in the
+/// meantime aren't subtracted, which errs on the side of stopping early.
+static USAGE: Mutex<Usage> = Mutex::new(Usage {
+    measured: None,
+    reserved: 0,
+});
+
+struct Usage {
+    measured: Option<u64>,
+    /// Passed checks whose files haven't all been downloaded yet, so that accounts synced
+    /// concurrently (`--account-concurrency`) can't each fill up the same space
+    reserved: u64,
+}
+
+/// Holds the space a check found room for, until its files are downloaded (or dropped, releasing
+/// what wasn't downloaded)
+#[derive(Debug)]
+pub struct Reservation {
+    remaining: u64,
+}
+
+impl Reservation {
+    /// Counts a file that has been downloaded towards the measured size, in place of the space
+    /// reserved for it
+    pub fn downloaded(&mut self, bytes: u64) {
+        let mut usage = USAGE.lock().unwrap();
+        if let Some(measured) = usage.measured.as_mut() {
+            *measured += bytes;
+        }
+        let released = bytes.min(self.remaining);
+        self.remaining -= released;
+        usage.reserved -= released;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        USAGE.lock().unwrap().reserved -= self.remaining;
+    }
+}
+
+/// Whether the checks are enabled
+pub fn enabled(args: &MediaArgs) -> bool {
+    args.max_disk_usage.is_some() || args.check_free_space
+}
+
+/// Measures the output folder again at the next check (e.g. at each check of `watch`, as other
+/// processes may have changed it)
+pub fn reset_disk_usage() {
+    USAGE.lock().unwrap().measured = None;
+}
+
+/// `out` is the output folder with all the accounts, whose combined size counts towards the quota.
+/// The space needed is reserved until the returned reservation is dropped.
+pub async fn check(
+    out: &Path,
+    urls: impl Iterator<Item = &Url>,
+    args: &MediaArgs,
+    connection_pool: &Client,
+) -> anyhow::Result<Reservation> {
+    let (needed, unknown_sizes) = estimate(connection_pool, urls, args.concurrency).await;
+    if unknown_sizes > 0 {
+        log::debug!("Size of {} files unknown", unknown_sizes);
+    }
+    let measured = match args.max_disk_usage {
+        Some(_) => used(out).await?,
+        None => 0,
+    };
+    let free = match args.check_free_space {
+        true => free_space(out),
+        false => None,
+    };
+    // Checked and reserved together, so that concurrent checks see each other's reservations
+    let mut usage = USAGE.lock().unwrap();
+    let reserved = usage.reserved;
+    if let Some(max) = args.max_disk_usage {
+        let used = usage.measured.unwrap_or(measured) + reserved;
+        if used + needed > max.0 {
+            exceeded(
+                args,
+                format!(
+                    "Downloading about {} would exceed --max-disk-usage ({} of {} used)",
+                    HumanBytes(needed),
+                    HumanBytes(used),
+                    HumanBytes(max.0)
+                ),
+            )?;
+        }
+    }
+    if args.check_free_space {
+        match free.map(|free| free.saturating_sub(reserved)) {
+            Some(free) if needed > free => exceeded(
+                args,
+                format!(
+                    "Downloading about {} would fill up the disk ({} free)",
+                    HumanBytes(needed),
+                    HumanBytes(free)
+                ),
+            )?,
+            Some(_) => {}
+            None => log::debug!("Unable to check the free space of {}", out.display()),
+        }
+    }
+    usage.reserved += needed;
+    Ok(Reservation { remaining: needed })
+}
+
+fn exceeded(args: &MediaArgs, problem: String) -> anyhow::Result<()> {
+    match args.on_disk_full {
+        DiskFullAction::Stop => bail!(problem),
+        DiskFullAction::Warn => {
+            log::warn!("{}", problem);
+            Ok(())
+        }
+    }
+}
+
+/// The combined size of the files, from `HEAD` requests, and the number of files whose size
+/// isn't known (which aren't included)
+pub async fn estimate(
+    client: &Client,
+    urls: impl Iterator<Item = &Url>,
+    concurrency: usize,
+) -> (u64, usize) {
+    let sizes = stream::iter(urls)
+        .map(|url| content_length(client, url))
+        .buffer_unordered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+    let bytes = sizes.iter().flatten().sum();
+    let unknown_sizes = sizes.iter().filter(|s| s.is_none()).count();
+    (bytes, unknown_sizes)
+}
+
+/// `None` if the server doesn't say, or for HLS playlists (whose size isn't known until they
+/// have been downloaded)
+async fn content_length(client: &Client, url: &Url) -> Option<u64> {
+    if is_hls(url) {
+        return None;
+    }
+    match client.head(url.clone()).send().await {
+        // Not `content_length()`, which is the length of the (empty) body
+        Ok(response) if response.status().is_success() => response
+            .headers()
+            .get(CONTENT_LENGTH)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok(),
+        Ok(response) => {
+            log::debug!("Unable to size {} ({})", url, response.status());
+            None
+        }
+        Err(e) => {
+            log::debug!("Unable to size {}: {}", url, e);
+            None
+        }
+    }
+}
+
+async fn used(out: &Path) -> anyhow::Result<u64> {
+    if let Some(used) = USAGE.lock().unwrap().measured {
+        return Ok(used);
+    }
+    let dir = out.to_path_buf();
+    let measured = tokio::task::spawn_blocking(move || folder_size(&dir)).await?;
+    // Accounts synced concurrently may both have measured it
+    Ok(*USAGE.lock().unwrap().measured.get_or_insert(measured))
+}
+
+fn folder_size(dir: &Path) -> u64 {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(t) if t.is_dir() => folder_size(&entry.path()),
+            Ok(t) if t.is_file() => entry.metadata().map(|m| m.len()).unwrap_or_default(),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Space available to unprivileged users on the file system `path` is on
+#[cfg(unix)]
+fn free_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is a valid C string and `stat` is only read once it has been filled in
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stat.assume_init()
+    };
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Answers every request with a `Content-Length` of 1000
+    async fn serve() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = [0; 1024];
+                let _ = socket.read(&mut request).await;
+                let response =
+                    "HTTP/1.1 200 OK\r\nContent-Length: 1000\r\nConnection: close\r\n\r\n";
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        Url::parse(&format!("http://{addr}/a.jpg")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn checks_disk_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("tweets.json"), [0; 500]).unwrap();
+        let url = serve().await;
+        let client = Client::builder().no_proxy().build().unwrap();
+        let check = |extra: &'static [&'static str]| {
+            let args = MediaArgs::parse_from(
+                ["twitter-dl", "--photos"]
+                    .into_iter()
+                    .chain(extra.iter().copied()),
+            );
+            let (dir, url, client) = (dir.path().to_path_buf(), url.clone(), client.clone());
+            async move { check(&dir, [&url].into_iter(), &args, &client).await }
+        };
+        reset_disk_usage();
+        check(&["--max-disk-usage", "2000"]).await.unwrap();
+        let error = check(&["--max-disk-usage", "1200"]).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Downloading about 1000 B would exceed --max-disk-usage (500 B of 1.17 KiB used)"
+        );
+        check(&["--max-disk-usage", "1200", "--on-disk-full", "warn"])
+            .await
+            .unwrap();
+
+        // A passed check holds its space until the files are downloaded or it is dropped
+        let reservation = check(&["--max-disk-usage", "2000"]).await.unwrap();
+        assert!(check(&["--max-disk-usage", "2000"]).await.is_err());
+        drop(reservation);
+        check(&["--max-disk-usage", "2000"]).await.unwrap();
+
+        // The folder isn't measured again, the downloaded files are counted instead
+        std::fs::write(dir.path().join("big.jpg"), [0; 5000]).unwrap();
+        let mut reservation = check(&["--max-disk-usage", "2000"]).await.unwrap();
+        reservation.downloaded(600);
+        drop(reservation);
+        assert!(check(&["--max-disk-usage", "2000"]).await.is_err());
+        reset_disk_usage();
+        assert!(check(&["--max-disk-usage", "6000"]).await.is_err());
+        check(&["--max-disk-usage", "7000"]).await.unwrap();
+    }
+
+    #[test]
+    fn measures_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("alice")).unwrap();
+        std::fs::write(dir.path().join("alice/1_2.jpg"), [0; 100]).unwrap();
+        std::fs::write(dir.path().join("tweets.json"), [0; 10]).unwrap();
+        assert_eq!(folder_size(dir.path()), 110);
+        #[cfg(unix)]
+        assert!(free_space(dir.path()).is_some_and(|free| free > 0));
+    }
+}
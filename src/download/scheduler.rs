@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use url::Url;
+
+/// Decides when each download may start, on top of the overall `--concurrency`: spacing out the
+/// start of requests and capping the number of connections to any one host
+#[derive(Clone)]
+pub struct Scheduler {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    delay: Duration,
+    per_host: Option<usize>,
+    next_start: Mutex<Instant>,
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+/// Held for as long as the download is in progress
+pub struct Slot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Scheduler {
+    pub fn new(delay: Duration, per_host: Option<usize>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                delay,
+                per_host: per_host.map(|n| n.max(1)),
+                next_start: Mutex::new(Instant::now()),
+                hosts: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Waits until a request to `url` may start
+    pub async fn acquire(&self, url: &Url) -> Slot {
+        let permit = match self.host(url) {
+            Some(semaphore) => Some(semaphore.acquire_owned().await.unwrap()),
+            None => None,
+        };
+        if !self.inner.delay.is_zero() {
+            // Reserve the next start time, so concurrent waiters are spaced out too
+            let start = {
+                let mut next_start = self.inner.next_start.lock().unwrap();
+                let start = (*next_start).max(Instant::now());
+                *next_start = start + self.inner.delay;
+                start
+            };
+            tokio::time::sleep_until(start.into()).await;
+        }
+        Slot { _permit: permit }
+    }
+
+    fn host(&self, url: &Url) -> Option<Arc<Semaphore>> {
+        let limit = self.inner.per_host?;
+        let host = url.host_str().unwrap_or_default().to_string();
+        let mut hosts = self.inner.hosts.lock().unwrap();
+        let semaphore = hosts
+            .entry(host)
+            .or_insert_with(|| Arc::new(Semaphore::new(limit)));
+        Some(semaphore.clone())
+    }
+}
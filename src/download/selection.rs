@@ -0,0 +1,386 @@
+use crate::download::media_types;
+use crate::external::ExternalCommand;
+use crate::filename::{numbered, sanitize_path};
+use crate::model::{FileNameTemplate, Media, MediaType, Tweet, VideoQuality};
+use crate::plugin::Plugins;
+use crate::time::TimeZoneSetting;
+use crate::{MediaArgs, OrganizeBy};
+use anyhow::bail;
+use regex::Regex;
+use std::collections::HashSet;
+use url::Url;
+
+/// Decides which media should be downloaded, and where within the user folder to save it
+pub struct MediaSelection {
+    media_types: Vec<MediaType>,
+    orig_quality: bool,
+    video_quality: VideoQuality,
+    file_name: FileNameTemplate,
+    restrict_filenames: bool,
+    since: Option<i64>,
+    until: Option<i64>,
+    /// Lower case app names to download from (all if empty), and to skip
+    include_sources: Vec<String>,
+    exclude_sources: Vec<String>,
+    filter_text: Option<Regex>,
+    exclude_text: Option<Regex>,
+    min_likes: Option<u64>,
+    min_retweets: Option<u64>,
+    /// Only the tweets that are (`Some(true)`) or aren't flagged as possibly sensitive
+    sensitive: Option<bool>,
+    organize_by: OrganizeBy,
+    timezone: TimeZoneSetting,
+    filter_command: Option<ExternalCommand>,
+    plugins: Plugins,
+}
+
+impl MediaSelection {
+    pub fn new(args: &MediaArgs) -> anyhow::Result<Self> {
+        Ok(Self {
+            media_types: media_types(args),
+            orig_quality: !args.no_orig_quality,
+            video_quality: args.video_quality,
+            file_name: args.file_name.clone(),
+            restrict_filenames: args.restrict_filenames,
+            since: args.since_date.map(|d| args.timezone.start_of_day(d)),
+            until: args.until_date.map(|d| args.timezone.start_of_day(d)),
+            include_sources: args
+                .include_source
+                .iter()
+                .map(|s| s.to_lowercase())
+                .collect(),
+            exclude_sources: args
+                .exclude_source
+                .iter()
+                .map(|s| s.to_lowercase())
+                .collect(),
+            filter_text: args.filter_text.clone(),
+            exclude_text: args.exclude_text.clone(),
+            min_likes: args.min_likes,
+            min_retweets: args.min_retweets,
+            sensitive: match (args.skip_sensitive, args.only_sensitive) {
+                (true, _) => Some(false),
+                (_, true) => Some(true),
+                _ => None,
+            },
+            organize_by: args.organize_by,
+            timezone: args.timezone,
+            filter_command: args.filter_cmd.clone(),
+            plugins: Plugins::load(&args.plugin)?,
+        })
+    }
+
+    pub fn plugins(&self) -> &Plugins {
+        &self.plugins
+    }
+
+    /// Whether the `--filter-cmd` and plugins accept a tweet that has media to download. The
+    /// command receives the tweet as JSON on stdin, and exits with 0 to accept it or 1 to reject it.
+    pub async fn accepts(&self, tweet: &Tweet) -> anyhow::Result<bool> {
+        if !self.plugins.filter(tweet)? {
+            return Ok(false);
+        }
+        let command = match &self.filter_command {
+            None => return Ok(true),
+            Some(command) => command,
+        };
+        let json = serde_json::to_vec(tweet)?;
+        let status = command.run_with_input(&json).await?;
+        match status.code() {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            _ => bail!("Filter command failed for tweet {} ({})", tweet.id, status),
+        }
+    }
+
+    /// If the media should be downloaded, returns the URL and the path (relative to the user
+    /// folder) to save it at
+    pub fn candidate(&self, tweet: &Tweet, media: &Media) -> anyhow::Result<Option<(Url, String)>> {
+        if !self.accepts_tweet(tweet) {
+            return Ok(None);
+        }
+        let (url, file_name) = match media.is_download_candidate(
+            tweet,
+            &self.media_types,
+            self.orig_quality,
+            self.video_quality,
+            &self.file_name,
+            self.restrict_filenames,
+        ) {
+            Some(candidate) => candidate,
+            None => return Ok(None),
+        };
+        let path = format!("{}{}", self.folder(tweet), file_name);
+        let path = self.plugins.file_name(tweet, media, path)?;
+        Ok(Some((url, sanitize_path(&path, self.restrict_filenames))))
+    }
+
+    /// Whether already downloaded media would still be selected, by its type and the tweet
+    /// filters (the `--filter-cmd` and plugins aren't consulted), for `--prune`
+    pub fn keeps(&self, tweet: &Tweet, media: &Media) -> bool {
+        self.media_types.contains(&media.r#type) && self.accepts_tweet(tweet)
+    }
+
+    /// Whether the dates, sources, text, engagement and sensitivity filters accept the tweet
+    fn accepts_tweet(&self, tweet: &Tweet) -> bool {
+        self.since.is_none_or(|since| tweet.timestamp >= since)
+            && self.until.is_none_or(|until| tweet.timestamp < until)
+            && self.accepts_source(tweet)
+            && self.accepts_text(tweet)
+            && self.accepts_engagement(tweet)
+            && self
+                .sensitive
+                .is_none_or(|sensitive| tweet.possibly_sensitive.unwrap_or_default() == sensitive)
+    }
+
+    /// Whether `--include-source` and `--exclude-source` accept the app the tweet was posted with,
+    /// tweets whose app isn't known are always accepted
+    fn accepts_source(&self, tweet: &Tweet) -> bool {
+        let source = match &tweet.source {
+            Some(source) => source.to_lowercase(),
+            None => return true,
+        };
+        (self.include_sources.is_empty() || self.include_sources.contains(&source))
+            && !self.exclude_sources.contains(&source)
+    }
+
+    /// Whether `--filter-text` and `--exclude-text` accept the tweet's text
+    fn accepts_text(&self, tweet: &Tweet) -> bool {
+        self.filter_text
+            .as_ref()
+            .is_none_or(|regex| regex.is_match(&tweet.text))
+            && !self
+                .exclude_text
+                .as_ref()
+                .is_some_and(|regex| regex.is_match(&tweet.text))
+    }
+
+    /// Whether the tweet has the `--min-likes` and `--min-retweets`, tweets whose counts aren't
+    /// known are always accepted
+    fn accepts_engagement(&self, tweet: &Tweet) -> bool {
+        let at_least = |count: Option<u64>, min: Option<u64>| match (count, min) {
+            (Some(count), Some(min)) => count >= min,
+            _ => true,
+        };
+        at_least(tweet.like_count, self.min_likes)
+            && at_least(tweet.retweet_count, self.min_retweets)
+    }
+
+    /// The sub folder (with a trailing `/`, or empty) files for a tweet are saved in by default
+    pub fn folder(&self, tweet: &Tweet) -> String {
+        match self.organize_by {
+            OrganizeBy::None => String::new(),
+            OrganizeBy::Date => {
+                let date = self.timezone.date(tweet.timestamp);
+                format!("{}/", date.format("%Y-%m-%d"))
+            }
+        }
+    }
+
+    pub fn timezone(&self) -> &TimeZoneSetting {
+        &self.timezone
+    }
+
+    /// All the media to download from a list of tweets, as (tweet index, media index, URL, path)
+    pub async fn candidates(
+        &self,
+        tweets: &[Tweet],
+    ) -> anyhow::Result<Vec<(usize, usize, Url, String)>> {
+        let mut candidates = vec![];
+        // Sanitizing (or a plugin) can give different media the same path, the later ones are
+        // numbered rather than overwriting the first
+        let mut taken = tweets
+            .iter()
+            .flat_map(|t| &t.media)
+            .flat_map(|m| [&m.file_name, &m.preview_file_name])
+            .flatten()
+            .cloned()
+            .collect::<HashSet<_>>();
+        for (tweet_index, tweet) in tweets.iter().enumerate() {
+            let mut media = vec![];
+            for (media_index, m) in tweet.media.iter().enumerate() {
+                if let Some((url, path)) = self.candidate(tweet, m)? {
+                    media.push((tweet_index, media_index, url, path));
+                }
+            }
+            // Only tweets that have something to download are passed to the filter command
+            if !media.is_empty() && self.accepts(tweet).await? {
+                for (_, _, _, path) in &mut media {
+                    if taken.contains(path) {
+                        if let Some(n) = (1..).find(|n| !taken.contains(&numbered(path, *n))) {
+                            *path = numbered(path, n);
+                        }
+                    }
+                    taken.insert(path.clone());
+                }
+                candidates.extend(media);
+            }
+        }
+        Ok(candidates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::data_file;
+    use clap::Parser;
+
+    fn selection(args: &[&str]) -> MediaSelection {
+        let args = MediaArgs::parse_from(
+            ["twitter-dl", "--photos", "--timezone", "UTC"]
+                .into_iter()
+                .chain(args.iter().copied()),
+        );
+        MediaSelection::new(&args).unwrap()
+    }
+
+    /// Posted on 2021-03-04 at noon UTC, with "Hello world" from the web app, 10 likes and 2
+    /// retweets
+    fn tweet() -> Tweet {
+        let mut tweet = data_file().tweets.remove(0);
+        tweet.timestamp = 1614859200;
+        tweet.text = "Hello world".to_string();
+        tweet.source = Some("Twitter Web App".to_string());
+        tweet.like_count = Some(10);
+        tweet.retweet_count = Some(2);
+        tweet.possibly_sensitive = Some(false);
+        tweet
+    }
+
+    #[test]
+    fn filters_sources() {
+        let cases: &[(&[&str], Option<&str>, bool)] = &[
+            (&[], Some("Twitter Web App"), true),
+            (
+                &["--include-source", "twitter web app"],
+                Some("Twitter Web App"),
+                true,
+            ),
+            (
+                &["--include-source", "dlvr.it,IFTTT"],
+                Some("Twitter Web App"),
+                false,
+            ),
+            (
+                &["--exclude-source", "TWITTER WEB APP"],
+                Some("Twitter Web App"),
+                false,
+            ),
+            (
+                &["--exclude-source", "dlvr.it"],
+                Some("Twitter Web App"),
+                true,
+            ),
+            // Tweets whose app isn't known are kept
+            (&["--include-source", "dlvr.it"], None, true),
+            (&["--exclude-source", "dlvr.it"], None, true),
+        ];
+        for (args, source, expected) in cases {
+            let mut tweet = tweet();
+            tweet.source = source.map(String::from);
+            assert_eq!(
+                selection(args).accepts_source(&tweet),
+                *expected,
+                "{args:?} {source:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn filters_text() {
+        let cases: &[(&[&str], bool)] = &[
+            (&[], true),
+            (&["--filter-text", "(?i)hello"], true),
+            (&["--filter-text", "^world"], false),
+            (&["--exclude-text", "world"], false),
+            (&["--exclude-text", "#ad"], true),
+            (
+                &["--filter-text", "Hello", "--exclude-text", "world"],
+                false,
+            ),
+        ];
+        for (args, expected) in cases {
+            assert_eq!(
+                selection(args).accepts_text(&tweet()),
+                *expected,
+                "{args:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn filters_engagement() {
+        let cases: &[(&[&str], Option<u64>, bool)] = &[
+            (&[], Some(10), true),
+            (&["--min-likes", "10"], Some(10), true),
+            (&["--min-likes", "11"], Some(10), false),
+            (&["--min-retweets", "2"], Some(10), true),
+            (&["--min-retweets", "3"], Some(10), false),
+            // Tweets whose counts aren't known are kept
+            (&["--min-likes", "11"], None, true),
+        ];
+        for (args, likes, expected) in cases {
+            let mut tweet = tweet();
+            tweet.like_count = *likes;
+            assert_eq!(
+                selection(args).accepts_engagement(&tweet),
+                *expected,
+                "{args:?} {likes:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn filters_tweets() {
+        let cases: &[(&[&str], Option<bool>, bool)] = &[
+            (&[], Some(false), true),
+            (&["--since-date", "2021-03-04"], Some(false), true),
+            (&["--since-date", "2021-03-05"], Some(false), false),
+            // `--until-date` is exclusive
+            (&["--until-date", "2021-03-04"], Some(false), false),
+            (&["--until-date", "2021-03-05"], Some(false), true),
+            (&["--skip-sensitive"], Some(true), false),
+            (&["--skip-sensitive"], None, true),
+            (&["--only-sensitive"], Some(true), true),
+            (&["--only-sensitive"], None, false),
+            // Every filter has to accept it
+            (
+                &["--since-date", "2021-03-01", "--min-likes", "11"],
+                Some(false),
+                false,
+            ),
+            (
+                &[
+                    "--include-source",
+                    "twitter web app",
+                    "--filter-text",
+                    "Hello",
+                ],
+                Some(false),
+                true,
+            ),
+        ];
+        for (args, sensitive, expected) in cases {
+            let mut tweet = tweet();
+            tweet.possibly_sensitive = *sensitive;
+            assert_eq!(
+                selection(args).accepts_tweet(&tweet),
+                *expected,
+                "{args:?} {sensitive:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn numbers_clashing_paths() {
+        let args = MediaArgs::parse_from(["twitter-dl", "--gifs"]);
+        let selection = MediaSelection::new(&args).unwrap();
+        let mut data_file = data_file();
+        // Another file already has the name the gif would be given
+        data_file.tweets[2].media[0].file_name = Some("40_41.mp4".to_string());
+        let candidates = selection.candidates(&data_file.tweets).await.unwrap();
+        let paths = candidates.iter().map(|c| c.3.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, ["40_41-1.mp4"]);
+    }
+}
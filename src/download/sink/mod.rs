@@ -0,0 +1,165 @@
+//! Where downloaded media is stored (`--sink`). By default that is the account's folder, but the
+//! media can also be written straight to object storage, with only the data files kept locally.
+
+use crate::download::download_task::DownloadError;
+use crate::{FileExistsPolicy, MediaArgs};
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use reqwest::Client;
+use std::path::Path;
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+use url::Url;
+
+mod s3;
+mod webdav;
+
+/// Stores each downloaded file, once it has been completely downloaded (and encrypted, if
+/// encryption is enabled). Each file is identified by both its `destination` in the local account
+/// folder and its `key` (`<username>/<file name>`, relative to the output folder), for sinks that
+/// store it elsewhere.
+#[async_trait]
+pub trait MediaSink: Send + Sync {
+    /// Whether a file has already been stored there
+    async fn exists(&self, destination: &Path, key: &str) -> Result<bool, DownloadError>;
+
+    async fn persist(
+        &self,
+        file: NamedTempFile,
+        destination: &Path,
+        key: &str,
+    ) -> Result<(), DownloadError>;
+
+    /// Whether the files end up at their `destination`, so that they can be processed further
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+/// The account folder
+pub struct LocalSink;
+
+#[async_trait]
+impl MediaSink for LocalSink {
+    async fn exists(&self, destination: &Path, _: &str) -> Result<bool, DownloadError> {
+        Ok(destination.exists())
+    }
+
+    async fn persist(
+        &self,
+        file: NamedTempFile,
+        destination: &Path,
+        _: &str,
+    ) -> Result<(), DownloadError> {
+        file.persist(destination)
+            .map_err(|e| DownloadError::FileError(e.error))?;
+        Ok(())
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+/// The sink selected with `--sink`, failing for the options that need the media to be stored
+/// locally
+pub fn from_args(args: &MediaArgs, client: &Client) -> anyhow::Result<Arc<dyn MediaSink>> {
+    let url = match &args.sink {
+        Some(url) => url,
+        None => return Ok(Arc::new(LocalSink)),
+    };
+    if args.repair
+        || args.prune
+        || args.file_exists_policy == FileExistsPolicy::Adopt
+        || args.ocr
+        || args.nsfw_command.is_some()
+        || args.dedupe.is_some()
+        || args.objects.is_some()
+        || args.embed_metadata
+        || args.render_html
+    {
+        bail!("`--repair`, `--prune`, `--file-exists-policy adopt`, `--ocr`, `--nsfw-command`, `--dedupe`, `--objects`, `--embed-metadata` and `--render-html` need the media to be stored locally, so can't be used with `--sink`")
+    }
+    Ok(match url.scheme() {
+        "s3" => Arc::new(
+            s3::S3Sink::new(url, args.s3_endpoint.as_ref(), client.clone())
+                .context("Invalid S3 sink")?,
+        ),
+        "webdav" | "webdavs" => {
+            Arc::new(webdav::WebDavSink::new(url, client.clone()).context("Invalid WebDAV sink")?)
+        }
+        scheme => {
+            bail!("Unsupported sink `{scheme}://`, expected `s3://`, `webdav://` or `webdavs://`")
+        }
+    })
+}
+
+/// The downloaded file as a request body, streamed from storage rather than read into memory,
+/// and its length (streamed bodies aren't sent with a `Content-Length` otherwise, which S3 requires)
+async fn body(file: &NamedTempFile) -> Result<(reqwest::Body, u64), DownloadError> {
+    let file = tokio::fs::File::open(file.path())
+        .await
+        .map_err(DownloadError::FileError)?;
+    let length = file
+        .metadata()
+        .await
+        .map_err(DownloadError::FileError)?
+        .len();
+    Ok((file.into(), length))
+}
+
+/// Joins the key onto the sink's path, which may or may not end with a `/`
+fn join_key(prefix: &str, key: &str) -> String {
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{prefix}/{key}")
+    }
+}
+
+/// The (decoded) path of the sink's URL, the prefix of the keys
+fn url_path(url: &Url) -> String {
+    let path = percent_encoding::percent_decode_str(url.path()).decode_utf8_lossy();
+    path.trim_matches('/').to_string()
+}
+
+/// Percent encodes each segment of the path (keeping the `/` separators)
+fn encode_path(path: &str) -> String {
+    // Everything but the unreserved characters, as for AWS signatures
+    const SEGMENT: &percent_encoding::AsciiSet = &percent_encoding::NON_ALPHANUMERIC
+        .remove(b'-')
+        .remove(b'.')
+        .remove(b'_')
+        .remove(b'~');
+    path.split('/')
+        .map(|segment| percent_encoding::utf8_percent_encode(segment, SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Fails for unsuccessful responses, including some of the body (which usually explains why)
+async fn check_response(response: reqwest::Response) -> Result<(), DownloadError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let url = response.url().clone();
+    let body = response.text().await.unwrap_or_default();
+    let body = body.chars().take(500).collect::<String>();
+    Err(DownloadError::StorageError(anyhow::anyhow!(
+        "Received unsuccessful response code {} for {}: {}",
+        status,
+        url,
+        body.trim()
+    )))
+}
+
+/// The host as it is sent in the `Host` header
+fn host_header(url: &Url) -> anyhow::Result<String> {
+    let host = url.host_str().context("Missing host")?;
+    Ok(match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    })
+}
@@ -0,0 +1,182 @@
+//! A WebDAV server (e.g. Nextcloud), `webdav://` for HTTP and `webdavs://` for HTTPS, with the
+//! credentials (if any) in the URL
+
+use crate::download::download_task::DownloadError;
+use crate::download::sink::{body, check_response, encode_path, join_key, url_path, MediaSink};
+use anyhow::bail;
+use async_trait::async_trait;
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::{Client, Method, RequestBuilder, StatusCode};
+use std::collections::HashSet;
+use std::path::Path;
+use tempfile::NamedTempFile;
+use tokio::sync::Mutex;
+use url::Url;
+
+pub struct WebDavSink {
+    client: Client,
+    /// The server, without the path or credentials
+    base: Url,
+    prefix: String,
+    username: Option<String>,
+    password: Option<String>,
+    /// Collections that are known to exist
+    collections: Mutex<HashSet<String>>,
+}
+
+impl WebDavSink {
+    pub fn new(url: &Url, client: Client) -> anyhow::Result<Self> {
+        let scheme = match url.scheme() {
+            "webdavs" => "https",
+            _ => "http",
+        };
+        let host = match url.host_str() {
+            Some(host) => host,
+            None => bail!("Missing host"),
+        };
+        let mut base = Url::parse(&format!("{scheme}://{host}/"))?;
+        base.set_port(url.port()).ok();
+        let decode = |s: &str| {
+            percent_encoding::percent_decode_str(s)
+                .decode_utf8_lossy()
+                .into_owned()
+        };
+        Ok(Self {
+            client,
+            base,
+            prefix: url_path(url),
+            username: Some(url.username()).filter(|u| !u.is_empty()).map(decode),
+            password: url.password().map(decode),
+            collections: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let mut url = self.base.clone();
+        url.set_path(&format!("/{}", encode_path(path)));
+        let request = self.client.request(method, url);
+        match &self.username {
+            Some(username) => request.basic_auth(username, self.password.as_ref()),
+            None => request,
+        }
+    }
+
+    /// Creates the collections the file is stored in, as `PUT` doesn't
+    async fn create_collections(&self, path: &str) -> Result<(), DownloadError> {
+        let parent = match path.rsplit_once('/') {
+            Some((parent, _)) => parent,
+            None => return Ok(()),
+        };
+        let mut collections = self.collections.lock().await;
+        let mut collection = String::new();
+        for segment in parent.split('/') {
+            collection = join_key(&collection, segment);
+            if collections.contains(&collection) {
+                continue;
+            }
+            let method = Method::from_bytes(b"MKCOL").expect("Valid method");
+            let response = self
+                .request(method, &format!("{collection}/"))
+                .send()
+                .await?;
+            // Method Not Allowed if it already exists
+            if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+                check_response(response).await?;
+            }
+            collections.insert(collection.clone());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MediaSink for WebDavSink {
+    async fn exists(&self, _: &Path, key: &str) -> Result<bool, DownloadError> {
+        let path = join_key(&self.prefix, key);
+        let response = self.request(Method::HEAD, &path).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        check_response(response).await?;
+        Ok(true)
+    }
+
+    async fn persist(&self, file: NamedTempFile, _: &Path, key: &str) -> Result<(), DownloadError> {
+        let path = join_key(&self.prefix, key);
+        self.create_collections(&path).await?;
+        let (body, length) = body(&file).await?;
+        let request = self
+            .request(Method::PUT, &path)
+            .header(CONTENT_LENGTH, length)
+            .body(body);
+        check_response(request.send().await?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    /// Accepts every request, sending each (with its body) to the receiver
+    async fn serve() -> (Url, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                // Until the headers and the body (by its `Content-Length`) have been received
+                loop {
+                    let read = socket.read(&mut buffer).await.unwrap_or_default();
+                    request.extend_from_slice(&buffer[..read]);
+                    let text = String::from_utf8_lossy(&request);
+                    let complete = text.split_once("\r\n\r\n").map(|(headers, body)| {
+                        let length = headers
+                            .lines()
+                            .find_map(|l| l.strip_prefix("content-length: "))
+                            .map(|l| l.parse::<usize>().unwrap())
+                            .unwrap_or_default();
+                        body.len() >= length
+                    });
+                    if read == 0 || complete == Some(true) {
+                        break;
+                    }
+                }
+                sender
+                    .send(String::from_utf8_lossy(&request).into_owned())
+                    .unwrap();
+                let response =
+                    "HTTP/1.1 201 Created\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        let url = Url::parse(&format!("webdav://user:pass@{addr}/dav")).unwrap();
+        (url, receiver)
+    }
+
+    #[tokio::test]
+    async fn uploads_files() {
+        let (url, mut requests) = serve().await;
+        let sink = WebDavSink::new(&url, Client::new()).unwrap();
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"a photo").unwrap();
+        sink.persist(file, Path::new("unused"), "user/a b.jpg")
+            .await
+            .unwrap();
+        assert!(requests.recv().await.unwrap().starts_with("MKCOL /dav/ "));
+        assert!(requests
+            .recv()
+            .await
+            .unwrap()
+            .starts_with("MKCOL /dav/user/ "));
+        let put = requests.recv().await.unwrap();
+        assert!(put.starts_with("PUT /dav/user/a%20b.jpg "));
+        assert!(put.contains("content-length: 7\r\n"));
+        assert!(put.ends_with("\r\n\r\na photo"));
+    }
+}
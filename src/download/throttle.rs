@@ -0,0 +1,49 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A token bucket, shared by all the concurrent downloads, that caps their combined throughput
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+    bytes_per_sec: f64,
+}
+
+struct Bucket {
+    /// May go negative, in which case the deficit has to be waited out
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: bytes_per_sec,
+                updated: Instant::now(),
+            })),
+            bytes_per_sec,
+        }
+    }
+
+    /// Waits until `bytes` can be consumed without exceeding the rate
+    pub async fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            // Allow bursts of up to a second's worth of data
+            bucket.tokens = (bucket.tokens + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+            bucket.updated = now;
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
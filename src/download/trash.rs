@@ -0,0 +1,273 @@
+//! Destructive operations move files into `<out>/.trash/<time>/` rather than deleting them, so a
+//! mistake can be reverted with `undo`. Each run's trash folder has a line delimited JSON journal,
+//! recording which media each file was recorded as, so that restoring the file also restores the
+//! data file's record of it.
+
+use crate::filename::numbered;
+use crate::lock;
+use crate::model::DataFile;
+use crate::UndoArgs;
+use anyhow::{bail, Context};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+const TRASH_DIR: &str = ".trash";
+const JOURNAL_FILE: &str = "trash.jsonl";
+
+/// Files trashed during this operation (the run, or a check of `watch`) all go into the same folder
+static RUN: Mutex<Option<String>> = Mutex::new(None);
+
+#[derive(Deserialize, Serialize, Debug)]
+struct Entry {
+    /// The account folder's name
+    user: String,
+    /// Relative to the account folder
+    file_name: String,
+    /// Where it is in the trash folder, if a file with the same name was trashed before it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trashed_as: Option<String>,
+    /// The media to restore the file to, if it was removed from the data file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    media_id: Option<u64>,
+    /// The hash recorded for the file before it was trashed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+/// Moves `file_name` (relative to the user folder) into the trash. If it was part of some media's
+/// record, `media_id` and the file's `sha256` let `undo` restore the record too.
+pub async fn trash(
+    user_dir: &Path,
+    file_name: &str,
+    media_id: Option<u64>,
+    sha256: Option<String>,
+) -> anyhow::Result<()> {
+    let out_dir = user_dir.parent().context("Invalid user folder")?;
+    let user = user_dir
+        .file_name()
+        .context("Invalid user folder")?
+        .to_string_lossy()
+        .into_owned();
+    let run_dir = out_dir
+        .join(TRASH_DIR)
+        .join(run_name(&out_dir.join(TRASH_DIR)));
+    let source = user_dir.join(file_name);
+    // e.g. an adopted file that was rejected, downloaded again and then pruned
+    let trashed_as = (0..)
+        .map(|n| match n {
+            0 => file_name.to_string(),
+            n => numbered(file_name, n),
+        })
+        .find(|name| !run_dir.join(&user).join(name).exists())
+        .unwrap();
+    let destination = run_dir.join(&user).join(&trashed_as);
+    fs::create_dir_all(destination.parent().unwrap()).await?;
+    fs::rename(&source, &destination)
+        .await
+        .with_context(|| format!("Unable to move {} to the trash", source.display()))?;
+    let entry = Entry {
+        user,
+        file_name: file_name.to_string(),
+        trashed_as: Some(trashed_as).filter(|name| name != file_name),
+        media_id,
+        sha256,
+    };
+    let mut line = serde_json::to_string(&entry).unwrap();
+    line.push('\n');
+    let mut journal = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(run_dir.join(JOURNAL_FILE))
+        .await
+        .context("Unable to write trash journal")?;
+    journal.write_all(line.as_bytes()).await?;
+    log::debug!("Moved {} to {}", source.display(), destination.display());
+    Ok(())
+}
+
+/// Starts a new trash folder for the files trashed from now on, so that `undo` restores each check
+/// of `watch` separately
+pub fn new_trash_folder() {
+    *RUN.lock().unwrap() = None;
+}
+
+/// The name of this operation's trash folder, the time it started at (numbered if another
+/// operation started in the same second)
+fn run_name(trash_dir: &Path) -> String {
+    let mut run = RUN.lock().unwrap();
+    run.get_or_insert_with(|| {
+        let time = Utc::now().format("%Y-%m-%dT%H-%M-%S").to_string();
+        (0..)
+            .map(|n| match n {
+                0 => time.clone(),
+                n => format!("{time}-{n}"),
+            })
+            .find(|name| !trash_dir.join(name).exists())
+            .unwrap()
+    })
+    .clone()
+}
+
+/// Restores the files from the most recent (or the given) trash folder
+pub async fn undo(args: UndoArgs) -> anyhow::Result<()> {
+    let trash_dir = args.dir.join(TRASH_DIR);
+    let runs = list_runs(&trash_dir).await?;
+    if args.list {
+        for run in &runs {
+            let entries = load_journal(&trash_dir.join(run)).await?;
+            println!("{run}: {} files", entries.len());
+        }
+        return Ok(());
+    }
+    let run = match (&args.id, runs.last()) {
+        (Some(id), _) if runs.contains(id) => id,
+        (Some(id), _) => bail!("No trash folder named {id}"),
+        (None, Some(last)) => last,
+        (None, None) => bail!("The trash is empty"),
+    };
+    let run_dir = trash_dir.join(run);
+    let mut by_user: BTreeMap<String, Vec<Entry>> = BTreeMap::new();
+    for entry in load_journal(&run_dir).await? {
+        by_user.entry(entry.user.clone()).or_default().push(entry);
+    }
+    let mut remaining = Vec::new();
+    let mut count = 0;
+    for (user, entries) in by_user {
+        let user_dir = args.dir.join(&user);
+        fs::create_dir_all(&user_dir).await?;
+        // So that a sync running at the same time doesn't overwrite the restored records
+        let _lock = lock::lock(&user_dir, false).await?;
+        let mut restored = Vec::new();
+        for entry in entries {
+            let trashed_as = entry.trashed_as.as_ref().unwrap_or(&entry.file_name);
+            let source = run_dir.join(&entry.user).join(trashed_as);
+            let destination = user_dir.join(&entry.file_name);
+            if !source.exists() {
+                continue;
+            }
+            if destination.exists() {
+                // e.g. it has since been downloaded again
+                log::warn!(
+                    "{} already exists, leaving it in the trash",
+                    destination.display()
+                );
+                remaining.push(entry);
+                continue;
+            }
+            fs::create_dir_all(destination.parent().unwrap()).await?;
+            fs::rename(&source, &destination)
+                .await
+                .with_context(|| format!("Unable to restore {}", destination.display()))?;
+            restored.push(entry);
+        }
+        count += restored.len();
+        let mut data_file = match DataFile::load_unchecked(&user_dir).await? {
+            Some(data_file) => data_file,
+            None => continue,
+        };
+        for entry in restored {
+            let media_id = match entry.media_id {
+                Some(media_id) => media_id,
+                None => continue,
+            };
+            let media = data_file
+                .tweets
+                .iter_mut()
+                .flat_map(|t| &mut t.media)
+                .find(|m| m.id == media_id);
+            if let Some(media) = media {
+                media.file_name = Some(entry.file_name);
+                media.sha256 = entry.sha256;
+                media.duplicate_of = None;
+                media.metadata_embedded = false;
+            }
+        }
+        data_file.save(&user_dir).await?;
+    }
+
+    if remaining.is_empty() {
+        fs::remove_dir_all(&run_dir)
+            .await
+            .with_context(|| format!("Unable to remove {}", run_dir.display()))?;
+    } else {
+        let text = remaining
+            .iter()
+            .map(|e| serde_json::to_string(e).unwrap() + "\n")
+            .collect::<String>();
+        fs::write(run_dir.join(JOURNAL_FILE), text).await?;
+    }
+    log::info!("Restored {} files from {}", count, run_dir.display());
+    Ok(())
+}
+
+/// The trash folders, oldest first
+async fn list_runs(trash_dir: &Path) -> anyhow::Result<Vec<String>> {
+    let mut runs = Vec::new();
+    if !trash_dir.is_dir() {
+        return Ok(runs);
+    }
+    let mut read_dir = fs::read_dir(trash_dir)
+        .await
+        .context("Unable to read trash folder")?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        if entry.path().join(JOURNAL_FILE).exists() {
+            runs.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    runs.sort();
+    Ok(runs)
+}
+
+async fn load_journal(run_dir: &Path) -> anyhow::Result<Vec<Entry>> {
+    let path = run_dir.join(JOURNAL_FILE);
+    let text = fs::read_to_string(&path)
+        .await
+        .with_context(|| format!("Unable to read {}", path.display()))?;
+    text.lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| serde_json::from_str(l).context("Invalid trash journal"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[tokio::test]
+    async fn numbers_trashed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let user_dir = dir.path().join("alice");
+        std::fs::create_dir(&user_dir).unwrap();
+        new_trash_folder();
+        for text in ["first", "second"] {
+            std::fs::write(user_dir.join("30_11.jpg"), text).unwrap();
+            trash(&user_dir, "30_11.jpg", None, None).await.unwrap();
+        }
+        let runs = list_runs(&dir.path().join(TRASH_DIR)).await.unwrap();
+        assert_eq!(runs.len(), 1);
+        let trashed = dir.path().join(TRASH_DIR).join(&runs[0]).join("alice");
+        assert_eq!(
+            std::fs::read_to_string(trashed.join("30_11.jpg")).unwrap(),
+            "first"
+        );
+        assert_eq!(
+            std::fs::read_to_string(trashed.join("30_11-1.jpg")).unwrap(),
+            "second"
+        );
+
+        // The second copy is left in the trash, as the first is restored to its place
+        let dir_arg = dir.path().to_str().unwrap();
+        undo(UndoArgs::parse_from(["undo", dir_arg])).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(user_dir.join("30_11.jpg")).unwrap(),
+            "first"
+        );
+        assert!(trashed.join("30_11-1.jpg").exists());
+    }
+}
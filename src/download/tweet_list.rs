@@ -0,0 +1,163 @@
+//! `--tweet-list`: downloads the media of individual tweets, listed by URL (e.g. exported from
+//! other tools), into the folders of the accounts that posted them
+
+use crate::cancel::{cancellable, Cancelled};
+use crate::download::{account_dir, download_media, dry_run};
+use crate::lock;
+use crate::model::{DataFile, Tweet};
+use crate::report;
+use crate::twitter::TwitterClient;
+use crate::DownloadArgs;
+use anyhow::{bail, Context};
+use regex::Regex;
+use reqwest::Client;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use tokio::fs;
+use tokio_util::sync::CancellationToken;
+
+pub async fn download_tweet_list(
+    list: &Path,
+    args: &DownloadArgs,
+    twitter: &dyn TwitterClient,
+    connection_pool: &Client,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    let text = fs::read_to_string(list)
+        .await
+        .context("Unable to read tweet list")?;
+    let ids = parse_tweet_ids(&text);
+    if ids.is_empty() {
+        bail!("No tweets found in {}", list.display());
+    }
+    log::info!("Looking up {} tweets", ids.len());
+    let tweets = cancellable(cancel, twitter.lookup_tweets_with_authors(&ids))
+        .await
+        .context("Unable to lookup tweets")?;
+    if tweets.len() < ids.len() {
+        log::warn!(
+            "{} of the tweets couldn't be found (they may have been deleted, or be protected)",
+            ids.len() - tweets.len()
+        );
+    }
+    let mut authors = BTreeMap::<String, (u64, Vec<Tweet>)>::new();
+    for (author, tweet) in tweets {
+        authors
+            .entry(author.username)
+            .or_insert_with(|| (author.user_id, Vec::new()))
+            .1
+            .push(tweet);
+    }
+    for (username, (user_id, tweets)) in authors {
+        let result = download_author(
+            &username,
+            user_id,
+            tweets,
+            args,
+            twitter,
+            connection_pool,
+            cancel,
+        )
+        .await;
+        let error = result.as_ref().err().map(|e| format!("{:#}", e));
+        let new_tweets = result.as_ref().map_or(0, |new| *new);
+        report::account_finished(&username, new_tweets, error);
+        if let Err(e) = result {
+            if args.continue_on_error && !e.is::<Cancelled>() {
+                log::warn!("Error downloading tweets for: {}, ignoring...", username);
+            } else {
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn download_author(
+    username: &str,
+    user_id: u64,
+    tweets: Vec<Tweet>,
+    args: &DownloadArgs,
+    twitter: &dyn TwitterClient,
+    connection_pool: &Client,
+    cancel: &CancellationToken,
+) -> anyhow::Result<usize> {
+    let user_dir = account_dir(&args.out, username);
+    let _lock = if args.dry_run {
+        None
+    } else {
+        fs::create_dir_all(&user_dir)
+            .await
+            .context("Unable to create output directory")?;
+        Some(cancellable(cancel, lock::lock(&user_dir, args.wait_lock)).await?)
+    };
+    let mut data_file = match DataFile::load(&user_dir, user_id).await? {
+        Some(data_file) => data_file,
+        // The account's timeline hasn't been synced, its first sync must fetch all of it rather
+        // than only the tweets newer than these
+        None => {
+            let mut data_file = DataFile::new(user_id);
+            data_file.version = 0;
+            data_file
+        }
+    };
+    let version = data_file.version;
+    let listed = tweets.len();
+    let new = data_file.merge_tweets(tweets, twitter.name());
+    data_file.version = version;
+    log::info!(
+        "{} of {} listed tweets are new for {}",
+        new,
+        listed,
+        username
+    );
+    if args.dry_run {
+        let report = dry_run::report(username, &data_file, new, &args.media, connection_pool);
+        cancellable(cancel, report).await?;
+        return Ok(new);
+    }
+    data_file.save(&user_dir).await?;
+    download_media(
+        username,
+        &user_dir,
+        &mut data_file,
+        &args.media,
+        connection_pool,
+        cancel,
+    )
+    .await?;
+    Ok(new)
+}
+
+/// The ids of the tweets linked to (`.../status/<id>`), or given as is (one per line), sorted and
+/// without duplicates
+fn parse_tweet_ids(text: &str) -> Vec<u64> {
+    let status = Regex::new(r"/status(?:es)?/(\d+)").unwrap();
+    let mut ids = BTreeSet::new();
+    for line in text.lines().map(str::trim) {
+        if let Ok(id) = line.parse() {
+            ids.insert(id);
+        }
+        for captures in status.captures_iter(line) {
+            if let Ok(id) = captures[1].parse() {
+                ids.insert(id);
+            }
+        }
+    }
+    ids.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tweet_ids() {
+        let text = "https://twitter.com/alice/status/20?s=20\n\
+            https://x.com/bob/status/10/photo/1\n\
+            30\n\
+            # https://mobile.twitter.com/i/web/statuses/20\n\
+            not a tweet\n";
+        assert_eq!(parse_tweet_ids(text), [10, 20, 30]);
+    }
+}
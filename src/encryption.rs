@@ -0,0 +1,113 @@
+//! Optional at-rest encryption of the archive (the data files and media), for archives stored on
+//! untrusted disks or cloud mounts. Files are encrypted whole with ChaCha20-Poly1305, and start
+//! with a magic header followed by the random nonce, so unencrypted files can still be read.
+
+use anyhow::{anyhow, bail, Context};
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::{AeadCore, ChaCha20Poly1305, Nonce};
+use std::path::Path;
+use std::sync::OnceLock;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+const MAGIC: &[u8] = b"TWDLENC1";
+const NONCE_LEN: usize = 12;
+
+static CIPHER: OnceLock<ChaCha20Poly1305> = OnceLock::new();
+
+/// Loads the key (64 hex characters) from `key_file`, enabling encryption for this run
+pub fn init(key_file: Option<&Path>) -> anyhow::Result<()> {
+    let key_file = match key_file {
+        Some(key_file) => key_file,
+        None => return Ok(()),
+    };
+    let text = std::fs::read_to_string(key_file).context("Unable to read key file")?;
+    let key = hex::decode(text.trim()).context("Key must be hex encoded")?;
+    if key.len() != 32 {
+        bail!("Key must be 32 bytes (64 hex characters)")
+    }
+    CIPHER.set(ChaCha20Poly1305::new_from_slice(&key)?).ok();
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    CIPHER.get().is_some()
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Encrypts `plaintext` if encryption is enabled, otherwise returns it unchanged
+pub fn encrypt(plaintext: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let cipher = match CIPHER.get() {
+        Some(cipher) => cipher,
+        None => return Ok(plaintext),
+    };
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext.as_slice())
+        .map_err(|_| anyhow!("Unable to encrypt"))?;
+    let mut data = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&ciphertext);
+    Ok(data)
+}
+
+/// Decrypts `data` if it is encrypted, otherwise returns it unchanged
+pub fn decrypt(data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    if !is_encrypted(&data) {
+        return Ok(data);
+    }
+    let cipher = CIPHER
+        .get()
+        .context("The archive is encrypted, the key is required (`--key-file`)")?;
+    if data.len() < MAGIC.len() + NONCE_LEN {
+        bail!("Encrypted file is truncated")
+    }
+    let (nonce, ciphertext) = data[MAGIC.len()..].split_at(NONCE_LEN);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Unable to decrypt, the key is wrong or the file is corrupted"))
+}
+
+/// Reads a file, decrypting it if necessary
+pub async fn read(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let data = fs::read(path)
+        .await
+        .with_context(|| format!("Unable to read {}", path.display()))?;
+    decrypt(data).with_context(|| format!("Unable to read {}", path.display()))
+}
+
+/// Writes a file, encrypting it if encryption is enabled
+pub async fn write(path: &Path, plaintext: Vec<u8>) -> anyhow::Result<()> {
+    // Write to a temporary file first, so the file is never left partially written
+    let file_name = path.file_name().context("Invalid file path")?;
+    let temp = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+    let write = async {
+        let mut file = fs::File::create(&temp).await?;
+        file.write_all(&encrypt(plaintext)?).await?;
+        // Flushed to the disk before replacing the file, so that a crash doesn't leave it empty
+        file.sync_all().await?;
+        fs::rename(&temp, path).await?;
+        Ok::<_, anyhow::Error>(())
+    };
+    write
+        .await
+        .with_context(|| format!("Unable to write {}", path.display()))
+}
+
+/// Encrypts an existing file in place (if encryption is enabled and it isn't already encrypted)
+pub async fn encrypt_file(path: &Path) -> anyhow::Result<()> {
+    if !enabled() {
+        return Ok(());
+    }
+    let data = fs::read(path)
+        .await
+        .with_context(|| format!("Unable to read {}", path.display()))?;
+    if !is_encrypted(&data) {
+        write(path, data).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,174 @@
+//! Machine readable events, written to stdout as line delimited JSON when running with `--json`.
+//! The downloading commands also append them to an events log in the output folder, which `serve`
+//! follows to show the progress of downloads running in another process.
+
+use crate::model::MediaType;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The events log, in the output folder
+pub const LOG_FILE: &str = ".events.jsonl";
+
+/// The log is only followed live, so it is emptied once it reaches this size
+const MAX_LOG_LENGTH: u64 = 1024 * 1024;
+
+/// Minimum time between `DownloadProgress` events for a file
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LOGGING: AtomicBool = AtomicBool::new(false);
+static LOG: Mutex<Option<File>> = Mutex::new(None);
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    AccountStarted {
+        username: &'a str,
+    },
+    TweetsFetched {
+        username: &'a str,
+        new_tweets: usize,
+        total_tweets: usize,
+    },
+    DownloadProgress {
+        username: &'a str,
+        tweet_id: u64,
+        media_id: u64,
+        path: &'a str,
+        bytes: u64,
+        total_bytes: Option<u64>,
+    },
+    DownloadCompleted {
+        username: &'a str,
+        tweet_id: u64,
+        media_id: u64,
+        path: &'a str,
+        media_type: MediaType,
+        bytes: usize,
+    },
+    DownloadFailed {
+        username: &'a str,
+        tweet_id: u64,
+        media_id: u64,
+        error: String,
+    },
+    /// What `--dry-run` would have downloaded
+    DryRun {
+        username: &'a str,
+        new_tweets: usize,
+        files: usize,
+        bytes: u64,
+        /// Files whose size isn't included in `bytes`
+        unknown_sizes: usize,
+    },
+    AccountFinished {
+        username: &'a str,
+        new_tweets: usize,
+        downloaded: usize,
+        error: Option<String>,
+    },
+}
+
+pub fn init(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Also appends the events to the log in `out`
+pub fn log_to(out: &Path) {
+    let path = out.join(LOG_FILE);
+    match OpenOptions::new().create(true).append(true).open(&path) {
+        Ok(file) => {
+            *LOG.lock().unwrap() = Some(file);
+            LOGGING.store(true, Ordering::Relaxed);
+        }
+        Err(e) => log::warn!("Unable to open {}: {}", path.display(), e),
+    }
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed) || LOGGING.load(Ordering::Relaxed)
+}
+
+pub fn emit(event: Event) {
+    if !enabled() {
+        return;
+    }
+    let line = serde_json::to_string(&event).unwrap();
+    if ENABLED.load(Ordering::Relaxed) {
+        // Lock so that lines from concurrent tasks aren't interleaved
+        let mut stdout = std::io::stdout().lock();
+        writeln!(stdout, "{line}").ok();
+        stdout.flush().ok();
+    }
+    if let Some(file) = LOG.lock().unwrap().as_mut() {
+        if file.metadata().is_ok_and(|m| m.len() > MAX_LOG_LENGTH) {
+            file.set_len(0).ok();
+        }
+        writeln!(file, "{line}").ok();
+    }
+}
+
+/// Identifies a download in its `DownloadProgress` events
+#[derive(Clone, Debug)]
+pub struct DownloadId {
+    pub username: String,
+    pub tweet_id: u64,
+    pub media_id: u64,
+    /// Relative to the user folder
+    pub path: String,
+}
+
+/// Emits the progress of one download, at most every `PROGRESS_INTERVAL`
+#[derive(Debug)]
+pub struct ProgressEvents {
+    id: DownloadId,
+    bytes: u64,
+    total_bytes: Option<u64>,
+    last_emitted: Option<Instant>,
+}
+
+impl ProgressEvents {
+    pub fn new(id: DownloadId) -> Self {
+        Self {
+            id,
+            bytes: 0,
+            total_bytes: None,
+            last_emitted: None,
+        }
+    }
+
+    pub fn set_length(&mut self, total_bytes: u64) {
+        self.total_bytes = Some(total_bytes);
+    }
+
+    pub fn inc(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        if self
+            .last_emitted
+            .is_some_and(|last| last.elapsed() < PROGRESS_INTERVAL)
+            || !enabled()
+        {
+            return;
+        }
+        self.last_emitted = Some(Instant::now());
+        emit(Event::DownloadProgress {
+            username: &self.id.username,
+            tweet_id: self.id.tweet_id,
+            media_id: self.id.media_id,
+            path: &self.id.path,
+            bytes: self.bytes,
+            total_bytes: self.total_bytes,
+        });
+    }
+
+    /// Starts again, e.g. when downloading from another URL
+    pub fn reset(&mut self) {
+        self.bytes = 0;
+        self.total_bytes = None;
+    }
+}
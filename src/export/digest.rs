@@ -0,0 +1,261 @@
+//! A single page of the media tweeted by every account over a recent period, like a newsletter of
+//! the archived accounts
+
+use crate::model::{list_user_dirs, DataFile, Media, MediaType, Tweet};
+use crate::render::{alt, escape, poll_and_card};
+use crate::{DigestFormat, ExportDigestArgs};
+use anyhow::{bail, Context};
+use chrono::{TimeZone, Utc};
+use std::fmt::Write;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use url::Url;
+
+const STYLE: &str = "body{font-family:\"Helvetica Neue\",Arial,sans-serif;max-width:700px;\
+margin:20px auto;padding:0 10px}.tweet{border-top:1px solid #e1e8ed;padding:8px 0}\
+.date{color:#657786;font-size:14px}.text{white-space:pre-wrap;overflow-wrap:break-word}\
+img,video{max-width:100%;border-radius:8px;display:block;margin:8px 0}\
+.card{display:block;border:1px solid #e1e8ed;border-radius:8px;padding:8px 12px}";
+
+/// The tweets of one account to include, newest first
+struct Section<'a> {
+    username: String,
+    tweets: Vec<&'a Tweet>,
+}
+
+pub async fn export_digest(args: ExportDigestArgs) -> anyhow::Result<()> {
+    if !args.dir.is_dir() {
+        bail!("expected a directory")
+    }
+    let until = Utc::now().timestamp();
+    let since = until - args.since.as_secs() as i64;
+    let mut accounts = Vec::new();
+    for user_dir in list_user_dirs(&args.dir).await? {
+        let username = user_dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let data_file = DataFile::load_unchecked(&user_dir)
+            .await?
+            .context("Missing data file")?;
+        accounts.push((username, data_file));
+    }
+    let sections = sections(&accounts, since, until);
+    let base = args.base_url.as_ref().map(|base_url| {
+        let mut base_url = base_url.clone();
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+        base_url
+    });
+    let text = match args.format {
+        DigestFormat::Html => html(&sections, since, until, base.as_ref()),
+        DigestFormat::Markdown => markdown(&sections, since, until, base.as_ref()),
+    };
+    match &args.out {
+        Some(out) => {
+            fs::write(out, text)
+                .await
+                .with_context(|| format!("Unable to write {}", out.display()))?;
+            let tweets: usize = sections.iter().map(|s| s.tweets.len()).sum();
+            log::info!(
+                "Wrote {} tweets from {} accounts to {}",
+                tweets,
+                sections.len(),
+                out.display()
+            );
+        }
+        None => tokio::io::stdout().write_all(text.as_bytes()).await?,
+    }
+    Ok(())
+}
+
+/// The accounts with tweets with media in the period (`since` inclusive)
+fn sections(accounts: &[(String, DataFile)], since: i64, until: i64) -> Vec<Section<'_>> {
+    accounts
+        .iter()
+        .filter_map(|(username, data_file)| {
+            let tweets: Vec<_> = data_file
+                .tweets
+                .iter()
+                .rev()
+                .filter(|t| t.timestamp >= since && t.timestamp < until && !t.media.is_empty())
+                .collect();
+            (!tweets.is_empty()).then(|| Section {
+                username: username.clone(),
+                tweets,
+            })
+        })
+        .collect()
+}
+
+/// The downloaded file (relative to the archive folder, unless a `base` URL is given), otherwise
+/// the media's original URL
+fn media_link(username: &str, media: &Media, base: Option<&Url>) -> Option<String> {
+    match &media.file_name {
+        Some(file_name) => {
+            let path = format!("{username}/{file_name}");
+            Some(match base {
+                Some(base) => base.join(&path).ok()?.to_string(),
+                None => path,
+            })
+        }
+        None => media.url.as_ref().map(Url::to_string),
+    }
+}
+
+fn title(since: i64, until: i64) -> String {
+    format!(
+        "Digest: {} to {}",
+        Utc.timestamp(since, 0).format("%b %-d, %Y"),
+        Utc.timestamp(until, 0).format("%b %-d, %Y")
+    )
+}
+
+fn date(tweet: &Tweet) -> String {
+    Utc.timestamp(tweet.timestamp, 0)
+        .format("%b %-d, %Y %H:%M UTC")
+        .to_string()
+}
+
+fn html(sections: &[Section], since: i64, until: i64, base: Option<&Url>) -> String {
+    let title = escape(&title(since, until));
+    let mut html = String::new();
+    write!(
+        html,
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"UTF-8\">\n\
+        <title>{title}</title>\n<style>{STYLE}</style>\n</head>\n<body>\n<h1>{title}</h1>\n"
+    )
+    .unwrap();
+    if sections.is_empty() {
+        html.push_str("<p>No new media.</p>\n");
+    }
+    for section in sections {
+        let user = escape(&section.username);
+        writeln!(html, "<h2>@{user}</h2>").unwrap();
+        for tweet in &section.tweets {
+            write!(
+                html,
+                "<div class=\"tweet\">\n\
+                <div class=\"date\"><a href=\"https://twitter.com/{user}/status/{id}\">{date}</a></div>\n\
+                <p class=\"text\">{text}</p>\n",
+                id = tweet.id,
+                date = date(tweet),
+                text = escape(&tweet.text),
+            )
+            .unwrap();
+            for media in &tweet.media {
+                let src = match media_link(&section.username, media, base) {
+                    Some(src) => escape(&src),
+                    None => continue,
+                };
+                match media.r#type {
+                    MediaType::Photo => {
+                        writeln!(html, "<img src=\"{src}\" alt=\"{}\">", alt(media))
+                    }
+                    MediaType::Video | MediaType::Gif => {
+                        writeln!(html, "<video controls src=\"{src}\"></video>")
+                    }
+                    MediaType::Audio => {
+                        writeln!(html, "<audio controls src=\"{src}\"></audio>")
+                    }
+                }
+                .unwrap();
+            }
+            html.push_str(&poll_and_card(tweet));
+            html.push_str("</div>\n");
+        }
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+fn markdown(sections: &[Section], since: i64, until: i64, base: Option<&Url>) -> String {
+    let mut markdown = format!("# {}\n", title(since, until));
+    if sections.is_empty() {
+        markdown.push_str("\nNo new media.\n");
+    }
+    for section in sections {
+        let user = &section.username;
+        write!(markdown, "\n## @{user}\n").unwrap();
+        for tweet in &section.tweets {
+            write!(
+                markdown,
+                "\n[{date}](https://twitter.com/{user}/status/{id})\n\n",
+                date = date(tweet),
+                id = tweet.id,
+            )
+            .unwrap();
+            for line in tweet.text.lines() {
+                writeln!(markdown, "> {line}").unwrap();
+            }
+            if let Some(poll) = &tweet.poll {
+                markdown.push('\n');
+                for option in &poll.options {
+                    match option.votes {
+                        Some(votes) => writeln!(markdown, "- {} ({votes} votes)", option.label),
+                        None => writeln!(markdown, "- {}", option.label),
+                    }
+                    .unwrap();
+                }
+            }
+            if let Some(card) = &tweet.card {
+                let title = card.title.as_deref().unwrap_or("Link");
+                let title = title.replace(']', "\\]");
+                match &card.url {
+                    Some(url) => writeln!(markdown, "\n[{title}]({url})"),
+                    None => writeln!(markdown, "\n{title}"),
+                }
+                .unwrap();
+            }
+            for media in &tweet.media {
+                let link = match media_link(user, media, base) {
+                    Some(link) => link.replace(' ', "%20"),
+                    None => continue,
+                };
+                match media.r#type {
+                    MediaType::Photo => {
+                        let alt = media.alt_text.as_deref().unwrap_or_default();
+                        let alt = alt.replace('\n', " ").replace(']', "\\]");
+                        writeln!(markdown, "\n![{alt}]({link})")
+                    }
+                    MediaType::Video => writeln!(markdown, "\n[Video]({link})"),
+                    MediaType::Gif => writeln!(markdown, "\n[GIF]({link})"),
+                    MediaType::Audio => {
+                        let title = media.title.as_deref().unwrap_or("Space");
+                        writeln!(markdown, "\n[{}]({link})", title.replace(']', "\\]"))
+                    }
+                }
+                .unwrap();
+            }
+        }
+    }
+    markdown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::{assert_golden, data_file, USERNAME};
+
+    /// From the second to the fourth tweet
+    fn digest(format: DigestFormat) -> String {
+        let accounts = [(USERNAME.to_string(), data_file())];
+        let (since, until) = (1614729600, 1614902401);
+        let sections = sections(&accounts, since, until);
+        match format {
+            DigestFormat::Html => html(&sections, since, until, None),
+            DigestFormat::Markdown => markdown(&sections, since, until, None),
+        }
+    }
+
+    #[test]
+    fn html_golden() {
+        assert_golden("digest.html", &digest(DigestFormat::Html));
+    }
+
+    #[test]
+    fn markdown_golden() {
+        assert_golden("digest.md", &digest(DigestFormat::Markdown));
+    }
+}
@@ -0,0 +1,97 @@
+//! A map of where an archive's tweets were posted from: each geotagged tweet as a GeoJSON point,
+//! linking to its downloaded media
+
+use crate::model::{list_user_dirs, DataFile};
+use crate::ExportGeojsonArgs;
+use anyhow::{bail, Context};
+use chrono::{TimeZone, Utc};
+use serde_json::{json, Value};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+pub async fn export_geojson(args: ExportGeojsonArgs) -> anyhow::Result<()> {
+    if !args.dir.is_dir() {
+        bail!("expected a directory")
+    }
+    let mut features = Vec::new();
+    for user_dir in list_user_dirs(&args.dir).await? {
+        let username = user_dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let data_file = DataFile::load_unchecked(&user_dir)
+            .await?
+            .context("Missing data file")?;
+        features.extend(features_of(&username, &data_file));
+    }
+    let count = features.len();
+    let mut text = serde_json::to_string_pretty(&feature_collection(features)).unwrap();
+    text.push('\n');
+    match &args.out {
+        Some(out) => {
+            fs::write(out, text)
+                .await
+                .with_context(|| format!("Unable to write {}", out.display()))?;
+            log::info!("Wrote {} geotagged tweets to {}", count, out.display());
+        }
+        None => tokio::io::stdout().write_all(text.as_bytes()).await?,
+    }
+    Ok(())
+}
+
+fn feature_collection(features: Vec<Value>) -> Value {
+    json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+}
+
+/// Tweets tagged with a place rather than exact coordinates are placed at its center, with
+/// `exact` false. The media are linked relative to the folder of tweet folders.
+fn features_of(username: &str, data_file: &DataFile) -> Vec<Value> {
+    data_file
+        .tweets
+        .iter()
+        .filter_map(|tweet| {
+            let (coordinates, exact) = tweet.location()?;
+            let media = tweet
+                .media
+                .iter()
+                .filter_map(|m| m.file_name.as_ref())
+                .map(|file_name| format!("{username}/{file_name}"))
+                .collect::<Vec<_>>();
+            Some(json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [coordinates.longitude, coordinates.latitude],
+                },
+                "properties": {
+                    "username": username,
+                    // As a string, as JavaScript numbers can't represent them exactly
+                    "tweet_id": tweet.id.to_string(),
+                    "url": format!("https://twitter.com/{username}/status/{}", tweet.id),
+                    "date": Utc.timestamp(tweet.timestamp, 0).to_rfc3339(),
+                    "text": tweet.text,
+                    "place": tweet.place.as_ref().map(|p| &p.full_name),
+                    "exact": exact,
+                    "media": media,
+                },
+            }))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::{assert_golden, data_file, USERNAME};
+
+    #[test]
+    fn geojson_golden() {
+        let collection = feature_collection(features_of(USERNAME, &data_file()));
+        let mut text = serde_json::to_string_pretty(&collection).unwrap();
+        text.push('\n');
+        assert_golden("geo.json", &text);
+    }
+}
@@ -0,0 +1,196 @@
+//! A static gallery of an account's archive, so that it can be browsed without running `serve`:
+//! a single self-contained `index.html` in the account folder, linking to the downloaded media
+
+use crate::model::{DataFile, MediaType, Tweet};
+use crate::render::{alt, escape, poll_and_card};
+use crate::thumbnail::ThumbnailCache;
+use crate::ExportHtmlArgs;
+use anyhow::{bail, Context};
+use chrono::{TimeZone, Utc};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+use tokio::fs;
+
+const GALLERY_FILE: &str = "index.html";
+
+const STYLE: &str = "body{font-family:\"Helvetica Neue\",Arial,sans-serif;max-width:900px;\
+margin:20px auto;padding:0 10px;background:#f5f8fa}.tweet{background:#fff;border:1px solid #e1e8ed;\
+border-radius:12px;padding:12px 16px;margin:12px 0}.date{color:#657786;font-size:14px}\
+.text{white-space:pre-wrap;overflow-wrap:break-word}\
+.media{display:grid;grid-template-columns:repeat(auto-fill,minmax(200px,1fr));gap:6px}\
+.media img{width:100%;height:200px;object-fit:cover;border-radius:8px;display:block}\
+video,audio{max-width:100%;border-radius:8px}\
+.card{display:block;border:1px solid #e1e8ed;border-radius:8px;padding:8px 12px}";
+
+/// Writes an `index.html` into the account folder, generating thumbnails of the photos into its
+/// `.thumbs` folder so that the gallery doesn't load every full resolution image
+pub async fn export_html(args: ExportHtmlArgs) -> anyhow::Result<()> {
+    if !args.dir.is_dir() {
+        bail!("expected a directory")
+    }
+    // The gallery and thumbnails would be an unencrypted copy of the archive
+    if crate::encryption::enabled() {
+        bail!("`export html` isn't supported for encrypted archives")
+    }
+    let data_file = DataFile::load_unchecked(&args.dir)
+        .await?
+        .context("Missing data file, expected an account folder")?;
+    // Canonicalized so that the name is known for `.`
+    let username = args
+        .dir
+        .canonicalize()?
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let thumbnails = thumbnails(&args.dir, &data_file, args.thumbnail_size).await;
+    let html = gallery(&username, &data_file, &thumbnails);
+    let path = args.dir.join(GALLERY_FILE);
+    fs::write(&path, html)
+        .await
+        .with_context(|| format!("Unable to write {}", path.display()))?;
+    log::info!(
+        "Wrote {} tweets and {} thumbnails to {}",
+        data_file.tweets.len(),
+        thumbnails.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// The thumbnail of each downloaded photo (by file name), relative to the account folder. Photos
+/// that thumbnails can't be generated for are shown in full.
+async fn thumbnails(user_dir: &Path, data_file: &DataFile, size: u32) -> BTreeMap<String, String> {
+    let cache = ThumbnailCache::for_output_dir(user_dir);
+    let mut thumbnails = BTreeMap::new();
+    let photos = data_file
+        .tweets
+        .iter()
+        .flat_map(|t| &t.media)
+        .filter(|m| m.r#type == MediaType::Photo)
+        .filter_map(|m| m.file_name.as_ref());
+    for file_name in photos {
+        let source = user_dir.join(file_name);
+        if thumbnails.contains_key(file_name)
+            || !ThumbnailCache::supports(&source)
+            || !source.exists()
+        {
+            continue;
+        }
+        match cache.get_or_create(&source, size).await {
+            Ok(thumbnail) => {
+                let relative = thumbnail
+                    .path
+                    .strip_prefix(user_dir)
+                    .unwrap_or(&thumbnail.path);
+                let relative = relative
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                thumbnails.insert(file_name.clone(), relative);
+            }
+            Err(e) => log::debug!("No thumbnail for {}: {:#}", source.display(), e),
+        }
+    }
+    thumbnails
+}
+
+fn date(tweet: &Tweet) -> String {
+    Utc.timestamp(tweet.timestamp, 0)
+        .format("%b %-d, %Y %H:%M UTC")
+        .to_string()
+}
+
+/// Every tweet, newest first. Media is loaded lazily, photos are shown as their thumbnails where
+/// there is one (each links to the full file).
+fn gallery(username: &str, data_file: &DataFile, thumbnails: &BTreeMap<String, String>) -> String {
+    let user = escape(username);
+    let mut html = String::new();
+    write!(
+        html,
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"UTF-8\">\n\
+        <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+        <title>@{user}</title>\n<style>{STYLE}</style>\n</head>\n<body>\n<h1>@{user}</h1>\n"
+    )
+    .unwrap();
+    if data_file.tweets.is_empty() {
+        html.push_str("<p>No tweets.</p>\n");
+    }
+    for tweet in data_file.tweets.iter().rev() {
+        write!(
+            html,
+            "<div class=\"tweet\" id=\"{id}\">\n\
+            <div class=\"date\"><a href=\"https://twitter.com/{user}/status/{id}\">{date}</a></div>\n\
+            <p class=\"text\">{text}</p>\n",
+            id = tweet.id,
+            date = date(tweet),
+            text = escape(&tweet.text),
+        )
+        .unwrap();
+        let downloaded = tweet
+            .media
+            .iter()
+            .filter_map(|m| Some((m, m.file_name.as_ref()?)))
+            .collect::<Vec<_>>();
+        if !downloaded.is_empty() {
+            html.push_str("<div class=\"media\">\n");
+        }
+        for (media, file_name) in &downloaded {
+            let src = escape(file_name);
+            match media.r#type {
+                MediaType::Photo => {
+                    let thumbnail = thumbnails.get(*file_name).map(|t| escape(t));
+                    writeln!(
+                        html,
+                        "<a href=\"{src}\"><img src=\"{}\" alt=\"{}\" loading=\"lazy\"></a>",
+                        thumbnail.as_ref().unwrap_or(&src),
+                        alt(media)
+                    )
+                }
+                MediaType::Video => {
+                    writeln!(
+                        html,
+                        "<video controls preload=\"none\" src=\"{src}\"></video>"
+                    )
+                }
+                MediaType::Gif => writeln!(
+                    html,
+                    "<video controls loop muted preload=\"none\" src=\"{src}\"></video>"
+                ),
+                MediaType::Audio => {
+                    writeln!(
+                        html,
+                        "<audio controls preload=\"none\" src=\"{src}\"></audio>"
+                    )
+                }
+            }
+            .unwrap();
+        }
+        if !downloaded.is_empty() {
+            html.push_str("</div>\n");
+        }
+        html.push_str(&poll_and_card(tweet));
+        html.push_str("</div>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::{assert_golden, data_file, USERNAME};
+
+    #[test]
+    fn gallery_golden() {
+        let thumbnails = BTreeMap::from([(
+            "30_11.jpg".to_string(),
+            ".thumbs/9f/9f86d081-320.jpg".to_string(),
+        )]);
+        assert_golden(
+            "gallery.html",
+            &gallery(USERNAME, &data_file(), &thumbnails),
+        );
+    }
+}
@@ -0,0 +1,92 @@
+//! Adds the downloaded media to a local IPFS node (e.g. Kubo) using its RPC API, so that archives
+//! can be distributed peer-to-peer. The CIDs are recorded in the data files, files that already
+//! have one aren't added again.
+
+use crate::lock;
+use crate::model::{list_user_dirs, DataFile};
+use crate::ExportIpfsArgs;
+use anyhow::{bail, Context};
+use reqwest::multipart::{Form, Part};
+use reqwest::Client;
+use serde::Deserialize;
+use std::path::Path;
+use tokio::fs;
+use url::Url;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct AddResponse {
+    hash: String,
+}
+
+pub async fn export_ipfs(args: ExportIpfsArgs) -> anyhow::Result<()> {
+    if !args.dir.is_dir() {
+        bail!("expected a directory")
+    }
+    let client = Client::new();
+    let endpoint = args.api.join("api/v0/add")?;
+    for user_dir in list_user_dirs(&args.dir).await? {
+        // Held while the CIDs are added, so that a sync doesn't overwrite them (or vice versa)
+        let _lock = lock::lock(&user_dir, false).await?;
+        let mut data_file = DataFile::load_unchecked(&user_dir)
+            .await?
+            .context("Missing data file")?;
+        let pending = data_file
+            .tweets
+            .iter()
+            .enumerate()
+            .flat_map(|(t, tweet)| {
+                tweet
+                    .media
+                    .iter()
+                    .enumerate()
+                    .map(move |(m, media)| (t, m, media))
+            })
+            .filter(|(_, _, media)| media.ipfs_cid.is_none())
+            .filter_map(|(t, m, media)| Some((t, m, user_dir.join(media.file_name.as_ref()?))))
+            .filter(|(_, _, path)| path.exists())
+            .collect::<Vec<_>>();
+        let mut added = 0;
+        for (tweet_index, media_index, path) in pending {
+            let cid = add(&client, &endpoint, &path, !args.no_pin).await?;
+            log::debug!("Added {} as {}", path.display(), cid);
+            data_file.tweets[tweet_index].media[media_index].ipfs_cid = Some(cid);
+            added += 1;
+            // Save as we go, as adding large archives can take a while
+            if added % 100 == 0 {
+                data_file.save(&user_dir).await?;
+            }
+        }
+        data_file.save(&user_dir).await?;
+        log::info!("Added {} files from {} to IPFS", added, user_dir.display());
+    }
+    Ok(())
+}
+
+async fn add(client: &Client, endpoint: &Url, path: &Path, pin: bool) -> anyhow::Result<String> {
+    let bytes = fs::read(path)
+        .await
+        .with_context(|| format!("Unable to read {}", path.display()))?;
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let form = Form::new().part("file", Part::bytes(bytes).file_name(name));
+    let response = client
+        .post(endpoint.clone())
+        .query(&[("pin", pin.to_string().as_str()), ("cid-version", "1")])
+        .multipart(form)
+        .send()
+        .await
+        .context("Unable to connect to the IPFS node")?;
+    let status = response.status();
+    let text = response.text().await.context("Bad response text")?;
+    if !status.is_success() {
+        bail!("IPFS node returned {}: {}", status.as_u16(), text.trim())
+    }
+    // Progress is reported as line delimited JSON, the last line being the result
+    let last = text.lines().rfind(|l| !l.trim().is_empty()).unwrap_or("");
+    let response =
+        serde_json::from_str::<AddResponse>(last).context("Unable to deserialize IPFS response")?;
+    Ok(response.hash)
+}
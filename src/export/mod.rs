@@ -0,0 +1,25 @@
+//! Exports downloaded tweets and media into formats used by other tools
+
+use crate::{ExportArgs, ExportCommands};
+
+mod digest;
+mod geojson;
+mod html;
+mod ipfs;
+mod rss;
+pub(crate) mod table;
+mod timelapse;
+mod warc;
+
+pub async fn export(args: ExportArgs) -> anyhow::Result<()> {
+    match args.command {
+        ExportCommands::Warc(args) => warc::export_warc(args).await,
+        ExportCommands::Ipfs(args) => ipfs::export_ipfs(args).await,
+        ExportCommands::Table(args) => table::export_table(args).await,
+        ExportCommands::Rss(args) => rss::export_rss(args).await,
+        ExportCommands::Timelapse(args) => timelapse::export_timelapse(args).await,
+        ExportCommands::Digest(args) => digest::export_digest(args).await,
+        ExportCommands::Geojson(args) => geojson::export_geojson(args).await,
+        ExportCommands::Html(args) => html::export_html(args).await,
+    }
+}
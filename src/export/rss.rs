@@ -0,0 +1,47 @@
+//! Writes an RSS feed of each account's most recent tweets next to its data file
+
+use crate::feed::feed;
+use crate::model::{list_user_dirs, DataFile};
+use crate::ExportRssArgs;
+use anyhow::{bail, Context};
+use tokio::fs;
+
+const FEED_FILE: &str = "feed.xml";
+
+/// Writes a `feed.xml` into each account folder
+pub async fn export_rss(args: ExportRssArgs) -> anyhow::Result<()> {
+    if !args.dir.is_dir() {
+        bail!("expected a directory")
+    }
+    // The feeds would be an unencrypted copy of the tweets
+    if crate::encryption::enabled() {
+        bail!("`export rss` isn't supported for encrypted archives")
+    }
+    for user_dir in list_user_dirs(&args.dir).await? {
+        let username = user_dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let data_file = DataFile::load_unchecked(&user_dir)
+            .await?
+            .context("Missing data file")?;
+        let base = match &args.base_url {
+            // The user folder, with a trailing slash so that file names are joined onto it
+            Some(base_url) => {
+                let mut base_url = base_url.clone();
+                if !base_url.path().ends_with('/') {
+                    base_url.set_path(&format!("{}/", base_url.path()));
+                }
+                Some(base_url.join(&format!("{username}/"))?)
+            }
+            None => None,
+        };
+        let xml = feed(&username, &user_dir, &data_file, base.as_ref(), args.limit).await;
+        let path = user_dir.join(FEED_FILE);
+        fs::write(&path, xml)
+            .await
+            .with_context(|| format!("Unable to write {}", path.display()))?;
+        log::info!("Wrote {}", path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,152 @@
+//! Flattens the data files into one row per media item, for spreadsheets and inventories of what
+//! has been backed up
+
+use crate::model::{list_user_dirs, DataFile, MediaType};
+use crate::{ExportTableArgs, TableFormat};
+use anyhow::{bail, Context};
+use chrono::{TimeZone, Utc};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+const COLUMNS: &[&str] = &[
+    "username",
+    "tweet_id",
+    "date",
+    "text",
+    "media_id",
+    "type",
+    "file_name",
+    "url",
+    "downloaded",
+];
+
+pub async fn export_table(args: ExportTableArgs) -> anyhow::Result<()> {
+    if !args.dir.is_dir() {
+        bail!("expected a directory")
+    }
+    let mut text = String::new();
+    push_row(&mut text, args.format, COLUMNS.iter().copied());
+    let mut rows = 0;
+    for user_dir in list_user_dirs(&args.dir).await? {
+        let username = user_dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let data_file = DataFile::load_unchecked(&user_dir)
+            .await?
+            .context("Missing data file")?;
+        rows += push_rows(&mut text, args.format, &username, &data_file);
+    }
+    match &args.out {
+        Some(out) => {
+            fs::write(out, text)
+                .await
+                .with_context(|| format!("Unable to write {}", out.display()))?;
+            log::info!("Wrote {} rows to {}", rows, out.display());
+        }
+        None => tokio::io::stdout().write_all(text.as_bytes()).await?,
+    }
+    Ok(())
+}
+
+/// Returns the number of rows added
+fn push_rows(
+    text: &mut String,
+    format: TableFormat,
+    username: &str,
+    data_file: &DataFile,
+) -> usize {
+    let mut rows = 0;
+    for tweet in &data_file.tweets {
+        let date = Utc.timestamp(tweet.timestamp, 0).to_rfc3339();
+        for media in &tweet.media {
+            let r#type = match media.r#type {
+                MediaType::Photo => "photo",
+                MediaType::Video => "video",
+                MediaType::Gif => "gif",
+                MediaType::Audio => "audio",
+            };
+            let row = [
+                username,
+                &tweet.id.to_string(),
+                &date,
+                &tweet.text,
+                &media.id.to_string(),
+                r#type,
+                media.file_name.as_deref().unwrap_or_default(),
+                media.url.as_ref().map(|u| u.as_str()).unwrap_or_default(),
+                if media.file_name.is_some() {
+                    "true"
+                } else {
+                    "false"
+                },
+            ];
+            push_row(text, format, row.into_iter());
+            rows += 1;
+        }
+    }
+    rows
+}
+
+pub(crate) fn push_row<'a>(
+    text: &mut String,
+    format: TableFormat,
+    fields: impl Iterator<Item = &'a str>,
+) {
+    for (i, field) in fields.enumerate() {
+        if i > 0 {
+            text.push(match format {
+                TableFormat::Csv => ',',
+                TableFormat::Tsv => '\t',
+            });
+        }
+        match format {
+            // Fields containing the delimiter, quotes or line breaks are quoted (RFC 4180)
+            TableFormat::Csv if field.contains([',', '"', '\n', '\r']) => {
+                text.push('"');
+                text.push_str(&field.replace('"', "\"\""));
+                text.push('"');
+            }
+            TableFormat::Csv => text.push_str(field),
+            // TSV can't contain tabs or line breaks, so they are escaped as `\t`, `\n` and `\r`
+            TableFormat::Tsv => {
+                for c in field.chars() {
+                    match c {
+                        '\\' => text.push_str("\\\\"),
+                        '\t' => text.push_str("\\t"),
+                        '\n' => text.push_str("\\n"),
+                        '\r' => text.push_str("\\r"),
+                        c => text.push(c),
+                    }
+                }
+            }
+        }
+    }
+    text.push_str(match format {
+        TableFormat::Csv => "\r\n",
+        TableFormat::Tsv => "\n",
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::{assert_golden, data_file, USERNAME};
+
+    fn table(format: TableFormat) -> String {
+        let mut text = String::new();
+        push_row(&mut text, format, COLUMNS.iter().copied());
+        push_rows(&mut text, format, USERNAME, &data_file());
+        text
+    }
+
+    #[test]
+    fn csv_golden() {
+        assert_golden("media.csv", &table(TableFormat::Csv));
+    }
+
+    #[test]
+    fn tsv_golden() {
+        assert_golden("media.tsv", &table(TableFormat::Tsv));
+    }
+}
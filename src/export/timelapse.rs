@@ -0,0 +1,162 @@
+//! Assembles the history of each account's avatars (or banners), as recorded in `profile.json`, into
+//! a contact sheet or an animated time-lapse
+
+use crate::model::{list_user_dirs, Profile, ProfileImageKind};
+use crate::{ExportTimelapseArgs, TimelapseFormat};
+use anyhow::{bail, Context};
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::imageops::FilterType;
+use image::{Delay, Frame, Rgba, RgbaImage};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::process::Stdio;
+use tempfile::NamedTempFile;
+use tokio::process::Command;
+
+/// Banners are shown at Twitter's 3:1 aspect ratio
+const BANNER_ASPECT_RATIO: u32 = 3;
+
+/// Writes a `<image>-timelapse.<png|gif|mp4>` into each account folder with a recorded history
+pub async fn export_timelapse(args: ExportTimelapseArgs) -> anyhow::Result<()> {
+    if !args.dir.is_dir() {
+        bail!("expected a directory")
+    }
+    // The time-lapse would be an unencrypted copy of the recorded profile images
+    if crate::encryption::enabled() {
+        bail!("`export timelapse` isn't supported for encrypted archives")
+    }
+    let (width, height) = match args.image {
+        ProfileImageKind::Avatar => (args.size, args.size),
+        ProfileImageKind::Banner => (args.size * BANNER_ASPECT_RATIO, args.size),
+    };
+    for user_dir in list_user_dirs(&args.dir).await? {
+        let profile = match Profile::load(&user_dir).await? {
+            Some(profile) => profile,
+            None => continue,
+        };
+        let mut frames = Vec::new();
+        for image in profile.history(args.image) {
+            let path = user_dir.join(&image.file_name);
+            match load_frame(&path, width, height).await {
+                Ok(frame) => frames.push(frame),
+                Err(e) => log::warn!("Skipping {}: {:#}", path.display(), e),
+            }
+        }
+        if frames.is_empty() {
+            log::debug!(
+                "No {} history for {}",
+                args.image.name(),
+                user_dir.display()
+            );
+            continue;
+        }
+        let ext = match args.format {
+            TimelapseFormat::Sheet => "png",
+            TimelapseFormat::Gif => "gif",
+            TimelapseFormat::Mp4 => "mp4",
+        };
+        let path = user_dir.join(format!("{}-timelapse.{ext}", args.image.name()));
+        let frame_ms = args.frame_ms;
+        match args.format {
+            TimelapseFormat::Sheet => {
+                let output = path.clone();
+                tokio::task::spawn_blocking(move || write_sheet(&output, &frames)).await??
+            }
+            TimelapseFormat::Gif => {
+                let output = path.clone();
+                tokio::task::spawn_blocking(move || write_gif(&output, frames, frame_ms)).await??
+            }
+            TimelapseFormat::Mp4 => {
+                let gif = NamedTempFile::new_in(&user_dir)?;
+                let output = gif.path().to_path_buf();
+                tokio::task::spawn_blocking(move || write_gif(&output, frames, frame_ms)).await??;
+                convert_to_mp4(gif.path(), &path, &args.ffmpeg).await?;
+            }
+        }
+        log::info!("Wrote {}", path.display());
+    }
+    Ok(())
+}
+
+async fn load_frame(path: &Path, width: u32, height: u32) -> anyhow::Result<RgbaImage> {
+    let data = crate::encryption::read(path).await?;
+    tokio::task::spawn_blocking(move || {
+        let image = image::load_from_memory(&data).context("Unable to decode image")?;
+        Ok(image
+            .resize_to_fill(width, height, FilterType::Triangle)
+            .to_rgba8())
+    })
+    .await?
+}
+
+/// The frames in a grid, oldest first, as close to square as possible
+fn write_sheet(path: &Path, frames: &[RgbaImage]) -> anyhow::Result<()> {
+    let (width, height) = frames[0].dimensions();
+    let columns = (frames.len() as f64).sqrt().ceil() as u32;
+    let rows = (frames.len() as u32).div_ceil(columns);
+    let mut sheet = RgbaImage::from_pixel(width * columns, height * rows, Rgba([0, 0, 0, 0]));
+    for (i, frame) in frames.iter().enumerate() {
+        let (column, row) = (i as u32 % columns, i as u32 / columns);
+        image::imageops::overlay(
+            &mut sheet,
+            frame,
+            (column * width).into(),
+            (row * height).into(),
+        );
+    }
+    sheet
+        .save_with_format(path, image::ImageFormat::Png)
+        .with_context(|| format!("Unable to write {}", path.display()))
+}
+
+fn write_gif(path: &Path, frames: Vec<RgbaImage>, frame_ms: u64) -> anyhow::Result<()> {
+    let file = File::create(path).with_context(|| format!("Unable to write {}", path.display()))?;
+    let mut encoder = GifEncoder::new(BufWriter::new(file));
+    encoder.set_repeat(Repeat::Infinite)?;
+    let delay = Delay::from_numer_denom_ms(frame_ms.min(u32::MAX as u64) as u32, 1);
+    encoder
+        .encode_frames(
+            frames
+                .into_iter()
+                .map(|frame| Frame::from_parts(frame, 0, 0, delay)),
+        )
+        .context("Unable to encode GIF")
+}
+
+async fn convert_to_mp4(gif: &Path, path: &Path, ffmpeg: &Path) -> anyhow::Result<()> {
+    let output = Command::new(ffmpeg)
+        .args([
+            "-hide_banner",
+            "-loglevel",
+            "error",
+            "-y",
+            "-f",
+            "gif",
+            "-i",
+        ])
+        .arg(gif)
+        // Most players only support even dimensions and 4:2:0 chroma subsampling
+        .args([
+            "-movflags",
+            "+faststart",
+            "-pix_fmt",
+            "yuv420p",
+            "-vf",
+            "scale=trunc(iw/2)*2:trunc(ih/2)*2",
+            "-f",
+            "mp4",
+        ])
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .with_context(|| format!("Unable to run {}", ffmpeg.display()))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("ffmpeg failed: {}", stderr.trim())
+    }
+    Ok(())
+}
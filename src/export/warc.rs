@@ -0,0 +1,204 @@
+//! Re-fetches the downloaded media, recording the HTTP requests and responses as WARC 1.1 records
+//! so that they can be ingested by web archiving tools (e.g. pywb, the Wayback Machine)
+
+use crate::download::connection_pool;
+use crate::model::{list_user_dirs, DataFile};
+use crate::ExportWarcArgs;
+use anyhow::{bail, Context};
+use chrono::{SecondsFormat, Utc};
+use futures::{stream, StreamExt};
+use reqwest::header::TRANSFER_ENCODING;
+use reqwest::{Client, Method};
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use url::Url;
+
+struct Exchange {
+    url: Url,
+    date: String,
+    request: Vec<u8>,
+    response: Vec<u8>,
+}
+
+pub async fn export_warc(args: ExportWarcArgs) -> anyhow::Result<()> {
+    if !args.dir.is_dir() {
+        bail!("expected a directory")
+    }
+    let mut urls = Vec::new();
+    for user_dir in list_user_dirs(&args.dir).await? {
+        let data_file = DataFile::load_unchecked(&user_dir)
+            .await?
+            .context("Missing data file")?;
+        urls.extend(
+            data_file
+                .tweets
+                .iter()
+                .flat_map(|t| &t.media)
+                .filter(|m| m.file_name.is_some())
+                .filter_map(|m| m.url.clone()),
+        );
+    }
+    log::info!("Recording {} media responses", urls.len());
+
+    let file = File::create(&args.out)
+        .await
+        .with_context(|| format!("Unable to create {}", args.out.display()))?;
+    let mut writer = BufWriter::new(file);
+    let info = format!(
+        "software: twitter-dl/{}\r\nformat: WARC File Format 1.1\r\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    write_record(&mut writer, &warcinfo(), info.as_bytes()).await?;
+
+    let client = connection_pool();
+    let mut exchanges = stream::iter(urls)
+        .map(|url| record(&client, url))
+        .buffered(args.concurrency);
+    let mut recorded = 0;
+    while let Some(result) = exchanges.next().await {
+        match result {
+            Ok(exchange) => {
+                write_exchange(&mut writer, exchange).await?;
+                recorded += 1;
+            }
+            // The media may since have been deleted, that shouldn't stop the rest being archived
+            Err(e) => log::warn!("{:#}", e),
+        }
+    }
+    writer.flush().await?;
+    log::info!("Wrote {} responses to {}", recorded, args.out.display());
+    Ok(())
+}
+
+async fn record(client: &Client, url: Url) -> anyhow::Result<Exchange> {
+    let request = client.request(Method::GET, url.clone()).build()?;
+    let mut head = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\n",
+        &url[url::Position::BeforePath..],
+        &url[url::Position::BeforeHost..url::Position::AfterPort]
+    );
+    for (name, value) in request.headers() {
+        head.push_str(&format!("{}: {}\r\n", name, value.to_str()?));
+    }
+    head.push_str("\r\n");
+
+    let date = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    let response = client
+        .execute(request)
+        .await
+        .with_context(|| format!("Unable to fetch {url}"))?;
+    let status = response.status();
+    let mut response_head = format!("{:?} {}\r\n", response.version(), status);
+    for (name, value) in response.headers() {
+        // The body is recorded after it has been de-chunked
+        if name == TRANSFER_ENCODING {
+            continue;
+        }
+        response_head.push_str(&format!("{}: {}\r\n", name, value.to_str().unwrap_or("")));
+    }
+    response_head.push_str("\r\n");
+    let body = response
+        .bytes()
+        .await
+        .with_context(|| format!("Unable to fetch {url}"))?;
+    if !status.is_success() {
+        bail!("Received unsuccessful response code {} for {}", status, url);
+    }
+    let mut response = response_head.into_bytes();
+    response.extend_from_slice(&body);
+    Ok(Exchange {
+        url,
+        date,
+        request: head.into_bytes(),
+        response,
+    })
+}
+
+async fn write_exchange(writer: &mut BufWriter<File>, exchange: Exchange) -> anyhow::Result<()> {
+    let response_id = record_id();
+    let payload = payload_digest(&exchange.response);
+    let response = [
+        ("WARC-Type", "response".to_string()),
+        ("WARC-Record-ID", response_id.clone()),
+        ("WARC-Date", exchange.date.clone()),
+        ("WARC-Target-URI", exchange.url.to_string()),
+        ("WARC-Payload-Digest", payload),
+        (
+            "Content-Type",
+            "application/http;msgtype=response".to_string(),
+        ),
+    ];
+    write_record(writer, &response, &exchange.response).await?;
+    let request = [
+        ("WARC-Type", "request".to_string()),
+        ("WARC-Record-ID", record_id()),
+        ("WARC-Date", exchange.date),
+        ("WARC-Target-URI", exchange.url.to_string()),
+        ("WARC-Concurrent-To", response_id),
+        (
+            "Content-Type",
+            "application/http;msgtype=request".to_string(),
+        ),
+    ];
+    write_record(writer, &request, &exchange.request).await
+}
+
+fn warcinfo() -> Vec<(&'static str, String)> {
+    vec![
+        ("WARC-Type", "warcinfo".to_string()),
+        ("WARC-Record-ID", record_id()),
+        (
+            "WARC-Date",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        ),
+        ("Content-Type", "application/warc-fields".to_string()),
+    ]
+}
+
+async fn write_record(
+    writer: &mut BufWriter<File>,
+    headers: &[(&str, String)],
+    block: &[u8],
+) -> anyhow::Result<()> {
+    let mut head = String::from("WARC/1.1\r\n");
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str(&format!("WARC-Block-Digest: {}\r\n", digest(block)));
+    head.push_str(&format!("Content-Length: {}\r\n\r\n", block.len()));
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(block).await?;
+    writer.write_all(b"\r\n\r\n").await?;
+    Ok(())
+}
+
+fn digest(data: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(data)))
+}
+
+/// The digest of the HTTP body, i.e. the response after the headers
+fn payload_digest(response: &[u8]) -> String {
+    let start = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|i| i + 4)
+        .unwrap_or(0);
+    digest(&response[start..])
+}
+
+fn record_id() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    // Version 4 (random) UUID
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    format!(
+        "<urn:uuid:{}-{}-{}-{}-{}>",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
@@ -0,0 +1,73 @@
+use anyhow::{anyhow, bail, Context};
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use std::str::FromStr;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// A user provided command that is run on a downloaded file. Any `{}` in the arguments is replaced
+/// with the file path, otherwise the path is appended as the last argument.
+#[derive(Debug, Clone)]
+pub struct ExternalCommand {
+    program: String,
+    args: Vec<String>,
+}
+
+impl FromStr for ExternalCommand {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = shell_words::split(s)?.into_iter();
+        let program = words.next().ok_or_else(|| anyhow!("Empty command"))?;
+        Ok(Self {
+            program,
+            args: words.collect(),
+        })
+    }
+}
+
+impl ExternalCommand {
+    /// Runs the command on a file, returning its stdout
+    pub async fn run(&self, path: &Path) -> anyhow::Result<String> {
+        let path = path.to_string_lossy();
+        let mut args = self
+            .args
+            .iter()
+            .map(|a| a.replace("{}", &path))
+            .collect::<Vec<_>>();
+        if !self.args.iter().any(|a| a.contains("{}")) {
+            args.push(path.into_owned());
+        }
+        let output = Command::new(&self.program)
+            .args(&args)
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .with_context(|| format!("Unable to run `{}`", self.program))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            bail!(
+                "`{}` failed ({}): {}",
+                self.program,
+                output.status,
+                stderr.trim()
+            )
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Runs the command (without substituting a path) with the input written to its stdin
+    pub async fn run_with_input(&self, input: &[u8]) -> anyhow::Result<ExitStatus> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Unable to run `{}`", self.program))?;
+        let mut stdin = child.stdin.take().unwrap();
+        // The command may exit without reading all of its input
+        stdin.write_all(input).await.ok();
+        drop(stdin);
+        Ok(child.wait().await?)
+    }
+}
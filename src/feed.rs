@@ -0,0 +1,140 @@
+//! Generates an RSS feed of an account's most recent archived tweets, with their downloaded media
+//! as enclosures, so an archive can be followed from a feed reader (see `serve` and `export rss`)
+
+use crate::model::{DataFile, Media, MediaType};
+use crate::render::{alt, escape, poll_and_card};
+use chrono::{TimeZone, Utc};
+use std::fmt::Write;
+use std::path::Path;
+use tokio::fs;
+use url::Url;
+
+/// Feed item titles are the start of the tweet's text
+const TITLE_LENGTH: usize = 80;
+
+/// With a `base` URL (where the user folder is served from) the enclosures link to the downloaded
+/// files, otherwise to the media's original URL
+pub async fn feed(
+    username: &str,
+    user_dir: &Path,
+    data_file: &DataFile,
+    base: Option<&Url>,
+    limit: usize,
+) -> String {
+    let profile = format!("https://twitter.com/{username}");
+    let mut xml = String::new();
+    write!(
+        xml,
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<rss version=\"2.0\">\n<channel>\n\
+        <title>@{user}</title>\n<link>{link}</link>\n\
+        <description>Tweets by @{user}, archived by twitter-dl</description>\n\
+        <generator>twitter-dl</generator>\n",
+        user = escape(username),
+        link = escape(&profile),
+    )
+    .unwrap();
+    // The time of the latest tweet rather than now, so the feed only changes when there are new
+    // tweets
+    if let Some(latest) = data_file.tweets.last() {
+        let date = Utc.timestamp(latest.timestamp, 0).to_rfc2822();
+        writeln!(xml, "<lastBuildDate>{date}</lastBuildDate>").unwrap();
+    }
+    for tweet in data_file.tweets.iter().rev().take(limit) {
+        let link = format!("{profile}/status/{}", tweet.id);
+        let mut title = tweet.text.chars().take(TITLE_LENGTH).collect::<String>();
+        if title.len() < tweet.text.len() {
+            title.push('…');
+        }
+        let mut description = format!("<p>{}</p>", escape(&tweet.text));
+        let mut enclosure = None;
+        for media in &tweet.media {
+            let url = match media_url(user_dir, base, media) {
+                Some(url) => url,
+                None => continue,
+            };
+            let mime = mime(url.path());
+            if media.r#type != MediaType::Photo {
+                write!(
+                    description,
+                    "<video controls src=\"{}\"></video>",
+                    escape(url.as_str())
+                )
+            } else {
+                write!(
+                    description,
+                    "<img src=\"{}\" alt=\"{}\">",
+                    escape(url.as_str()),
+                    alt(media)
+                )
+            }
+            .unwrap();
+            // RSS only allows one enclosure per item
+            if enclosure.is_none() {
+                let length = match &media.file_name {
+                    Some(file_name) => fs::metadata(user_dir.join(file_name))
+                        .await
+                        .map(|m| m.len())
+                        .unwrap_or_default(),
+                    None => 0,
+                };
+                enclosure = Some(format!(
+                    "<enclosure url=\"{}\" length=\"{length}\" type=\"{mime}\"/>\n",
+                    escape(url.as_str())
+                ));
+            }
+        }
+        description.push_str(&poll_and_card(tweet));
+        write!(
+            xml,
+            "<item>\n<title>{title}</title>\n<link>{link}</link>\n\
+            <guid isPermaLink=\"true\">{link}</guid>\n<pubDate>{date}</pubDate>\n\
+            <description>{description}</description>\n{enclosure}</item>\n",
+            title = escape(&title),
+            link = escape(&link),
+            date = Utc.timestamp(tweet.timestamp, 0).to_rfc2822(),
+            description = escape(&description),
+            enclosure = enclosure.unwrap_or_default(),
+        )
+        .unwrap();
+    }
+    xml.push_str("</channel>\n</rss>\n");
+    xml
+}
+
+fn media_url(user_dir: &Path, base: Option<&Url>, media: &Media) -> Option<Url> {
+    match (base, &media.file_name) {
+        (Some(base), Some(file_name)) if user_dir.join(file_name).exists() => {
+            base.join(file_name).ok()
+        }
+        _ => media.url.clone(),
+    }
+}
+
+fn mime(path: &str) -> &'static str {
+    let ext = path
+        .rsplit_once('.')
+        .map(|(_, ext)| ext)
+        .unwrap_or_default();
+    match ext.to_lowercase().as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "mp4" => "video/mp4",
+        "m3u8" => "application/x-mpegURL",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::{assert_golden, data_file, USERNAME};
+
+    #[tokio::test]
+    async fn feed_golden() {
+        let dir = tempfile::tempdir().unwrap();
+        let xml = feed(USERNAME, dir.path(), &data_file(), None, 3).await;
+        assert_golden("feed.xml", &xml);
+    }
+}
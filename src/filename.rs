@@ -0,0 +1,107 @@
+//! Makes usernames and rendered file names safe to use as paths everywhere the archive might be
+//! copied to, Windows and exFAT drives included
+
+/// Characters Windows doesn't allow in file names (besides control characters)
+const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Device names Windows reserves, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// A single path component. `restrict` (`--restrict-filenames`) also replaces everything but
+/// ASCII letters, digits, `-`, `_` and `.`.
+pub fn sanitize(name: &str, restrict: bool) -> String {
+    sanitize_component(name, restrict, true)
+}
+
+/// The name of an account's folder. Device names are only avoided on Windows, where they can't be
+/// created: elsewhere `con` or `aux` are valid handles whose existing folders have to be found.
+pub fn sanitize_username(username: &str) -> String {
+    sanitize_component(username, false, cfg!(windows))
+}
+
+fn sanitize_component(name: &str, restrict: bool, reserved_names: bool) -> String {
+    let mut safe = name
+        .chars()
+        .map(|c| {
+            let allowed = if restrict {
+                c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')
+            } else {
+                !c.is_control() && !RESERVED_CHARS.contains(&c)
+            };
+            if allowed {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    // Windows drops them, so `a.` and `a` would be the same file
+    safe.truncate(safe.trim_end_matches(['.', ' ']).len());
+    // Nor hidden, where they could be mistaken for the lock and partial downloads
+    if safe.starts_with('.') {
+        safe.replace_range(..1, "_");
+    }
+    let stem = safe.split('.').next().unwrap_or_default();
+    let reserved = reserved_names && RESERVED_NAMES.iter().any(|n| n.eq_ignore_ascii_case(stem));
+    if safe.is_empty() || reserved {
+        safe.insert(0, '_');
+    }
+    safe
+}
+
+/// A path of `/` separated components relative to the account folder, e.g. from a file name
+/// template or a plugin
+pub fn sanitize_path(path: &str, restrict: bool) -> String {
+    path.split('/')
+        .filter(|c| !c.is_empty() && *c != "." && *c != "..")
+        .map(|c| sanitize(c, restrict))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// e.g. `2021-03-04/30_11-1.jpg`, for a file whose name is already taken
+pub fn numbered(file_name: &str, n: usize) -> String {
+    let start = file_name.rfind('/').map_or(0, |i| i + 1);
+    match file_name[start..].rfind('.') {
+        Some(dot) => {
+            let (stem, ext) = file_name.split_at(start + dot);
+            format!("{stem}-{n}{ext}")
+        }
+        None => format!("{file_name}-{n}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitizes_names() {
+        assert_eq!(sanitize("30_11.jpg", false), "30_11.jpg");
+        assert_eq!(sanitize("what?: \"no\".jpg", false), "what__ _no_.jpg");
+        assert_eq!(sanitize("café ☕.jpg", true), "caf___.jpg");
+        assert_eq!(sanitize("café.jpg", false), "café.jpg");
+        assert_eq!(sanitize("name. ", false), "name");
+        assert_eq!(sanitize("con.jpg", false), "_con.jpg");
+        assert_eq!(sanitize(".objects", false), "_objects");
+        assert_eq!(sanitize("", false), "_");
+        assert_eq!(
+            sanitize_username("con"),
+            if cfg!(windows) { "_con" } else { "con" }
+        );
+        assert_eq!(sanitize_username("a/b"), "a_b");
+        assert_eq!(
+            sanitize_path("../2021-03-04/a|b\\c.jpg", false),
+            "2021-03-04/a_b_c.jpg"
+        );
+    }
+
+    #[test]
+    fn numbers_file_names() {
+        assert_eq!(numbered("30_11.jpg", 1), "30_11-1.jpg");
+        assert_eq!(numbered("2021.03/30_11", 2), "2021.03/30_11-2");
+    }
+}
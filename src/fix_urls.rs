@@ -0,0 +1,93 @@
+//! Older archives (in particular those downloaded with API v2) may contain media without a URL,
+//! this re-resolves those URLs in bulk so that the media can be downloaded.
+
+use crate::download::{connection_pool, download_media, media_types};
+use crate::lock;
+use crate::model::{list_user_dirs, DataFile};
+use crate::proxy;
+use crate::twitter::v1::TwitterClientV1;
+use crate::twitter::{Authentication, TwitterClient};
+use crate::FixMissingUrlsArgs;
+use anyhow::{bail, Context};
+use std::path::Path;
+use tokio_util::sync::CancellationToken;
+
+pub async fn fix_missing_urls(
+    args: FixMissingUrlsArgs,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    if !args.dir.is_dir() {
+        bail!("expected a directory")
+    }
+    let auth = Authentication::load(&args.auth).await?;
+    // API v1.1 is the only backend that returns video variants. egg-mode makes its own
+    // connections, so it would bypass the proxy.
+    if proxy::api_enabled() {
+        bail!("fix-missing-urls uses Twitter API v1.1, which can't be used with a proxy");
+    }
+    let client = TwitterClientV1::new(&auth)?;
+    let connection_pool = connection_pool();
+    for user_dir in list_user_dirs(&args.dir).await? {
+        let username = user_dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        // Both the data file and any partial downloads belong to a sync in progress
+        let _lock = lock::lock(&user_dir, false).await?;
+        let mut data_file = fix_account(&username, &user_dir, &args, &client)
+            .await
+            .with_context(|| format!("Unable to fix URLs for {username}"))?;
+        download_media(
+            &username,
+            &user_dir,
+            &mut data_file,
+            &args.media,
+            &connection_pool,
+            cancel,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+async fn fix_account(
+    username: &str,
+    user_dir: &Path,
+    args: &FixMissingUrlsArgs,
+    client: &dyn TwitterClient,
+) -> anyhow::Result<DataFile> {
+    let mut data_file = DataFile::load_unchecked(user_dir)
+        .await?
+        .context("Missing data file")?;
+    let media_types = media_types(&args.media);
+    let ids = data_file
+        .tweets
+        .iter()
+        .filter(|t| {
+            t.media
+                .iter()
+                .any(|m| m.url.is_none() && media_types.contains(&m.r#type))
+        })
+        .map(|t| t.id)
+        .collect::<Vec<_>>();
+    if ids.is_empty() {
+        return Ok(data_file);
+    }
+    log::info!(
+        "Resolving missing URLs for {} tweets from {}",
+        ids.len(),
+        username
+    );
+    let tweets = client.lookup_tweets(&ids).await?;
+    let resolved = tweets.len();
+    data_file.merge_tweets(tweets, client.name());
+    data_file.save(user_dir).await?;
+    log::info!(
+        "Resolved {} of {} tweets for {} ({} may have been deleted)",
+        resolved,
+        ids.len(),
+        username,
+        ids.len() - resolved
+    );
+    Ok(data_file)
+}
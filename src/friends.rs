@@ -0,0 +1,112 @@
+//! `friends`: lists the accounts following an account and those it follows, writing them to
+//! `followers.json`/`.csv` and `following.json`/`.csv` in its folder, so that an archive preserves
+//! its connections as well as its tweets
+
+use crate::download::account_dir;
+use crate::export::table::push_row;
+use crate::twitter::graphql::TwitterClientGraphql;
+use crate::twitter::v1::TwitterClientV1;
+use crate::twitter::v2::TwitterClientV2;
+use crate::twitter::{Account, Authentication, Connections, TwitterClient};
+use crate::{proxy, Backend, FriendsArgs, TableFormat};
+use anyhow::{bail, Context};
+use std::path::Path;
+use tokio::fs;
+
+const COLUMNS: &[&str] = &["user_id", "username", "name"];
+
+pub async fn friends(args: FriendsArgs) -> anyhow::Result<()> {
+    if !matches!(args.backend, Backend::V1 | Backend::V2 | Backend::Graphql) {
+        bail!("Listing followers requires the v1, v2 or graphql backend");
+    }
+    let auth = match &args.auth_data {
+        Some(text) => Authentication::parse(text)?,
+        None => Authentication::load(&args.auth).await?,
+    };
+    let client: Box<dyn TwitterClient> = match args.backend {
+        Backend::V1 => {
+            // egg-mode makes its own connections
+            if proxy::api_enabled() {
+                bail!("Twitter API v1.1 can't be used with a proxy, use another `--backend`");
+            }
+            Box::new(TwitterClientV1::new(&auth)?)
+        }
+        Backend::V2 => Box::new(TwitterClientV2::new(&auth)?),
+        _ => Box::new(TwitterClientGraphql::new(&auth)?),
+    };
+    let profile = client
+        .get_user(&args.username)
+        .await
+        .context("Unable to find user")?;
+    let user_dir = account_dir(&args.out, &args.username);
+    fs::create_dir_all(&user_dir)
+        .await
+        .context("Unable to create output directory")?;
+    for connections in [Connections::Followers, Connections::Following] {
+        let accounts = client.get_connections(profile.user_id, connections).await?;
+        write(&user_dir, connections, &accounts).await?;
+        log::info!(
+            "Wrote {} {} of {}",
+            accounts.len(),
+            connections.name(),
+            args.username
+        );
+    }
+    Ok(())
+}
+
+async fn write(
+    user_dir: &Path,
+    connections: Connections,
+    accounts: &[Account],
+) -> anyhow::Result<()> {
+    let mut json = serde_json::to_string_pretty(accounts).unwrap();
+    json.push('\n');
+    for (ext, text) in [("json", json), ("csv", csv(accounts))] {
+        let path = user_dir.join(format!("{}.{ext}", connections.name()));
+        crate::encryption::write(&path, text.into_bytes())
+            .await
+            .with_context(|| format!("Unable to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+fn csv(accounts: &[Account]) -> String {
+    let mut csv = String::new();
+    push_row(&mut csv, TableFormat::Csv, COLUMNS.iter().copied());
+    for account in accounts {
+        let user_id = account.user_id.to_string();
+        let row = [
+            user_id.as_str(),
+            &account.username,
+            account.name.as_deref().unwrap_or_default(),
+        ];
+        push_row(&mut csv, TableFormat::Csv, row.into_iter());
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_csv() {
+        let accounts = [
+            Account {
+                user_id: 1,
+                username: "alice".to_string(),
+                name: Some("Alice, \"Al\"".to_string()),
+            },
+            Account {
+                user_id: 2,
+                username: "bob".to_string(),
+                name: None,
+            },
+        ];
+        assert_eq!(
+            csv(&accounts),
+            "user_id,username,name\r\n1,alice,\"Alice, \"\"Al\"\"\"\r\n2,bob,\r\n"
+        );
+    }
+}
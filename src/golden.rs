@@ -0,0 +1,148 @@
+//! Golden-file tests of the files written into an archive, so that any change to their ordering or
+//! field names (which would show up in every diff of a git-tracked archive) is deliberate. After an
+//! intended change, run the tests with `UPDATE_GOLDEN=1` to rewrite the files in `tests/golden/`.
+
+use crate::model::{
+    Card, Coordinates, DataFile, Media, MediaType, Place, Poll, PollOption, Tweet, VideoVariant,
+};
+use std::path::PathBuf;
+use url::Url;
+
+pub const USERNAME: &str = "alice";
+
+fn url(url: &str) -> Option<Url> {
+    Some(Url::parse(url).unwrap())
+}
+
+fn tweet(id: u64, timestamp: i64, text: &str, media: Vec<Media>) -> Tweet {
+    Tweet {
+        id,
+        timestamp,
+        text: text.to_string(),
+        media,
+        conversation_id: Some(id),
+        in_reply_to: None,
+        quoted_tweet_id: None,
+        poll: None,
+        card: None,
+        place: None,
+        coordinates: None,
+        batch: None,
+        source: None,
+        like_count: None,
+        retweet_count: None,
+        possibly_sensitive: None,
+        unavailable_since: None,
+    }
+}
+
+/// An account with a few tweets, fetched out of order over two pages
+pub fn data_file() -> DataFile {
+    let mut photo = Media::new(
+        11,
+        MediaType::Photo,
+        url("https://pbs.twimg.com/media/photo.jpg"),
+    );
+    photo.file_name = Some("30_11.jpg".to_string());
+    photo.alt_text = Some("A cartoon cat & mouse".to_string());
+    photo.sha256 = Some("9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08".into());
+    let mut second_photo = Media::new(
+        12,
+        MediaType::Photo,
+        url("https://pbs.twimg.com/media/second.png"),
+    );
+    second_photo.file_name = Some("30_12.png".to_string());
+    let mut video = Media::new(
+        21,
+        MediaType::Video,
+        url("https://video.twimg.com/ext_tw_video/21/vid/1280x720/video.mp4"),
+    );
+    video.variants = vec![
+        VideoVariant {
+            url: url("https://video.twimg.com/ext_tw_video/21/vid/1280x720/video.mp4").unwrap(),
+            bitrate: Some(2176000),
+        },
+        VideoVariant {
+            url: url("https://video.twimg.com/ext_tw_video/21/pl/video.m3u8").unwrap(),
+            bitrate: None,
+        },
+    ];
+    video.preview_url = url("https://pbs.twimg.com/ext_tw_video_thumb/21/pu/img/preview.jpg");
+    video.file_name = Some("20_21.mp4".to_string());
+    let gif = Media::new(
+        41,
+        MediaType::Gif,
+        url("https://video.twimg.com/tweet_video/gif.mp4"),
+    );
+
+    let mut cartoon = tweet(
+        30,
+        1614816000,
+        "Tom & \"Jerry\" <3",
+        vec![photo, second_photo],
+    );
+    cartoon.card = Some(Card {
+        url: url("https://example.com/tom-and-jerry"),
+        title: Some("Tom & Jerry".to_string()),
+        description: Some("The <cartoon>".to_string()),
+        thumbnail_url: url("https://pbs.twimg.com/card_img/1/thumb.jpg"),
+    });
+    cartoon.coordinates = Some(Coordinates {
+        longitude: -0.1275,
+        latitude: 51.5072,
+    });
+    let mut poll = tweet(20, 1614729600, "A video", vec![video]);
+    poll.poll = Some(Poll {
+        options: vec![
+            PollOption {
+                label: "Cats".to_string(),
+                votes: Some(3),
+            },
+            PollOption {
+                label: "Mice & [others]".to_string(),
+                votes: None,
+            },
+        ],
+        ends_at: Some(1614816000),
+    });
+    poll.place = Some(Place {
+        full_name: "Paris, France".to_string(),
+        country: Some("France".to_string()),
+        bounding_box: Some([2.25, 48.8, 2.45, 49.0]),
+    });
+
+    let mut data_file = DataFile::new(42);
+    data_file.merge_tweets(
+        vec![
+            tweet(40, 1614902400, "Not downloaded yet", vec![gif]),
+            cartoon,
+        ],
+        "v1",
+    );
+    data_file.merge_tweets(vec![poll, tweet(10, 1614643200, "First!", vec![])], "v1");
+    // These would otherwise change between runs
+    for batch in &mut data_file.batches {
+        batch.tool_version = "0.0.0".to_string();
+        batch.timestamp = 1614988800;
+    }
+    data_file
+}
+
+/// Compares `actual` with the golden file `name`, or rewrites it with `UPDATE_GOLDEN` set
+pub fn assert_golden(name: &str, actual: &str) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Unable to read {}: {e}", path.display()));
+    assert!(
+        expected == actual,
+        "{name} differs from its golden file, run with UPDATE_GOLDEN=1 if the change is intended\n\
+        --- expected\n{expected}\n--- actual\n{actual}"
+    );
+}
@@ -0,0 +1,225 @@
+//! Basic visual attributes of the archived images, recorded while thumbnailing so that large
+//! archives can be searched (e.g. for landscape photos, or mostly blue images)
+
+use anyhow::Context;
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio::fs;
+
+const INDEX_FILE: &str = "index.json";
+
+// Colours covering less than this share of an image aren't recorded
+const MIN_COLOR_SHARE: f32 = 0.05;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Orientation {
+    Landscape,
+    Portrait,
+    Square,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorName {
+    Black,
+    Gray,
+    White,
+    Red,
+    Orange,
+    Yellow,
+    Green,
+    Cyan,
+    Blue,
+    Purple,
+    Pink,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ColorShare {
+    pub name: ColorName,
+    /// Fraction of the image's pixels
+    pub share: f32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ImageAttributes {
+    /// SHA-256 of the source image (the thumbnail cache key)
+    pub hash: String,
+    pub width: u32,
+    pub height: u32,
+    pub aspect_ratio: f32,
+    pub orientation: Orientation,
+    /// The most common colour as `#rrggbb`
+    pub dominant_color: String,
+    /// Most common first
+    pub colors: Vec<ColorShare>,
+}
+
+/// Which images to return from a search (by `serve`), all the conditions must match
+#[cfg(feature = "serve")]
+#[derive(Deserialize, Debug, Default)]
+pub struct ImageQuery {
+    pub orientation: Option<Orientation>,
+    /// Matches images where this is the most common colour
+    pub color: Option<ColorName>,
+    pub min_aspect_ratio: Option<f32>,
+    pub max_aspect_ratio: Option<f32>,
+}
+
+/// Stored within the thumbnail cache, keyed by the image path relative to the output directory
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct ImageIndex {
+    pub images: BTreeMap<String, ImageAttributes>,
+}
+
+impl ImageIndex {
+    pub async fn load(cache_dir: &Path) -> anyhow::Result<Self> {
+        let path = cache_dir.join(INDEX_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let text = fs::read_to_string(&path)
+            .await
+            .context("Unable to read image index")?;
+        serde_json::from_str(&text).context("Unable to deserialize image index")
+    }
+
+    pub async fn save(&self, cache_dir: &Path) -> anyhow::Result<()> {
+        fs::create_dir_all(cache_dir).await?;
+        let text = serde_json::to_string_pretty(&self).unwrap();
+        fs::write(cache_dir.join(INDEX_FILE), &text)
+            .await
+            .context("Unable to write image index")
+    }
+
+    #[cfg(feature = "serve")]
+    pub fn search<'a>(
+        &'a self,
+        query: &'a ImageQuery,
+    ) -> impl Iterator<Item = (&'a String, &'a ImageAttributes)> + 'a {
+        self.images.iter().filter(|(_, a)| query.matches(a))
+    }
+}
+
+#[cfg(feature = "serve")]
+impl ImageQuery {
+    pub fn matches(&self, attributes: &ImageAttributes) -> bool {
+        self.orientation.is_none_or(|o| o == attributes.orientation)
+            && self
+                .color
+                .is_none_or(|c| attributes.colors.first().is_some_and(|s| s.name == c))
+            && self
+                .min_aspect_ratio
+                .is_none_or(|r| attributes.aspect_ratio >= r)
+            && self
+                .max_aspect_ratio
+                .is_none_or(|r| attributes.aspect_ratio <= r)
+    }
+}
+
+impl ImageAttributes {
+    /// The colours are sampled from the (much smaller) thumbnail rather than the original image
+    pub fn analyze(hash: String, (width, height): (u32, u32), thumbnail: &DynamicImage) -> Self {
+        let aspect_ratio = width as f32 / height.max(1) as f32;
+        // Allow a little tolerance so that near square crops count as square
+        let orientation = if (aspect_ratio - 1.0).abs() < 0.05 {
+            Orientation::Square
+        } else if aspect_ratio > 1.0 {
+            Orientation::Landscape
+        } else {
+            Orientation::Portrait
+        };
+
+        let pixels = thumbnail.to_rgb8();
+        let total = pixels.pixels().len().max(1) as f32;
+        // Quantize to 4 bits per channel to find the dominant colour
+        let mut buckets = BTreeMap::<[u8; 3], u32>::new();
+        let mut names = BTreeMap::<ColorName, u32>::new();
+        for pixel in pixels.pixels() {
+            let quantized = pixel.0.map(|c| c & 0xF0);
+            *buckets.entry(quantized).or_default() += 1;
+            *names.entry(ColorName::classify(pixel.0)).or_default() += 1;
+        }
+        let dominant = buckets
+            .into_iter()
+            .max_by_key(|(_, count)| *count)
+            .map(|(rgb, _)| rgb.map(|c| c | 0x08))
+            .unwrap_or_default();
+        let mut colors = names
+            .into_iter()
+            .map(|(name, count)| ColorShare {
+                name,
+                share: count as f32 / total,
+            })
+            .filter(|c| c.share >= MIN_COLOR_SHARE)
+            .collect::<Vec<_>>();
+        colors.sort_by(|a, b| b.share.total_cmp(&a.share));
+
+        Self {
+            hash,
+            width,
+            height,
+            aspect_ratio,
+            orientation,
+            dominant_color: format!("#{}", hex::encode(dominant)),
+            colors,
+        }
+    }
+
+    /// Analyzes an image that already has a cached thumbnail
+    pub async fn from_thumbnail(
+        hash: String,
+        source: &Path,
+        thumbnail: &Path,
+    ) -> anyhow::Result<Self> {
+        let (source, thumbnail) = (source.to_owned(), thumbnail.to_owned());
+        tokio::task::spawn_blocking(move || {
+            let dimensions =
+                image::image_dimensions(&source).context("Unable to read image dimensions")?;
+            let thumbnail = image::open(&thumbnail).context("Unable to decode thumbnail")?;
+            Ok(Self::analyze(hash, dimensions, &thumbnail))
+        })
+        .await?
+    }
+}
+
+impl ColorName {
+    fn classify([r, g, b]: [u8; 3]) -> Self {
+        let (r, g, b) = (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = max - min;
+        let saturation = if max == 0.0 { 0.0 } else { delta / max };
+        if max < 0.2 {
+            return ColorName::Black;
+        }
+        if saturation < 0.15 {
+            return if max > 0.85 {
+                ColorName::White
+            } else {
+                ColorName::Gray
+            };
+        }
+        let hue = if max == r {
+            60.0 * ((g - b) / delta).rem_euclid(6.0)
+        } else if max == g {
+            60.0 * ((b - r) / delta + 2.0)
+        } else {
+            60.0 * ((r - g) / delta + 4.0)
+        };
+        match hue {
+            h if h < 15.0 => ColorName::Red,
+            h if h < 45.0 => ColorName::Orange,
+            h if h < 70.0 => ColorName::Yellow,
+            h if h < 165.0 => ColorName::Green,
+            h if h < 195.0 => ColorName::Cyan,
+            h if h < 255.0 => ColorName::Blue,
+            h if h < 290.0 => ColorName::Purple,
+            h if h < 335.0 => ColorName::Pink,
+            _ => ColorName::Red,
+        }
+    }
+}
@@ -0,0 +1,350 @@
+//! Seeds a DataFile from the official Twitter data export ZIP ("Download an archive of your data")
+
+use crate::download::{account_dir, connection_pool, download_media, MediaSelection};
+use crate::lock;
+use crate::model::{Coordinates, DataFile, Media, MediaType, Tweet};
+use crate::twitter::{source_name, LEGACY_DATE_FORMAT};
+use crate::ImportArchiveArgs;
+use anyhow::{bail, Context};
+use chrono::DateTime;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+use tokio::fs;
+use tokio_util::sync::CancellationToken;
+use url::Url;
+use zip::ZipArchive;
+
+#[derive(Deserialize)]
+struct AccountEntry {
+    account: ArchiveAccount,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ArchiveAccount {
+    account_id: String,
+    username: String,
+}
+
+// Newer exports wrap each tweet in a `{ "tweet": { ... } }` object
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TweetEntry {
+    Wrapped { tweet: ArchiveTweet },
+    Bare(ArchiveTweet),
+}
+
+#[derive(Deserialize)]
+struct ArchiveTweet {
+    id_str: String,
+    full_text: String,
+    created_at: String,
+    in_reply_to_status_id_str: Option<String>,
+    source: Option<String>,
+    /// The counts are strings in the archive
+    favorite_count: Option<String>,
+    retweet_count: Option<String>,
+    possibly_sensitive: Option<bool>,
+    extended_entities: Option<ArchiveEntities>,
+    coordinates: Option<ArchivePoint>,
+}
+
+#[derive(Deserialize)]
+struct ArchivePoint {
+    /// `[longitude, latitude]`, the archive usually stores them as strings
+    coordinates: [Value; 2],
+}
+
+#[derive(Deserialize)]
+struct ArchiveEntities {
+    #[serde(default)]
+    media: Vec<ArchiveMedia>,
+}
+
+#[derive(Deserialize)]
+struct ArchiveMedia {
+    id_str: String,
+    r#type: String,
+    media_url_https: String,
+    video_info: Option<ArchiveVideoInfo>,
+}
+
+#[derive(Deserialize)]
+struct ArchiveVideoInfo {
+    #[serde(default)]
+    variants: Vec<ArchiveVideoVariant>,
+}
+
+#[derive(Deserialize)]
+struct ArchiveVideoVariant {
+    // The archive stores bitrates as strings
+    bitrate: Option<String>,
+    url: String,
+}
+
+struct ParsedArchive {
+    user_id: u64,
+    username: String,
+    tweets: Vec<Tweet>,
+    /// Paths of media files bundled within the ZIP
+    media_files: Vec<String>,
+}
+
+pub async fn import_archive(
+    args: ImportArchiveArgs,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    if !args.out.is_dir() {
+        bail!("Destination must be a directory");
+    }
+    let path = args.archive.clone();
+    let parsed = tokio::task::spawn_blocking(move || parse_archive(&path)).await??;
+    // The same folder `download` syncs the account into, which also keeps a crafted archive's
+    // username from pointing outside `out`
+    let user_dir = account_dir(&args.out, &parsed.username);
+    fs::create_dir_all(&user_dir)
+        .await
+        .context("Unable to create output directory")?;
+    let _lock = lock::lock(&user_dir, args.wait_lock).await?;
+    let mut data_file = DataFile::load(&user_dir, parsed.user_id)
+        .await?
+        .unwrap_or_else(|| DataFile::new(parsed.user_id));
+    let new = data_file.merge_tweets(parsed.tweets, "archive");
+    log::info!(
+        "Imported {} new tweets for {} from archive",
+        new,
+        parsed.username
+    );
+    data_file.save(&user_dir).await?;
+
+    // Prefer copying media bundled in the archive, as it may no longer be online
+    let extracted = extract_bundled_media(
+        &args.archive,
+        &parsed.media_files,
+        &user_dir,
+        &mut data_file,
+        &MediaSelection::new(&args.media)?,
+    )
+    .await?;
+    log::info!("Extracted {} files from archive", extracted);
+    data_file.save(&user_dir).await?;
+
+    download_media(
+        &parsed.username,
+        &user_dir,
+        &mut data_file,
+        &args.media,
+        &connection_pool(),
+        cancel,
+    )
+    .await?;
+    Ok(())
+}
+
+fn parse_archive(path: &Path) -> anyhow::Result<ParsedArchive> {
+    let file = File::open(path).context("Unable to open archive")?;
+    let mut zip = ZipArchive::new(file).context("Unable to read archive")?;
+    let names = zip.file_names().map(String::from).collect::<Vec<_>>();
+
+    let account = read_ytd_file::<AccountEntry>(&mut zip, "data/account.js")?
+        .into_iter()
+        .next()
+        .context("Archive does not contain an account")?
+        .account;
+
+    // Tweets may be split over several parts, e.g. `tweet.js`, `tweet-part1.js`
+    let mut tweets = Vec::new();
+    for name in names.iter().filter(|n| is_tweet_file(n)) {
+        let entries = read_ytd_file::<TweetEntry>(&mut zip, name)?;
+        for entry in entries {
+            let tweet = match entry {
+                TweetEntry::Wrapped { tweet } => tweet,
+                TweetEntry::Bare(tweet) => tweet,
+            };
+            // The API clients exclude retweets, so the archive import should too
+            if tweet.full_text.starts_with("RT @") {
+                continue;
+            }
+            tweets.push(Tweet::try_from(tweet)?);
+        }
+    }
+    if tweets.is_empty() {
+        bail!("Archive does not contain any tweets")
+    }
+
+    let media_files = names
+        .into_iter()
+        .filter(|n| n.starts_with("data/tweet_media/") || n.starts_with("data/tweets_media/"))
+        .collect();
+
+    Ok(ParsedArchive {
+        user_id: account
+            .account_id
+            .parse()
+            .context("Couldn't parse user id")?,
+        username: account.username,
+        tweets,
+        media_files,
+    })
+}
+
+fn is_tweet_file(name: &str) -> bool {
+    ["data/tweet.js", "data/tweets.js"].contains(&name)
+        || ((name.starts_with("data/tweet-part") || name.starts_with("data/tweets-part"))
+            && name.ends_with(".js"))
+}
+
+/// The archive `.js` files are JSON arrays assigned to a variable, e.g. `window.YTD.tweet.part0 = [...]`
+fn read_ytd_file<T: DeserializeOwned>(
+    zip: &mut ZipArchive<File>,
+    name: &str,
+) -> anyhow::Result<Vec<T>> {
+    let mut text = String::new();
+    zip.by_name(name)
+        .with_context(|| format!("Archive is missing {name}"))?
+        .read_to_string(&mut text)
+        .with_context(|| format!("Unable to read {name}"))?;
+    let json = match text.find('=') {
+        Some(idx) => &text[idx + 1..],
+        None => &text,
+    };
+    serde_json::from_str(json).with_context(|| format!("Unable to deserialize {name}"))
+}
+
+async fn extract_bundled_media(
+    archive: &Path,
+    media_files: &[String],
+    user_dir: &Path,
+    data_file: &mut DataFile,
+    selection: &MediaSelection,
+) -> anyhow::Result<usize> {
+    let mut extractions = Vec::new();
+    for (tweet_index, media_index, url, filename) in selection.candidates(&data_file.tweets).await?
+    {
+        // Bundled media is named `<tweet id>-<original file name>`
+        let tweet = &data_file.tweets[tweet_index];
+        let original = tweet.media[media_index]
+            .url
+            .as_ref()
+            .unwrap_or(&url)
+            .path_segments()
+            .and_then(|mut s| s.next_back())
+            .unwrap_or("");
+        let bundled = format!("{}-{}", tweet.id, original);
+        if let Some(name) = media_files.iter().find(|n| n.ends_with(&bundled)) {
+            extractions.push((tweet_index, media_index, name.clone(), filename));
+        }
+    }
+    let archive = archive.to_path_buf();
+    let destination_dir = user_dir.to_path_buf();
+    let extractions = tokio::task::spawn_blocking(move || {
+        let file = File::open(&archive).context("Unable to open archive")?;
+        let mut zip = ZipArchive::new(file).context("Unable to read archive")?;
+        for (_, _, name, filename) in &extractions {
+            let destination = destination_dir.join(filename);
+            if destination.exists() {
+                continue;
+            }
+            if let Some(parent) = destination.parent() {
+                std::fs::create_dir_all(parent).context("Unable to create directory")?;
+            }
+            let mut entry = zip.by_name(name)?;
+            let mut out = File::create(&destination).context("Unable to create file")?;
+            std::io::copy(&mut entry, &mut out).context("Unable to extract file")?;
+        }
+        Ok::<_, anyhow::Error>(extractions)
+    })
+    .await??;
+    for (tweet_index, media_index, _, filename) in &extractions {
+        let media = &mut data_file.tweets[*tweet_index].media[*media_index];
+        let path = user_dir.join(filename);
+        crate::encryption::encrypt_file(&path).await?;
+        media.file_name = Some(filename.clone());
+        media.sha256 = Some(crate::verify::hash_file(&path).await?);
+        let tweet = &data_file.tweets[*tweet_index];
+        selection
+            .plugins()
+            .post_download(tweet, &tweet.media[*media_index], &path)?;
+    }
+    Ok(extractions.len())
+}
+
+impl TryFrom<ArchiveTweet> for Tweet {
+    type Error = anyhow::Error;
+
+    fn try_from(tweet: ArchiveTweet) -> anyhow::Result<Self> {
+        let media = match tweet.extended_entities {
+            None => Vec::new(),
+            Some(entities) => entities
+                .media
+                .into_iter()
+                .map(Media::try_from)
+                .collect::<Result<_, _>>()?,
+        };
+        Ok(Tweet {
+            id: tweet.id_str.parse().context("Couldn't parse tweet id")?,
+            timestamp: DateTime::parse_from_str(&tweet.created_at, LEGACY_DATE_FORMAT)
+                .context("Couldn't parse tweet date")?
+                .timestamp(),
+            text: tweet.full_text,
+            media,
+            conversation_id: None,
+            in_reply_to: tweet
+                .in_reply_to_status_id_str
+                .and_then(|id| id.parse().ok()),
+            quoted_tweet_id: None,
+            poll: None,
+            card: None,
+            // The archive only has the exact locations
+            place: None,
+            coordinates: tweet.coordinates.and_then(|point| {
+                let degrees =
+                    |value: &Value| value.as_f64().or_else(|| value.as_str()?.parse().ok());
+                Some(Coordinates {
+                    longitude: degrees(&point.coordinates[0])?,
+                    latitude: degrees(&point.coordinates[1])?,
+                })
+            }),
+            batch: None,
+            source: tweet.source.as_deref().and_then(source_name),
+            like_count: tweet.favorite_count.and_then(|c| c.parse().ok()),
+            retweet_count: tweet.retweet_count.and_then(|c| c.parse().ok()),
+            possibly_sensitive: tweet.possibly_sensitive,
+            unavailable_since: None,
+        })
+    }
+}
+
+impl TryFrom<ArchiveMedia> for Media {
+    type Error = anyhow::Error;
+
+    fn try_from(media: ArchiveMedia) -> anyhow::Result<Self> {
+        let id = media.id_str.parse().context("Couldn't parse media id")?;
+        Ok(match media.r#type.as_str() {
+            "photo" => Media::new(
+                id,
+                MediaType::Photo,
+                Some(Url::from_str(&media.media_url_https)?),
+            ),
+            "video" => Media::new(id, MediaType::Video, Some(get_video_url(&media)?)),
+            "animated_gif" => Media::new(id, MediaType::Gif, Some(get_video_url(&media)?)),
+            other => bail!("Unknown media type: {other}"),
+        })
+    }
+}
+
+fn get_video_url(media: &ArchiveMedia) -> anyhow::Result<Url> {
+    let info = media.video_info.as_ref().context("Missing video info")?;
+    let best_variant = info
+        .variants
+        .iter()
+        .filter_map(|v| Some((v.bitrate.as_ref()?.parse::<u64>().ok()?, v)))
+        .max_by_key(|(bitrate, _)| *bitrate)
+        .context("Missing video variant")?;
+    Ok(Url::from_str(&best_variant.1.url)?)
+}
@@ -0,0 +1,1029 @@
+//! Downloads the media of Twitter accounts into self-describing archive folders (`tweets.json`
+//! and the media next to it), see the README for the command line.
+//!
+//! The same pipeline can be embedded into other programs: a [`twitter::TwitterClient`] fetches an
+//! account's tweets from one of the backends, [`download::download_account`] syncs them into the
+//! account's folder, and [`model::DataFile`] reads and writes the archived tweets. The options are
+//! the command line's, parsed with [`clap::Parser`]:
+//!
+//! ```no_run
+//! use clap::Parser;
+//! use tokio_util::sync::CancellationToken;
+//! use twitter_dl::{download, DownloadArgs};
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let args = DownloadArgs::parse_from(["twitter-dl", "--backend", "guest", "-o", "./twitter"]);
+//! let client = download::setup(&args).await?;
+//! let connection_pool = download::connection_pool();
+//! let cancel = CancellationToken::new();
+//! download::download_account("alice", &args, client.as_ref(), &connection_pool, &cancel).await?;
+//!
+//! let data_file = twitter_dl::model::DataFile::load_unchecked("./twitter/alice".as_ref()).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod bench;
+pub mod cancel;
+mod dedupe;
+pub mod download;
+mod encryption;
+mod events;
+mod export;
+mod external;
+mod feed;
+mod filename;
+mod fix_urls;
+mod friends;
+#[cfg(test)]
+mod golden;
+mod image_index;
+mod import;
+mod lock;
+mod merge;
+mod messages;
+mod metadata;
+pub mod model;
+mod nsfw;
+mod objects;
+mod ocr;
+mod pack;
+mod plugin;
+mod progress;
+mod proxy;
+mod rclone;
+mod render;
+mod report;
+mod retry;
+mod run;
+mod sidecar;
+mod size;
+mod telegram;
+mod thumbnail;
+mod time;
+pub mod twitter;
+mod verify;
+mod watch;
+mod wayback;
+mod webhook;
+
+#[cfg(feature = "serve")]
+mod serve;
+
+use crate::external::ExternalCommand;
+use crate::model::{FileNameTemplate, ProfileImageKind, VideoQuality};
+use crate::size::ByteSize;
+use crate::time::TimeZoneSetting;
+use chrono::NaiveDate;
+use clap::{Parser, Subcommand};
+use regex::Regex;
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio_util::sync::CancellationToken;
+use url::Url;
+
+#[derive(Parser, Debug)]
+#[clap(version)]
+struct Args {
+    #[clap(subcommand)]
+    command: Commands,
+    /// Don't show progress bars
+    #[clap(short, long, global = true)]
+    quiet: bool,
+    /// Write line delimited JSON events to stdout (implies `--quiet`)
+    #[clap(long, global = true)]
+    json: bool,
+    /// Encrypt the archive (data files and media) with the key in this file (64 hex characters),
+    /// an encrypted archive can only be read with the same key
+    #[clap(long, global = true)]
+    key_file: Option<PathBuf>,
+    /// Keep memory use down for huge accounts, data files are streamed from and to storage (and
+    /// written without indentation)
+    #[clap(long, global = true, env = "TWITTER_DL_LOW_MEMORY")]
+    low_memory: bool,
+    /// JSON file of templates replacing the wording of the summaries (e.g. to translate them)
+    #[clap(long, global = true, env = "TWITTER_DL_MESSAGES")]
+    messages: Option<PathBuf>,
+    /// Send all requests through this proxy, e.g. `http://proxy:8080` or `socks5h://127.0.0.1:9050`
+    /// (`socks5h` resolves the host names through the proxy)
+    #[clap(long, global = true, env = "TWITTER_DL_PROXY")]
+    proxy: Option<Url>,
+    /// Proxy for the API requests only, instead of `--proxy`
+    #[clap(long, global = true, env = "TWITTER_DL_API_PROXY")]
+    api_proxy: Option<Url>,
+    /// Proxy for the media downloads only, instead of `--proxy`
+    #[clap(long, global = true, env = "TWITTER_DL_MEDIA_PROXY")]
+    media_proxy: Option<Url>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Download tweets
+    Download(DownloadArgs),
+    /// Serve the downloaded tweet viewer
+    Serve(ServeArgs),
+    /// Import tweets from an official Twitter data export (ZIP)
+    ImportArchive(ImportArchiveArgs),
+    /// Find media without a URL (e.g. downloaded using API v2), re-resolve and download them
+    FixMissingUrls(FixMissingUrlsArgs),
+    /// Manage the thumbnail cache
+    Cache(CacheArgs),
+    /// Keep running, periodically checking the accounts for new media
+    Watch(WatchArgs),
+    /// Export downloaded tweets and media for use by other tools
+    Export(ExportArgs),
+    /// Check the downloaded files against their recorded hashes, reporting missing or corrupted files
+    Verify(VerifyArgs),
+    /// Sync every account once and exit, logging as JSON (for containers, every option can be
+    /// set with a `TWITTER_DL_*` environment variable)
+    Run(RunArgs),
+    /// Measure data file performance and simulate download scheduling, to help tune the settings
+    Bench(BenchArgs),
+    /// Restore the files most recently moved to the trash (e.g. by `--dedupe` or `verify
+    /// --redownload`)
+    Undo(UndoArgs),
+    /// Re-attempt the downloads that failed in a previous `download` or `run`, from its report
+    RetryFailed(RetryFailedArgs),
+    /// List an account's followers and the accounts it follows, written as JSON and CSV into its
+    /// folder
+    Friends(FriendsArgs),
+    /// Merge the account folders of one archive into another (e.g. from another machine),
+    /// combining their tweets and moving over the files it lacks
+    Merge(MergeArgs),
+    /// Bundle an account folder into a single `.tar.zst` file, listing the hash of every file
+    Pack(PackArgs),
+    /// Extract a bundle made by `pack`, checking every file against its hash
+    Unpack(UnpackArgs),
+}
+
+#[derive(Parser, Debug)]
+pub struct DownloadArgs {
+    /// Path to the authentication details file (`auth.json`, or a browser cookie export)
+    #[clap(short, long, default_value = "./auth.json", env = "TWITTER_DL_AUTH")]
+    auth: PathBuf,
+    /// The authentication details themselves (in any of the `--auth` formats), e.g. to pass them
+    /// to a container without mounting a file
+    #[clap(long, env = "TWITTER_DL_AUTH_DATA", hide_env_values = true)]
+    auth_data: Option<String>,
+    /// Where to save downloaded media (a sub folder will be created for each username)
+    #[clap(short, long, default_value = "./", env = "TWITTER_DL_OUT")]
+    out: PathBuf,
+    /// Username(s) to download from (comma seperated)
+    #[clap(short, long, env = "TWITTER_DL_USERS")]
+    users: Option<String>,
+    /// File containing list of usernames to download from (one per line)
+    #[clap(short, long, env = "TWITTER_DL_LIST")]
+    list: Option<PathBuf>,
+    /// File of tweet URLs (or ids) to download the media of, saved into their authors' folders
+    #[clap(long, env = "TWITTER_DL_TWEET_LIST")]
+    tweet_list: Option<PathBuf>,
+    #[clap(flatten)]
+    media: MediaArgs,
+    /// Rescan tweets that have already been loaded
+    #[clap(long, env = "TWITTER_DL_RESCAN")]
+    rescan: bool,
+    /// Only fetch (about) this many of the newest tweets of each account, e.g. to sample heavy
+    /// accounts. The older tweets aren't fetched by the following runs either, without `--rescan`.
+    #[clap(long, env = "TWITTER_DL_MAX_TWEETS")]
+    max_tweets: Option<usize>,
+    /// Number of backups of each account's `tweets.json` to keep (`tweets.json.1` being the newest),
+    /// one being made at the start of each sync. A data file that can't be read is restored from
+    /// the newest backup that can.
+    #[clap(long, default_value_t = 1, env = "TWITTER_DL_BACKUPS")]
+    backups: usize,
+    /// Wait for another process syncing the same account (or watching the same output folder) to
+    /// finish, rather than failing
+    #[clap(long, env = "TWITTER_DL_WAIT_LOCK")]
+    wait_lock: bool,
+    /// Number of accounts to sync at the same time (each downloading `--concurrency` files at a
+    /// time). `watch` checks its accounts one at a time regardless.
+    #[clap(long, default_value_t = 1, env = "TWITTER_DL_ACCOUNT_CONCURRENCY")]
+    account_concurrency: usize,
+    /// Also download the account's avatar and banner (for the viewer), keeping a dated copy of
+    /// each new one. Earlier versions always downloaded them.
+    #[clap(long, env = "TWITTER_DL_PROFILE_MEDIA")]
+    profile_media: bool,
+    /// When an account that was previously synced no longer exists, look it up by its id, in case
+    /// it was renamed. Its folder is moved to the new name, leaving a symlink from the old one.
+    #[clap(long, env = "TWITTER_DL_FOLLOW_RENAMES")]
+    follow_renames: bool,
+    /// Continue even if an account fails to download
+    #[clap(long, env = "TWITTER_DL_CONTINUE_ON_ERROR")]
+    continue_on_error: bool,
+    /// Only report how many files (and roughly how many bytes) would be downloaded, without
+    /// downloading them or saving the new tweets
+    #[clap(long, env = "TWITTER_DL_DRY_RUN")]
+    dry_run: bool,
+    /// Where to fetch tweets from
+    #[clap(long, arg_enum, default_value_t = Backend::V1, env = "TWITTER_DL_BACKEND")]
+    backend: Backend,
+    /// Use Twitter API 2 (shorthand for `--backend v2`)
+    #[clap(long, env = "TWITTER_DL_API_V2")]
+    api_v2: bool,
+    /// Base URL of the Nitter instance to use with `--backend nitter`
+    #[clap(long, env = "TWITTER_DL_NITTER_URL")]
+    nitter_url: Option<Url>,
+    /// JSON config file declaring the backend to use with `--backend extractor`
+    #[clap(long, env = "TWITTER_DL_EXTRACTOR")]
+    extractor: Option<PathBuf>,
+    /// Also download the rest of any threads (conversations) authored by the user (requires API 2).
+    /// They are found with the recent search, which only covers the last 7 days.
+    #[clap(long, env = "TWITTER_DL_THREADS")]
+    threads: bool,
+    /// With the GraphQL backend, only fetch the tweets with media (the profile's Media tab), which
+    /// reaches further back than the full timeline. The tweets without media aren't recorded, and
+    /// later syncs without this flag only fetch tweets newer than those already recorded. Ignored
+    /// with `--threads`, `--submit-wayback` and `--audio`, which need the whole timeline.
+    #[clap(long, env = "TWITTER_DL_MEDIA_TIMELINE")]
+    media_timeline: bool,
+    /// Ask the Wayback Machine to capture each tweet, as an independent off-site backup
+    #[clap(long, env = "TWITTER_DL_SUBMIT_WAYBACK")]
+    submit_wayback: bool,
+    /// Upload each account folder to this rclone remote (e.g. `s3:bucket/twitter`) once synced
+    #[clap(long, env = "TWITTER_DL_RCLONE_REMOTE")]
+    rclone_remote: Option<String>,
+    /// Delete the media from the output directory once uploaded (the data files are kept, as
+    /// they record what has already been downloaded)
+    #[clap(
+        long,
+        requires = "rclone-remote",
+        conflicts_with = "repair",
+        env = "TWITTER_DL_RCLONE_MOVE"
+    )]
+    rclone_move: bool,
+    /// The rclone binary
+    #[clap(long, default_value = "rclone", env = "TWITTER_DL_RCLONE")]
+    rclone: PathBuf,
+    /// Send a summary of each synced account to this Telegram chat (only when it has new tweets or
+    /// files, or failed), using the `--telegram-token` bot
+    #[clap(long, requires = "telegram-token", env = "TWITTER_DL_TELEGRAM_CHAT_ID")]
+    telegram_chat_id: Option<String>,
+    /// Token of the Telegram bot sending the summaries (from @BotFather)
+    #[clap(
+        long,
+        requires = "telegram-chat-id",
+        env = "TWITTER_DL_TELEGRAM_TOKEN",
+        hide_env_values = true
+    )]
+    telegram_token: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct ImportArchiveArgs {
+    /// Path to the Twitter archive ZIP file
+    archive: PathBuf,
+    /// Where to save imported tweets (a sub folder will be created for the username)
+    #[clap(short, long, default_value = "./")]
+    out: PathBuf,
+    /// Wait for a sync of the account to finish, rather than failing
+    #[clap(long)]
+    wait_lock: bool,
+    #[clap(flatten)]
+    media: MediaArgs,
+}
+
+#[derive(Parser, Debug)]
+pub struct FixMissingUrlsArgs {
+    /// Location of tweet folders to fix
+    #[clap(default_value = "./")]
+    dir: PathBuf,
+    /// Path to the authentication details file (requires API v1.1 access)
+    #[clap(short, long, default_value = "./auth.json")]
+    auth: PathBuf,
+    #[clap(flatten)]
+    media: MediaArgs,
+}
+
+#[derive(Parser, Debug)]
+pub struct VerifyArgs {
+    /// Location of tweet folders to verify
+    #[clap(default_value = "./")]
+    dir: PathBuf,
+    /// Download the missing and corrupted files again
+    #[clap(long)]
+    redownload: bool,
+    #[clap(flatten)]
+    media: MediaArgs,
+}
+
+#[derive(Parser, Debug)]
+pub struct FriendsArgs {
+    username: String,
+    /// Where the account folders are (a sub folder will be created for the username)
+    #[clap(short, long, default_value = "./")]
+    out: PathBuf,
+    /// Path to the authentication details file (`auth.json`, or a browser cookie export)
+    #[clap(short, long, default_value = "./auth.json", env = "TWITTER_DL_AUTH")]
+    auth: PathBuf,
+    /// The authentication details themselves (in any of the `--auth` formats)
+    #[clap(long, env = "TWITTER_DL_AUTH_DATA", hide_env_values = true)]
+    auth_data: Option<String>,
+    /// Where to fetch the lists from (`v1`, `v2` or `graphql`)
+    #[clap(long, arg_enum, default_value_t = Backend::V1, env = "TWITTER_DL_BACKEND")]
+    backend: Backend,
+}
+
+#[derive(Parser, Debug)]
+pub struct MergeArgs {
+    /// Location of the tweet folders to merge (duplicate files are left there)
+    src_dir: PathBuf,
+    /// Location of the tweet folders to merge them into
+    dst_dir: PathBuf,
+    /// Wait for syncs of the accounts to finish, rather than failing
+    #[clap(long)]
+    wait_lock: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct PackArgs {
+    /// The account to pack
+    username: String,
+    /// Location of the tweet folders
+    #[clap(short, long, default_value = "./")]
+    dir: PathBuf,
+    /// Where to write the bundle (`<username>.tar.zst` by default)
+    #[clap(short, long)]
+    out: Option<PathBuf>,
+    /// Wait for a sync of the account to finish, rather than failing
+    #[clap(long)]
+    wait_lock: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct UnpackArgs {
+    /// The `.tar.zst` file made by `pack`
+    bundle: PathBuf,
+    /// Location of the tweet folders to extract the account folder into
+    #[clap(short, long, default_value = "./")]
+    out: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+pub struct RetryFailedArgs {
+    /// The `report.json` of the run, in its output folder
+    #[clap(default_value = "./report.json")]
+    report: PathBuf,
+    /// The rest of the options apply as for `download`, the file naming options (e.g.
+    /// `--organize-by`) must be the same as those of the run
+    #[clap(flatten)]
+    media: MediaArgs,
+}
+
+#[derive(Parser, Debug)]
+pub struct UndoArgs {
+    /// Location of tweet folders
+    #[clap(default_value = "./")]
+    dir: PathBuf,
+    /// Restore this trash folder (see `--list`) rather than the most recent
+    #[clap(long)]
+    id: Option<String>,
+    /// List the trash folders instead of restoring any
+    #[clap(long, conflicts_with = "id")]
+    list: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct BenchArgs {
+    /// Location of tweet folders to measure
+    #[clap(long, default_value = "./")]
+    dir: PathBuf,
+    /// Maximum number of downloads to simulate
+    #[clap(long, default_value_t = 200)]
+    downloads: usize,
+    /// How long each simulated download takes, in milliseconds
+    #[clap(long, default_value_t = 100)]
+    latency_ms: u64,
+    #[clap(flatten)]
+    media: MediaArgs,
+}
+
+#[derive(Parser, Debug)]
+pub struct CacheArgs {
+    #[clap(subcommand)]
+    command: CacheCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommands {
+    /// Generate thumbnails for all downloaded images, and index their visual attributes
+    Build(CacheBuildArgs),
+    /// Evict thumbnails from the cache
+    Prune(CachePruneArgs),
+}
+
+#[derive(Parser, Debug)]
+pub struct CacheBuildArgs {
+    /// Location of tweet folders
+    #[clap(default_value = "./")]
+    dir: PathBuf,
+    /// Maximum width/height of the thumbnails
+    #[clap(long, default_value_t = 320)]
+    width: u32,
+}
+
+#[derive(Parser, Debug)]
+pub struct ExportArgs {
+    #[clap(subcommand)]
+    command: ExportCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ExportCommands {
+    /// Re-fetch the downloaded media, recording the HTTP exchanges into a WARC file
+    Warc(ExportWarcArgs),
+    /// Add the downloaded media to an IPFS node, recording their CIDs in the data files
+    Ipfs(ExportIpfsArgs),
+    /// Write one row per media item (tweet, date, text, type, file and URL) as CSV or TSV
+    Table(ExportTableArgs),
+    /// Write an RSS feed of each account's most recent tweets (`feed.xml` in its folder)
+    Rss(ExportRssArgs),
+    /// Assemble each account's past avatars (or banners) into a contact sheet or time-lapse
+    Timelapse(ExportTimelapseArgs),
+    /// Write a single page of the media tweeted by every account over a recent period
+    Digest(ExportDigestArgs),
+    /// Write the geotagged tweets as a GeoJSON FeatureCollection, for mapping where the media was
+    /// posted from
+    Geojson(ExportGeojsonArgs),
+    /// Write a static HTML gallery of an account (`index.html` in its folder), for browsing it
+    /// without `serve`
+    Html(ExportHtmlArgs),
+}
+
+#[derive(Parser, Debug)]
+pub struct ExportWarcArgs {
+    /// Location of tweet folders
+    #[clap(default_value = "./")]
+    dir: PathBuf,
+    /// WARC file to write
+    #[clap(short, long, default_value = "./twitter-dl.warc")]
+    out: PathBuf,
+    /// Number of requests to do concurrently
+    #[clap(long, default_value_t = 4)]
+    concurrency: usize,
+}
+
+#[derive(Parser, Debug)]
+pub struct ExportIpfsArgs {
+    /// Location of tweet folders
+    #[clap(default_value = "./")]
+    dir: PathBuf,
+    /// Base URL of the IPFS node's RPC API
+    #[clap(long, default_value = "http://127.0.0.1:5001/")]
+    api: Url,
+    /// Don't pin the added files (they may be garbage collected by the node)
+    #[clap(long)]
+    no_pin: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct ExportTableArgs {
+    /// Location of tweet folders
+    #[clap(default_value = "./")]
+    dir: PathBuf,
+    /// File to write, otherwise the table is written to stdout
+    #[clap(short, long)]
+    out: Option<PathBuf>,
+    #[clap(long, arg_enum, default_value_t = TableFormat::Csv)]
+    format: TableFormat,
+}
+
+#[derive(Parser, Debug)]
+pub struct ExportRssArgs {
+    /// Location of tweet folders
+    #[clap(default_value = "./")]
+    dir: PathBuf,
+    /// Where the tweet folders are served from (e.g. `https://example.com/twitter/`), so that the
+    /// enclosures link to the downloaded files rather than to Twitter
+    #[clap(long)]
+    base_url: Option<Url>,
+    /// Number of tweets in each feed
+    #[clap(long, default_value_t = 50)]
+    limit: usize,
+}
+
+#[derive(Parser, Debug)]
+pub struct ExportTimelapseArgs {
+    /// Location of tweet folders
+    #[clap(default_value = "./")]
+    dir: PathBuf,
+    /// Which of the profile images to assemble
+    #[clap(long, arg_enum, default_value_t = ProfileImageKind::Avatar)]
+    image: ProfileImageKind,
+    #[clap(long, arg_enum, default_value_t = TimelapseFormat::Sheet)]
+    format: TimelapseFormat,
+    /// Height of each image in pixels (banners are three times as wide)
+    #[clap(long, default_value_t = 200)]
+    size: u32,
+    /// How long each image is shown in the time-lapse, in milliseconds
+    #[clap(long, default_value_t = 500)]
+    frame_ms: u64,
+    /// The ffmpeg binary, used to encode mp4s
+    #[clap(long, default_value = "ffmpeg")]
+    ffmpeg: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+pub struct ExportDigestArgs {
+    /// Location of tweet folders
+    #[clap(default_value = "./")]
+    dir: PathBuf,
+    /// How far back to include tweets (e.g. `7d`, `1month`)
+    #[clap(long, default_value = "7d")]
+    since: humantime::Duration,
+    #[clap(long, arg_enum, default_value_t = DigestFormat::Html)]
+    format: DigestFormat,
+    /// File to write, otherwise the digest is written to stdout
+    #[clap(short, long)]
+    out: Option<PathBuf>,
+    /// Where the tweet folders are served from (e.g. `https://example.com/twitter/`), otherwise
+    /// the downloaded files are linked relative to the folder of tweet folders
+    #[clap(long)]
+    base_url: Option<Url>,
+}
+
+#[derive(Parser, Debug)]
+pub struct ExportGeojsonArgs {
+    /// Location of tweet folders
+    #[clap(default_value = "./")]
+    dir: PathBuf,
+    /// File to write, otherwise the GeoJSON is written to stdout
+    #[clap(short, long)]
+    out: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub struct ExportHtmlArgs {
+    /// The account folder
+    #[clap(default_value = "./")]
+    dir: PathBuf,
+    /// Maximum width/height of the photo thumbnails, in pixels
+    #[clap(long, default_value_t = 320)]
+    thumbnail_size: u32,
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DigestFormat {
+    Html,
+    Markdown,
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TimelapseFormat {
+    /// A PNG with the images in a grid, oldest first
+    Sheet,
+    /// An animated GIF
+    Gif,
+    /// An mp4 video (using ffmpeg)
+    Mp4,
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TableFormat {
+    /// Comma separated, quoted as per RFC 4180
+    Csv,
+    /// Tab separated, with tabs and line breaks within fields escaped (`\t`, `\n`)
+    Tsv,
+}
+
+#[derive(Parser, Debug)]
+pub struct CachePruneArgs {
+    /// Location of tweet folders
+    #[clap(default_value = "./")]
+    dir: PathBuf,
+    /// Evict the least recently used thumbnails until the cache is at most this size (e.g. 500M)
+    #[clap(long)]
+    max_size: Option<ByteSize>,
+    /// Evict thumbnails that haven't been used for this many days
+    #[clap(long)]
+    max_age_days: Option<u64>,
+}
+
+#[derive(Parser, Debug)]
+pub struct MediaArgs {
+    /// Download photos
+    #[clap(long, env = "TWITTER_DL_PHOTOS")]
+    photos: bool,
+    /// Download videos
+    #[clap(long, env = "TWITTER_DL_VIDEOS")]
+    videos: bool,
+    /// Download gifs
+    #[clap(long, env = "TWITTER_DL_GIFS")]
+    gifs: bool,
+    /// Download the recordings of the Spaces linked from tweets (with `--backend graphql`), as
+    /// m4a files (requires `ffmpeg`)
+    #[clap(long, env = "TWITTER_DL_AUDIO")]
+    audio: bool,
+    /// Also download the preview images of videos and gifs, which the viewer shows until they are
+    /// played
+    #[clap(long, env = "TWITTER_DL_THUMBNAILS")]
+    thumbnails: bool,
+    /// Download the scaled down photos served by default, rather than the original resolution
+    #[clap(long, env = "TWITTER_DL_NO_ORIG_QUALITY")]
+    no_orig_quality: bool,
+    /// Leave the downloaded files' modification times as when they were downloaded, rather than
+    /// setting them to when the tweet was posted
+    #[clap(long, env = "TWITTER_DL_NO_MTIME")]
+    no_mtime: bool,
+    /// Which video variant to download: `best`, `worst`, or the best at most this height (e.g.
+    /// `720`)
+    #[clap(long, default_value = "best", env = "TWITTER_DL_VIDEO_QUALITY")]
+    video_quality: VideoQuality,
+    /// How downloaded files are named (before their extension), from the tokens `{tweet_id}`,
+    /// `{media_id}` and `{index}` (the media's position within the tweet, starting from 1)
+    #[clap(
+        long,
+        default_value = "{tweet_id}_{media_id}",
+        env = "TWITTER_DL_FILE_NAME"
+    )]
+    file_name: FileNameTemplate,
+    /// Only use ASCII letters, digits, `-`, `_` and `.` in the media's file and folder names
+    /// (characters Windows doesn't allow are always replaced)
+    #[clap(long, env = "TWITTER_DL_RESTRICT_FILENAMES")]
+    restrict_filenames: bool,
+    /// Number of downloads to do concurrently
+    #[clap(long, default_value_t = 4, env = "TWITTER_DL_CONCURRENCY")]
+    concurrency: usize,
+    /// Maximum number of concurrent downloads from any one host (e.g. `pbs.twimg.com`)
+    #[clap(long, env = "TWITTER_DL_PER_HOST_CONCURRENCY")]
+    per_host_concurrency: Option<usize>,
+    /// Milliseconds to wait between starting each download
+    #[clap(long, default_value_t = 0, env = "TWITTER_DL_DELAY_MS")]
+    delay_ms: u64,
+    /// Maximum combined download speed, in bytes per second (e.g. `500K`, `2M`)
+    #[clap(long, env = "TWITTER_DL_LIMIT_RATE")]
+    limit_rate: Option<ByteSize>,
+    /// Don't let the output folder grow beyond this size (e.g. `50G`), checked against the sizes
+    /// of the queued files before downloading them
+    #[clap(long, env = "TWITTER_DL_MAX_DISK_USAGE")]
+    max_disk_usage: Option<ByteSize>,
+    /// Check that the queued files fit in the free space of the disk before downloading them
+    #[clap(long, env = "TWITTER_DL_CHECK_FREE_SPACE")]
+    check_free_space: bool,
+    /// What to do when the queued files won't fit, with `--max-disk-usage` or
+    /// `--check-free-space`
+    #[clap(long, arg_enum, default_value_t = DiskFullAction::Stop, env = "TWITTER_DL_ON_DISK_FULL")]
+    on_disk_full: DiskFullAction,
+    #[clap(long, arg_enum, default_value_t = FileExistsPolicy::Warn, env = "TWITTER_DL_FILE_EXISTS_POLICY")]
+    file_exists_policy: FileExistsPolicy,
+    /// How to arrange media within each user folder
+    #[clap(long, arg_enum, default_value_t = OrganizeBy::None, env = "TWITTER_DL_ORGANIZE_BY")]
+    organize_by: OrganizeBy,
+    /// Download at most this many files of each account per run, the rest are left for the
+    /// following runs
+    #[clap(long, env = "TWITTER_DL_MAX_FILES")]
+    max_files: Option<usize>,
+    /// Which tweets' media is downloaded first (and kept with `--max-files`)
+    #[clap(long, arg_enum, default_value_t = DownloadOrder::Oldest, env = "TWITTER_DL_ORDER")]
+    order: DownloadOrder,
+    /// Only download media from tweets posted on or after this date (YYYY-MM-DD), older tweets
+    /// aren't fetched either
+    #[clap(long, visible_alias = "since", env = "TWITTER_DL_SINCE_DATE")]
+    since_date: Option<NaiveDate>,
+    /// Only download media from tweets posted before this date (YYYY-MM-DD), the v1, v2 and guest
+    /// backends don't fetch newer tweets either
+    #[clap(long, visible_alias = "until", env = "TWITTER_DL_UNTIL_DATE")]
+    until_date: Option<NaiveDate>,
+    /// Only download media from tweets posted with these apps (e.g. `Twitter for iPhone`), tweets
+    /// whose app isn't known are still downloaded
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        use_value_delimiter = true,
+        env = "TWITTER_DL_INCLUDE_SOURCE"
+    )]
+    include_source: Vec<String>,
+    /// Skip the media from tweets posted with these apps (e.g. `dlvr.it` for automated reposts)
+    #[clap(
+        long,
+        multiple_occurrences = true,
+        use_value_delimiter = true,
+        env = "TWITTER_DL_EXCLUDE_SOURCE"
+    )]
+    exclude_source: Vec<String>,
+    /// Only download media from tweets whose text matches this regular expression (e.g.
+    /// `#fanart`, prefix with `(?i)` to ignore case)
+    #[clap(long, env = "TWITTER_DL_FILTER_TEXT")]
+    filter_text: Option<Regex>,
+    /// Skip the media from tweets whose text matches this regular expression (e.g. `#ad|#sponsored`)
+    #[clap(long, env = "TWITTER_DL_EXCLUDE_TEXT")]
+    exclude_text: Option<Regex>,
+    /// Only download media from tweets with at least this many likes (as of when they were
+    /// fetched), tweets whose count isn't known are still downloaded
+    #[clap(long, env = "TWITTER_DL_MIN_LIKES")]
+    min_likes: Option<u64>,
+    /// Only download media from tweets retweeted at least this many times (as of when they were
+    /// fetched), tweets whose count isn't known are still downloaded
+    #[clap(long, env = "TWITTER_DL_MIN_RETWEETS")]
+    min_retweets: Option<u64>,
+    /// Skip the media from tweets Twitter flagged as possibly sensitive
+    #[clap(
+        long,
+        conflicts_with = "only-sensitive",
+        env = "TWITTER_DL_SKIP_SENSITIVE"
+    )]
+    skip_sensitive: bool,
+    /// Only download media from tweets Twitter flagged as possibly sensitive
+    #[clap(long, env = "TWITTER_DL_ONLY_SENSITIVE")]
+    only_sensitive: bool,
+    /// Time zone used for calendar dates: `local`, an IANA name (e.g. `Europe/London`) or an
+    /// offset (e.g. `+05:30`)
+    #[clap(long, default_value = "local", env = "TWITTER_DL_TIMEZONE")]
+    timezone: TimeZoneSetting,
+    /// Script that decides which tweets to download media from, it receives each tweet as JSON on
+    /// stdin and must exit with 0 to accept or 1 to reject it
+    #[clap(long, env = "TWITTER_DL_FILTER_CMD")]
+    filter_cmd: Option<ExternalCommand>,
+    /// The ffmpeg binary, used to download videos that are only available as HLS (`.m3u8`)
+    /// playlists
+    #[clap(long, default_value = "ffmpeg", env = "TWITTER_DL_FFMPEG")]
+    ffmpeg: PathBuf,
+    /// Check that previously downloaded files still exist, and download any that are missing again
+    #[clap(long, env = "TWITTER_DL_REPAIR")]
+    repair: bool,
+    /// Move the downloaded files that the media types and filters no longer select into the
+    /// trash, and forget them, so that the archive shrinks to match the current settings
+    #[clap(long, env = "TWITTER_DL_PRUNE")]
+    prune: bool,
+    /// Attempt the files that were no longer available (404 or 403) again, rather than skipping them
+    #[clap(long, env = "TWITTER_DL_RETRY_UNAVAILABLE")]
+    retry_unavailable: bool,
+    /// If media no longer exists (404), download an archived copy from the Wayback Machine instead
+    #[clap(long, env = "TWITTER_DL_WAYBACK_FALLBACK")]
+    wayback_fallback: bool,
+    /// Also save each tweet as a standalone HTML page, alongside its media
+    #[clap(long, env = "TWITTER_DL_RENDER_HTML")]
+    render_html: bool,
+    /// Write the tweet's text, author, URL and date into the downloaded files (XMP for JPEGs and
+    /// PNGs, metadata atoms for mp4s using ffmpeg)
+    #[clap(long, env = "TWITTER_DL_EMBED_METADATA")]
+    embed_metadata: bool,
+    /// Write a `<file>.json` next to each downloaded file, describing its tweet (in the same layout
+    /// as gallery-dl)
+    #[clap(long, env = "TWITTER_DL_WRITE_METADATA")]
+    write_metadata: bool,
+    /// Lua script(s) customising which media is downloaded and where it is saved (see `plugin.rs`)
+    #[clap(long, multiple_occurrences = true)]
+    plugin: Vec<PathBuf>,
+    /// Extract text from downloaded photos using OCR, so that it can be searched in the viewer
+    #[clap(long, env = "TWITTER_DL_OCR")]
+    ocr: bool,
+    /// The OCR command, `{}` is replaced with the image path and the text is read from stdout
+    #[clap(
+        long,
+        default_value = "tesseract {} stdout",
+        env = "TWITTER_DL_OCR_COMMAND"
+    )]
+    ocr_command: ExternalCommand,
+    /// Classify downloaded photos with this command, `{}` is replaced with the image path and it
+    /// must print a NSFW score between 0 and 1
+    #[clap(long, env = "TWITTER_DL_NSFW_COMMAND")]
+    nsfw_command: Option<ExternalCommand>,
+    /// Score at or above which a photo is considered NSFW
+    #[clap(long, default_value_t = 0.8, env = "TWITTER_DL_NSFW_THRESHOLD")]
+    nsfw_threshold: f32,
+    /// Move NSFW photos into a `quarantine` sub folder
+    #[clap(long, requires = "nsfw-command", env = "TWITTER_DL_QUARANTINE_NSFW")]
+    quarantine_nsfw: bool,
+    /// Only store one copy of media that has been posted more than once
+    #[clap(long, arg_enum, env = "TWITTER_DL_DEDUPE")]
+    dedupe: Option<DedupeMode>,
+    /// Also treat visually identical photos (e.g. re-encoded or resized) as duplicates, these are
+    /// always deduplicated by reference as the files differ
+    #[clap(long, requires = "dedupe", env = "TWITTER_DL_DEDUPE_PERCEPTUAL")]
+    dedupe_perceptual: bool,
+    /// Store each distinct file once in `.objects/<sha256>` in the output folder, linking to it
+    /// from the account folders, so that media shared by several accounts only takes up space once
+    #[clap(long, arg_enum, env = "TWITTER_DL_OBJECTS")]
+    objects: Option<ObjectLink>,
+    /// Store the media in object storage instead of the account folder, either
+    /// `s3://<bucket>/<prefix>` or `webdav(s)://[<user>:<password>@]<host>/<path>` (the data files
+    /// are still kept locally)
+    #[clap(long, env = "TWITTER_DL_SINK", hide_env_values = true)]
+    sink: Option<Url>,
+    /// The endpoint of an S3 compatible service other than AWS (e.g. `https://<account>.r2.cloudflarestorage.com`)
+    #[clap(long, requires = "sink", env = "TWITTER_DL_S3_ENDPOINT")]
+    s3_endpoint: Option<Url>,
+    /// POST a JSON event to this URL after each downloaded file (`download_completed`) and once
+    /// each account is synced (`account_finished`)
+    #[clap(long, env = "TWITTER_DL_WEBHOOK_URL")]
+    webhook_url: Option<Url>,
+    /// On Ctrl-C, how long to wait for the downloads in progress to complete before stopping them
+    #[clap(long, default_value = "30s", env = "TWITTER_DL_SHUTDOWN_TIMEOUT")]
+    shutdown_timeout: humantime::Duration,
+    /// Give up on a download (and retry it) if it hasn't completed in this long, e.g. `10m`
+    #[clap(long, env = "TWITTER_DL_DOWNLOAD_TIMEOUT")]
+    download_timeout: Option<humantime::Duration>,
+    /// Give up on a download (and retry it) if no data has been received for this long
+    #[clap(long, default_value = "60s", env = "TWITTER_DL_STALL_TIMEOUT")]
+    stall_timeout: humantime::Duration,
+    /// How many times to retry a download that timed out or stalled
+    #[clap(long, default_value_t = 2, env = "TWITTER_DL_DOWNLOAD_RETRIES")]
+    download_retries: usize,
+    /// Only the media with these (tweet, media) ids, set by `retry-failed`
+    #[clap(skip)]
+    retry: Option<BTreeSet<(u64, u64)>>,
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OrganizeBy {
+    /// All media is saved directly in the user folder
+    None,
+    /// Media is saved in a sub folder for the date the tweet was posted (YYYY-MM-DD)
+    Date,
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DiskFullAction {
+    /// Fail the account without downloading anything
+    Stop,
+    /// Log a warning and download the files anyway
+    Warn,
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DownloadOrder {
+    /// In the order the tweets were posted
+    Oldest,
+    /// The most recent tweets first
+    Newest,
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DedupeMode {
+    /// Duplicate files are replaced with hard links to the first copy
+    HardLink,
+    /// Duplicate files are deleted, and the media refers to the first copy's file instead
+    Reference,
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ObjectLink {
+    /// The account folders hold hard links to the objects (which must be on the same file system)
+    HardLink,
+    /// The account folders hold relative symlinks to the objects
+    Symlink,
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Backend {
+    /// Twitter API v1.1
+    V1,
+    /// Twitter API v2 (Warning: Does not support Video and Gif downloads)
+    V2,
+    /// Scrape a Nitter instance (no Twitter credentials required)
+    Nitter,
+    /// Use the Twitter web client's guest access (no Twitter credentials required)
+    Guest,
+    /// Use the Twitter web client's GraphQL API (requires browser session cookies)
+    Graphql,
+    /// Use a backend declared in a JSON config file (see `--extractor`)
+    Extractor,
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Eq, PartialEq)]
+pub enum FileExistsPolicy {
+    /// The existing file will be overwritten with a new download
+    Overwrite,
+    /// The data file will be updated to include the already present file, unless it is empty or
+    /// corrupted (in which case it is moved to the trash and downloaded again)
+    Adopt,
+    /// A warning is printed to the console
+    Warn,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ServeArgs {
+    /// Location of tweet folders to serve
+    #[clap(default_value = "./")]
+    dir: PathBuf,
+    /// Socket to serve the server on
+    #[clap(long, default_value = "127.0.0.1:7008")]
+    socket: SocketAddr,
+    /// Don't launch the web browser
+    #[clap(long)]
+    no_launch: bool,
+    /// Don't use TLS/HTTP2
+    #[clap(long)]
+    no_tls: bool,
+    /// Certificate chain to serve, as PEM (e.g. Let's Encrypt's `fullchain.pem`), instead of
+    /// the embedded self-signed certificate
+    #[clap(long, requires = "key", conflicts_with = "no-tls")]
+    cert: Option<PathBuf>,
+    /// Private key of `--cert`, as PEM
+    #[clap(long, requires = "cert")]
+    key: Option<PathBuf>,
+    /// Require HTTP Basic authentication with these credentials (`user:pass`)
+    #[clap(long, env = "TWITTER_DL_SERVE_AUTH", hide_env_values = true)]
+    auth: Option<String>,
+    /// Require this token, as a `Bearer` authorization header or a `token` query parameter (which
+    /// the browser then remembers)
+    #[clap(long, env = "TWITTER_DL_SERVE_AUTH_TOKEN", hide_env_values = true)]
+    auth_token: Option<String>,
+    /// NSFW classifier score at or above which the viewer blurs photos until they're clicked (the
+    /// same variable as `download --nsfw-threshold`, so that they agree)
+    #[clap(long, default_value_t = 0.8, env = "TWITTER_DL_NSFW_THRESHOLD")]
+    nsfw_threshold: f32,
+}
+
+#[derive(Parser, Debug)]
+pub struct WatchArgs {
+    #[clap(flatten)]
+    download: DownloadArgs,
+    /// How often to check each account (e.g. `30m`, `6h`)
+    #[clap(long, default_value = "1h")]
+    every: humantime::Duration,
+    /// Maximum random delay added to each check, to avoid requests in lockstep (default: a tenth
+    /// of `--every`)
+    #[clap(long)]
+    jitter: Option<humantime::Duration>,
+}
+
+#[derive(Parser, Debug)]
+pub struct RunArgs {
+    #[clap(flatten)]
+    download: DownloadArgs,
+    /// Keep running, checking each account this often (e.g. `30m`, `6h`) rather than exiting
+    #[clap(long, env = "TWITTER_DL_EVERY")]
+    every: Option<humantime::Duration>,
+    /// Maximum random delay added to each check (default: a tenth of `--every`)
+    #[clap(long, requires = "every", env = "TWITTER_DL_JITTER")]
+    jitter: Option<humantime::Duration>,
+}
+
+/// Runs the command line interface, exiting with its exit code on failure
+pub async fn cli() {
+    let args: Args = Args::parse();
+    let run = matches!(args.command, Commands::Run(_));
+    crate::progress::init(args.quiet || args.json || run, run);
+    crate::events::init(args.json);
+    crate::model::set_low_memory(args.low_memory);
+    let out = match &args.command {
+        Commands::Download(args) => Some(&args.out),
+        Commands::Watch(args) => Some(&args.download.out),
+        Commands::Run(args) => Some(&args.download.out),
+        _ => None,
+    };
+    let dry_run = match &args.command {
+        Commands::Download(args) => args.dry_run,
+        Commands::Watch(args) => args.download.dry_run,
+        Commands::Run(args) => args.download.dry_run,
+        _ => false,
+    };
+    // The log names the accounts and files, which an encrypted archive shouldn't reveal
+    let encrypted = args.key_file.is_some();
+    if let Some(out) = out.filter(|out| out.is_dir() && !dry_run && !encrypted) {
+        crate::events::log_to(out);
+    }
+    // The other commands are simply stopped by the signal
+    let cancel = match args.command {
+        Commands::Download(_)
+        | Commands::ImportArchive(_)
+        | Commands::FixMissingUrls(_)
+        | Commands::Watch(_)
+        | Commands::Verify(_)
+        | Commands::Run(_)
+        | Commands::RetryFailed(_) => crate::cancel::on_signal(),
+        _ => CancellationToken::new(),
+    };
+    if let Err(e) = async {
+        crate::encryption::init(args.key_file.as_deref())?;
+        crate::messages::init(args.messages.as_deref())?;
+        crate::proxy::init(
+            args.proxy.as_ref(),
+            args.api_proxy.as_ref(),
+            args.media_proxy.as_ref(),
+        )?;
+        match args.command {
+            Commands::Download(args) => crate::download::download(args, &cancel).await?,
+            Commands::ImportArchive(args) => crate::import::import_archive(args, &cancel).await?,
+            Commands::FixMissingUrls(args) => {
+                crate::fix_urls::fix_missing_urls(args, &cancel).await?
+            }
+            Commands::Cache(args) => crate::thumbnail::cache(args).await?,
+            Commands::Watch(args) => crate::watch::watch(args, &cancel).await?,
+            Commands::Export(args) => crate::export::export(args).await?,
+            Commands::Verify(args) => crate::verify::verify(args, &cancel).await?,
+            Commands::Run(args) => crate::run::run(args, &cancel).await?,
+            Commands::Bench(args) => crate::bench::bench(args).await?,
+            Commands::Undo(args) => crate::download::undo(args).await?,
+            Commands::RetryFailed(args) => crate::retry::retry_failed(args, &cancel).await?,
+            Commands::Friends(args) => crate::friends::friends(args).await?,
+            Commands::Merge(args) => crate::merge::merge(args).await?,
+            Commands::Pack(args) => crate::pack::pack(args).await?,
+            Commands::Unpack(args) => crate::pack::unpack(args).await?,
+            #[cfg(feature = "serve")]
+            Commands::Serve(args) => crate::serve::serve(args).await?,
+            #[cfg(not(feature = "serve"))]
+            Commands::Serve(_) => {
+                anyhow::bail!("Application must be built with the `serve` feature")
+            }
+        };
+        Ok::<_, anyhow::Error>(())
+    }
+    .await
+    {
+        log::error!("{:#}", e);
+        std::process::exit(crate::run::exit_code(&e));
+    }
+}
@@ -0,0 +1,58 @@
+//! Advisory locks (`.twitter-dl.lock`) that stop two processes from syncing into the same folder
+//! at once, which would otherwise overwrite each other's data files
+
+use anyhow::{bail, Context};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::Path;
+
+pub const LOCK_FILE: &str = ".twitter-dl.lock";
+
+/// Held until dropped (or the process exits)
+#[derive(Debug)]
+pub struct DirLock {
+    _file: File,
+}
+
+/// Locks the folder, waiting for the other process to finish with `wait`, failing otherwise
+pub async fn lock(dir: &Path, wait: bool) -> anyhow::Result<DirLock> {
+    let path = dir.join(LOCK_FILE);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Unable to open {}", path.display()))?;
+    match file.try_lock() {
+        Ok(()) => return Ok(DirLock { _file: file }),
+        Err(TryLockError::WouldBlock) if wait => {}
+        Err(TryLockError::WouldBlock) => bail!(
+            "{} is being synced by another twitter-dl process (`--wait-lock` waits for it to finish)",
+            dir.display()
+        ),
+        Err(TryLockError::Error(e)) => {
+            return Err(e).with_context(|| format!("Unable to lock {}", path.display()))
+        }
+    }
+    log::info!(
+        "Waiting for another twitter-dl process to finish with {}",
+        dir.display()
+    );
+    let file = tokio::task::spawn_blocking(move || file.lock().map(|()| file))
+        .await?
+        .with_context(|| format!("Unable to lock {}", path.display()))?;
+    Ok(DirLock { _file: file })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn locks_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let held = lock(dir.path(), false).await.unwrap();
+        assert!(lock(dir.path(), false).await.is_err());
+        drop(held);
+        assert!(lock(dir.path(), false).await.is_ok());
+    }
+}
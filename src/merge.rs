@@ -0,0 +1,411 @@
+//! `merge`: consolidates the account folders of one archive into another (e.g. archives made on
+//! different machines), combining their tweets and moving over the files the destination lacks
+
+use crate::filename::numbered;
+use crate::lock;
+use crate::model::{list_user_dirs, DataFile, Media};
+use crate::verify::hash_file;
+use crate::MergeArgs;
+use anyhow::{bail, Context};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use tokio::fs;
+
+#[derive(Default, Debug, PartialEq, Eq)]
+struct Counts {
+    new_tweets: usize,
+    moved: usize,
+    /// Moved under another name, as a different file had the same name
+    renamed: usize,
+    /// Already in the destination, these are left in the source folder
+    duplicates: usize,
+}
+
+/// Where a file ended up, relative to the destination account folder
+enum Placed {
+    Moved(String),
+    Duplicate(String),
+}
+
+pub async fn merge(args: MergeArgs) -> anyhow::Result<()> {
+    if !args.src_dir.is_dir() {
+        bail!("expected a directory")
+    }
+    fs::create_dir_all(&args.dst_dir)
+        .await
+        .context("Unable to create the destination directory")?;
+    for src_user_dir in list_user_dirs(&args.src_dir).await? {
+        let username = src_user_dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let dst_user_dir = args.dst_dir.join(&username);
+        let counts = merge_account(&src_user_dir, &dst_user_dir, args.wait_lock)
+            .await
+            .with_context(|| format!("Unable to merge {username}"))?;
+        log::info!(
+            "Merged {}: {} new tweets, {} files moved ({} renamed), {} duplicates left behind",
+            username,
+            counts.new_tweets,
+            counts.moved,
+            counts.renamed,
+            counts.duplicates
+        );
+    }
+    Ok(())
+}
+
+async fn merge_account(src_dir: &Path, dst_dir: &Path, wait_lock: bool) -> anyhow::Result<Counts> {
+    fs::create_dir_all(dst_dir)
+        .await
+        .context("Unable to create output directory")?;
+    let _src_lock = lock::lock(src_dir, wait_lock).await?;
+    let _dst_lock = lock::lock(dst_dir, wait_lock).await?;
+    let mut src = DataFile::load_unchecked(src_dir)
+        .await?
+        .context("Missing data file")?;
+    let mut dst = match DataFile::load_unchecked(dst_dir).await? {
+        Some(dst) if dst.user_id != src.user_id => {
+            bail!(
+                "The folders are for different accounts (user ids {} and {})",
+                src.user_id,
+                dst.user_id
+            )
+        }
+        Some(dst) => dst,
+        None => {
+            let mut dst = DataFile::new(src.user_id);
+            dst.version = src.version;
+            dst
+        }
+    };
+    // Only as up to date as the older of the two, so that the next sync refreshes the rest
+    let version = dst.version.min(src.version);
+
+    let mut counts = Counts::default();
+    let mut moved = HashSet::new();
+    // The moved files are recorded in both data files even if a later one fails to move
+    let placed = place_files(
+        src_dir,
+        dst_dir,
+        &mut src,
+        &mut dst,
+        &mut counts,
+        &mut moved,
+    )
+    .await;
+    let wayback_submitted = std::mem::take(&mut src.wayback_submitted);
+    let profile = src.profile.take();
+    counts.new_tweets = dst.merge_tweets(src.tweets, "merge");
+    dst.version = version;
+    dst.wayback_submitted.extend(wayback_submitted);
+    if dst.profile.is_none() {
+        dst.profile = profile;
+    }
+    dst.save(dst_dir).await?;
+    forget_moved(src_dir, &moved).await?;
+    placed.map(|()| counts)
+}
+
+/// Moves the files that the destination lacks, recording them against its media (or against the
+/// source's tweets, which are merged into it afterwards)
+async fn place_files(
+    src_dir: &Path,
+    dst_dir: &Path,
+    src: &mut DataFile,
+    dst: &mut DataFile,
+    counts: &mut Counts,
+    moved: &mut HashSet<String>,
+) -> anyhow::Result<()> {
+    let mut dst_media = HashMap::new();
+    for (tweet_index, tweet) in dst.tweets.iter().enumerate() {
+        for (media_index, media) in tweet.media.iter().enumerate() {
+            dst_media.insert((tweet.id, media.id), (tweet_index, media_index));
+        }
+    }
+    // With `--dedupe reference` media can share a file, which is only moved once
+    let mut placed = HashMap::<String, String>::new();
+    let mut failed = None;
+    for tweet in &mut src.tweets {
+        for media in &mut tweet.media {
+            // Once a file fails to move, the rest are left in the source
+            if failed.is_some() {
+                media.file_name = None;
+                media.preview_file_name = None;
+                continue;
+            }
+            let existing = dst_media
+                .get(&(tweet.id, media.id))
+                .map(|(t, m)| &mut dst.tweets[*t].media[*m]);
+            let result = place_media(
+                src_dir,
+                dst_dir,
+                media,
+                existing,
+                &mut placed,
+                counts,
+                moved,
+            );
+            if let Err(e) = result.await {
+                media.preview_file_name = None;
+                failed = Some(e);
+            }
+        }
+    }
+    failed.map_or(Ok(()), Err)
+}
+
+async fn place_media(
+    src_dir: &Path,
+    dst_dir: &Path,
+    media: &mut Media,
+    existing: Option<&mut Media>,
+    placed: &mut HashMap<String, String>,
+    counts: &mut Counts,
+    moved: &mut HashSet<String>,
+) -> anyhow::Result<()> {
+    let downloaded = |m: &Media| {
+        m.file_name
+            .as_ref()
+            .is_some_and(|f| dst_dir.join(f).exists())
+    };
+    if existing.as_deref().is_some_and(downloaded) {
+        if media.file_name.is_some() {
+            counts.duplicates += 1;
+        }
+        return Ok(());
+    }
+    if let Some(file_name) = media.file_name.take() {
+        let new_name = match placed.get(&file_name) {
+            Some(new_name) => Some(new_name.clone()),
+            None => match place(src_dir, dst_dir, &file_name).await? {
+                Some(Placed::Moved(new_name)) => {
+                    moved.insert(file_name.clone());
+                    counts.moved += 1;
+                    if new_name != file_name {
+                        counts.renamed += 1;
+                    }
+                    Some(new_name)
+                }
+                Some(Placed::Duplicate(new_name)) => {
+                    counts.duplicates += 1;
+                    Some(new_name)
+                }
+                None => None,
+            },
+        };
+        if let Some(new_name) = &new_name {
+            placed.insert(file_name, new_name.clone());
+        } else {
+            media.sha256 = None;
+        }
+        media.file_name = new_name;
+    }
+    if let Some(preview) = media.preview_file_name.take() {
+        media.preview_file_name = match place(src_dir, dst_dir, &preview).await? {
+            Some(Placed::Moved(name)) => {
+                moved.insert(preview);
+                Some(name)
+            }
+            Some(Placed::Duplicate(name)) => Some(name),
+            None => None,
+        };
+    }
+    // Merging keeps the destination's record of the files, which is now this one
+    if let Some(existing) = existing {
+        adopt_file(existing, media);
+    }
+    Ok(())
+}
+
+/// Rewrites the source's data file without the files that were moved out of its folder
+async fn forget_moved(src_dir: &Path, moved: &HashSet<String>) -> anyhow::Result<()> {
+    if moved.is_empty() {
+        return Ok(());
+    }
+    let mut src = DataFile::load_unchecked(src_dir)
+        .await?
+        .context("Missing data file")?;
+    for media in src.tweets.iter_mut().flat_map(|t| &mut t.media) {
+        if media.file_name.as_ref().is_some_and(|f| moved.contains(f)) {
+            media.file_name = None;
+            media.sha256 = None;
+        }
+        if media
+            .preview_file_name
+            .as_ref()
+            .is_some_and(|f| moved.contains(f))
+        {
+            media.preview_file_name = None;
+        }
+    }
+    src.save(src_dir).await
+}
+
+/// Takes over the file recorded by media from the other archive, and what was derived from it
+fn adopt_file(existing: &mut Media, media: &Media) {
+    if media.file_name.is_none() {
+        return;
+    }
+    existing.file_name = media.file_name.clone();
+    existing.sha256 = media.sha256.clone();
+    existing.ocr_text = media.ocr_text.clone();
+    existing.nsfw_score = media.nsfw_score;
+    existing.recovered_from = media.recovered_from.clone();
+    existing.duplicate_of = media.duplicate_of;
+    existing.ipfs_cid = media.ipfs_cid.clone();
+    existing.preview_file_name = media.preview_file_name.clone();
+    existing.metadata_embedded = media.metadata_embedded;
+    existing.unavailable_since = None;
+}
+
+/// Moves a file (relative to the account folders) across, numbering it if a different file
+/// already has its name. Returns None if the source file doesn't exist.
+async fn place(src_dir: &Path, dst_dir: &Path, file_name: &str) -> anyhow::Result<Option<Placed>> {
+    let source = src_dir.join(file_name);
+    if !source.exists() {
+        return Ok(None);
+    }
+    let mut candidate = file_name.to_string();
+    for n in 1.. {
+        let destination = dst_dir.join(&candidate);
+        if !destination.exists() {
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            move_file(&source, &destination)
+                .await
+                .with_context(|| format!("Unable to move {}", source.display()))?;
+            return Ok(Some(Placed::Moved(candidate)));
+        }
+        if hash_file(&source).await? == hash_file(&destination).await? {
+            return Ok(Some(Placed::Duplicate(candidate)));
+        }
+        candidate = numbered(file_name, n);
+    }
+    unreachable!()
+}
+
+/// Renames the file, or copies it if the archives are on different file systems. Links into an
+/// `--objects` store are relative to the source archive, so the file they point to is copied.
+async fn move_file(source: &Path, destination: &Path) -> std::io::Result<()> {
+    if fs::symlink_metadata(source).await?.is_symlink() {
+        fs::copy(source, destination).await?;
+        return fs::remove_file(source).await;
+    }
+    if fs::rename(source, destination).await.is_ok() {
+        return Ok(());
+    }
+    fs::copy(source, destination).await?;
+    fs::remove_file(source).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::data_file;
+
+    #[tokio::test]
+    async fn merges_accounts() {
+        let out = tempfile::tempdir().unwrap();
+        let (src_dir, dst_dir) = (out.path().join("src"), out.path().join("dst"));
+        std::fs::create_dir_all(&src_dir).unwrap();
+        std::fs::create_dir_all(&dst_dir).unwrap();
+        for (file_name, contents) in [("30_11.jpg", "a"), ("30_12.png", "b"), ("20_21.mp4", "v")] {
+            std::fs::write(src_dir.join(file_name), contents).unwrap();
+        }
+        data_file().save(&src_dir).await.unwrap();
+        // The destination lacks the second photo and the video, and has another file named as it
+        let mut dst = data_file();
+        dst.tweets[2].media[1].file_name = None;
+        dst.tweets[1].media[0].file_name = None;
+        dst.tweets.remove(0);
+        std::fs::write(dst_dir.join("30_11.jpg"), "a").unwrap();
+        std::fs::write(dst_dir.join("20_21.mp4"), "other").unwrap();
+        dst.save(&dst_dir).await.unwrap();
+
+        let counts = merge_account(&src_dir, &dst_dir, false).await.unwrap();
+        let expected = Counts {
+            new_tweets: 1,
+            moved: 2,
+            renamed: 1,
+            duplicates: 1,
+        };
+        assert_eq!(counts, expected);
+        let merged = DataFile::load_unchecked(&dst_dir).await.unwrap().unwrap();
+        assert_eq!(merged.tweets.len(), 4);
+        let file_name = |t: usize, m: usize| merged.tweets[t].media[m].file_name.as_deref();
+        assert_eq!(file_name(1, 0), Some("20_21-1.mp4"));
+        assert_eq!(file_name(2, 1), Some("30_12.png"));
+        assert_eq!(
+            std::fs::read_to_string(dst_dir.join("20_21-1.mp4")).unwrap(),
+            "v"
+        );
+        assert!(src_dir.join("30_11.jpg").exists());
+        // The source no longer records the files that were moved out of it
+        let src = DataFile::load_unchecked(&src_dir).await.unwrap().unwrap();
+        let file_name = |t: usize, m: usize| src.tweets[t].media[m].file_name.as_deref();
+        assert_eq!(file_name(2, 0), Some("30_11.jpg"));
+        assert_eq!(file_name(2, 1), None);
+        assert_eq!(file_name(1, 0), None);
+    }
+
+    #[tokio::test]
+    async fn records_files_moved_before_failing() {
+        let out = tempfile::tempdir().unwrap();
+        let (src_dir, dst_dir) = (out.path().join("src"), out.path().join("dst"));
+        std::fs::create_dir_all(src_dir.join("d")).unwrap();
+        std::fs::create_dir_all(&dst_dir).unwrap();
+        for (file_name, contents) in [("d/30_11.jpg", "a"), ("30_12.png", "b"), ("20_21.mp4", "v")]
+        {
+            std::fs::write(src_dir.join(file_name), contents).unwrap();
+        }
+        let mut src = data_file();
+        src.tweets[2].media[0].file_name = Some("d/30_11.jpg".to_string());
+        src.save(&src_dir).await.unwrap();
+        // The photo can't be moved, as a file is in the way of its folder
+        std::fs::write(dst_dir.join("d"), "").unwrap();
+
+        assert!(merge_account(&src_dir, &dst_dir, false).await.is_err());
+        let dst = DataFile::load_unchecked(&dst_dir).await.unwrap().unwrap();
+        let file_name = |t: usize, m: usize| dst.tweets[t].media[m].file_name.as_deref();
+        assert_eq!(file_name(1, 0), Some("20_21.mp4"));
+        assert_eq!(file_name(2, 0), None);
+        assert_eq!(file_name(2, 1), None);
+        let src = DataFile::load_unchecked(&src_dir).await.unwrap().unwrap();
+        let file_name = |t: usize, m: usize| src.tweets[t].media[m].file_name.as_deref();
+        assert_eq!(file_name(1, 0), None);
+        assert_eq!(file_name(2, 0), Some("d/30_11.jpg"));
+        assert_eq!(file_name(2, 1), Some("30_12.png"));
+
+        // Once the problem is fixed, merging again moves the rest
+        std::fs::remove_file(dst_dir.join("d")).unwrap();
+        merge_account(&src_dir, &dst_dir, false).await.unwrap();
+        let dst = DataFile::load_unchecked(&dst_dir).await.unwrap().unwrap();
+        let file_name = |t: usize, m: usize| dst.tweets[t].media[m].file_name.as_deref();
+        assert_eq!(file_name(1, 0), Some("20_21.mp4"));
+        assert_eq!(file_name(2, 0), Some("d/30_11.jpg"));
+        assert_eq!(file_name(2, 1), Some("30_12.png"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn resolves_object_links() {
+        let out = tempfile::tempdir().unwrap();
+        let (src_out, dst_out) = (out.path().join("a"), out.path().join("b"));
+        std::fs::create_dir_all(src_out.join(".objects")).unwrap();
+        std::fs::create_dir_all(src_out.join("alice")).unwrap();
+        std::fs::write(src_out.join(".objects/abc"), "a").unwrap();
+        std::os::unix::fs::symlink("../.objects/abc", src_out.join("alice/30_11.jpg")).unwrap();
+        let dst_dir = dst_out.join("alice");
+        std::fs::create_dir_all(&dst_dir).unwrap();
+
+        let placed = place(&src_out.join("alice"), &dst_dir, "30_11.jpg")
+            .await
+            .unwrap();
+        assert!(matches!(placed, Some(Placed::Moved(_))));
+        let moved = dst_dir.join("30_11.jpg");
+        assert!(!std::fs::symlink_metadata(&moved).unwrap().is_symlink());
+        assert_eq!(std::fs::read_to_string(moved).unwrap(), "a");
+    }
+}
@@ -0,0 +1,216 @@
+//! The wording of the summaries shown to the user (e.g. how many files were downloaded), which can
+//! be replaced with `--messages`, so that shared deployments can reword or translate them. The file
+//! is JSON, mapping message names to templates with `{placeholder}`s, e.g.
+//! `{"messages": {"downloaded": "{count} Dateien für {username} heruntergeladen"}}`, and optionally
+//! a `thousands_separator` for the numbers.
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::OnceLock;
+
+static TEMPLATES: OnceLock<Templates> = OnceLock::new();
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Templates {
+    #[serde(default)]
+    messages: BTreeMap<String, String>,
+    #[serde(default)]
+    thousands_separator: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    NewTweets,
+    NewThreadTweets,
+    Downloaded,
+    Verified,
+    Synced,
+    CheckingEvery,
+    NextCheck,
+}
+
+impl Message {
+    const ALL: [Message; 7] = [
+        Message::NewTweets,
+        Message::NewThreadTweets,
+        Message::Downloaded,
+        Message::Verified,
+        Message::Synced,
+        Message::CheckingEvery,
+        Message::NextCheck,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Message::NewTweets => "new_tweets",
+            Message::NewThreadTweets => "new_thread_tweets",
+            Message::Downloaded => "downloaded",
+            Message::Verified => "verified",
+            Message::Synced => "synced",
+            Message::CheckingEvery => "checking_every",
+            Message::NextCheck => "next_check",
+        }
+    }
+
+    fn default_template(self) -> &'static str {
+        match self {
+            Message::NewTweets => "Got {count} new tweets for {username}",
+            Message::NewThreadTweets => "Got {count} new thread tweets for {username}",
+            Message::Downloaded => "Downloaded {count} files for {username}",
+            Message::Verified => {
+                "{username}: {verified} files verified, {recorded} hashes recorded, \
+                {missing} missing, {corrupted} corrupted"
+            }
+            Message::Synced => "Synced {count} accounts",
+            Message::CheckingEvery => "Checking accounts every {every}",
+            Message::NextCheck => "Next check is for {username} in {seconds}s",
+        }
+    }
+
+    fn placeholders(self) -> &'static [&'static str] {
+        match self {
+            Message::NewTweets | Message::NewThreadTweets | Message::Downloaded => {
+                &["count", "username"]
+            }
+            Message::Verified => &["username", "verified", "recorded", "missing", "corrupted"],
+            Message::Synced => &["count"],
+            Message::CheckingEvery => &["every"],
+            Message::NextCheck => &["username", "seconds"],
+        }
+    }
+}
+
+/// A value substituted into a template
+pub enum Arg<'a> {
+    Number(i64),
+    Text(&'a str),
+}
+
+impl From<usize> for Arg<'_> {
+    fn from(n: usize) -> Self {
+        Arg::Number(n as i64)
+    }
+}
+
+impl From<i64> for Arg<'_> {
+    fn from(n: i64) -> Self {
+        Arg::Number(n)
+    }
+}
+
+impl<'a> From<&'a str> for Arg<'a> {
+    fn from(s: &'a str) -> Self {
+        Arg::Text(s)
+    }
+}
+
+impl<'a> From<&'a String> for Arg<'a> {
+    fn from(s: &'a String) -> Self {
+        Arg::Text(s)
+    }
+}
+
+/// Loads the templates from `path`, checking that they only use the placeholders available
+pub fn init(path: Option<&Path>) -> anyhow::Result<()> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let text = std::fs::read_to_string(path).context("Unable to read messages file")?;
+    let templates =
+        serde_json::from_str::<Templates>(&text).context("Unable to parse messages file")?;
+    for (name, template) in &templates.messages {
+        let message = match Message::ALL.iter().find(|m| m.name() == name) {
+            Some(message) => message,
+            None => bail!(
+                "Unknown message `{}`, expected one of: {}",
+                name,
+                Message::ALL.map(Message::name).join(", ")
+            ),
+        };
+        for placeholder in placeholders(template) {
+            if !message.placeholders().contains(&placeholder) {
+                bail!(
+                    "Unknown placeholder `{{{}}}` in `{}`, expected one of: {}",
+                    placeholder,
+                    name,
+                    message.placeholders().join(", ")
+                )
+            }
+        }
+    }
+    TEMPLATES.set(templates).ok();
+    Ok(())
+}
+
+/// Formats a message, with its `{placeholder}`s replaced by `args`
+pub fn format<'a>(message: Message, args: impl IntoIterator<Item = (&'a str, Arg<'a>)>) -> String {
+    let templates = TEMPLATES.get();
+    let template = templates
+        .and_then(|t| t.messages.get(message.name()))
+        .map(String::as_str)
+        .unwrap_or_else(|| message.default_template());
+    let separator = templates.and_then(|t| t.thousands_separator.as_deref());
+    let args = args
+        .into_iter()
+        .map(|(name, arg)| {
+            let value = match arg {
+                Arg::Number(n) => group_digits(n, separator),
+                Arg::Text(s) => s.to_string(),
+            };
+            (name, value)
+        })
+        .collect::<BTreeMap<_, _>>();
+    let mut formatted = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        formatted.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after
+            .find('}')
+            .and_then(|end| Some((args.get(&after[..end])?, end)))
+        {
+            Some((value, end)) => {
+                formatted.push_str(value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                formatted.push('{');
+                rest = after;
+            }
+        }
+    }
+    formatted.push_str(rest);
+    formatted
+}
+
+/// The names of the `{placeholder}`s in a template
+fn placeholders(template: &str) -> impl Iterator<Item = &str> {
+    template.split('{').skip(1).filter_map(|s| {
+        let (name, _) = s.split_once('}')?;
+        let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        valid.then_some(name)
+    })
+}
+
+fn group_digits(n: i64, separator: Option<&str>) -> String {
+    let separator = match separator {
+        Some(separator) => separator,
+        None => return n.to_string(),
+    };
+    let digits = n.unsigned_abs().to_string();
+    let mut grouped = String::new();
+    if n < 0 {
+        grouped.push('-');
+    }
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push_str(separator);
+        }
+        grouped.push(c);
+    }
+    grouped
+}
@@ -0,0 +1,263 @@
+//! Embeds each tweet's details (text, author, URL and date) into its downloaded files, so that
+//! they remain self-describing outside of `tweets.json`. JPEGs and PNGs get an XMP packet, mp4s
+//! get metadata atoms (written by ffmpeg, without re-encoding).
+
+use crate::model::{DataFile, Tweet};
+use crate::render::escape;
+use anyhow::{bail, Context};
+use chrono::{TimeZone, Utc};
+use std::path::Path;
+use std::process::Stdio;
+use tempfile::NamedTempFile;
+use tokio::fs;
+use tokio::process::Command;
+
+const JPEG_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+
+#[derive(Clone)]
+struct Details {
+    text: String,
+    /// The media's alt text, written as its (EXIF) image description
+    alt_text: Option<String>,
+    author: String,
+    url: String,
+    /// RFC 3339
+    date: String,
+}
+
+impl Details {
+    fn new(username: &str, tweet: &Tweet) -> Self {
+        Self {
+            text: tweet.text.clone(),
+            alt_text: None,
+            author: format!("@{username}"),
+            url: format!("https://twitter.com/{username}/status/{}", tweet.id),
+            date: Utc.timestamp(tweet.timestamp, 0).to_rfc3339(),
+        }
+    }
+
+    fn xmp(&self) -> String {
+        // `tiff:ImageDescription` is how XMP holds EXIF's ImageDescription, the IPTC property is
+        // what accessibility tools read
+        let alt_text = match &self.alt_text {
+            Some(alt_text) => format!(
+                "<tiff:ImageDescription>{text}</tiff:ImageDescription>\n\
+                <Iptc4xmpCore:AltTextAccessibility><rdf:Alt><rdf:li xml:lang=\"x-default\">{text}\
+                </rdf:li></rdf:Alt></Iptc4xmpCore:AltTextAccessibility>\n",
+                text = escape(alt_text)
+            ),
+            None => String::new(),
+        };
+        format!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n\
+            <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n\
+            <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n\
+            <rdf:Description rdf:about=\"\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+            xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\" xmlns:tiff=\"http://ns.adobe.com/tiff/1.0/\" \
+            xmlns:Iptc4xmpCore=\"http://iptc.org/std/Iptc4xmpCore/1.0/xmlns/\">\n\
+            <dc:description><rdf:Alt><rdf:li xml:lang=\"x-default\">{text}</rdf:li></rdf:Alt></dc:description>\n\
+            <dc:creator><rdf:Seq><rdf:li>{author}</rdf:li></rdf:Seq></dc:creator>\n\
+            <dc:source>{url}</dc:source>\n\
+            <xmp:CreateDate>{date}</xmp:CreateDate>\n\
+            {alt_text}</rdf:Description>\n</rdf:RDF>\n</x:xmpmeta>\n<?xpacket end=\"w\"?>",
+            text = escape(&self.text),
+            author = escape(&self.author),
+            url = escape(&self.url),
+            date = self.date,
+        )
+    }
+}
+
+/// Embeds the details into every downloaded file that doesn't have them yet. Duplicates (see
+/// `--dedupe`) are skipped, as their file belongs to another tweet.
+pub async fn embed_metadata(
+    username: &str,
+    user_dir: &Path,
+    data_file: &mut DataFile,
+    ffmpeg: &Path,
+) -> anyhow::Result<()> {
+    let mut counter = 0;
+    for tweet in &mut data_file.tweets {
+        let tweet_details = Details::new(username, tweet);
+        for media in &mut tweet.media {
+            let details = Details {
+                alt_text: media.alt_text.clone(),
+                ..tweet_details.clone()
+            };
+            if media.metadata_embedded || media.duplicate_of.is_some() {
+                continue;
+            }
+            let path = match &media.file_name {
+                Some(file_name) => user_dir.join(file_name),
+                None => continue,
+            };
+            if !path.exists() {
+                continue;
+            }
+            let ext = path
+                .extension()
+                .map(|e| e.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            // Rewriting the file shouldn't change its time (see `--no-mtime`)
+            let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+            let result = match ext.as_str() {
+                "jpg" | "jpeg" => embed_image(&path, &details, embed_jpeg).await,
+                "png" => embed_image(&path, &details, embed_png).await,
+                "mp4" => embed_mp4(&path, &details, ffmpeg).await,
+                _ => continue,
+            };
+            match result {
+                Ok(()) => {
+                    if let Some(modified) = modified {
+                        std::fs::File::options()
+                            .write(true)
+                            .open(&path)
+                            .and_then(|f| f.set_modified(modified))
+                            .ok();
+                    }
+                    // The file has changed
+                    media.sha256 = Some(crate::verify::hash_file(&path).await?);
+                    media.metadata_embedded = true;
+                    counter += 1;
+                }
+                Err(e) => log::warn!("Unable to embed metadata in {}: {:#}", path.display(), e),
+            }
+        }
+    }
+    if counter > 0 {
+        log::info!("Embedded metadata in {} files", counter);
+    }
+    Ok(())
+}
+
+async fn embed_image(
+    path: &Path,
+    details: &Details,
+    embed: fn(&[u8], &str) -> anyhow::Result<Vec<u8>>,
+) -> anyhow::Result<()> {
+    let data = fs::read(path).await?;
+    let data = embed(&data, &details.xmp())?;
+    // Replace the file in one go, so it is never left partially written
+    let parent = path.parent().context("Invalid file path")?;
+    let temp = NamedTempFile::new_in(parent)?;
+    fs::write(temp.path(), data).await?;
+    temp.persist(path)?;
+    Ok(())
+}
+
+/// Inserts the XMP packet as an APP1 segment, after any JFIF/Exif segments at the start, replacing
+/// any existing XMP
+fn embed_jpeg(data: &[u8], xmp: &str) -> anyhow::Result<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        bail!("Not a JPEG")
+    }
+    let length = 2 + JPEG_XMP_HEADER.len() + xmp.len();
+    if length > u16::MAX as usize {
+        bail!("Tweet text is too long to embed")
+    }
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend_from_slice(&(length as u16).to_be_bytes());
+    segment.extend_from_slice(JPEG_XMP_HEADER);
+    segment.extend_from_slice(xmp.as_bytes());
+
+    let mut out = data[..2].to_vec();
+    let mut pos = 2;
+    let mut inserted = false;
+    // Only the segments before the image data (start of scan) need to be looked at
+    while pos + 4 <= data.len() && data[pos] == 0xFF && data[pos + 1] != 0xDA {
+        let marker = data[pos + 1];
+        let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let end = pos + 2 + length;
+        if length < 2 || end > data.len() {
+            bail!("Truncated JPEG segment")
+        }
+        if !inserted && marker != 0xE0 && marker != 0xE1 {
+            out.extend_from_slice(&segment);
+            inserted = true;
+        }
+        let existing_xmp = marker == 0xE1 && data[pos + 4..end].starts_with(JPEG_XMP_HEADER);
+        if !existing_xmp {
+            out.extend_from_slice(&data[pos..end]);
+        }
+        pos = end;
+    }
+    if !inserted {
+        out.extend_from_slice(&segment);
+    }
+    out.extend_from_slice(&data[pos..]);
+    Ok(out)
+}
+
+/// Inserts the XMP packet as an iTXt chunk after the header chunk, replacing any existing XMP
+fn embed_png(data: &[u8], xmp: &str) -> anyhow::Result<Vec<u8>> {
+    if !data.starts_with(PNG_SIGNATURE) {
+        bail!("Not a PNG")
+    }
+    // Keyword, null separator, not compressed, no language or translated keyword
+    let mut chunk_data = PNG_XMP_KEYWORD.to_vec();
+    chunk_data.extend_from_slice(&[0, 0, 0, 0, 0]);
+    chunk_data.extend_from_slice(xmp.as_bytes());
+    let mut chunk = (chunk_data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(b"iTXt");
+    chunk.extend_from_slice(&chunk_data);
+    chunk.extend_from_slice(&crc32fast::hash(&chunk[4..]).to_be_bytes());
+
+    let mut out = PNG_SIGNATURE.to_vec();
+    let mut pos = PNG_SIGNATURE.len();
+    while pos + 8 <= data.len() {
+        let length = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        let end = pos + 12 + length;
+        if end > data.len() {
+            bail!("Truncated PNG chunk")
+        }
+        let kind = &data[pos + 4..pos + 8];
+        let existing_xmp = kind == b"iTXt"
+            && data[pos + 8..end - 4].starts_with(PNG_XMP_KEYWORD)
+            && data.get(pos + 8 + PNG_XMP_KEYWORD.len()) == Some(&0);
+        if !existing_xmp {
+            out.extend_from_slice(&data[pos..end]);
+        }
+        if kind == b"IHDR" {
+            out.extend_from_slice(&chunk);
+        }
+        pos = end;
+    }
+    Ok(out)
+}
+
+async fn embed_mp4(path: &Path, details: &Details, ffmpeg: &Path) -> anyhow::Result<()> {
+    let parent = path.parent().context("Invalid file path")?;
+    let temp = NamedTempFile::new_in(parent)?;
+    let metadata = [
+        ("description", details.text.as_str()),
+        ("artist", &details.author),
+        ("comment", &details.url),
+        ("date", &details.date),
+        ("creation_time", &details.date),
+    ];
+    let output = Command::new(ffmpeg)
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+        .arg(path)
+        .args(["-map", "0", "-c", "copy"])
+        .args(
+            metadata
+                .iter()
+                .flat_map(|(key, value)| ["-metadata".to_string(), format!("{key}={value}")]),
+        )
+        .args(["-f", "mp4"])
+        .arg(temp.path())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .with_context(|| format!("Unable to run {}", ffmpeg.display()))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("ffmpeg failed: {}", stderr.trim())
+    }
+    temp.persist(path)?;
+    Ok(())
+}
@@ -1,16 +1,36 @@
+//! The archive's files: each account's data file (`tweets.json`) and profile (`profile.json`)
+
+use crate::filename::sanitize_path;
+use crate::thumbnail::ThumbnailCache;
 use anyhow::{bail, Context};
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::collections::btree_map::BTreeMap;
-use std::path::Path;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 use tokio::fs;
+use tokio::io::AsyncWriteExt;
 use url::Url;
 
 // If we introduce new features to the model we will want to refresh as much of the data
 // as possible. Although there is no guarantee we can be successful (because of the 3200)
 // tweet limit in the API, and that tweets may have since been deleted, so model changes must
 // always be backwards compatible with previous data.
-pub const MODEL_VERSION: u64 = 1;
+// 2: replies and quotes (`in_reply_to` and `quoted_tweet_id`)
+pub const MODEL_VERSION: u64 = 2;
+
+const PROFILE_FILE: &str = "profile.json";
+
+static LOW_MEMORY: AtomicBool = AtomicBool::new(false);
+
+/// With `--low-memory`, data files are parsed from and written to the file as a stream (a tweet at
+/// a time when writing), rather than via a copy of their whole text, and are written without
+/// pretty-printing
+pub fn set_low_memory(enabled: bool) {
+    LOW_MEMORY.store(enabled, AtomicOrdering::Relaxed);
+}
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Tweet {
@@ -18,6 +38,135 @@ pub struct Tweet {
     pub timestamp: i64,
     pub text: String,
     pub media: Vec<Media>,
+    /// Id of the first tweet in the thread this tweet belongs to
+    #[serde(default)]
+    pub conversation_id: Option<u64>,
+    /// Id of the tweet this is a reply to, if the backend reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<u64>,
+    /// Id of the tweet this quotes, if the backend reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quoted_tweet_id: Option<u64>,
+    /// The poll attached to the tweet, if the backend reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poll: Option<Poll>,
+    /// The preview of the link in the tweet, if the backend reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub card: Option<Card>,
+    /// The place the tweet was tagged with, if it was geotagged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub place: Option<Place>,
+    /// The exact location, if the author shared it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coordinates: Option<Coordinates>,
+    /// Id of the [FetchBatch] this tweet was most recently fetched in
+    #[serde(default)]
+    pub batch: Option<u64>,
+    /// Name of the app the tweet was posted with (e.g. `Twitter for iPhone`), if the backend
+    /// reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Number of likes as of when the tweet was last fetched, if the backend reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub like_count: Option<u64>,
+    /// Number of retweets as of when the tweet was last fetched, if the backend reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retweet_count: Option<u64>,
+    /// Whether Twitter flagged the media as possibly sensitive, if the backend reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub possibly_sensitive: Option<bool>,
+    /// When its media was first found to be gone (as happens once the tweet is deleted), cleared
+    /// if the tweet is fetched again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unavailable_since: Option<i64>,
+}
+
+/// A poll attached to a tweet, whose text is the question
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Poll {
+    pub options: Vec<PollOption>,
+    /// When voting closes (or closed)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ends_at: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PollOption {
+    pub label: String,
+    /// As of when the tweet was last fetched, not every backend reports them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub votes: Option<u64>,
+}
+
+/// The preview Twitter shows for a link in a tweet
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Card {
+    pub url: Option<Url>,
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail_url: Option<Url>,
+}
+
+/// A place a tweet was tagged with, which may be as large as a city (or a country)
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Place {
+    pub full_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    /// `[west, south, east, north]`, in degrees
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bounding_box: Option<[f64; 4]>,
+}
+
+impl Place {
+    /// The box around the points of a polygon, given as `(longitude, latitude)`
+    pub fn bounding_box(points: &[(f64, f64)]) -> Option<[f64; 4]> {
+        let (first, rest) = points.split_first()?;
+        let mut bounds = [first.0, first.1, first.0, first.1];
+        for (longitude, latitude) in rest {
+            bounds[0] = bounds[0].min(*longitude);
+            bounds[1] = bounds[1].min(*latitude);
+            bounds[2] = bounds[2].max(*longitude);
+            bounds[3] = bounds[3].max(*latitude);
+        }
+        Some(bounds)
+    }
+
+    pub fn center(&self) -> Option<Coordinates> {
+        let [west, south, east, north] = self.bounding_box?;
+        Some(Coordinates {
+            longitude: (west + east) / 2.0,
+            latitude: (south + north) / 2.0,
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Coordinates {
+    pub longitude: f64,
+    pub latitude: f64,
+}
+
+impl Tweet {
+    /// Numbers the media in the order the backend listed them (unless it reported their
+    /// positions), and sorts them by position so that a tweet's files always come out the same
+    pub fn order_media(&mut self) {
+        for (index, media) in self.media.iter_mut().enumerate() {
+            media.position.get_or_insert(index + 1);
+        }
+        self.media.sort_by_key(|m| (m.position, m.id));
+    }
+
+    /// Where the tweet was posted from, and whether that's exact rather than the center of its
+    /// place
+    pub fn location(&self) -> Option<(Coordinates, bool)> {
+        match self.coordinates {
+            Some(coordinates) => Some((coordinates, true)),
+            None => Some((self.place.as_ref()?.center()?, false)),
+        }
+    }
 }
 
 impl PartialEq<Self> for Tweet {
@@ -30,7 +179,7 @@ impl Eq for Tweet {}
 
 impl PartialOrd<Self> for Tweet {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.id.cmp(&other.id))
+        Some(self.cmp(other))
     }
 }
 
@@ -46,6 +195,52 @@ pub struct Media {
     pub r#type: MediaType,
     pub file_name: Option<String>,
     pub url: Option<Url>,
+    /// Order of the media within the tweet, starting from 1 (not recorded by older versions)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<usize>,
+    /// The description the author wrote for the media (its alt text), if the backend reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alt_text: Option<String>,
+    /// The title of a Space
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// How long a Space's recording is, if the backend reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    /// Text extracted from the image by OCR
+    #[serde(default)]
+    pub ocr_text: Option<String>,
+    /// Score from the NSFW classifier, between 0 (safe) and 1
+    #[serde(default)]
+    pub nsfw_score: Option<f32>,
+    /// SHA-256 hash (hex) of the downloaded file, for detecting corruption
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// The Wayback Machine snapshot the file was downloaded from, if the original was unavailable
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recovered_from: Option<Url>,
+    /// Id of an earlier media item with the same content, that the file is shared with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate_of: Option<u64>,
+    /// IPFS content identifier of the file, once it has been added to a node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipfs_cid: Option<String>,
+    /// The available encodings of a video or gif, `url` being the highest quality
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub variants: Vec<VideoVariant>,
+    /// The still image shown before a video or gif is played
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview_url: Option<Url>,
+    /// Where the preview image was saved, relative to the user folder (see `--thumbnails`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview_file_name: Option<String>,
+    /// Whether the tweet's details have been embedded into the file (see `--embed-metadata`)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub metadata_embedded: bool,
+    /// When downloading the file was first refused (404 or 403), it isn't attempted again (unless
+    /// it is recovered from the Wayback Machine)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unavailable_since: Option<i64>,
 }
 
 impl Media {
@@ -55,6 +250,21 @@ impl Media {
             r#type,
             file_name: None,
             url,
+            position: None,
+            alt_text: None,
+            title: None,
+            duration_ms: None,
+            ocr_text: None,
+            nsfw_score: None,
+            sha256: None,
+            recovered_from: None,
+            duplicate_of: None,
+            ipfs_cid: None,
+            variants: Vec::new(),
+            preview_url: None,
+            preview_file_name: None,
+            metadata_embedded: false,
+            unavailable_since: None,
         }
     }
 
@@ -63,39 +273,244 @@ impl Media {
         &self,
         tweet: &Tweet,
         media_types: &[MediaType],
+        orig_quality: bool,
+        video_quality: VideoQuality,
+        file_name: &FileNameTemplate,
+        restrict_filenames: bool,
     ) -> Option<(Url, String)> {
         if !media_types.contains(&self.r#type) {
             return None;
         }
-        // Only download if we haven't already got it
-        if self.file_name.is_some() {
+        // Only download if we haven't already got it, and it hasn't been found to be gone
+        if self.file_name.is_some() || self.unavailable_since.is_some() {
             return None;
         }
         // Only download if a URL is available
-        self.url.as_ref().map(|url| {
+        let url = match video_quality.select(&self.variants) {
+            Some(variant) => Some(&variant.url),
+            None => self.url.as_ref(),
+        };
+        url.map(|url| {
             let dot_idx = url.path().rfind('.');
-            let ext = dot_idx
-                .map(|idx| url.path()[idx + 1..].to_string())
-                .unwrap_or_else(String::new);
-            let file_name = format!("{}_{}.{ext}", tweet.id, self.id);
-            (url.clone(), file_name)
+            let ext = match dot_idx.map(|idx| &url.path()[idx + 1..]) {
+                // HLS playlists are muxed into an mp4, or m4a for the Spaces' audio
+                Some("m3u8") if self.r#type == MediaType::Audio => "m4a".to_string(),
+                Some("m3u8") => "mp4".to_string(),
+                Some(ext) => ext.to_string(),
+                // e.g. `pbs.twimg.com/media/<id>?format=jpg&name=small`
+                None => url
+                    .query_pairs()
+                    .find(|(k, _)| k == "format")
+                    .map(|(_, v)| v.into_owned())
+                    .unwrap_or_default(),
+            };
+            let file_name = sanitize_path(
+                &format!("{}.{ext}", file_name.render(tweet, self)),
+                restrict_filenames,
+            );
+            if orig_quality && self.r#type == MediaType::Photo {
+                (original_quality(url, &ext), file_name)
+            } else {
+                (url.clone(), file_name)
+            }
         })
     }
+
+    /// The media's position within the tweet, starting from 1
+    pub fn position_in(&self, tweet: &Tweet) -> usize {
+        self.position.unwrap_or_else(|| {
+            tweet
+                .media
+                .iter()
+                .position(|m| m.id == self.id)
+                .map_or(1, |index| index + 1)
+        })
+    }
+}
+
+/// Photos are served scaled down by default, this requests the original resolution instead, e.g.
+/// `pbs.twimg.com/media/<id>.jpg` becomes `pbs.twimg.com/media/<id>?format=jpg&name=orig`
+fn original_quality(url: &Url, ext: &str) -> Url {
+    if url.host_str() != Some("pbs.twimg.com") || !url.path().starts_with("/media/") {
+        return url.clone();
+    }
+    let mut url = url.clone();
+    let format = url
+        .query_pairs()
+        .find(|(k, _)| k == "format")
+        .map(|(_, v)| v.into_owned())
+        .unwrap_or_else(|| ext.to_string());
+    let path = url.path();
+    let path = match path.rfind('.') {
+        Some(idx) => path[..idx].to_string(),
+        None => path.to_string(),
+    };
+    url.set_path(&path);
+    url.query_pairs_mut()
+        .clear()
+        .append_pair("format", &format)
+        .append_pair("name", "orig");
+    url
+}
+
+/// An encoding of a video available for download
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct VideoVariant {
+    pub url: Url,
+    /// Not set for HLS playlists
+    pub bitrate: Option<u64>,
 }
 
-#[derive(Deserialize, Serialize, Debug, PartialEq)]
+impl VideoVariant {
+    /// The height of the video, from the resolution in the URL (e.g. `.../vid/1280x720/...`)
+    pub fn height(&self) -> Option<u32> {
+        self.url.path_segments()?.find_map(|segment| {
+            let (width, height) = segment.split_once('x')?;
+            width.parse::<u32>().ok()?;
+            height.parse().ok()
+        })
+    }
+}
+
+/// How downloaded files are named (before their extension), e.g. `{tweet_id}_{index}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileNameTemplate(String);
+
+impl FileNameTemplate {
+    const TOKENS: [&'static str; 3] = ["{tweet_id}", "{media_id}", "{index}"];
+
+    pub fn render(&self, tweet: &Tweet, media: &Media) -> String {
+        self.0
+            .replace("{tweet_id}", &tweet.id.to_string())
+            .replace("{media_id}", &media.id.to_string())
+            .replace("{index}", &media.position_in(tweet).to_string())
+    }
+}
+
+impl FromStr for FileNameTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s.to_string();
+        for token in Self::TOKENS {
+            rest = rest.replace(token, "");
+        }
+        if let Some(start) = rest.find('{') {
+            let token = rest[start..]
+                .split_inclusive('}')
+                .next()
+                .unwrap_or_default();
+            bail!(
+                "Unknown file name token {token}, expected one of {}",
+                Self::TOKENS.join(", ")
+            );
+        }
+        // Otherwise the media of a tweet would overwrite each other
+        if !s.contains("{tweet_id}") || !(s.contains("{media_id}") || s.contains("{index}")) {
+            bail!("The file name needs {{tweet_id}}, and {{media_id}} or {{index}}");
+        }
+        Ok(Self(s.to_string()))
+    }
+}
+
+/// Which video variant to download
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoQuality {
+    Best,
+    Worst,
+    /// The best variant at most this many pixels high
+    MaxHeight(u32),
+}
+
+impl VideoQuality {
+    pub fn select(self, variants: &[VideoVariant]) -> Option<&VideoVariant> {
+        let mp4 = variants.iter().filter(|v| v.bitrate.is_some());
+        let worst = || mp4.clone().min_by_key(|v| v.bitrate);
+        let selected = match self {
+            VideoQuality::Best => mp4.clone().max_by_key(|v| v.bitrate),
+            VideoQuality::Worst => worst(),
+            VideoQuality::MaxHeight(max) => mp4
+                .clone()
+                .filter(|v| v.height().is_some_and(|h| h <= max))
+                .max_by_key(|v| v.bitrate)
+                .or_else(worst),
+        };
+        // Some videos are only available as an HLS playlist (which has no bitrate)
+        selected.or_else(|| variants.iter().find(|v| v.url.path().ends_with(".m3u8")))
+    }
+}
+
+impl FromStr for VideoQuality {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "best" => VideoQuality::Best,
+            "worst" => VideoQuality::Worst,
+            height => VideoQuality::MaxHeight(
+                height
+                    .trim_end_matches('p')
+                    .parse()
+                    .with_context(|| format!("Invalid video quality: {s}"))?,
+            ),
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum MediaType {
     Video,
     Photo,
     Gif,
+    /// The recording of a Space linked from the tweet
+    Audio,
+}
+
+/// Records where a set of tweets came from, so that data fetched by a particular backend or
+/// tool version can later be identified (e.g. for refetching)
+#[derive(Deserialize, Serialize, Debug)]
+pub struct FetchBatch {
+    pub id: u64,
+    pub backend: String,
+    pub tool_version: String,
+    pub timestamp: i64,
 }
 
+/// An account's archived tweets, stored as `tweets.json` in its folder
 #[derive(Deserialize, Serialize, Debug)]
 pub struct DataFile {
     pub user_id: u64,
+    /// Always sorted by id (oldest first), so that each save only differs where tweets have changed
     pub tweets: Vec<Tweet>,
     pub version: u64,
+    #[serde(default)]
+    pub batches: Vec<FetchBatch>,
+    /// Tweets that have been submitted to the Wayback Machine for capture
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub wayback_submitted: BTreeSet<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<ProfileRecord>,
+    // The batch that tweets merged during this run are recorded against
+    #[serde(skip)]
+    current_batch: Option<u64>,
+    /// The backup this was loaded from, as `tweets.json` couldn't be read
+    #[serde(skip)]
+    recovered_from: Option<PathBuf>,
+}
+
+/// `DataFile` without its tweets, which `--low-memory` writes one at a time. The fields must stay
+/// in the same order as `DataFile`'s.
+#[derive(Serialize)]
+struct StreamedHeader<'a> {
+    user_id: u64,
+    tweets: [u64; 0],
+    version: u64,
+    batches: &'a [FetchBatch],
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    wayback_submitted: &'a BTreeSet<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<&'a ProfileRecord>,
 }
 
 impl DataFile {
@@ -104,61 +519,659 @@ impl DataFile {
             user_id,
             tweets: vec![],
             version: MODEL_VERSION,
+            batches: vec![],
+            wayback_submitted: BTreeSet::new(),
+            profile: None,
+            current_batch: None,
+            recovered_from: None,
         }
     }
 
+    /// Loads the data file in `user_dir`, if there is one, checking that it is for `validate_user_id`
     pub async fn load(user_dir: &Path, validate_user_id: u64) -> anyhow::Result<Option<DataFile>> {
-        let data_file = user_dir.join("tweets.json");
-        Ok(if data_file.exists() {
-            let data_file = fs::read_to_string(&data_file)
-                .await
-                .context("Unable to read data file")?;
-            let mut data_file = serde_json::from_str::<Self>(&data_file)
-                .context("Unable to deserialize data file")?;
+        let data_file = Self::load_unchecked(user_dir).await?;
+        if let Some(data_file) = &data_file {
             if data_file.user_id != validate_user_id {
                 bail!("User id mismatch! The username you have provided is not for the same account that was previously downloaded")
             }
+        }
+        Ok(data_file)
+    }
+
+    /// Loads the data file without validating which account it is for. If it can't be read, the
+    /// newest backup that can be is loaded instead.
+    pub async fn load_unchecked(user_dir: &Path) -> anyhow::Result<Option<DataFile>> {
+        let path = user_dir.join("tweets.json");
+        if !path.exists() {
+            return Ok(None);
+        }
+        let error = match Self::load_from(path.clone()).await {
+            Ok(data_file) => return Ok(Some(data_file)),
+            Err(e) => e,
+        };
+        let backups = (1..).map(|n| backup_path(user_dir, n));
+        for backup in backups.take_while(|backup| backup.exists()) {
+            if let Ok(mut data_file) = Self::load_from(backup.clone()).await {
+                log::warn!(
+                    "{}: {:#}, using the backup {} instead",
+                    path.display(),
+                    error,
+                    backup.display()
+                );
+                data_file.recovered_from = Some(backup);
+                return Ok(Some(data_file));
+            }
+        }
+        Err(error)
+    }
+
+    async fn load_from(path: PathBuf) -> anyhow::Result<DataFile> {
+        // Encrypted files have to be decrypted as a whole
+        if LOW_MEMORY.load(AtomicOrdering::Relaxed) && !crate::encryption::enabled() {
+            let mut data_file = tokio::task::spawn_blocking(move || {
+                let file = std::fs::File::open(path)?;
+                let reader = std::io::BufReader::new(file);
+                Ok::<_, anyhow::Error>(serde_json::from_reader::<_, Self>(reader)?)
+            })
+            .await?
+            .context("Unable to deserialize data file")?;
             data_file.tweets.sort();
-            Some(data_file)
-        } else {
-            None
-        })
+            return Ok(data_file);
+        }
+        let data_file = crate::encryption::read(&path)
+            .await
+            .context("Unable to read data file")?;
+        let data_file = String::from_utf8(data_file).context("Invalid data file")?;
+        let mut data_file =
+            serde_json::from_str::<Self>(&data_file).context("Unable to deserialize data file")?;
+        data_file.tweets.sort();
+        Ok(data_file)
     }
 
+    /// Keeps the data file as it was before this sync as `tweets.json.1`, shifting the older
+    /// backups along (up to `tweets.json.<count>`). A data file that couldn't be read is set aside
+    /// as `tweets.json.corrupt` instead, leaving the backups as they are, and the backup it was
+    /// recovered from is copied into its place (so an interrupted sync doesn't lose the account).
+    pub async fn rotate_backups(&self, user_dir: &Path, count: usize) -> anyhow::Result<()> {
+        let path = user_dir.join("tweets.json");
+        if let Some(backup) = &self.recovered_from {
+            fs::rename(&path, user_dir.join("tweets.json.corrupt"))
+                .await
+                .context("Unable to move corrupt data file")?;
+            return fs::copy(backup, &path)
+                .await
+                .map(|_| ())
+                .context("Unable to restore data file from its backup");
+        }
+        if count == 0 || !path.exists() {
+            return Ok(());
+        }
+        let rotate = async {
+            for n in (1..count).rev() {
+                let backup = backup_path(user_dir, n);
+                if backup.exists() {
+                    fs::rename(&backup, backup_path(user_dir, n + 1)).await?;
+                }
+            }
+            fs::copy(&path, backup_path(user_dir, 1)).await?;
+            Ok::<_, std::io::Error>(())
+        };
+        rotate.await.context("Unable to back up data file")
+    }
+
+    /// Writes the data file into `user_dir`, encrypted if encryption is enabled
     pub async fn save(&self, user_dir: &Path) -> anyhow::Result<()> {
+        if LOW_MEMORY.load(AtomicOrdering::Relaxed) {
+            return self.save_streamed(user_dir).await;
+        }
         let text = serde_json::to_string_pretty(&self).unwrap();
-        fs::write(user_dir.join("tweets.json"), &text)
+        crate::encryption::write(&user_dir.join("tweets.json"), text.into_bytes())
+            .await
+            .context("Unable to write data file")
+    }
+
+    async fn save_streamed(&self, user_dir: &Path) -> anyhow::Result<()> {
+        let path = user_dir.join("tweets.json");
+        if crate::encryption::enabled() {
+            let data = serde_json::to_vec(&self).unwrap();
+            return crate::encryption::write(&path, data)
+                .await
+                .context("Unable to write data file");
+        }
+        // Written to a temporary file first, so the data file is never left partially written
+        let temp = user_dir.join(".tweets.json.tmp");
+        self.write_streamed(&temp)
+            .await
+            .context("Unable to write data file")?;
+        fs::rename(&temp, &path)
             .await
             .context("Unable to write data file")
     }
 
+    /// Writes the same JSON as `serde_json::to_writer`, serializing one tweet at a time
+    async fn write_streamed(&self, path: &Path) -> anyhow::Result<()> {
+        let header = serde_json::to_string(&StreamedHeader {
+            user_id: self.user_id,
+            tweets: [],
+            version: self.version,
+            batches: &self.batches,
+            wayback_submitted: &self.wayback_submitted,
+            profile: self.profile.as_ref(),
+        })?;
+        // `user_id` is a number, so the first `"tweets":[]` is the field
+        let (before, after) = header
+            .split_once(r#""tweets":[]"#)
+            .context("Missing tweets")?;
+        let mut writer = tokio::io::BufWriter::new(fs::File::create(path).await?);
+        writer.write_all(before.as_bytes()).await?;
+        writer.write_all(br#""tweets":["#).await?;
+        for (i, tweet) in self.tweets.iter().enumerate() {
+            if i > 0 {
+                writer.write_all(b",").await?;
+            }
+            writer.write_all(&serde_json::to_vec(tweet)?).await?;
+        }
+        writer.write_all(b"]").await?;
+        writer.write_all(after.as_bytes()).await?;
+        writer.flush().await?;
+        writer.into_inner().sync_all().await?;
+        Ok(())
+    }
+
+    /// Records the account's latest handle, name, bio and image URLs, keeping the previous ones in
+    /// the history when they have changed. Details the backend didn't report are carried over.
+    pub fn record_profile(&mut self, profile: &Profile) {
+        let previous = self.profile.as_ref().map(|p| &p.current);
+        let carry = |new: Option<String>, old: Option<&String>| new.or_else(|| old.cloned());
+        let details = ProfileDetails {
+            username: carry(
+                profile.username.clone(),
+                previous.and_then(|p| p.username.as_ref()),
+            ),
+            name: carry(profile.name.clone(), previous.and_then(|p| p.name.as_ref())),
+            bio: carry(profile.bio.clone(), previous.and_then(|p| p.bio.as_ref())),
+            avatar_url: profile
+                .avatar_url
+                .clone()
+                .or_else(|| previous.and_then(|p| p.avatar_url.clone())),
+            banner_url: profile
+                .banner_url
+                .clone()
+                .or_else(|| previous.and_then(|p| p.banner_url.clone())),
+            first_seen: chrono::Utc::now().timestamp(),
+        };
+        match &mut self.profile {
+            Some(record) if record.current.same_as(&details) => {}
+            Some(record) => {
+                if record.current.username != details.username {
+                    log::info!(
+                        "{} is now known as {}",
+                        record.current.username.as_deref().unwrap_or("The account"),
+                        details.username.as_deref().unwrap_or_default()
+                    );
+                }
+                let previous = std::mem::replace(&mut record.current, details);
+                record.history.push(previous);
+            }
+            None => {
+                self.profile = Some(ProfileRecord {
+                    current: details,
+                    history: Vec::new(),
+                })
+            }
+        }
+    }
+
     /// Returns number of not seen before tweets
-    pub fn merge_tweets(&mut self, new_tweets: Vec<Tweet>) -> usize {
-        let mut new = 0;
-        let existing = std::mem::take(&mut self.tweets);
-        let mut map = existing
-            .into_iter()
-            .map(|t| (t.id, t))
-            .collect::<BTreeMap<_, _>>();
+    pub fn merge_tweets(&mut self, new_tweets: Vec<Tweet>, backend: &str) -> usize {
+        if new_tweets.is_empty() {
+            return 0;
+        }
+        let batch = self.batch_id(backend);
+        // Merged into the (sorted) tweets in place, only the unseen tweets are collected, rather
+        // than a copy of the whole archive
+        let mut added = BTreeMap::new();
         for mut tweet in new_tweets {
-            // We don't want to overwrite the filenames though
-            if let Some(existing) = map.get(&tweet.id) {
+            tweet.batch = Some(batch);
+            tweet.order_media();
+            let position = self.tweets.binary_search_by_key(&tweet.id, |t| t.id);
+            let existing = match position {
+                Ok(index) => Some(&self.tweets[index]),
+                Err(_) => added.get(&tweet.id),
+            };
+            // We don't want to overwrite the filenames though, or anything derived from the files
+            if let Some(existing) = existing {
+                if tweet.source.is_none() {
+                    tweet.source = existing.source.clone();
+                }
+                tweet.like_count = tweet.like_count.or(existing.like_count);
+                tweet.retweet_count = tweet.retweet_count.or(existing.retweet_count);
+                tweet.possibly_sensitive = tweet.possibly_sensitive.or(existing.possibly_sensitive);
+                tweet.conversation_id = tweet.conversation_id.or(existing.conversation_id);
+                tweet.in_reply_to = tweet.in_reply_to.or(existing.in_reply_to);
+                tweet.quoted_tweet_id = tweet.quoted_tweet_id.or(existing.quoted_tweet_id);
+                if tweet.poll.is_none() {
+                    tweet.poll = existing.poll.clone();
+                }
+                if tweet.card.is_none() {
+                    tweet.card = existing.card.clone();
+                }
+                if tweet.place.is_none() {
+                    tweet.place = existing.place.clone();
+                }
+                tweet.coordinates = tweet.coordinates.or(existing.coordinates);
                 for media in &mut tweet.media {
                     if let Some(equal) = existing.media.iter().find(|m| m.id == media.id) {
                         media.file_name = equal.file_name.clone();
+                        media.ocr_text = equal.ocr_text.clone();
+                        if media.alt_text.is_none() {
+                            media.alt_text = equal.alt_text.clone();
+                        }
+                        if media.title.is_none() {
+                            media.title = equal.title.clone();
+                        }
+                        media.duration_ms = media.duration_ms.or(equal.duration_ms);
+                        media.nsfw_score = equal.nsfw_score;
+                        media.sha256 = equal.sha256.clone();
+                        media.recovered_from = equal.recovered_from.clone();
+                        media.duplicate_of = equal.duplicate_of;
+                        media.ipfs_cid = equal.ipfs_cid.clone();
+                        media.preview_file_name = equal.preview_file_name.clone();
+                        media.metadata_embedded = equal.metadata_embedded;
+                        media.unavailable_since = equal.unavailable_since;
+                        if media.preview_url.is_none() {
+                            media.preview_url = equal.preview_url.clone();
+                        }
                     }
                 }
             }
-            if map.insert(tweet.id, tweet).is_none() {
-                new += 1;
+            match position {
+                Ok(index) => self.tweets[index] = tweet,
+                Err(_) => {
+                    added.insert(tweet.id, tweet);
+                }
             }
         }
-        self.tweets = map.into_values().collect();
+        let new = added.len();
+        self.tweets.extend(added.into_values());
+        self.tweets.sort();
         self.version = MODEL_VERSION;
         new
     }
 
+    fn batch_id(&mut self, backend: &str) -> u64 {
+        if let Some(id) = self.current_batch {
+            return id;
+        }
+        let id = self.batches.last().map(|b| b.id + 1).unwrap_or(0);
+        self.batches.push(FetchBatch {
+            id,
+            backend: backend.to_string(),
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            timestamp: chrono::Utc::now().timestamp(),
+        });
+        self.current_batch = Some(id);
+        id
+    }
+
     pub fn latest_tweet_id(&self) -> Option<u64> {
         self.tweets.last().map(|l| l.id)
     }
+
+    /// Forgets about downloaded files that no longer exist on disk, so that they will be downloaded
+    /// again. Returns the number of files that were missing.
+    pub fn clear_missing_files(&mut self, user_dir: &Path) -> usize {
+        let mut missing = 0;
+        for media in self.tweets.iter_mut().flat_map(|t| &mut t.media) {
+            if let Some(file_name) = &media.file_name {
+                if !user_dir.join(file_name).exists() {
+                    log::warn!("Missing: {}", user_dir.join(file_name).display());
+                    media.file_name = None;
+                    media.sha256 = None;
+                    media.recovered_from = None;
+                    media.duplicate_of = None;
+                    missing += 1;
+                }
+            }
+        }
+        missing
+    }
+
+    /// Returns the (tweet index, media index, path) of the downloaded photos matching a predicate
+    pub fn downloaded_photos(
+        &self,
+        user_dir: &Path,
+        predicate: impl Fn(&Media) -> bool,
+    ) -> Vec<(usize, usize, PathBuf)> {
+        let mut photos = vec![];
+        for (tweet_index, tweet) in self.tweets.iter().enumerate() {
+            for (media_index, media) in tweet.media.iter().enumerate() {
+                if media.r#type != MediaType::Photo || !predicate(media) {
+                    continue;
+                }
+                if let Some(file_name) = &media.file_name {
+                    let path = user_dir.join(file_name);
+                    if ThumbnailCache::supports(&path) && path.exists() {
+                        photos.push((tweet_index, media_index, path));
+                    }
+                }
+            }
+        }
+        photos
+    }
+}
+
+/// An account's display name, avatar and banner, kept in `profile.json` so the viewer can show
+/// them
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub user_id: u64,
+    /// The account's current handle, where the backend reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bio: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<Url>,
+    /// Where the avatar was saved, relative to the user folder
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_file: Option<String>,
+    /// The header image shown above the profile, where the backend reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub banner_url: Option<Url>,
+    /// Where the banner was saved, relative to the user folder
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub banner_file: Option<String>,
+    /// Every avatar and banner the account has had since it was first synced, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<ProfileImage>,
+}
+
+/// The `profile` section of the data file: the account's details as of the latest sync, and those
+/// it had before
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ProfileRecord {
+    pub current: ProfileDetails,
+    /// Oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<ProfileDetails>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ProfileDetails {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bio: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<Url>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub banner_url: Option<Url>,
+    /// When these details were first seen (unix seconds)
+    pub first_seen: i64,
+}
+
+impl ProfileDetails {
+    fn same_as(&self, other: &ProfileDetails) -> bool {
+        (
+            &self.username,
+            &self.name,
+            &self.bio,
+            &self.avatar_url,
+            &self.banner_url,
+        ) == (
+            &other.username,
+            &other.name,
+            &other.bio,
+            &other.avatar_url,
+            &other.banner_url,
+        )
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileImageKind {
+    Avatar,
+    Banner,
+}
+
+impl ProfileImageKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ProfileImageKind::Avatar => "avatar",
+            ProfileImageKind::Banner => "banner",
+        }
+    }
+}
+
+/// A dated copy of an avatar or banner, kept once the account has changed it
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ProfileImage {
+    pub kind: ProfileImageKind,
+    pub url: Url,
+    /// When the image was first seen (unix seconds)
+    pub first_seen: i64,
+    /// Relative to the user folder
+    pub file_name: String,
+    /// Of the image's contents, to tell when it has changed (not recorded by older versions)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+impl Profile {
+    pub fn new(user_id: u64) -> Self {
+        Self {
+            user_id,
+            username: None,
+            name: None,
+            bio: None,
+            avatar_url: None,
+            avatar_file: None,
+            banner_url: None,
+            banner_file: None,
+            history: Vec::new(),
+        }
+    }
+
+    /// The current image's URL and file
+    pub fn image(&self, kind: ProfileImageKind) -> (Option<&Url>, Option<&String>) {
+        match kind {
+            ProfileImageKind::Avatar => (self.avatar_url.as_ref(), self.avatar_file.as_ref()),
+            ProfileImageKind::Banner => (self.banner_url.as_ref(), self.banner_file.as_ref()),
+        }
+    }
+
+    pub fn image_mut(&mut self, kind: ProfileImageKind) -> (&mut Option<Url>, &mut Option<String>) {
+        match kind {
+            ProfileImageKind::Avatar => (&mut self.avatar_url, &mut self.avatar_file),
+            ProfileImageKind::Banner => (&mut self.banner_url, &mut self.banner_file),
+        }
+    }
+
+    /// The history of one kind of image, oldest first
+    pub fn history(&self, kind: ProfileImageKind) -> impl Iterator<Item = &ProfileImage> {
+        self.history.iter().filter(move |i| i.kind == kind)
+    }
+
+    pub async fn load(user_dir: &Path) -> anyhow::Result<Option<Profile>> {
+        let path = user_dir.join(PROFILE_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = crate::encryption::read(&path).await?;
+        Ok(Some(
+            serde_json::from_slice(&data).context("Unable to deserialize profile")?,
+        ))
+    }
+
+    pub async fn save(&self, user_dir: &Path) -> anyhow::Result<()> {
+        let text = serde_json::to_string_pretty(&self).unwrap();
+        crate::encryption::write(&user_dir.join(PROFILE_FILE), text.into_bytes())
+            .await
+            .context("Unable to write profile")
+    }
+}
+
+/// The `n`th newest backup of the data file
+fn backup_path(user_dir: &Path, n: usize) -> PathBuf {
+    user_dir.join(format!("tweets.json.{n}"))
+}
+
+/// Returns the user folders (i.e. those containing a data file) within an output directory
+pub async fn list_user_dirs(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut read_dir = fs::read_dir(dir)
+        .await
+        .context("Unable to read directory")?;
+    let mut user_dirs = Vec::new();
+    while let Some(entry) = read_dir.next_entry().await? {
+        if entry.path().join("tweets.json").exists() {
+            user_dirs.push(entry.path());
+        }
+    }
+    user_dirs.sort();
+    Ok(user_dirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::{assert_golden, data_file};
+
+    #[test]
+    fn data_file_golden() {
+        let text = serde_json::to_string_pretty(&data_file()).unwrap();
+        assert_golden("tweets.json", &text);
+    }
+
+    #[tokio::test]
+    async fn load_sorts_tweets() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut data_file = data_file();
+        data_file.tweets.reverse();
+        data_file.save(dir.path()).await.unwrap();
+        let loaded = DataFile::load_unchecked(dir.path()).await.unwrap().unwrap();
+        let ids = loaded.tweets.iter().map(|t| t.id).collect::<Vec<_>>();
+        assert_eq!(ids, [10, 20, 30, 40]);
+    }
+
+    #[tokio::test]
+    async fn streams_data_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut data_file = data_file();
+        data_file.wayback_submitted.insert(20);
+        data_file.save_streamed(dir.path()).await.unwrap();
+        let text = std::fs::read_to_string(dir.path().join("tweets.json")).unwrap();
+        assert_eq!(text, serde_json::to_string(&data_file).unwrap());
+        assert!(!dir.path().join(".tweets.json.tmp").exists());
+    }
+
+    #[test]
+    fn merges_tweets_in_place() {
+        let mut data_file = data_file();
+        let mut new_tweets = crate::golden::data_file().tweets;
+        new_tweets.truncate(2);
+        new_tweets[1].text = "edited".to_string();
+        new_tweets[1].media[0].file_name = None;
+        let mut unseen = crate::golden::data_file().tweets.remove(0);
+        unseen.id = 25;
+        new_tweets.push(unseen);
+        assert_eq!(data_file.merge_tweets(new_tweets, "test"), 1);
+        let ids = data_file.tweets.iter().map(|t| t.id).collect::<Vec<_>>();
+        assert_eq!(ids, [10, 20, 25, 30, 40]);
+        assert_eq!(data_file.tweets[1].text, "edited");
+        assert_eq!(
+            data_file.tweets[1].media[0].file_name.as_deref(),
+            Some("20_21.mp4")
+        );
+    }
+
+    #[tokio::test]
+    async fn recovers_from_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut data_file = data_file();
+        data_file.save(dir.path()).await.unwrap();
+        data_file.rotate_backups(dir.path(), 2).await.unwrap();
+        data_file.tweets.pop();
+        data_file.save(dir.path()).await.unwrap();
+        data_file.rotate_backups(dir.path(), 2).await.unwrap();
+        assert!(dir.path().join("tweets.json.2").exists());
+
+        std::fs::write(dir.path().join("tweets.json"), "{").unwrap();
+        let loaded = DataFile::load_unchecked(dir.path()).await.unwrap().unwrap();
+        assert_eq!(loaded.tweets.len(), 3);
+        loaded.rotate_backups(dir.path(), 2).await.unwrap();
+        assert!(dir.path().join("tweets.json.corrupt").exists());
+        // The backup is in place until the data file is saved again
+        let restored = DataFile::load_unchecked(dir.path()).await.unwrap().unwrap();
+        assert_eq!(restored.tweets.len(), 3);
+        assert!(restored.recovered_from.is_none());
+    }
+
+    #[test]
+    fn records_profile_history() {
+        let mut data_file = DataFile::new(1);
+        let profile = Profile {
+            username: Some("old".to_string()),
+            name: Some("Name".to_string()),
+            ..Profile::new(1)
+        };
+        data_file.record_profile(&profile);
+        data_file.record_profile(&profile);
+        // Nothing changed, and a missing name is carried over
+        data_file.record_profile(&Profile {
+            name: None,
+            ..profile.clone()
+        });
+        data_file.record_profile(&Profile {
+            username: Some("new".to_string()),
+            ..profile
+        });
+        let record = data_file.profile.unwrap();
+        assert_eq!(record.current.username.as_deref(), Some("new"));
+        assert_eq!(record.current.name.as_deref(), Some("Name"));
+        assert_eq!(record.history.len(), 1);
+        assert_eq!(record.history[0].username.as_deref(), Some("old"));
+    }
+
+    #[test]
+    fn names_files_by_position() {
+        let mut tweet = data_file().tweets.remove(2);
+        tweet.media[0].position = Some(2);
+        tweet.media[1].position = Some(1);
+        tweet.order_media();
+        let ids = tweet.media.iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!(ids, [12, 11]);
+
+        let template = "{tweet_id}_{index}".parse::<FileNameTemplate>().unwrap();
+        assert_eq!(template.render(&tweet, &tweet.media[0]), "30_1");
+        assert_eq!(template.render(&tweet, &tweet.media[1]), "30_2");
+        assert!("{tweet_id}_{id}".parse::<FileNameTemplate>().is_err());
+        assert!("{tweet_id}".parse::<FileNameTemplate>().is_err());
+    }
+
+    #[test]
+    fn bounds_places() {
+        let square = [
+            (2.0, 48.0),
+            (3.0, 48.0),
+            (3.0, 49.0),
+            (2.0, 49.0),
+            (2.0, 48.0),
+        ];
+        let bounding_box = Place::bounding_box(&square);
+        assert_eq!(bounding_box, Some([2.0, 48.0, 3.0, 49.0]));
+        let place = Place {
+            full_name: "Paris, France".to_string(),
+            country: None,
+            bounding_box,
+        };
+        let center = place.center().unwrap();
+        assert_eq!((center.longitude, center.latitude), (2.5, 48.5));
+        assert_eq!(Place::bounding_box(&[]), None);
+    }
 }
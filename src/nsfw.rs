@@ -0,0 +1,91 @@
+//! Scores downloaded photos with an external NSFW classifier, optionally moving those above the
+//! threshold into a quarantine folder (the viewer blurs them by default)
+
+use crate::external::ExternalCommand;
+use crate::model::DataFile;
+use crate::MediaArgs;
+use anyhow::{anyhow, Context};
+use futures::{stream, StreamExt};
+use std::path::Path;
+use tokio::fs;
+
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// Classifies every downloaded photo that hasn't been scored yet
+pub async fn classify_media(
+    user_dir: &Path,
+    data_file: &mut DataFile,
+    command: &ExternalCommand,
+    args: &MediaArgs,
+) -> anyhow::Result<()> {
+    let pending = data_file.downloaded_photos(user_dir, |m| m.nsfw_score.is_none());
+    let mut results = stream::iter(pending)
+        .map(|(tweet_index, media_index, path)| async move {
+            let score = command.run(&path).await.and_then(|out| parse_score(&out));
+            (tweet_index, media_index, score, path)
+        })
+        .buffer_unordered(args.concurrency);
+
+    let mut flagged = 0;
+    while let Some((tweet_index, media_index, result, path)) = results.next().await {
+        match result {
+            Ok(score) => {
+                data_file.tweets[tweet_index].media[media_index].nsfw_score = Some(score);
+                if score >= args.nsfw_threshold {
+                    flagged += 1;
+                }
+            }
+            // The command couldn't be started at all, so it will fail for every image
+            Err(e) if e.downcast_ref::<std::io::Error>().is_some() => return Err(e),
+            Err(e) => log::warn!("Classification failed for {}: {:#}", path.display(), e),
+        }
+    }
+    if flagged > 0 {
+        log::info!("Classified {} new images as NSFW", flagged);
+    }
+
+    if args.quarantine_nsfw {
+        quarantine(user_dir, data_file, args.nsfw_threshold).await?;
+    }
+    Ok(())
+}
+
+/// The classifier must print the score at the start of its last line (so it can log before it)
+fn parse_score(output: &str) -> anyhow::Result<f32> {
+    let score = output
+        .lines()
+        .rfind(|l| !l.trim().is_empty())
+        .and_then(|l| l.split_whitespace().next())
+        .ok_or_else(|| anyhow!("Classifier printed no score"))?;
+    let score = score
+        .parse::<f32>()
+        .with_context(|| format!("Invalid score: {score}"))?;
+    Ok(score.clamp(0.0, 1.0))
+}
+
+/// Moves photos scoring at or above the threshold into the quarantine folder
+async fn quarantine(
+    user_dir: &Path,
+    data_file: &mut DataFile,
+    threshold: f32,
+) -> anyhow::Result<()> {
+    let prefix = format!("{QUARANTINE_DIR}/");
+    let matches = data_file.downloaded_photos(user_dir, |m| {
+        m.nsfw_score.is_some_and(|s| s >= threshold)
+            && m.file_name
+                .as_ref()
+                .is_some_and(|f| !f.starts_with(&prefix))
+    });
+    for (tweet_index, media_index, source) in matches {
+        let media = &mut data_file.tweets[tweet_index].media[media_index];
+        let file_name = format!("{prefix}{}", media.file_name.as_ref().unwrap());
+        let destination = user_dir.join(&file_name);
+        fs::create_dir_all(destination.parent().unwrap()).await?;
+        fs::rename(&source, &destination)
+            .await
+            .with_context(|| format!("Unable to quarantine {}", source.display()))?;
+        log::debug!("Quarantined {}", source.display());
+        media.file_name = Some(file_name);
+    }
+    Ok(())
+}
@@ -0,0 +1,231 @@
+//! `--objects`: a content addressed layout, where each distinct file is stored once in
+//! `.objects/<sha256>` in the output folder, and the account folders link to it. Media posted (or
+//! retweeted) by several accounts then only takes up space once.
+
+use crate::model::DataFile;
+use crate::verify::hash_file;
+use crate::ObjectLink;
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+pub const OBJECTS_DIR: &str = ".objects";
+
+/// Moves the account's downloaded files into the store, recording their hashes in the data file
+pub async fn store_objects(
+    user_dir: &Path,
+    data_file: &mut DataFile,
+    link: ObjectLink,
+) -> anyhow::Result<()> {
+    let objects_dir = user_dir
+        .parent()
+        .context("The account folder has no parent")?
+        .join(OBJECTS_DIR);
+    fs::create_dir_all(&objects_dir)
+        .await
+        .context("Unable to create the objects folder")?;
+    let mut stored = 0;
+    for media in data_file.tweets.iter_mut().flat_map(|t| &mut t.media) {
+        let file_name = match &media.file_name {
+            Some(file_name) => file_name.clone(),
+            None => continue,
+        };
+        let path = user_dir.join(&file_name);
+        let metadata = match fs::symlink_metadata(&path).await {
+            // Links already point into the store (even when `--objects` changed since)
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => continue,
+        };
+        // Hard links are files too, those already linked to their object are left as they are
+        if let Some(recorded) = &media.sha256 {
+            let object = fs::metadata(objects_dir.join(recorded)).await;
+            if object.is_ok_and(|object| same_file(&metadata, &object)) {
+                continue;
+            }
+        }
+        // Hashed rather than trusting the recorded hash, which would link the file to another
+        // object's content if it were out of date
+        let sha256 = hash_file(&path)
+            .await
+            .with_context(|| format!("Unable to read {}", path.display()))?;
+        if media
+            .sha256
+            .as_ref()
+            .is_some_and(|recorded| *recorded != sha256)
+        {
+            log::warn!(
+                "{} doesn't match its recorded hash, storing it as it is",
+                path.display()
+            );
+        }
+        let object = objects_dir.join(&sha256);
+        let result = match link {
+            ObjectLink::HardLink => store_hard_link(&path, &object, &metadata).await,
+            ObjectLink::Symlink => store_symlink(&path, &object, &file_name).await,
+        };
+        match result {
+            Ok(true) => stored += 1,
+            Ok(false) => {}
+            Err(e) => {
+                log::warn!("Unable to store {} as an object: {}", path.display(), e);
+                continue;
+            }
+        }
+        media.sha256 = Some(sha256);
+    }
+    if stored > 0 {
+        log::info!("Stored {} files in {}", stored, objects_dir.display());
+    }
+    Ok(())
+}
+
+/// Returns whether the file was changed, i.e. isn't already a link to the object
+async fn store_hard_link(
+    path: &Path,
+    object: &Path,
+    metadata: &std::fs::Metadata,
+) -> std::io::Result<bool> {
+    match fs::metadata(object).await {
+        Ok(object_metadata) if same_file(metadata, &object_metadata) => Ok(false),
+        Ok(_) => {
+            replace(path, |temp| {
+                let object = object.to_path_buf();
+                async move { fs::hard_link(object, temp).await }
+            })
+            .await?;
+            Ok(true)
+        }
+        Err(_) => {
+            fs::hard_link(path, object).await?;
+            Ok(true)
+        }
+    }
+}
+
+async fn store_symlink(path: &Path, object: &Path, file_name: &str) -> std::io::Result<bool> {
+    if !object.exists() {
+        fs::rename(path, object).await?;
+    }
+    let target = relative_target(file_name, object);
+    replace(path, |temp| async move { symlink_file(&target, &temp) }).await?;
+    Ok(true)
+}
+
+/// Creates the replacement with a temporary name first, so that the file is only replaced once
+/// the link exists
+async fn replace<F, Fut>(path: &Path, create: F) -> std::io::Result<()>
+where
+    F: FnOnce(PathBuf) -> Fut,
+    Fut: std::future::Future<Output = std::io::Result<()>>,
+{
+    let temp = path.with_extension("objects");
+    let _ = fs::remove_file(&temp).await;
+    create(temp.clone()).await?;
+    fs::rename(&temp, path).await
+}
+
+/// Relative, so that the output folder can itself be moved, e.g. `../../.objects/<sha256>` for
+/// `2021-03-04/30_11.jpg`
+fn relative_target(file_name: &str, object: &Path) -> PathBuf {
+    let mut target = PathBuf::new();
+    for _ in 0..file_name.split('/').count() {
+        target.push("..");
+    }
+    target.push(OBJECTS_DIR);
+    target.push(object.file_name().unwrap_or_default());
+    target
+}
+
+#[cfg(unix)]
+fn same_file(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+/// Without inode numbers the file is linked again, which is harmless
+#[cfg(not(unix))]
+fn same_file(_: &std::fs::Metadata, _: &std::fs::Metadata) -> bool {
+    false
+}
+
+fn symlink_file(target: &Path, link: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(target, link);
+    #[cfg(windows)]
+    return std::os::windows::fs::symlink_file(target, link);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::data_file;
+    use sha2::Digest;
+
+    #[test]
+    fn links_relative_to_the_store() {
+        let object = Path::new("/out/.objects/abc");
+        assert_eq!(
+            relative_target("30_11.jpg", object),
+            Path::new("../.objects/abc")
+        );
+        assert_eq!(
+            relative_target("2021-03-04/30_11.jpg", object),
+            Path::new("../../.objects/abc")
+        );
+    }
+
+    #[tokio::test]
+    async fn shares_files_across_accounts() {
+        let out = tempfile::tempdir().unwrap();
+        for (username, link) in [
+            ("alice", ObjectLink::Symlink),
+            ("bob", ObjectLink::HardLink),
+        ] {
+            let user_dir = out.path().join(username);
+            std::fs::create_dir(&user_dir).unwrap();
+            std::fs::write(user_dir.join("30_11.jpg"), "same").unwrap();
+            std::fs::write(user_dir.join("30_12.png"), username).unwrap();
+            let mut data_file = data_file();
+            data_file.tweets[2].media[0].sha256 = None;
+            data_file.tweets[2].media[1].sha256 = None;
+            store_objects(&user_dir, &mut data_file, link)
+                .await
+                .unwrap();
+            let sha256 = data_file.tweets[2].media[0].sha256.clone().unwrap();
+            assert!(out.path().join(OBJECTS_DIR).join(sha256).exists());
+            assert_eq!(
+                std::fs::read_to_string(user_dir.join("30_11.jpg")).unwrap(),
+                "same"
+            );
+            // Running again leaves the links as they are
+            store_objects(&user_dir, &mut data_file, link)
+                .await
+                .unwrap();
+        }
+        // A stale hash doesn't swap the file for another object
+        let carol = out.path().join("carol");
+        std::fs::create_dir(&carol).unwrap();
+        std::fs::write(carol.join("30_11.jpg"), "changed").unwrap();
+        let mut data_file = data_file();
+        let stale = hex::encode(sha2::Sha256::digest("same"));
+        data_file.tweets[2].media[0].sha256 = Some(stale.clone());
+        data_file.tweets[2].media[1].file_name = None;
+        store_objects(&carol, &mut data_file, ObjectLink::Symlink)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(carol.join("30_11.jpg")).unwrap(),
+            "changed"
+        );
+        assert_ne!(data_file.tweets[2].media[0].sha256, Some(stale));
+        let alice = std::fs::symlink_metadata(out.path().join("alice/30_11.jpg")).unwrap();
+        assert!(alice.is_symlink());
+        // Each distinct file is stored once
+        assert_eq!(
+            std::fs::read_dir(out.path().join(OBJECTS_DIR))
+                .unwrap()
+                .count(),
+            4
+        );
+    }
+}
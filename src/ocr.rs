@@ -0,0 +1,41 @@
+//! Extracts the text from downloaded images (lots of Twitter content is screenshots of text), so
+//! that it can be searched in the viewer
+
+use crate::external::ExternalCommand;
+use crate::model::DataFile;
+use futures::{stream, StreamExt};
+use std::path::Path;
+
+/// Runs OCR on every downloaded photo that doesn't have any text recorded yet
+pub async fn ocr_media(
+    user_dir: &Path,
+    data_file: &mut DataFile,
+    command: &ExternalCommand,
+    concurrency: usize,
+) -> anyhow::Result<()> {
+    let pending = data_file.downloaded_photos(user_dir, |m| m.ocr_text.is_none());
+    let mut results = stream::iter(pending)
+        .map(|(tweet_index, media_index, path)| async move {
+            (tweet_index, media_index, command.run(&path).await, path)
+        })
+        .buffer_unordered(concurrency);
+
+    let mut counter = 0;
+    while let Some((tweet_index, media_index, result, path)) = results.next().await {
+        match result {
+            // Images without any text are recorded as empty so that they aren't retried
+            Ok(text) => {
+                data_file.tweets[tweet_index].media[media_index].ocr_text =
+                    Some(text.trim().to_string());
+                counter += 1;
+            }
+            // The command couldn't be started at all, so it will fail for every image
+            Err(e) if e.downcast_ref::<std::io::Error>().is_some() => return Err(e),
+            Err(e) => log::warn!("OCR failed for {}: {:#}", path.display(), e),
+        }
+    }
+    if counter > 0 {
+        log::info!("Extracted text from {} images", counter);
+    }
+    Ok(())
+}
@@ -0,0 +1,305 @@
+//! `pack` and `unpack`: an account folder as a single `.tar.zst` file, to move or back up an
+//! archive as one piece. The bundle lists the SHA-256 of every file, checked when unpacking.
+
+mod tar;
+
+use crate::download::account_dir;
+use crate::lock;
+use crate::{PackArgs, UnpackArgs};
+use anyhow::{bail, Context};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// The last entry of a bundle, in the format of `sha256sum` so that an extracted bundle can also
+/// be checked with `sha256sum -c`
+const MANIFEST: &str = "SHA256SUMS";
+
+pub async fn pack(args: PackArgs) -> anyhow::Result<()> {
+    let user_dir = account_dir(&args.dir, &args.username);
+    if !user_dir.is_dir() {
+        bail!("No folder for {} in {}", args.username, args.dir.display());
+    }
+    // The folder's (sanitized) name, which `unpack` recreates and `download` then looks for
+    let username = user_dir
+        .file_name()
+        .context("Invalid account folder")?
+        .to_string_lossy()
+        .into_owned();
+    let out = args
+        .out
+        .unwrap_or_else(|| PathBuf::from(format!("{username}.tar.zst")));
+    let _lock = lock::lock(&user_dir, args.wait_lock).await?;
+    let bundle = out.clone();
+    let name = username.clone();
+    let count = tokio::task::spawn_blocking(move || {
+        let result = pack_dir(&user_dir, &name, &bundle);
+        if result.is_err() {
+            let _ = fs::remove_file(&bundle);
+        }
+        result
+    })
+    .await??;
+    log::info!(
+        "Packed {} files of {} into {}",
+        count,
+        username,
+        out.display()
+    );
+    Ok(())
+}
+
+pub async fn unpack(args: UnpackArgs) -> anyhow::Result<()> {
+    let (username, count) =
+        tokio::task::spawn_blocking(move || unpack_bundle(&args.bundle, &args.out)).await??;
+    log::info!("Unpacked {} files of {}", count, username);
+    Ok(())
+}
+
+/// Returns the number of files packed
+fn pack_dir(user_dir: &Path, username: &str, out: &Path) -> anyhow::Result<usize> {
+    let mut files = Vec::new();
+    list_files(user_dir, Path::new(""), &mut files)?;
+    let file = File::create(out).with_context(|| format!("Unable to create {}", out.display()))?;
+    let encoder = zstd::Encoder::new(BufWriter::new(file), 0)?;
+    let mut writer = tar::Writer::new(encoder);
+    let mut manifest = String::new();
+    for relative in &files {
+        let path = format!("{username}/{}", slashed(relative));
+        let source = user_dir.join(relative);
+        let file =
+            File::open(&source).with_context(|| format!("Unable to read {}", source.display()))?;
+        let metadata = file.metadata()?;
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        let mut reader = HashingReader::new(file);
+        writer.append(&path, metadata.len(), mtime, &mut reader)?;
+        manifest.push_str(&format!("{}  {path}\n", reader.finish()));
+    }
+    writer.append(MANIFEST, manifest.len() as u64, 0, &mut manifest.as_bytes())?;
+    writer.finish()?.finish()?.flush()?;
+    Ok(files.len())
+}
+
+/// The files of the folder, relative to it and sorted. Hidden files (the lock and unfinished
+/// downloads) are left out.
+fn list_files(root: &Path, relative: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    let mut entries = fs::read_dir(root.join(relative))?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = relative.join(entry.file_name());
+        // Following symlinks (e.g. into `--objects symlink`'s store), so the bundle has the files
+        let metadata = fs::metadata(entry.path())?;
+        if metadata.is_dir() {
+            list_files(root, &path, files)?;
+        } else if metadata.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn slashed(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Extracts into a staging folder, only moving the account folder into place once every file
+/// matches the manifest. Returns the username and the number of files.
+fn unpack_bundle(bundle: &Path, out: &Path) -> anyhow::Result<(String, usize)> {
+    let file =
+        File::open(bundle).with_context(|| format!("Unable to read {}", bundle.display()))?;
+    fs::create_dir_all(out).context("Unable to create output directory")?;
+    let staging = tempfile::Builder::new()
+        .prefix(".unpack-")
+        .tempdir_in(out)?;
+    let mut reader = tar::Reader::new(zstd::Decoder::new(file)?);
+    let mut username = None::<String>;
+    let mut hashes = BTreeMap::new();
+    let mut manifest = None;
+    while let Some(entry) = reader.next_entry()? {
+        if entry.path == MANIFEST {
+            let mut text = String::new();
+            reader.read_to_string(&mut text)?;
+            manifest = Some(text);
+            continue;
+        }
+        let relative = safe_path(&entry.path)?;
+        let top = relative
+            .iter()
+            .next()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        match &username {
+            Some(username) if *username != top => bail!("The bundle has more than one account"),
+            Some(_) => {}
+            None => {
+                if out.join(&top).exists() {
+                    bail!(
+                        "{} already exists, unpack elsewhere and `merge` the folders instead",
+                        out.join(&top).display()
+                    );
+                }
+                username = Some(top);
+            }
+        }
+        let destination = staging.path().join(&relative);
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = BufWriter::new(File::create(&destination)?);
+        let mut hashing = HashingReader::new(&mut reader);
+        io::copy(&mut hashing, &mut file)?;
+        file.flush()?;
+        // Keeping the files' dates as they were
+        let _ = file
+            .get_ref()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(entry.mtime));
+        hashes.insert(entry.path, hashing.finish());
+    }
+    let username = username.context("The bundle is empty")?;
+    let manifest = manifest.context("The bundle has no manifest")?;
+    check_manifest(&manifest, &hashes)?;
+    let count = hashes.len();
+    let target = out.join(&username);
+    if target.exists() {
+        bail!("{} already exists", target.display());
+    }
+    fs::rename(staging.path().join(&username), &target)
+        .with_context(|| format!("Unable to create {}", target.display()))?;
+    Ok((username, count))
+}
+
+/// Refuses absolute paths and `..`, which would write outside of the output folder
+fn safe_path(path: &str) -> anyhow::Result<PathBuf> {
+    let relative = PathBuf::from(path);
+    let safe = relative.components().count() >= 2
+        && relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+    if !safe {
+        bail!("Refusing to extract {path}");
+    }
+    Ok(relative)
+}
+
+fn check_manifest(manifest: &str, hashes: &BTreeMap<String, String>) -> anyhow::Result<()> {
+    let mut listed = BTreeMap::new();
+    for line in manifest.lines() {
+        let (hash, path) = line.split_once("  ").context("Invalid manifest")?;
+        listed.insert(path, hash);
+    }
+    for (path, hash) in hashes {
+        match listed.remove(path.as_str()) {
+            Some(expected) if expected == hash => {}
+            Some(_) => bail!("{path} is corrupted (its SHA-256 doesn't match)"),
+            None => bail!("{path} isn't in the manifest"),
+        }
+    }
+    if let Some(path) = listed.keys().next() {
+        bail!("{path} is missing from the bundle");
+    }
+    Ok(())
+}
+
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    fn finish(self) -> String {
+        hex::encode(self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn packs_and_unpacks() {
+        let dir = tempfile::tempdir().unwrap();
+        let user_dir = dir.path().join("alice");
+        fs::create_dir_all(user_dir.join("2021-03-04")).unwrap();
+        fs::write(user_dir.join("tweets.json"), "{}").unwrap();
+        fs::write(user_dir.join("2021-03-04/30_11.jpg"), "photo").unwrap();
+        fs::write(user_dir.join(lock::LOCK_FILE), "").unwrap();
+        let bundle = dir.path().join("alice.tar.zst");
+        assert_eq!(pack_dir(&user_dir, "alice", &bundle).unwrap(), 2);
+
+        let out = dir.path().join("restored");
+        assert_eq!(
+            unpack_bundle(&bundle, &out).unwrap(),
+            ("alice".to_string(), 2)
+        );
+        let restored = out.join("alice");
+        assert_eq!(
+            fs::read_to_string(restored.join("2021-03-04/30_11.jpg")).unwrap(),
+            "photo"
+        );
+        assert!(!restored.join(lock::LOCK_FILE).exists());
+        assert!(unpack_bundle(&bundle, &out).is_err());
+        // Only the account folder is left behind
+        assert_eq!(fs::read_dir(&out).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn packs_the_sanitized_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let user_dir = dir.path().join("a_b");
+        fs::create_dir_all(&user_dir).unwrap();
+        fs::write(user_dir.join("tweets.json"), "{}").unwrap();
+        let bundle = dir.path().join("bundle.tar.zst");
+        let (dir_arg, out_arg) = (dir.path().to_str().unwrap(), bundle.to_str().unwrap());
+        pack(PackArgs::parse_from([
+            "pack", "a:b", "-d", dir_arg, "-o", out_arg,
+        ]))
+        .await
+        .unwrap();
+        let out = dir.path().join("restored");
+        assert_eq!(
+            unpack_bundle(&bundle, &out).unwrap(),
+            ("a_b".to_string(), 1)
+        );
+    }
+
+    #[test]
+    fn checks_manifest() {
+        let hashes = BTreeMap::from([("alice/a.jpg".to_string(), "00".to_string())]);
+        assert!(check_manifest("00  alice/a.jpg\n", &hashes).is_ok());
+        assert!(check_manifest("11  alice/a.jpg\n", &hashes).is_err());
+        assert!(check_manifest("00  alice/a.jpg\n00  alice/b.jpg\n", &hashes).is_err());
+        assert!(check_manifest("", &hashes).is_err());
+        assert!(safe_path("alice/../../etc/passwd").is_err());
+        assert!(safe_path("/alice/a.jpg").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! Just enough of the ustar format to write and read back the bundles: regular files only, with
+//! paths of up to 255 bytes (split between the `prefix` and `name` fields)
+
+use anyhow::{bail, Context};
+use std::io::{self, Read, Write};
+
+const BLOCK: usize = 512;
+
+/// The largest size that fits the 11 octal digits of the header
+const MAX_SIZE: u64 = 0o77777777777;
+
+pub struct Writer<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    /// Adds a file of `size` bytes, copying them from `data`
+    pub fn append(
+        &mut self,
+        path: &str,
+        size: u64,
+        mtime: u64,
+        data: &mut impl Read,
+    ) -> anyhow::Result<()> {
+        if size > MAX_SIZE {
+            bail!("{path} is too large to be packed");
+        }
+        self.inner.write_all(&header(path, size, mtime)?)?;
+        let copied = io::copy(&mut data.take(size), &mut self.inner)?;
+        if copied != size {
+            bail!("{path} changed while it was being packed");
+        }
+        self.pad(size)?;
+        Ok(())
+    }
+
+    fn pad(&mut self, size: u64) -> io::Result<()> {
+        let remainder = (size % BLOCK as u64) as usize;
+        if remainder > 0 {
+            self.inner.write_all(&[0; BLOCK][remainder..])?;
+        }
+        Ok(())
+    }
+
+    /// Writes the end of archive marker, returning the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(&[0; BLOCK * 2])?;
+        Ok(self.inner)
+    }
+}
+
+fn header(path: &str, size: u64, mtime: u64) -> anyhow::Result<[u8; BLOCK]> {
+    let (prefix, name) = split_path(path).with_context(|| format!("{path} is too long to pack"))?;
+    let mut header = [0; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size);
+    octal(&mut header[136..148], mtime);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    // The checksum is calculated with its own field as spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum = header.iter().map(|b| *b as u64).sum();
+    octal(&mut header[148..155], checksum);
+    Ok(header)
+}
+
+/// Splits a path longer than the `name` field at a `/`, so that the rest fits in `prefix`
+fn split_path(path: &str) -> Option<(&str, &str)> {
+    if path.len() <= 100 {
+        return Some(("", path));
+    }
+    path.char_indices()
+        .filter(|(_, c)| *c == '/')
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .find(|(prefix, name)| prefix.len() <= 155 && name.len() <= 100 && !name.is_empty())
+}
+
+/// Zero padded, NUL terminated
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+fn parse_octal(field: &[u8]) -> anyhow::Result<u64> {
+    let text = std::str::from_utf8(field)?.trim_matches(|c| c == '\0' || c == ' ');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).context("Invalid number in header")
+}
+
+fn parse_string(field: &[u8]) -> anyhow::Result<&str> {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    std::str::from_utf8(&field[..end]).context("Invalid path in header")
+}
+
+pub struct Reader<R: Read> {
+    inner: R,
+    /// Of the current entry, which must be read (or skipped) before the next
+    remaining: u64,
+    padding: usize,
+}
+
+pub struct Entry {
+    pub path: String,
+    pub mtime: u64,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            padding: 0,
+        }
+    }
+
+    /// The next file, whose contents are then read with `read`. Directories are skipped.
+    pub fn next_entry(&mut self) -> anyhow::Result<Option<Entry>> {
+        loop {
+            io::copy(
+                &mut (&mut self.inner).take(self.remaining + self.padding as u64),
+                &mut io::sink(),
+            )?;
+            self.remaining = 0;
+            self.padding = 0;
+            let mut header = [0; BLOCK];
+            self.inner
+                .read_exact(&mut header)
+                .context("Truncated archive")?;
+            if header.iter().all(|b| *b == 0) {
+                return Ok(None);
+            }
+            let expected = parse_octal(&header[148..156])?;
+            header[148..156].copy_from_slice(b"        ");
+            if expected != header.iter().map(|b| *b as u64).sum::<u64>() {
+                bail!("Corrupted archive (bad header checksum)");
+            }
+            let size = parse_octal(&header[124..136])?;
+            let mtime = parse_octal(&header[136..148])?;
+            let name = parse_string(&header[..100])?;
+            let prefix = parse_string(&header[345..500])?;
+            let path = if prefix.is_empty() {
+                name.to_string()
+            } else {
+                format!("{prefix}/{name}")
+            };
+            self.remaining = size;
+            self.padding = (BLOCK - (size % BLOCK as u64) as usize) % BLOCK;
+            match header[156] {
+                b'0' | 0 => return Ok(Some(Entry { path, mtime })),
+                b'5' => continue,
+                other => bail!("Unsupported entry type `{}` for {path}", other as char),
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = buf.len().min(self.remaining as usize);
+        // zstd errors on reads into an empty buffer
+        if max == 0 {
+            return Ok(0);
+        }
+        let read = self.inner.read(&mut buf[..max])?;
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let long = format!("{}/{}.jpg", "d".repeat(120), "f".repeat(90));
+        let mut writer = Writer::new(Vec::new());
+        writer.append("a.txt", 5, 1, &mut &b"hello"[..]).unwrap();
+        writer.append(&long, 0, 2, &mut &b""[..]).unwrap();
+        let data = writer.finish().unwrap();
+        assert_eq!(data.len() % BLOCK, 0);
+
+        let mut reader = Reader::new(&data[..]);
+        let entry = reader.next_entry().unwrap().unwrap();
+        assert_eq!((entry.path.as_str(), entry.mtime), ("a.txt", 1));
+        let mut text = String::new();
+        reader.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "hello");
+        assert_eq!(reader.next_entry().unwrap().unwrap().path, long);
+        assert!(reader.next_entry().unwrap().is_none());
+        assert!(split_path(&"x".repeat(101)).is_none());
+    }
+}
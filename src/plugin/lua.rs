@@ -0,0 +1,106 @@
+use crate::model::{Media, Tweet};
+use crate::plugin::validate_file_name;
+use anyhow::{anyhow, Context};
+use mlua::{Function, Lua, LuaSerdeExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Default)]
+pub struct Plugins {
+    plugins: Vec<Plugin>,
+}
+
+struct Plugin {
+    name: String,
+    // A Lua state can't be used from multiple threads at once
+    lua: Mutex<Lua>,
+}
+
+impl Plugins {
+    pub fn load(paths: &[PathBuf]) -> anyhow::Result<Self> {
+        let mut plugins = Vec::new();
+        for path in paths {
+            let source = std::fs::read_to_string(path)
+                .with_context(|| format!("Unable to read plugin {}", path.display()))?;
+            let name = path.display().to_string();
+            let lua = Lua::new();
+            lua.load(&source)
+                .set_name(&name)
+                .exec()
+                .map_err(|e| anyhow!("Unable to load plugin {name}: {e}"))?;
+            log::info!("Loaded plugin {}", name);
+            plugins.push(Plugin {
+                name,
+                lua: Mutex::new(lua),
+            });
+        }
+        Ok(Self { plugins })
+    }
+
+    /// Whether all the plugins accept downloading the media of a tweet
+    pub fn filter(&self, tweet: &Tweet) -> anyhow::Result<bool> {
+        for plugin in &self.plugins {
+            let accepted = plugin.call("filter", |lua, f| {
+                f.call::<_, Option<bool>>(lua.to_value(tweet)?)
+            })?;
+            if accepted.flatten() == Some(false) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// The path to save media at (relative to the user folder), given the default path
+    pub fn file_name(
+        &self,
+        tweet: &Tweet,
+        media: &Media,
+        default: String,
+    ) -> anyhow::Result<String> {
+        let mut file_name = default;
+        for plugin in &self.plugins {
+            let custom = plugin.call("file_name", |lua, f| {
+                f.call::<_, Option<String>>((
+                    lua.to_value(tweet)?,
+                    lua.to_value(media)?,
+                    file_name.as_str(),
+                ))
+            })?;
+            if let Some(custom) = custom.flatten() {
+                file_name = custom;
+            }
+        }
+        validate_file_name(&file_name)?;
+        Ok(file_name)
+    }
+
+    pub fn post_download(&self, tweet: &Tweet, media: &Media, path: &Path) -> anyhow::Result<()> {
+        for plugin in &self.plugins {
+            plugin.call("post_download", |lua, f| {
+                f.call::<_, ()>((
+                    lua.to_value(tweet)?,
+                    lua.to_value(media)?,
+                    path.to_string_lossy(),
+                ))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl Plugin {
+    /// Calls a hook, returning None if the plugin doesn't define it
+    fn call<R>(
+        &self,
+        hook: &str,
+        call: impl FnOnce(&Lua, Function) -> mlua::Result<R>,
+    ) -> anyhow::Result<Option<R>> {
+        let lua = self.lua.lock().unwrap();
+        let result = match lua.globals().get::<_, Option<Function>>(hook) {
+            Ok(Some(function)) => call(&lua, function).map(Some),
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        };
+        result.map_err(|e| anyhow!("Plugin {} failed in `{hook}`: {e}", self.name))
+    }
+}
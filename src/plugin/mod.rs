@@ -0,0 +1,65 @@
+//! Lua plugins, loaded with `--plugin <script.lua>`, which can customise downloads by defining any
+//! of these global functions:
+//!
+//! - `filter(tweet)`: return `false` to skip downloading the media of a tweet
+//! - `file_name(tweet, media, default)`: return the path (relative to the user folder) to save the
+//!   media at, or `nil` to use the default
+//! - `post_download(tweet, media, path)`: called after each media file has been saved
+//!
+//! `tweet` and `media` are tables with the same fields as in `tweets.json`.
+
+use anyhow::bail;
+use std::path::{Component, Path};
+
+#[cfg(feature = "plugins")]
+mod lua;
+
+#[cfg(feature = "plugins")]
+pub use lua::Plugins;
+
+#[cfg(not(feature = "plugins"))]
+pub use disabled::Plugins;
+
+/// Media must be saved within the user folder
+fn validate_file_name(file_name: &str) -> anyhow::Result<()> {
+    let valid = !file_name.is_empty()
+        && Path::new(file_name)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+    if !valid {
+        bail!("Invalid file name: {file_name}")
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "plugins"))]
+mod disabled {
+    use crate::model::{Media, Tweet};
+    use anyhow::bail;
+    use std::path::{Path, PathBuf};
+
+    #[derive(Default)]
+    pub struct Plugins;
+
+    impl Plugins {
+        pub fn load(paths: &[PathBuf]) -> anyhow::Result<Self> {
+            if !paths.is_empty() {
+                bail!("Application must be built with the `plugins` feature")
+            }
+            Ok(Self)
+        }
+
+        pub fn filter(&self, _tweet: &Tweet) -> anyhow::Result<bool> {
+            Ok(true)
+        }
+
+        pub fn file_name(&self, _: &Tweet, _: &Media, default: String) -> anyhow::Result<String> {
+            super::validate_file_name(&default)?;
+            Ok(default)
+        }
+
+        pub fn post_download(&self, _: &Tweet, _: &Media, _: &Path) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Progress bars for media downloads. Log output is routed through the progress display so that
+//! log lines don't get mixed up with the bars.
+
+use chrono::Utc;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use indicatif_log_bridge::LogWrapper;
+use std::io::Write;
+use std::sync::OnceLock;
+
+static MULTI: OnceLock<MultiProgress> = OnceLock::new();
+
+/// Initialises logging, with the progress bars hidden if `quiet`, and each log line written as a
+/// JSON object if `json`
+pub fn init(quiet: bool, json: bool) {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::new().default_filter_or("info"));
+    if json {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "time": Utc::now().to_rfc3339(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{line}")
+        });
+    }
+    let logger = builder.build();
+    let level = logger.filter();
+    let multi = MultiProgress::new();
+    if quiet {
+        multi.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    LogWrapper::new(multi.clone(), logger).try_init().unwrap();
+    log::set_max_level(level);
+    MULTI.set(multi).ok();
+}
+
+/// The overall progress of downloading a set of media, shared by each download
+#[derive(Clone)]
+pub struct Progress {
+    overall: ProgressBar,
+}
+
+impl Progress {
+    pub fn new(total: usize, label: &str) -> Self {
+        let overall = add(ProgressBar::new(total as u64));
+        overall.set_style(
+            ProgressStyle::with_template("{prefix} [{bar:40}] {pos}/{len} media ({eta})")
+                .unwrap()
+                .progress_chars("=> "),
+        );
+        overall.set_prefix(label.to_string());
+        Self { overall }
+    }
+
+    /// A bar for a single file, its length should be set once the size is known
+    pub fn file(&self, name: &str) -> ProgressBar {
+        let bar = match MULTI.get() {
+            Some(multi) => multi.insert_before(&self.overall, ProgressBar::no_length()),
+            None => ProgressBar::hidden(),
+        };
+        bar.set_style(
+            ProgressStyle::with_template(
+                "  {msg:40!} {bytes:>10}/{total_bytes:10} {bytes_per_sec}",
+            )
+            .unwrap(),
+        );
+        bar.set_message(name.to_string());
+        bar
+    }
+
+    /// Records that a media item has been completed (successfully or not)
+    pub fn inc(&self) {
+        self.overall.inc(1);
+    }
+
+    pub fn finish(&self) {
+        self.overall.finish_and_clear();
+    }
+}
+
+fn add(bar: ProgressBar) -> ProgressBar {
+    match MULTI.get() {
+        Some(multi) => multi.add(bar),
+        None => ProgressBar::hidden(),
+    }
+}
@@ -0,0 +1,71 @@
+//! `--proxy`: sends the requests through an HTTP(S) or SOCKS5 proxy (e.g. `socks5h://127.0.0.1:9050`
+//! for Tor), optionally a different one for the API requests (`--api-proxy`) and the media
+//! downloads (`--media-proxy`)
+
+use anyhow::Context;
+use reqwest::{ClientBuilder, Proxy};
+use std::sync::OnceLock;
+use url::Url;
+
+static PROXIES: OnceLock<Proxies> = OnceLock::new();
+
+#[derive(Debug, Default)]
+struct Proxies {
+    api: Option<Proxy>,
+    media: Option<Proxy>,
+    /// For ffmpeg, which makes its own connections
+    media_url: Option<Url>,
+}
+
+pub fn init(proxy: Option<&Url>, api: Option<&Url>, media: Option<&Url>) -> anyhow::Result<()> {
+    let parse = |url: Option<&Url>| {
+        url.map(|url| Proxy::all(url.as_str()).with_context(|| format!("Invalid proxy: {url}")))
+            .transpose()
+    };
+    let proxies = Proxies {
+        api: parse(api.or(proxy))?,
+        media: parse(media.or(proxy))?,
+        media_url: media.or(proxy).cloned(),
+    };
+    PROXIES.set(proxies).ok();
+    Ok(())
+}
+
+fn proxies() -> &'static Proxies {
+    PROXIES.get_or_init(Proxies::default)
+}
+
+/// Whether the API requests go through a proxy
+pub fn api_enabled() -> bool {
+    proxies().api.is_some()
+}
+
+/// Applies the proxy for the requests to Twitter (or Nitter, or the extractor's site)
+pub fn api(builder: ClientBuilder) -> ClientBuilder {
+    match &proxies().api {
+        Some(proxy) => builder.proxy(proxy.clone()),
+        None => builder,
+    }
+}
+
+/// Applies the proxy for downloading media (and the other requests made with the connection
+/// pool)
+pub fn media(builder: ClientBuilder) -> ClientBuilder {
+    match &proxies().media {
+        Some(proxy) => builder.proxy(proxy.clone()),
+        None => builder,
+    }
+}
+
+/// The arguments that send ffmpeg's requests for HLS playlists through the media proxy. ffmpeg
+/// only supports HTTP proxies, so fails for the others rather than connecting directly.
+pub fn ffmpeg_args() -> anyhow::Result<Vec<String>> {
+    match &proxies().media_url {
+        None => Ok(Vec::new()),
+        Some(url) if url.scheme() == "http" => Ok(vec!["-http_proxy".into(), url.to_string()]),
+        Some(url) => anyhow::bail!(
+            "HLS videos are downloaded with ffmpeg, which can't use a `{}://` proxy",
+            url.scheme()
+        ),
+    }
+}
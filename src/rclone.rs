@@ -0,0 +1,77 @@
+//! Uploads account folders to an rclone remote, so that any of rclone's cloud backends can be used
+//! as the destination. The output directory acts as a local staging area.
+
+use anyhow::{bail, Context};
+use std::path::Path;
+use std::process::Stdio;
+use tokio::process::Command;
+
+/// Files that are kept locally when moving, as they are needed to know what has been downloaded
+/// (and by the viewer)
+const INDEX_FILES: &[&str] = &[
+    "/tweets.json",
+    "/tweets.json.*",
+    "/manifest.json",
+    "/profile.json",
+    "/avatar.*",
+    "/banner.*",
+];
+
+pub struct Remote<'a> {
+    /// The rclone binary
+    pub rclone: &'a Path,
+    /// e.g. `s3:bucket/twitter`
+    pub destination: &'a str,
+    /// Delete the media from the local folder once it has been uploaded
+    pub move_media: bool,
+}
+
+impl Remote<'_> {
+    pub async fn upload(&self, user_dir: &Path, username: &str) -> anyhow::Result<()> {
+        let destination = format!("{}/{}", self.destination.trim_end_matches('/'), username);
+        // The sync's lock is only meaningful locally
+        let lock = format!("/{}", crate::lock::LOCK_FILE);
+        if self.move_media {
+            let excludes = INDEX_FILES
+                .iter()
+                .copied()
+                .chain([lock.as_str()])
+                .flat_map(|f| ["--exclude", f]);
+            self.run("move", user_dir, &destination, excludes).await?;
+            let includes = INDEX_FILES.iter().flat_map(|f| ["--include", f]);
+            self.run("copy", user_dir, &destination, includes).await?;
+        } else {
+            self.run("copy", user_dir, &destination, ["--exclude", &lock])
+                .await?;
+        }
+        log::info!("Uploaded {} to {}", username, destination);
+        Ok(())
+    }
+
+    async fn run<'a>(
+        &self,
+        command: &str,
+        source: &Path,
+        destination: &str,
+        filters: impl IntoIterator<Item = &'a str>,
+    ) -> anyhow::Result<()> {
+        let output = Command::new(self.rclone)
+            .arg(command)
+            .arg(source)
+            .arg(destination)
+            .args(filters)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            // So that rclone is stopped if the sync is cancelled
+            .kill_on_drop(true)
+            .output()
+            .await
+            .with_context(|| format!("Unable to run {}", self.rclone.display()))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            bail!("rclone {} failed: {}", command, stderr.trim())
+        }
+        Ok(())
+    }
+}
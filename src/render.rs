@@ -0,0 +1,153 @@
+//! Renders each tweet as a standalone HTML page, saved alongside its media, for archives where
+//! the presentation of the tweet itself matters
+
+use crate::download::MediaSelection;
+use crate::model::{DataFile, Media, MediaType, Tweet};
+use anyhow::Context;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::path::Path;
+use tokio::fs;
+
+const STYLE: &str = "body{font-family:\"Helvetica Neue\",Arial,sans-serif;background:#f5f8fa}\
+.tweet{max-width:600px;margin:20px auto;padding:16px 20px;background:#fff;border:1px solid #e1e8ed;\
+border-radius:12px}.author{font-weight:bold}.date{color:#657786;font-size:14px}\
+.text{font-size:18px;white-space:pre-wrap;overflow-wrap:break-word}\
+img,video{max-width:100%;border-radius:8px;display:block;margin:8px 0}\
+.poll{padding-left:20px}.card{display:block;border:1px solid #e1e8ed;border-radius:8px;\
+padding:8px 12px;color:inherit;text-decoration:none}";
+
+/// Renders the tweets that haven't been rendered yet, and those in `changed` (e.g. because more
+/// of their media has been downloaded since they were last rendered)
+pub async fn render_tweets(
+    username: &str,
+    user_dir: &Path,
+    data_file: &DataFile,
+    selection: &MediaSelection,
+    changed: &BTreeSet<usize>,
+) -> anyhow::Result<usize> {
+    let mut rendered = 0;
+    for (index, tweet) in data_file.tweets.iter().enumerate() {
+        let folder = selection.folder(tweet);
+        let path = user_dir.join(format!("{folder}{}.html", tweet.id));
+        if path.exists() && !changed.contains(&index) {
+            continue;
+        }
+        // Media paths are relative to the user folder
+        let base = "../".repeat(folder.matches('/').count());
+        let html = render(username, tweet, selection, &base);
+        fs::create_dir_all(path.parent().unwrap()).await?;
+        fs::write(&path, html)
+            .await
+            .with_context(|| format!("Unable to write {}", path.display()))?;
+        rendered += 1;
+    }
+    if rendered > 0 {
+        log::info!("Rendered {} tweets for {}", rendered, username);
+    }
+    Ok(rendered)
+}
+
+fn render(username: &str, tweet: &Tweet, selection: &MediaSelection, base: &str) -> String {
+    let date = selection.timezone().datetime(tweet.timestamp);
+    let mut html = String::new();
+    write!(
+        html,
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"UTF-8\">\n\
+        <title>@{user}: {id}</title>\n<style>{STYLE}</style>\n</head>\n<body>\n\
+        <div class=\"tweet\">\n<div class=\"author\">@{user}</div>\n\
+        <div class=\"date\"><a href=\"https://twitter.com/{user}/status/{id}\">{date}</a></div>\n\
+        <p class=\"text\">{text}</p>\n",
+        user = escape(username),
+        id = tweet.id,
+        date = date.format("%-I:%M %p · %b %-d, %Y"),
+        text = escape(&tweet.text),
+    )
+    .unwrap();
+    for media in &tweet.media {
+        let src = match &media.file_name {
+            Some(file_name) => format!("{base}{}", escape(file_name)),
+            None => continue,
+        };
+        match media.r#type {
+            MediaType::Photo => writeln!(html, "<img src=\"{src}\" alt=\"{}\">", alt(media)),
+            MediaType::Video => writeln!(html, "<video controls src=\"{src}\"></video>"),
+            MediaType::Gif => writeln!(html, "<video autoplay loop muted src=\"{src}\"></video>"),
+            MediaType::Audio => writeln!(html, "<audio controls src=\"{src}\"></audio>"),
+        }
+        .unwrap();
+    }
+    html.push_str(&poll_and_card(tweet));
+    html.push_str("</div>\n</body>\n</html>\n");
+    html
+}
+
+/// The tweet's poll (with the votes, when they were reported) and link preview, as HTML
+pub fn poll_and_card(tweet: &Tweet) -> String {
+    let mut html = String::new();
+    if let Some(poll) = &tweet.poll {
+        html.push_str("<ul class=\"poll\">\n");
+        for option in &poll.options {
+            match option.votes {
+                Some(votes) => writeln!(html, "<li>{} ({votes} votes)</li>", escape(&option.label)),
+                None => writeln!(html, "<li>{}</li>", escape(&option.label)),
+            }
+            .unwrap();
+        }
+        html.push_str("</ul>\n");
+    }
+    if let Some(card) = &tweet.card {
+        let href = card
+            .url
+            .as_ref()
+            .map(|u| escape(u.as_str()))
+            .unwrap_or_default();
+        write!(html, "<a class=\"card\" href=\"{href}\">").unwrap();
+        if let Some(title) = &card.title {
+            write!(html, "<strong>{}</strong>", escape(title)).unwrap();
+        }
+        if let Some(description) = &card.description {
+            write!(html, "<br>{}", escape(description)).unwrap();
+        }
+        html.push_str("</a>\n");
+    }
+    html
+}
+
+/// The media's alt text, escaped for an attribute (empty without one, marking the image as
+/// decorative)
+pub fn alt(media: &Media) -> String {
+    escape(media.alt_text.as_deref().unwrap_or_default())
+}
+
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::{assert_golden, data_file, USERNAME};
+    use crate::MediaArgs;
+    use clap::Parser;
+
+    #[test]
+    fn render_golden() {
+        let args = MediaArgs::parse_from(["twitter-dl", "--timezone", "UTC"]);
+        let selection = MediaSelection::new(&args).unwrap();
+        let data_file = data_file();
+        let html = render(USERNAME, &data_file.tweets[2], &selection, "");
+        assert_golden("30.html", &html);
+    }
+}
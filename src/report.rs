@@ -0,0 +1,198 @@
+//! The outcome of a `download`/`run`: the downloads that failed, the files that were skipped and
+//! what each account's sync did, written to `report.json` in the output folder (and summarized on
+//! the console) so that `retry-failed` can re-attempt just the failures
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+use url::Url;
+
+pub const REPORT_FILE: &str = "report.json";
+
+/// Only collected between `start` and `finish`, so that library users don't accumulate one
+static REPORT: Mutex<Option<Report>> = Mutex::new(None);
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct Report {
+    pub accounts: BTreeMap<String, AccountCounters>,
+    pub failed: Vec<FailedDownload>,
+    pub skipped: Vec<SkippedFile>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct AccountCounters {
+    pub new_tweets: usize,
+    pub downloaded: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    /// Files that were skipped as they are no longer available
+    #[serde(default)]
+    pub unavailable: usize,
+    /// Why the sync stopped, if it didn't complete
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct FailedDownload {
+    pub username: String,
+    pub tweet_id: u64,
+    pub media_id: u64,
+    pub url: Url,
+    /// Relative to the user folder
+    pub path: String,
+    pub error: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SkippedFile {
+    pub username: String,
+    pub tweet_id: u64,
+    pub media_id: u64,
+    /// Relative to the user folder
+    pub path: String,
+    pub reason: String,
+}
+
+impl Report {
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = crate::encryption::read(path).await?;
+        serde_json::from_slice(&data).context("Unable to deserialize report")
+    }
+
+    fn account(&mut self, username: &str) -> &mut AccountCounters {
+        self.accounts.entry(username.to_string()).or_default()
+    }
+
+    fn summarize(&self, path: &Path) {
+        log::info!(
+            "Report for {} accounts written to {}",
+            self.accounts.len(),
+            path.display()
+        );
+        for (username, account) in &self.accounts {
+            let outcome = match &account.error {
+                Some(error) => format!(", stopped: {error}"),
+                None => String::new(),
+            };
+            log::info!(
+                "  {}: {} new tweets, {} downloaded, {} failed, {} skipped, {} no longer available{}",
+                username,
+                account.new_tweets,
+                account.downloaded,
+                account.failed,
+                account.skipped,
+                account.unavailable,
+                outcome
+            );
+        }
+        for failed in &self.failed {
+            log::warn!(
+                "  Failed {}/{} ({}): {}",
+                failed.username,
+                failed.path,
+                failed.url,
+                failed.error
+            );
+        }
+        for skipped in &self.skipped {
+            log::info!(
+                "  Skipped {}/{}: {}",
+                skipped.username,
+                skipped.path,
+                skipped.reason
+            );
+        }
+        if !self.failed.is_empty() {
+            log::info!(
+                "Re-attempt the failed downloads with `twitter-dl retry-failed {}`",
+                path.display()
+            );
+        }
+    }
+}
+
+/// Starts collecting a report
+pub fn start() {
+    *REPORT.lock().unwrap() = Some(Report::default());
+}
+
+fn record(f: impl FnOnce(&mut Report)) {
+    if let Some(report) = REPORT.lock().unwrap().as_mut() {
+        f(report);
+    }
+}
+
+pub fn failed(failed: FailedDownload) {
+    record(|report| {
+        report.account(&failed.username).failed += 1;
+        report.failed.push(failed);
+    });
+}
+
+pub fn skipped(skipped: SkippedFile) {
+    record(|report| {
+        report.account(&skipped.username).skipped += 1;
+        report.skipped.push(skipped);
+    });
+}
+
+/// Counted as each file completes, so that syncs that stop early are still accounted for
+pub fn downloaded(username: &str) {
+    record(|report| report.account(username).downloaded += 1);
+}
+
+/// Also listed with the skipped files
+pub fn unavailable(skipped: SkippedFile) {
+    record(|report| {
+        report.account(&skipped.username).unavailable += 1;
+        report.skipped.push(skipped);
+    });
+}
+
+pub fn account_finished(username: &str, new_tweets: usize, error: Option<String>) {
+    record(|report| {
+        let account = report.account(username);
+        account.new_tweets += new_tweets;
+        account.error = error;
+    });
+}
+
+/// Writes the collected report to `out` (replacing the previous one) and summarizes it
+pub async fn finish(out: &Path) -> anyhow::Result<()> {
+    let report = match REPORT.lock().unwrap().take() {
+        Some(report) => report,
+        None => return Ok(()),
+    };
+    let path = out.join(REPORT_FILE);
+    // Encrypted like the data files, as it names the accounts and files
+    let text = serde_json::to_string_pretty(&report).unwrap();
+    crate::encryption::write(&path, text.into_bytes())
+        .await
+        .context("Unable to write report")?;
+    report.summarize(&path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let mut report = Report::default();
+        report.account("alice").downloaded += 2;
+        report.skipped.push(SkippedFile {
+            username: "alice".to_string(),
+            tweet_id: 1,
+            media_id: 2,
+            path: "1_2.jpg".to_string(),
+            reason: "already exists".to_string(),
+        });
+        let text = serde_json::to_string(&report).unwrap();
+        let loaded = serde_json::from_str::<Report>(&text).unwrap();
+        assert_eq!(loaded.accounts["alice"].downloaded, 2);
+        assert_eq!(loaded.skipped[0].path, "1_2.jpg");
+    }
+}
@@ -0,0 +1,83 @@
+//! `retry-failed`: re-attempts just the downloads that failed in a previous run, as listed in its
+//! `report.json`, writing a new report of the retry
+
+use crate::cancel::Cancelled;
+use crate::download::{account_dir, connection_pool, download_media};
+use crate::lock;
+use crate::model::DataFile;
+use crate::report::{self, Report};
+use crate::RetryFailedArgs;
+use anyhow::{bail, Context};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use tokio_util::sync::CancellationToken;
+
+pub async fn retry_failed(
+    mut args: RetryFailedArgs,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    let previous = Report::load(&args.report).await?;
+    let out = args
+        .report
+        .parent()
+        .unwrap_or(Path::new("./"))
+        .to_path_buf();
+    let mut failures = BTreeMap::<_, BTreeSet<_>>::new();
+    for failed in &previous.failed {
+        failures
+            .entry(failed.username.clone())
+            .or_default()
+            .insert((failed.tweet_id, failed.media_id));
+    }
+    if failures.is_empty() {
+        log::info!("No failed downloads to retry");
+        return Ok(());
+    }
+    // The ids already select the media
+    let media = &mut args.media;
+    media.photos = true;
+    media.videos = true;
+    media.gifs = true;
+    media.audio = true;
+    media.thumbnails |= previous.failed.iter().any(|f| f.path.contains("_preview."));
+
+    let connection_pool = connection_pool();
+    report::start();
+    let mut failed_accounts = 0;
+    for (username, ids) in failures {
+        log::info!("Retrying {} failed downloads for {}", ids.len(), username);
+        args.media.retry = Some(ids);
+        let user_dir = account_dir(&out, &username);
+        let result = async {
+            let _lock = lock::lock(&user_dir, false).await?;
+            let mut data_file = DataFile::load_unchecked(&user_dir)
+                .await?
+                .context("Missing data file")?;
+            download_media(
+                &username,
+                &user_dir,
+                &mut data_file,
+                &args.media,
+                &connection_pool,
+                cancel,
+            )
+            .await
+        }
+        .await;
+        let error = result.as_ref().err().map(|e| format!("{:#}", e));
+        report::account_finished(&username, 0, error);
+        if let Err(e) = result {
+            if e.is::<Cancelled>() {
+                report::finish(&out).await?;
+                return Err(e);
+            }
+            log::error!("Error retrying downloads for {}: {:#}", username, e);
+            failed_accounts += 1;
+        }
+    }
+    report::finish(&out).await?;
+    if failed_accounts > 0 {
+        bail!("Unable to retry the downloads of {failed_accounts} accounts");
+    }
+    Ok(())
+}
@@ -0,0 +1,89 @@
+//! A one-shot sync for running in containers (e.g. as a scheduled job), configured entirely through
+//! `TWITTER_DL_*` environment variables. The exit code tells the scheduler whether it succeeded.
+
+use crate::cancel::{Cancelled, EXIT_CANCELLED};
+use crate::download::{connection_pool, parse_usernames, setup, sync_accounts};
+use crate::messages::{self, Message};
+use crate::{RunArgs, WatchArgs};
+use anyhow::bail;
+use futures::StreamExt;
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+
+/// Exit code when the configuration is invalid, or the sync couldn't start
+const EXIT_ERROR: i32 = 1;
+/// Exit code when some of the accounts failed to sync, the others were synced
+const EXIT_PARTIAL: i32 = 2;
+
+#[derive(Error, Debug)]
+#[error("{failed} of {total} accounts failed to sync")]
+pub struct AccountsFailed {
+    failed: usize,
+    total: usize,
+}
+
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    if error.is::<Cancelled>() {
+        EXIT_CANCELLED
+    } else if error.is::<AccountsFailed>() {
+        EXIT_PARTIAL
+    } else {
+        EXIT_ERROR
+    }
+}
+
+pub async fn run(args: RunArgs, cancel: &CancellationToken) -> anyhow::Result<()> {
+    if let Some(every) = args.every {
+        return crate::watch::watch(
+            WatchArgs {
+                download: args.download,
+                every,
+                jitter: args.jitter,
+            },
+            cancel,
+        )
+        .await;
+    }
+    let download = &args.download;
+    if download.tweet_list.is_some() {
+        bail!("`--tweet-list` can only be used with `download`");
+    }
+    let client = setup(download).await?;
+    let usernames = parse_usernames(download).await?;
+    let connection_pool = connection_pool();
+    let mut failed = 0;
+    if !download.dry_run {
+        crate::report::start();
+    }
+    // Always carry on with the other accounts, the failures are reported in the exit code
+    let mut syncs = sync_accounts(
+        &usernames,
+        download,
+        client.as_ref(),
+        &connection_pool,
+        cancel,
+    );
+    while let Some((username, result)) = syncs.next().await {
+        if let Err(e) = result {
+            if e.is::<Cancelled>() {
+                crate::report::finish(&download.out).await?;
+                return Err(e);
+            }
+            log::error!("Error downloading tweets for {}: {:#}", username, e);
+            failed += 1;
+        }
+    }
+    crate::report::finish(&download.out).await?;
+    if failed > 0 {
+        return Err(AccountsFailed {
+            failed,
+            total: usernames.len(),
+        }
+        .into());
+    }
+    log::info!(
+        "{}",
+        messages::format(Message::Synced, [("count", usernames.len().into())])
+    );
+    Ok(())
+}
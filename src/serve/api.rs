@@ -0,0 +1,226 @@
+//! Pages of an account's tweets, filtered server side, so the viewer doesn't need to download the
+//! whole data file of a huge archive
+
+use crate::model::{DataFile, MediaType, Tweet};
+use crate::serve::error::{HttpError, IntoHttpError};
+use crate::serve::find_user_dir;
+use crate::ServeArgs;
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Path, Query};
+use actix_web::{get, HttpResponse};
+use anyhow::Context;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+const DEFAULT_PER_PAGE: usize = 100;
+const MAX_PER_PAGE: usize = 1000;
+
+/// The most recently loaded data files, so each page doesn't have to parse the whole file again.
+/// They are reloaded once the file has been modified.
+#[derive(Default)]
+pub struct DataFileCache {
+    data_files: Mutex<BTreeMap<PathBuf, (SystemTime, Arc<DataFile>)>>,
+}
+
+impl DataFileCache {
+    pub(super) async fn load(&self, user_dir: PathBuf) -> anyhow::Result<Arc<DataFile>> {
+        let modified = std::fs::metadata(user_dir.join("tweets.json"))
+            .and_then(|m| m.modified())
+            .context("Missing data file")?;
+        if let Some((cached_modified, data_file)) = self.data_files.lock().unwrap().get(&user_dir) {
+            if *cached_modified == modified {
+                return Ok(data_file.clone());
+            }
+        }
+        let data_file = DataFile::load_unchecked(&user_dir)
+            .await?
+            .context("Missing data file")?;
+        let data_file = Arc::new(data_file);
+        self.data_files
+            .lock()
+            .unwrap()
+            .insert(user_dir, (modified, data_file.clone()));
+        Ok(data_file)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Sort {
+    #[default]
+    Newest,
+    Oldest,
+}
+
+/// Which tweets to return, all the conditions must match
+#[derive(Deserialize, Debug)]
+pub struct TweetQuery {
+    /// Starting from 1
+    #[serde(default = "first_page")]
+    page: usize,
+    #[serde(default = "default_per_page")]
+    per_page: usize,
+    /// Tweets with at least one media item of this type
+    r#type: Option<MediaType>,
+    /// Tweets posted on or after this date (UTC)
+    from: Option<NaiveDate>,
+    /// Tweets posted on or before this date (UTC)
+    to: Option<NaiveDate>,
+    /// Text to search for (case insensitive) in the tweet or its media's OCR text
+    q: Option<String>,
+    /// Tweets in this thread
+    conversation: Option<u64>,
+    #[serde(default)]
+    sort: Sort,
+}
+
+fn first_page() -> usize {
+    1
+}
+
+fn default_per_page() -> usize {
+    DEFAULT_PER_PAGE
+}
+
+impl TweetQuery {
+    fn matches(&self, tweet: &Tweet, search: Option<&str>) -> bool {
+        let start = |date: NaiveDate| date.and_hms(0, 0, 0).timestamp();
+        self.r#type
+            .as_ref()
+            .is_none_or(|t| tweet.media.iter().any(|m| &m.r#type == t))
+            && self.from.is_none_or(|from| tweet.timestamp >= start(from))
+            && self.to.is_none_or(|to| tweet.timestamp < start(to.succ()))
+            && self
+                .conversation
+                .is_none_or(|c| tweet.conversation_id == Some(c) || tweet.id == c)
+            && search.is_none_or(|search| {
+                tweet.text.to_lowercase().contains(search)
+                    || tweet.media.iter().any(|m| {
+                        m.ocr_text
+                            .as_ref()
+                            .is_some_and(|t| t.to_lowercase().contains(search))
+                    })
+                    || tweet
+                        .poll
+                        .iter()
+                        .flat_map(|p| &p.options)
+                        .any(|o| o.label.to_lowercase().contains(search))
+                    || tweet
+                        .card
+                        .as_ref()
+                        .and_then(|c| c.title.as_ref())
+                        .is_some_and(|t| t.to_lowercase().contains(search))
+            })
+    }
+}
+
+#[derive(Serialize)]
+struct TweetPage<'a> {
+    /// Number of tweets matching the query
+    total: usize,
+    page: usize,
+    per_page: usize,
+    pages: usize,
+    tweets: Vec<TweetView<'a>>,
+}
+
+/// A tweet in the same format as `tweets.json`, with the ids it links to also given as strings,
+/// as JavaScript numbers can't represent them exactly
+#[derive(Serialize)]
+struct TweetView<'a> {
+    #[serde(flatten)]
+    tweet: &'a Tweet,
+    id_str: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conversation_id_str: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    in_reply_to_str: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quoted_tweet_id_str: Option<String>,
+}
+
+impl<'a> From<&'a Tweet> for TweetView<'a> {
+    fn from(tweet: &'a Tweet) -> Self {
+        Self {
+            tweet,
+            id_str: tweet.id.to_string(),
+            conversation_id_str: tweet.conversation_id.map(|id| id.to_string()),
+            in_reply_to_str: tweet.in_reply_to.map(|id| id.to_string()),
+            quoted_tweet_id_str: tweet.quoted_tweet_id.map(|id| id.to_string()),
+        }
+    }
+}
+
+#[get("/api/{username}/tweets")]
+pub async fn tweets(
+    args: Data<ServeArgs>,
+    cache: Data<DataFileCache>,
+    username: Path<String>,
+    query: Query<TweetQuery>,
+) -> Result<HttpResponse, HttpError> {
+    if query.page == 0 || query.per_page == 0 || query.per_page > MAX_PER_PAGE {
+        return Err(anyhow::anyhow!(
+            "`page` must be at least 1 and `per_page` between 1 and {}",
+            MAX_PER_PAGE
+        ))
+        .map_http_error(StatusCode::BAD_REQUEST);
+    }
+    let user_dir = find_user_dir(&args, &username).await?;
+    let data_file = cache.load(user_dir).await.map_500()?;
+    let search = query.q.as_ref().map(|q| q.trim().to_lowercase());
+    let search = search.as_deref().filter(|s| !s.is_empty());
+    let mut matching = data_file
+        .tweets
+        .iter()
+        .filter(|t| query.matches(t, search))
+        .collect::<Vec<_>>();
+    // The data file is sorted oldest first
+    if query.sort == Sort::Newest {
+        matching.reverse();
+    }
+    let total = matching.len();
+    let tweets = matching
+        .into_iter()
+        .skip((query.page - 1) * query.per_page)
+        .take(query.per_page)
+        .map(TweetView::from)
+        .collect();
+    Ok(HttpResponse::build(StatusCode::OK).json(TweetPage {
+        total,
+        page: query.page,
+        per_page: query.per_page,
+        pages: total.div_ceil(query.per_page),
+        tweets,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::data_file;
+
+    fn matching(query: &str) -> Vec<u64> {
+        let query = Query::<TweetQuery>::from_query(query).unwrap();
+        let search = query.q.as_ref().map(|q| q.to_lowercase());
+        data_file()
+            .tweets
+            .iter()
+            .filter(|t| query.matches(t, search.as_deref()))
+            .map(|t| t.id)
+            .collect()
+    }
+
+    #[test]
+    fn filters_tweets() {
+        assert_eq!(matching(""), vec![10, 20, 30, 40]);
+        assert_eq!(matching("type=photo"), vec![30]);
+        assert_eq!(matching("from=2021-03-03&to=2021-03-04"), vec![20, 30]);
+        assert_eq!(matching("q=JERRY"), vec![30]);
+        assert_eq!(matching("q=cats"), vec![20]);
+        assert_eq!(matching("conversation=20"), vec![20]);
+    }
+}
@@ -0,0 +1,352 @@
+//! A zip of an account's folder, streamed as it is written so that even huge archives can be
+//! downloaded from the browser without first being written out in full
+
+use crate::serve::error::HttpError;
+use crate::serve::find_user_dir;
+use crate::ServeArgs;
+use actix_web::http::header::CONTENT_DISPOSITION;
+use actix_web::web::{Bytes, Data, Path};
+use actix_web::{get, HttpResponse};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+
+/// Size of the chunks files are read and sent in
+const CHUNK_LENGTH: usize = 64 * 1024;
+
+/// Chunks buffered ahead of the client
+const CHANNEL_CAPACITY: usize = 8;
+
+/// Sizes and offsets from this need the zip64 extensions
+const ZIP64_THRESHOLD: u64 = 0xFFFF_FFFF;
+
+#[get("/api/{username}/archive.zip")]
+pub async fn archive(
+    args: Data<ServeArgs>,
+    username: Path<String>,
+) -> Result<HttpResponse, HttpError> {
+    let username = username.into_inner();
+    let user_dir = find_user_dir(&args, &username).await?;
+    let (sender, mut receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    let disposition = format!("attachment; filename=\"{username}.zip\"");
+    tokio::task::spawn(async move {
+        if let Err(e) = write_archive(&username, user_dir, &sender).await {
+            log::warn!("Unable to write the archive of {}: {}", username, e);
+            // Fails the response, rather than leaving the client with a truncated zip
+            sender.send(Err(e)).await.ok();
+        }
+    });
+    let stream = futures::stream::poll_fn(move |cx| receiver.poll_recv(cx));
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header((CONTENT_DISPOSITION, disposition))
+        .streaming(stream))
+}
+
+async fn write_archive(
+    username: &str,
+    user_dir: PathBuf,
+    sender: &mpsc::Sender<io::Result<Bytes>>,
+) -> io::Result<()> {
+    let dir = user_dir.clone();
+    let files = tokio::task::spawn_blocking(move || {
+        let mut files = Vec::new();
+        list_files(&dir, "", &mut files);
+        files.sort();
+        files
+    })
+    .await?;
+    let send = |data: Vec<u8>| async move {
+        sender
+            .send(Ok(Bytes::from(data)))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Client disconnected"))
+    };
+    let mut zip = ZipEncoder::default();
+    for relative in files {
+        let path = user_dir.join(&relative);
+        // Possibly removed since it was listed (e.g. trashed by a running download)
+        let mut file = match tokio::fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(e) => {
+                log::debug!("Not adding {} to the archive: {}", path.display(), e);
+                continue;
+            }
+        };
+        let metadata = file.metadata().await?;
+        let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+        let name = format!("{username}/{relative}");
+        if crate::encryption::enabled() {
+            drop(file);
+            let data = crate::encryption::read(&path)
+                .await
+                .map_err(|e| io::Error::other(format!("{e:#}")))?;
+            send(zip.start_file(&name, data.len() as u64, modified)).await?;
+            zip.update(&data);
+            send(data).await?;
+        } else {
+            send(zip.start_file(&name, metadata.len(), modified)).await?;
+            loop {
+                let mut chunk = Vec::with_capacity(CHUNK_LENGTH);
+                (&mut file)
+                    .take(CHUNK_LENGTH as u64)
+                    .read_to_end(&mut chunk)
+                    .await?;
+                if chunk.is_empty() {
+                    break;
+                }
+                zip.update(&chunk);
+                send(chunk).await?;
+            }
+        }
+        send(zip.finish_file()?).await?;
+    }
+    send(zip.finish()).await
+}
+
+/// The files within `dir` (relative to the user folder, `/` separated), leaving out hidden files
+/// such as the trash and partial downloads
+fn list_files(dir: &std::path::Path, prefix: &str, files: &mut Vec<String>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let relative = format!("{prefix}{name}");
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => list_files(&entry.path(), &format!("{relative}/"), files),
+            Ok(t) if t.is_file() => files.push(relative),
+            _ => {}
+        }
+    }
+}
+
+struct Entry {
+    name: String,
+    /// MS-DOS time and date
+    modified: (u16, u16),
+    crc: u32,
+    size: u64,
+    /// Of the local header
+    offset: u64,
+    zip64: bool,
+}
+
+/// Writes a zip file sequentially, without seeking back to fill in headers. Files are stored
+/// uncompressed (media is already compressed), with their checksum and size in a data descriptor
+/// after their contents. Each method returns the bytes to append to the zip.
+#[derive(Default)]
+struct ZipEncoder {
+    entries: Vec<Entry>,
+    /// Bytes written so far
+    offset: u64,
+    /// Of the file being written
+    hasher: crc32fast::Hasher,
+}
+
+impl ZipEncoder {
+    /// The `size` decides whether the file needs the zip64 extensions
+    fn start_file(&mut self, name: &str, size: u64, modified: SystemTime) -> Vec<u8> {
+        let zip64 = size >= ZIP64_THRESHOLD;
+        let modified = dos_date_time(modified.into());
+        let mut header = Vec::new();
+        header.extend(0x04034b50u32.to_le_bytes());
+        header.extend(version(zip64).to_le_bytes());
+        header.extend(FLAGS.to_le_bytes());
+        // Stored
+        header.extend(0u16.to_le_bytes());
+        header.extend(modified.0.to_le_bytes());
+        header.extend(modified.1.to_le_bytes());
+        // The checksum and sizes are in the data descriptor
+        header.extend(0u32.to_le_bytes());
+        let size32 = if zip64 { 0xFFFF_FFFF } else { 0u32 };
+        header.extend(size32.to_le_bytes());
+        header.extend(size32.to_le_bytes());
+        header.extend((name.len() as u16).to_le_bytes());
+        header.extend((if zip64 { 20u16 } else { 0 }).to_le_bytes());
+        header.extend(name.as_bytes());
+        if zip64 {
+            header.extend(1u16.to_le_bytes());
+            header.extend(16u16.to_le_bytes());
+            header.extend([0; 16]);
+        }
+        self.entries.push(Entry {
+            name: name.to_string(),
+            modified,
+            crc: 0,
+            size: 0,
+            offset: self.offset,
+            zip64,
+        });
+        self.hasher = crc32fast::Hasher::new();
+        self.offset += header.len() as u64;
+        header
+    }
+
+    /// Records the next part of the current file's contents
+    fn update(&mut self, data: &[u8]) {
+        let entry = self.entries.last_mut().expect("No file started");
+        self.hasher.update(data);
+        entry.size += data.len() as u64;
+        self.offset += data.len() as u64;
+    }
+
+    /// The data descriptor of the current file
+    fn finish_file(&mut self) -> io::Result<Vec<u8>> {
+        let entry = self.entries.last_mut().expect("No file started");
+        if !entry.zip64 && entry.size >= ZIP64_THRESHOLD {
+            return Err(io::Error::other(format!("{} grew too large", entry.name)));
+        }
+        entry.crc = std::mem::take(&mut self.hasher).finalize();
+        let mut descriptor = Vec::new();
+        descriptor.extend(0x08074b50u32.to_le_bytes());
+        descriptor.extend(entry.crc.to_le_bytes());
+        for _ in 0..2 {
+            if entry.zip64 {
+                descriptor.extend(entry.size.to_le_bytes());
+            } else {
+                descriptor.extend((entry.size as u32).to_le_bytes());
+            }
+        }
+        self.offset += descriptor.len() as u64;
+        Ok(descriptor)
+    }
+
+    /// The central directory, ending the zip
+    fn finish(&self) -> Vec<u8> {
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            // Only the fields that don't fit are in the zip64 extra field
+            let mut extra = Vec::new();
+            if entry.size >= ZIP64_THRESHOLD {
+                extra.extend(entry.size.to_le_bytes());
+                extra.extend(entry.size.to_le_bytes());
+            }
+            if entry.offset >= ZIP64_THRESHOLD {
+                extra.extend(entry.offset.to_le_bytes());
+            }
+            let zip64 = entry.zip64 || !extra.is_empty();
+            directory.extend(0x02014b50u32.to_le_bytes());
+            directory.extend(version(zip64).to_le_bytes());
+            directory.extend(version(zip64).to_le_bytes());
+            directory.extend(FLAGS.to_le_bytes());
+            directory.extend(0u16.to_le_bytes());
+            directory.extend(entry.modified.0.to_le_bytes());
+            directory.extend(entry.modified.1.to_le_bytes());
+            directory.extend(entry.crc.to_le_bytes());
+            directory.extend(clamp32(entry.size).to_le_bytes());
+            directory.extend(clamp32(entry.size).to_le_bytes());
+            directory.extend((entry.name.len() as u16).to_le_bytes());
+            let extra_length = if extra.is_empty() { 0 } else { extra.len() + 4 };
+            directory.extend((extra_length as u16).to_le_bytes());
+            // Comment length, disk number, internal and external attributes
+            directory.extend([0; 10]);
+            directory.extend(clamp32(entry.offset).to_le_bytes());
+            directory.extend(entry.name.as_bytes());
+            if !extra.is_empty() {
+                directory.extend(1u16.to_le_bytes());
+                directory.extend((extra.len() as u16).to_le_bytes());
+                directory.extend(extra);
+            }
+        }
+        let (start, length, count) = (
+            self.offset,
+            directory.len() as u64,
+            self.entries.len() as u64,
+        );
+        if start >= ZIP64_THRESHOLD || length >= ZIP64_THRESHOLD || count >= 0xFFFF {
+            let end = start + length;
+            directory.extend(0x06064b50u32.to_le_bytes());
+            directory.extend(44u64.to_le_bytes());
+            directory.extend(45u16.to_le_bytes());
+            directory.extend(45u16.to_le_bytes());
+            directory.extend([0; 8]);
+            directory.extend(count.to_le_bytes());
+            directory.extend(count.to_le_bytes());
+            directory.extend(length.to_le_bytes());
+            directory.extend(start.to_le_bytes());
+            // Locator
+            directory.extend(0x07064b50u32.to_le_bytes());
+            directory.extend(0u32.to_le_bytes());
+            directory.extend(end.to_le_bytes());
+            directory.extend(1u32.to_le_bytes());
+        }
+        directory.extend(0x06054b50u32.to_le_bytes());
+        directory.extend([0; 4]);
+        let count16 = count.min(0xFFFF) as u16;
+        directory.extend(count16.to_le_bytes());
+        directory.extend(count16.to_le_bytes());
+        directory.extend(clamp32(length).to_le_bytes());
+        directory.extend(clamp32(start).to_le_bytes());
+        directory.extend(0u16.to_le_bytes());
+        directory
+    }
+}
+
+/// With a data descriptor, and UTF-8 file names
+const FLAGS: u16 = 1 << 3 | 1 << 11;
+
+fn version(zip64: bool) -> u16 {
+    if zip64 {
+        45
+    } else {
+        20
+    }
+}
+
+fn clamp32(value: u64) -> u32 {
+    value.min(ZIP64_THRESHOLD) as u32
+}
+
+/// Zip files can't represent times before 1980
+fn dos_date_time(time: DateTime<Utc>) -> (u16, u16) {
+    if time.year() < 1980 {
+        return (0, 1 << 5 | 1);
+    }
+    let year = (time.year() - 1980).min(127) as u16;
+    let date = year << 9 | (time.month() as u16) << 5 | time.day() as u16;
+    let time =
+        (time.hour() as u16) << 11 | (time.minute() as u16) << 5 | (time.second() as u16 / 2);
+    (time, date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn writes_readable_zip() {
+        // The last with the zip64 headers, as if it were huge
+        let files = [
+            ("alice/tweets.json", &b"{}"[..], 2),
+            ("alice/a/1.jpg", &[7; 1000], 1000),
+            ("alice/a/2.mp4", &[8; 10], ZIP64_THRESHOLD),
+        ];
+        let mut encoder = ZipEncoder::default();
+        let mut zip = Vec::new();
+        for (name, data, size) in files {
+            zip.extend(encoder.start_file(name, size, SystemTime::now()));
+            encoder.update(data);
+            zip.extend(data);
+            zip.extend(encoder.finish_file().unwrap());
+        }
+        zip.extend(encoder.finish());
+
+        let mut reader = ::zip::ZipArchive::new(Cursor::new(zip)).unwrap();
+        assert_eq!(reader.len(), 3);
+        for (name, data, _) in files {
+            let mut file = reader.by_name(name).unwrap();
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents).unwrap();
+            assert_eq!(contents, data);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Optional protection of everything served (including the archive's files), with HTTP Basic
+//! authentication (`--auth`) and/or a token (`--auth-token`)
+
+use crate::ServeArgs;
+use actix_web::cookie::Cookie;
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use anyhow::bail;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// Remembers a token given as a query parameter, so that the viewer's requests include it
+const TOKEN_COOKIE: &str = "twitter_dl_token";
+
+#[derive(Clone, Debug, Default)]
+pub struct Access {
+    /// The expected `Authorization` header
+    basic: Option<String>,
+    token: Option<String>,
+    /// Whether the cookie may only be sent over HTTPS, i.e. when serving with TLS
+    secure: bool,
+}
+
+/// How a request was allowed
+#[derive(Debug, PartialEq, Eq)]
+pub enum Allowed {
+    /// With the `Authorization` header or cookie, or because no authentication is required
+    Yes,
+    /// With a `token` query parameter, which should be remembered as a cookie
+    WithQueryToken,
+}
+
+#[derive(Debug, Error)]
+#[error("Unauthorized")]
+pub struct Unauthorized {
+    /// Whether the browser should ask for a username and password
+    basic: bool,
+}
+
+impl ResponseError for Unauthorized {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::UNAUTHORIZED
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::Unauthorized();
+        if self.basic {
+            response.insert_header((WWW_AUTHENTICATE, "Basic realm=\"twitter-dl\""));
+        }
+        response.body("Unauthorized")
+    }
+}
+
+impl Access {
+    pub fn new(args: &ServeArgs) -> anyhow::Result<Self> {
+        if args.auth.as_ref().is_some_and(|auth| !auth.contains(':')) {
+            bail!("`--auth` must be given as `user:pass`")
+        }
+        if args
+            .auth_token
+            .as_ref()
+            .is_some_and(|token| token.is_empty())
+        {
+            bail!("`--auth-token` can't be empty")
+        }
+        Ok(Self {
+            basic: args
+                .auth
+                .as_ref()
+                .map(|auth| format!("Basic {}", base64::encode(auth))),
+            token: args.auth_token.clone(),
+            secure: !args.no_tls,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.basic.is_some() || self.token.is_some()
+    }
+
+    pub fn check(&self, request: &ServiceRequest) -> Result<Allowed, Unauthorized> {
+        if !self.enabled() {
+            return Ok(Allowed::Yes);
+        }
+        let header = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok());
+        if let (Some(expected), Some(header)) = (&self.basic, header) {
+            if secure_eq(expected, header) {
+                return Ok(Allowed::Yes);
+            }
+        }
+        if let Some(token) = &self.token {
+            let bearer = header.and_then(|h| h.strip_prefix("Bearer "));
+            let cookie = request.cookie(TOKEN_COOKIE);
+            if bearer
+                .into_iter()
+                .chain(cookie.as_ref().map(Cookie::value))
+                .any(|given| secure_eq(token, given))
+            {
+                return Ok(Allowed::Yes);
+            }
+            let query = url::form_urlencoded::parse(request.query_string().as_bytes());
+            if query
+                .filter(|(name, _)| name == "token")
+                .any(|(_, given)| secure_eq(token, &given))
+            {
+                return Ok(Allowed::WithQueryToken);
+            }
+        }
+        Err(Unauthorized {
+            basic: self.basic.is_some(),
+        })
+    }
+
+    /// The `Set-Cookie` header remembering the token
+    pub fn token_cookie(&self) -> Option<HeaderValue> {
+        let token = self.token.as_ref()?;
+        let cookie = Cookie::build(TOKEN_COOKIE, token.as_str())
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(actix_web::cookie::SameSite::Lax)
+            .finish();
+        HeaderValue::from_str(&cookie.to_string()).ok()
+    }
+
+    /// The query string to open the viewer with, so the browser is let in straight away
+    pub fn launch_query(&self) -> String {
+        match &self.token {
+            Some(token) => format!(
+                "?{}",
+                url::form_urlencoded::Serializer::new(String::new())
+                    .append_pair("token", token)
+                    .finish()
+            ),
+            None => String::new(),
+        }
+    }
+}
+
+/// Compares the hashes, so the time taken doesn't reveal how much of the secret was guessed
+fn secure_eq(expected: &str, given: &str) -> bool {
+    Sha256::digest(expected.as_bytes()) == Sha256::digest(given.as_bytes())
+}
@@ -0,0 +1,104 @@
+//! Live progress of the downloads writing to the archive (`download`, `watch` or `run`, in another
+//! process), streamed to the viewer as Server-Sent Events by following their events log
+
+use crate::events::LOG_FILE;
+use actix_web::web::{Bytes, Data};
+use actix_web::{get, HttpResponse};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// How often the events log is checked for new events
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Comments are sent when there are no events, so that proxies don't close the connection
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Events buffered for each client, slower clients miss events
+const CAPACITY: usize = 256;
+
+pub struct Events {
+    sender: broadcast::Sender<String>,
+}
+
+impl Events {
+    /// Follows the events log in `dir` in the background, from its current end
+    pub fn follow(dir: &Path) -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        let path = dir.join(LOG_FILE);
+        tokio::task::spawn(follow(path, sender.clone()));
+        Self { sender }
+    }
+}
+
+async fn follow(path: PathBuf, sender: broadcast::Sender<String>) {
+    let mut offset = log_length(&path).await;
+    let mut partial = Vec::new();
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let length = log_length(&path).await;
+        // Emptied once it gets too long
+        if length < offset {
+            offset = 0;
+            partial.clear();
+        }
+        if length == offset {
+            continue;
+        }
+        let read = async {
+            let mut file = tokio::fs::File::open(&path).await?;
+            file.seek(std::io::SeekFrom::Start(offset)).await?;
+            let mut data = Vec::new();
+            file.take(length - offset).read_to_end(&mut data).await?;
+            Ok::<_, std::io::Error>(data)
+        };
+        let data = match read.await {
+            Ok(data) => data,
+            Err(e) => {
+                log::debug!("Unable to read {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        offset += data.len() as u64;
+        partial.extend(data);
+        // The last line may still be being written
+        let complete = match partial.iter().rposition(|&b| b == b'\n') {
+            Some(end) => partial.drain(..=end).collect::<Vec<_>>(),
+            None => continue,
+        };
+        for line in String::from_utf8_lossy(&complete).lines() {
+            if !line.trim().is_empty() && sender.receiver_count() > 0 {
+                sender.send(line.to_string()).ok();
+            }
+        }
+    }
+}
+
+async fn log_length(path: &Path) -> u64 {
+    tokio::fs::metadata(path)
+        .await
+        .map(|m| m.len())
+        .unwrap_or_default()
+}
+
+/// Each event as a JSON object in a `data` field, as written with `--json`
+#[get("/api/events")]
+pub async fn events(events: Data<Events>) -> HttpResponse {
+    let receiver = events.sender.subscribe();
+    let stream = futures::stream::unfold(receiver, |mut receiver| async move {
+        let message = loop {
+            match tokio::time::timeout(KEEP_ALIVE_INTERVAL, receiver.recv()).await {
+                Ok(Ok(event)) => break format!("data: {event}\n\n"),
+                Ok(Err(RecvError::Lagged(_))) => continue,
+                Ok(Err(RecvError::Closed)) => return None,
+                Err(_) => break ": keep-alive\n\n".to_string(),
+            }
+        };
+        Some((Ok::<_, actix_web::Error>(Bytes::from(message)), receiver))
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream)
+}
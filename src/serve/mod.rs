@@ -1,51 +1,172 @@
+mod api;
+mod archive;
+mod auth;
 mod error;
+mod events;
+mod search;
+mod stats;
+mod thumb;
+mod tls;
 
+use crate::image_index::{ImageAttributes, ImageIndex, ImageQuery};
+use crate::model::{list_user_dirs, DataFile, Profile};
+use crate::thumbnail::ThumbnailCache;
 use crate::ServeArgs;
 use actix_files::Files;
+use actix_web::dev::Service;
+use actix_web::http::header::SET_COOKIE;
 use actix_web::http::StatusCode;
 use actix_web::middleware::Logger;
-use actix_web::web::{Data, Path, ServiceConfig};
-use actix_web::{get, App, HttpResponse, HttpServer};
+use actix_web::web::{Data, Path, Query, ServiceConfig};
+use actix_web::{get, App, HttpRequest, HttpResponse, HttpServer};
 use anyhow::{anyhow, bail, Context};
 use error::{HttpError, IntoHttpError};
-use futures::StreamExt;
 use rust_embed::RustEmbed;
-use rustls::{Certificate, PrivateKey, ServerConfig};
+use serde::Serialize;
+use std::path::{Component, PathBuf};
 use std::time::Duration;
-use tokio::fs;
-use tokio_stream::wrappers::ReadDirStream;
+use url::Url;
 
-// Generated with:
-// openssl req -x509 -newkey rsa:4096 -sha256 -days 14600 -nodes   -keyout "key.pem" \
-//  -out "cert.der" -subj "/CN=127.0.0.1" -outform der
-// openssl rsa -inform pem -in key.pem -outform der -out key.der
-const CERT: &[u8] = include_bytes!("cert.der");
-const PKEY: &[u8] = include_bytes!("key.der");
+/// Number of tweets in each feed
+const FEED_LENGTH: usize = 50;
 
 #[derive(RustEmbed)]
 #[folder = "viewer/"]
 struct Viewer;
 
+#[derive(Serialize)]
+struct Account {
+    username: String,
+    /// The display name, if the profile has been recorded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// Relative to `/dir`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar: Option<String>,
+}
+
 #[get("/list")]
 async fn list(args: Data<ServeArgs>) -> Result<HttpResponse, HttpError> {
-    let dir = ReadDirStream::new(
-        fs::read_dir(&args.dir)
-            .await
-            .context("Unable to read directory")
-            .map_500()?,
-    );
-    let filtered = dir
-        .filter_map(|d| async move {
-            if let Ok(d) = d {
-                if d.path().join("tweets.json").exists() {
-                    return Some(d.file_name().to_string_lossy().into_owned());
-                };
-            }
-            None
-        })
-        .collect::<Vec<_>>()
-        .await;
-    Ok(HttpResponse::build(StatusCode::OK).json(filtered))
+    let mut accounts = Vec::new();
+    for user_dir in list_user_dirs(&args.dir).await.map_500()? {
+        let username = match user_dir.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        let profile = Profile::load(&user_dir).await.ok().flatten();
+        let (name, avatar) = match profile {
+            Some(profile) => (
+                profile.name,
+                profile.avatar_file.map(|f| format!("{username}/{f}")),
+            ),
+            None => (None, None),
+        };
+        accounts.push(Account {
+            username,
+            name,
+            avatar,
+        });
+    }
+    Ok(HttpResponse::build(StatusCode::OK).json(accounts))
+}
+
+/// The options of `serve` that the viewer follows
+#[derive(Serialize)]
+struct Settings {
+    nsfw_threshold: f32,
+}
+
+#[get("/settings")]
+async fn settings(args: Data<ServeArgs>) -> HttpResponse {
+    HttpResponse::build(StatusCode::OK).json(Settings {
+        nsfw_threshold: args.nsfw_threshold,
+    })
+}
+
+#[derive(Serialize)]
+struct ImageResult<'a> {
+    /// Relative to `/dir`
+    path: &'a str,
+    #[serde(flatten)]
+    attributes: &'a ImageAttributes,
+}
+
+/// Searches the image index built by `cache build`
+#[get("/api/images")]
+async fn images(
+    args: Data<ServeArgs>,
+    query: Query<ImageQuery>,
+) -> Result<HttpResponse, HttpError> {
+    let cache = ThumbnailCache::for_output_dir(&args.dir);
+    let index = ImageIndex::load(cache.dir()).await.map_500()?;
+    let results = index
+        .search(&query)
+        .map(|(path, attributes)| ImageResult { path, attributes })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::build(StatusCode::OK).json(results))
+}
+
+/// An RSS feed of the account's most recent tweets, the enclosures linking to the served files
+#[get("/feed/{username}.xml")]
+async fn feed(
+    args: Data<ServeArgs>,
+    request: HttpRequest,
+    username: Path<String>,
+) -> Result<HttpResponse, HttpError> {
+    let base = {
+        let info = request.connection_info();
+        Url::parse(&format!("{}://{}/dir/", info.scheme(), info.host()))
+            .and_then(|u| u.join(&format!("{username}/")))
+            .map_500()?
+    };
+    let user_dir = find_user_dir(&args, &username).await?;
+    let data_file = DataFile::load_unchecked(&user_dir)
+        .await
+        .map_500()?
+        .context("Missing data file")
+        .map_500()?;
+    let xml = crate::feed::feed(&username, &user_dir, &data_file, Some(&base), FEED_LENGTH).await;
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type("application/rss+xml; charset=utf-8")
+        .body(xml))
+}
+
+/// The account's folder, only if it is one of the archived accounts
+async fn find_user_dir(args: &ServeArgs, username: &str) -> Result<PathBuf, HttpError> {
+    let user_dir = list_user_dirs(&args.dir)
+        .await
+        .map_500()?
+        .into_iter()
+        .find(|d| {
+            d.file_name()
+                .is_some_and(|n| n.to_string_lossy() == username)
+        });
+    match user_dir {
+        Some(user_dir) => Ok(user_dir),
+        None => Err(anyhow!("Not found")).map_http_error(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Serves the archive's files decrypted, in place of `Files` when the archive is encrypted
+#[get("/dir/{path:.*}")]
+async fn decrypted(args: Data<ServeArgs>, path: Path<String>) -> Result<HttpResponse, HttpError> {
+    let relative = std::path::Path::new(path.as_str());
+    let file = args.dir.join(relative);
+    // Don't allow escaping the served directory
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+        || !file.is_file()
+    {
+        return Err(anyhow!("Not found")).map_http_error(StatusCode::NOT_FOUND);
+    }
+    let data = crate::encryption::read(&file).await.map_500()?;
+    let mime = file
+        .extension()
+        .map(|ext| actix_files::file_extension_to_mime(&ext.to_string_lossy()));
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type(mime.unwrap_or(mime::APPLICATION_OCTET_STREAM))
+        .body(data))
 }
 
 #[get("/{path:.*}")]
@@ -69,11 +190,24 @@ async fn viewer(path: Path<String>) -> Result<HttpResponse, HttpError> {
 
 fn configure(cfg: &mut ServiceConfig, args: &ServeArgs) {
     cfg.service(list);
-    cfg.service(
-        Files::new("/dir", &args.dir)
-            .prefer_utf8(true)
-            .disable_content_disposition(),
-    );
+    cfg.service(settings);
+    cfg.service(images);
+    cfg.service(feed);
+    cfg.service(api::tweets);
+    cfg.service(stats::stats);
+    cfg.service(archive::archive);
+    cfg.service(search::search);
+    cfg.service(thumb::thumbnail);
+    cfg.service(events::events);
+    if crate::encryption::enabled() {
+        cfg.service(decrypted);
+    } else {
+        cfg.service(
+            Files::new("/dir", &args.dir)
+                .prefer_utf8(true)
+                .disable_content_disposition(),
+        );
+    }
     cfg.service(viewer);
 }
 
@@ -81,23 +215,57 @@ pub async fn serve(args: ServeArgs) -> anyhow::Result<()> {
     if !args.dir.is_dir() {
         bail!("expected a directory")
     }
+    let access = auth::Access::new(&args)?;
+    let launch_query = access.launch_query();
+    if access.enabled() && args.no_tls {
+        log::warn!("Without TLS the credentials are sent unencrypted");
+    }
     let args2 = args.clone();
+    // Shared between the workers
+    let data_files = Data::new(api::DataFileCache::default());
+    let search_index = Data::new(search::SearchIndex::default());
+    let thumbnails = Data::new(thumb::ThumbnailSources::default());
+    let events = Data::new(events::Events::follow(&args.dir));
+    build_search_index(&args, &data_files, &search_index);
 
     // Using TLS allows us to use ALPN for HTTP/2 which will make serving large
     // quantities of media much quicker
-    let tls = ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(vec![Certificate(CERT.into())], PrivateKey(PKEY.into()))
-        .unwrap();
+    let tls = if args.no_tls {
+        None
+    } else {
+        Some(tls::server_config(&args).await?)
+    };
 
     let mut server = HttpServer::new(move || {
         App::new()
             .app_data(Data::new(args2.clone()))
+            .app_data(data_files.clone())
+            .app_data(search_index.clone())
+            .app_data(thumbnails.clone())
+            .app_data(events.clone())
             .configure(|s| configure(s, &args2))
+            .wrap_fn({
+                let access = access.clone();
+                move |request, service| {
+                    let checked = access
+                        .check(&request)
+                        .map(|allowed| (allowed, service.call(request)));
+                    let cookie = access.token_cookie();
+                    async move {
+                        let (allowed, response) = checked?;
+                        let mut response = response.await?;
+                        if allowed == auth::Allowed::WithQueryToken {
+                            if let Some(cookie) = cookie {
+                                response.headers_mut().insert(SET_COOKIE, cookie);
+                            }
+                        }
+                        Ok(response)
+                    }
+                }
+            })
             .wrap(Logger::default())
     });
-    if !args.no_tls {
+    if let Some(tls) = tls {
         server = server.bind_rustls(args.socket, tls)?;
     } else {
         server = server.bind(args.socket)?;
@@ -105,14 +273,30 @@ pub async fn serve(args: ServeArgs) -> anyhow::Result<()> {
     let server = server.run();
 
     if !args.no_launch {
-        open_browser(args);
+        open_browser(args, launch_query);
     }
 
     server.await.context("Unable to run HTTP server")?;
     Ok(())
 }
 
-fn open_browser(args: ServeArgs) {
+/// Indexes the archive in the background, so the first search doesn't have to wait for it
+fn build_search_index(
+    args: &ServeArgs,
+    data_files: &Data<api::DataFileCache>,
+    search_index: &Data<search::SearchIndex>,
+) {
+    let (dir, data_files, search_index) =
+        (args.dir.clone(), data_files.clone(), search_index.clone());
+    tokio::task::spawn(async move {
+        match search_index.refresh(&dir, &data_files).await {
+            Ok(accounts) => log::debug!("Indexed {} accounts for search", accounts.len()),
+            Err(e) => log::warn!("Unable to build search index: {:#}", e),
+        }
+    });
+}
+
+fn open_browser(args: ServeArgs, query: String) {
     tokio::task::spawn(async move {
         let url = if args.no_tls {
             format!("http://{}/", args.socket)
@@ -120,7 +304,7 @@ fn open_browser(args: ServeArgs) {
             format!("https://{}/", args.socket)
         };
         tokio::time::sleep(Duration::from_millis(300)).await;
-        open::that(&url).ok();
+        open::that(format!("{url}{query}")).ok();
         log::info!("Hosting at: {}", url);
     });
 }
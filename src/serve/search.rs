@@ -0,0 +1,212 @@
+//! Full-text search over the tweets of every archived account. The index is built in memory when
+//! the server starts, and an account is only reindexed once its data file has been modified.
+
+use crate::model::{list_user_dirs, DataFile, Tweet};
+use crate::serve::api::DataFileCache;
+use crate::serve::error::{HttpError, IntoHttpError};
+use crate::ServeArgs;
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Query};
+use actix_web::{get, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+/// The words of an account's tweets (and their media's OCR text), mapped to the tweets' indices
+pub struct AccountIndex {
+    data_file: Arc<DataFile>,
+    words: BTreeMap<String, BTreeSet<usize>>,
+}
+
+impl AccountIndex {
+    fn new(data_file: Arc<DataFile>) -> Self {
+        let mut words: BTreeMap<String, BTreeSet<usize>> = BTreeMap::new();
+        for (i, tweet) in data_file.tweets.iter().enumerate() {
+            let ocr = tweet.media.iter().filter_map(|m| m.ocr_text.as_deref());
+            for text in std::iter::once(tweet.text.as_str()).chain(ocr) {
+                for word in tokenize(text) {
+                    words.entry(word).or_default().insert(i);
+                }
+            }
+        }
+        AccountIndex { data_file, words }
+    }
+
+    /// The tweets containing every term, each term matching the start of a word
+    fn search<'a>(&'a self, terms: &[String]) -> impl Iterator<Item = &'a Tweet> + 'a {
+        let mut matching: Option<BTreeSet<usize>> = None;
+        for term in terms {
+            let tweets = self
+                .words
+                .range::<str, _>((Bound::Included(term.as_str()), Bound::Unbounded))
+                .take_while(|(word, _)| word.starts_with(term.as_str()))
+                .flat_map(|(_, tweets)| tweets.iter().copied())
+                .collect::<BTreeSet<_>>();
+            matching = Some(match matching {
+                Some(matching) => matching.intersection(&tweets).copied().collect(),
+                None => tweets,
+            });
+        }
+        matching
+            .unwrap_or_default()
+            .into_iter()
+            .map(|i| &self.data_file.tweets[i])
+    }
+}
+
+/// Lower case words, split on anything that isn't alphanumeric (so `#hashtags` and `@mentions`
+/// are found by the word alone)
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+}
+
+#[derive(Default)]
+pub struct SearchIndex {
+    accounts: Mutex<BTreeMap<PathBuf, Arc<AccountIndex>>>,
+}
+
+impl SearchIndex {
+    /// Indexes the accounts whose data files have changed since they were last indexed, and drops
+    /// the ones that have been removed
+    pub async fn refresh(
+        &self,
+        dir: &Path,
+        data_files: &DataFileCache,
+    ) -> anyhow::Result<Vec<(String, Arc<AccountIndex>)>> {
+        let user_dirs = list_user_dirs(dir).await?;
+        let mut accounts = Vec::new();
+        for user_dir in &user_dirs {
+            let username = match user_dir.file_name() {
+                Some(name) => name.to_string_lossy().into_owned(),
+                None => continue,
+            };
+            let data_file = match data_files.load(user_dir.clone()).await {
+                Ok(data_file) => data_file,
+                Err(e) => {
+                    log::warn!("Unable to index {}: {:#}", user_dir.display(), e);
+                    continue;
+                }
+            };
+            let existing = self.accounts.lock().unwrap().get(user_dir).cloned();
+            let index = match existing {
+                Some(index) if Arc::ptr_eq(&index.data_file, &data_file) => index,
+                _ => {
+                    log::debug!("Indexing {}", user_dir.display());
+                    let index =
+                        tokio::task::spawn_blocking(move || AccountIndex::new(data_file)).await?;
+                    let index = Arc::new(index);
+                    self.accounts
+                        .lock()
+                        .unwrap()
+                        .insert(user_dir.clone(), index.clone());
+                    index
+                }
+            };
+            accounts.push((username, index));
+        }
+        self.accounts
+            .lock()
+            .unwrap()
+            .retain(|user_dir, _| user_dirs.contains(user_dir));
+        Ok(accounts)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SearchQuery {
+    q: String,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    DEFAULT_LIMIT
+}
+
+#[derive(Serialize)]
+struct SearchResult<'a> {
+    username: &'a str,
+    /// In the same format as `tweets.json`
+    tweet: &'a Tweet,
+    /// The tweet's downloaded files, relative to `/dir`
+    files: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct SearchResults<'a> {
+    /// Number of tweets matching the query, of which at most `limit` are returned
+    total: usize,
+    results: Vec<SearchResult<'a>>,
+}
+
+/// Searches every account for tweets containing all the words of `q`, newest first
+#[get("/api/search")]
+pub async fn search(
+    args: Data<ServeArgs>,
+    data_files: Data<DataFileCache>,
+    index: Data<SearchIndex>,
+    query: Query<SearchQuery>,
+) -> Result<HttpResponse, HttpError> {
+    if query.limit == 0 || query.limit > MAX_LIMIT {
+        return Err(anyhow::anyhow!(
+            "`limit` must be between 1 and {}",
+            MAX_LIMIT
+        ))
+        .map_http_error(StatusCode::BAD_REQUEST);
+    }
+    let terms = tokenize(&query.q).collect::<Vec<_>>();
+    if terms.is_empty() {
+        return Err(anyhow::anyhow!("Missing search terms"))
+            .map_http_error(StatusCode::BAD_REQUEST);
+    }
+    let accounts = index.refresh(&args.dir, &data_files).await.map_500()?;
+    let mut matching = accounts
+        .iter()
+        .flat_map(|(username, index)| index.search(&terms).map(move |t| (username, t)))
+        .collect::<Vec<_>>();
+    matching.sort_by_key(|(_, t)| Reverse((t.timestamp, t.id)));
+    let total = matching.len();
+    let results = matching
+        .into_iter()
+        .take(query.limit)
+        .map(|(username, tweet)| SearchResult {
+            username,
+            tweet,
+            files: tweet
+                .media
+                .iter()
+                .filter_map(|m| m.file_name.as_ref())
+                .map(|f| format!("{username}/{f}"))
+                .collect(),
+        })
+        .collect();
+    Ok(HttpResponse::build(StatusCode::OK).json(SearchResults { total, results }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::data_file;
+
+    fn search(index: &AccountIndex, q: &str) -> Vec<u64> {
+        let terms = tokenize(q).collect::<Vec<_>>();
+        index.search(&terms).map(|t| t.id).collect()
+    }
+
+    #[test]
+    fn searches_words() {
+        let index = AccountIndex::new(Arc::new(data_file()));
+        assert_eq!(search(&index, "jerry"), vec![30]);
+        assert_eq!(search(&index, "TOM jer"), vec![30]);
+        assert_eq!(search(&index, "tom video"), Vec::<u64>::new());
+        assert_eq!(search(&index, "a"), vec![20]);
+    }
+}
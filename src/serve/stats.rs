@@ -0,0 +1,142 @@
+//! A summary of an account's archive: what has been archived, what is still missing and how much
+//! space it takes up
+
+use crate::model::{DataFile, MediaType};
+use crate::serve::api::DataFileCache;
+use crate::serve::error::{HttpError, IntoHttpError};
+use crate::serve::find_user_dir;
+use crate::ServeArgs;
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Path};
+use actix_web::{get, HttpResponse};
+use chrono::{TimeZone, Utc};
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+struct MediaCounts {
+    photo: usize,
+    video: usize,
+    gif: usize,
+    audio: usize,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct Stats {
+    tweet_count: usize,
+    media_count: usize,
+    media_by_type: MediaCounts,
+    /// Media whose file is in the account folder
+    downloaded_count: usize,
+    missing_count: usize,
+    /// Size of the downloaded media files (files shared by duplicates are counted once)
+    media_bytes: u64,
+    /// Size of everything in the account folder, including the data files
+    total_bytes: u64,
+    first_tweet: Option<String>,
+    last_tweet: Option<String>,
+}
+
+#[get("/api/{username}/stats")]
+pub async fn stats(
+    args: Data<ServeArgs>,
+    cache: Data<DataFileCache>,
+    username: Path<String>,
+) -> Result<HttpResponse, HttpError> {
+    let user_dir = find_user_dir(&args, &username).await?;
+    let data_file = cache.load(user_dir.clone()).await.map_500()?;
+    let stats = tokio::task::spawn_blocking(move || account_stats(&user_dir, &data_file))
+        .await
+        .map_500()?;
+    Ok(HttpResponse::build(StatusCode::OK).json(stats))
+}
+
+fn account_stats(user_dir: &std::path::Path, data_file: &DataFile) -> Stats {
+    let format_timestamp = |ts: i64| Utc.timestamp(ts, 0).to_rfc3339();
+    let mut media_by_type = MediaCounts::default();
+    let mut media_count = 0;
+    let mut downloaded_count = 0;
+    let mut files = BTreeSet::new();
+    for media in data_file.tweets.iter().flat_map(|t| &t.media) {
+        media_count += 1;
+        *match media.r#type {
+            MediaType::Photo => &mut media_by_type.photo,
+            MediaType::Video => &mut media_by_type.video,
+            MediaType::Gif => &mut media_by_type.gif,
+            MediaType::Audio => &mut media_by_type.audio,
+        } += 1;
+        let size = media
+            .file_name
+            .as_ref()
+            .and_then(|f| Some((f, std::fs::metadata(user_dir.join(f)).ok()?.len())));
+        if let Some((file_name, size)) = size {
+            downloaded_count += 1;
+            files.insert((file_name, size));
+        }
+    }
+    Stats {
+        tweet_count: data_file.tweets.len(),
+        media_count,
+        media_by_type,
+        downloaded_count,
+        missing_count: media_count - downloaded_count,
+        media_bytes: files.iter().map(|(_, size)| size).sum(),
+        total_bytes: folder_size(user_dir),
+        first_tweet: data_file
+            .tweets
+            .first()
+            .map(|t| format_timestamp(t.timestamp)),
+        last_tweet: data_file
+            .tweets
+            .last()
+            .map(|t| format_timestamp(t.timestamp)),
+    }
+}
+
+fn folder_size(dir: &std::path::Path) -> u64 {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(t) if t.is_dir() => folder_size(&entry.path()),
+            Ok(t) if t.is_file() => entry.metadata().map(|m| m.len()).unwrap_or_default(),
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::data_file;
+
+    #[test]
+    fn counts_media() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("30_11.jpg"), [0; 100]).unwrap();
+        std::fs::create_dir(dir.path().join("profile")).unwrap();
+        std::fs::write(dir.path().join("profile/avatar.jpg"), [0; 10]).unwrap();
+        assert_eq!(
+            account_stats(dir.path(), &data_file()),
+            Stats {
+                tweet_count: 4,
+                media_count: 4,
+                media_by_type: MediaCounts {
+                    photo: 2,
+                    video: 1,
+                    gif: 1,
+                    audio: 0
+                },
+                downloaded_count: 1,
+                missing_count: 3,
+                media_bytes: 100,
+                total_bytes: 110,
+                first_tweet: Some("2021-03-02T00:00:00+00:00".to_string()),
+                last_tweet: Some("2021-03-05T00:00:00+00:00".to_string()),
+            }
+        );
+    }
+}
@@ -0,0 +1,94 @@
+//! Thumbnails of the downloaded photos, generated on the fly (into the same cache as `cache build`)
+//! so the viewer doesn't have to load the full resolution images
+
+use crate::serve::error::{HttpError, IntoHttpError};
+use crate::serve::find_user_dir;
+use crate::thumbnail::ThumbnailCache;
+use crate::ServeArgs;
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Path, Query};
+use actix_web::{get, HttpResponse};
+use anyhow::anyhow;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Component, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+const DEFAULT_WIDTH: u32 = 320;
+const MAX_WIDTH: u32 = 1024;
+
+/// The thumbnail last served for each image and width. The cache is keyed by the image's hash,
+/// which would mean reading the whole image for every request, so it is only hashed again once the
+/// image has been modified.
+#[derive(Default)]
+pub struct ThumbnailSources {
+    thumbnails: Mutex<HashMap<(PathBuf, u32), (SystemTime, PathBuf)>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ThumbnailQuery {
+    /// Maximum width/height
+    #[serde(default = "default_width")]
+    w: u32,
+}
+
+fn default_width() -> u32 {
+    DEFAULT_WIDTH
+}
+
+#[get("/thumb/{username}/{file:.*}")]
+pub async fn thumbnail(
+    args: Data<ServeArgs>,
+    sources: Data<ThumbnailSources>,
+    path: Path<(String, String)>,
+    query: Query<ThumbnailQuery>,
+) -> Result<HttpResponse, HttpError> {
+    let (username, file) = path.into_inner();
+    if query.w == 0 || query.w > MAX_WIDTH {
+        return Err(anyhow!("`w` must be between 1 and {}", MAX_WIDTH))
+            .map_http_error(StatusCode::BAD_REQUEST);
+    }
+    // The thumbnails would be an unencrypted copy of the archive
+    if crate::encryption::enabled() {
+        return Err(anyhow!(
+            "Thumbnails aren't supported for encrypted archives"
+        ))
+        .map_http_error(StatusCode::NOT_FOUND);
+    }
+    let relative = std::path::Path::new(&file);
+    let source = find_user_dir(&args, &username).await?.join(relative);
+    // Don't allow escaping the user folder
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+        || !ThumbnailCache::supports(&source)
+        || !source.is_file()
+    {
+        return Err(anyhow!("Not found")).map_http_error(StatusCode::NOT_FOUND);
+    }
+    let modified = std::fs::metadata(&source)
+        .and_then(|m| m.modified())
+        .map_500()?;
+    let key = (source, query.w);
+    let cached = sources.thumbnails.lock().unwrap().get(&key).cloned();
+    let thumbnail = match cached {
+        Some((cached_modified, thumbnail)) if cached_modified == modified && thumbnail.exists() => {
+            thumbnail
+        }
+        _ => {
+            let cache = ThumbnailCache::for_output_dir(&args.dir);
+            let thumbnail = cache.get_or_create(&key.0, key.1).await.map_500()?.path;
+            sources
+                .thumbnails
+                .lock()
+                .unwrap()
+                .insert(key, (modified, thumbnail.clone()));
+            thumbnail
+        }
+    };
+    let data = tokio::fs::read(&thumbnail).await.map_500()?;
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type(mime::IMAGE_JPEG)
+        .body(data))
+}
@@ -0,0 +1,138 @@
+//! Writes a `<file>.json` sidecar next to each downloaded file, describing the tweet it came from.
+//! The layout follows gallery-dl's (`--write-metadata`), so tools written for its archives can
+//! read these too.
+
+use crate::model::{DataFile, Media, MediaType, Tweet};
+use chrono::{TimeZone, Utc};
+use serde::Serialize;
+use std::path::Path;
+use url::Url;
+
+#[derive(Serialize)]
+struct Sidecar<'a> {
+    category: &'static str,
+    subcategory: &'static str,
+    tweet_id: u64,
+    conversation_id: Option<u64>,
+    content: &'a str,
+    /// UTC, `YYYY-MM-DD HH:MM:SS`
+    date: String,
+    timestamp: i64,
+    author: Author<'a>,
+    user: Author<'a>,
+    /// Position of the media within the tweet, starting from 1
+    num: usize,
+    count: usize,
+    filename: &'a str,
+    extension: &'a str,
+    r#type: &'static str,
+    url: Option<&'a Url>,
+    tweet_url: String,
+}
+
+#[derive(Serialize)]
+struct Author<'a> {
+    id: u64,
+    name: &'a str,
+}
+
+impl<'a> Sidecar<'a> {
+    fn new(
+        username: &'a str,
+        user_id: u64,
+        tweet: &'a Tweet,
+        num: usize,
+        media: &'a Media,
+        file_name: &'a str,
+    ) -> Self {
+        let name = Path::new(file_name)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or(file_name);
+        let (filename, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+        let author = || Author {
+            id: user_id,
+            name: username,
+        };
+        Self {
+            category: "twitter",
+            subcategory: "timeline",
+            tweet_id: tweet.id,
+            conversation_id: tweet.conversation_id,
+            content: &tweet.text,
+            date: Utc
+                .timestamp(tweet.timestamp, 0)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+            timestamp: tweet.timestamp,
+            author: author(),
+            user: author(),
+            num,
+            count: tweet.media.len(),
+            filename,
+            extension,
+            r#type: match media.r#type {
+                MediaType::Photo => "photo",
+                MediaType::Video => "video",
+                MediaType::Gif => "animated_gif",
+                MediaType::Audio => "audio",
+            },
+            url: media.url.as_ref(),
+            tweet_url: format!("https://twitter.com/{username}/status/{}", tweet.id),
+        }
+    }
+}
+
+/// Writes the sidecars of the downloaded files that don't have one yet
+pub async fn write_sidecars(
+    username: &str,
+    user_dir: &Path,
+    data_file: &DataFile,
+) -> anyhow::Result<()> {
+    let mut counter = 0;
+    for tweet in &data_file.tweets {
+        for media in &tweet.media {
+            let file_name = match &media.file_name {
+                Some(file_name) => file_name,
+                None => continue,
+            };
+            let path = user_dir.join(format!("{file_name}.json"));
+            if path.exists() || !user_dir.join(file_name).exists() {
+                continue;
+            }
+            let sidecar = Sidecar::new(
+                username,
+                data_file.user_id,
+                tweet,
+                media.position_in(tweet),
+                media,
+                file_name,
+            );
+            let mut text = serde_json::to_string_pretty(&sidecar).unwrap();
+            text.push('\n');
+            crate::encryption::write(&path, text.into_bytes()).await?;
+            counter += 1;
+        }
+    }
+    if counter > 0 {
+        log::info!("Wrote {} metadata files", counter);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::{assert_golden, data_file, USERNAME};
+
+    #[test]
+    fn sidecar_golden() {
+        let data_file = data_file();
+        let tweet = &data_file.tweets[2];
+        let media = &tweet.media[1];
+        let file_name = media.file_name.as_deref().unwrap();
+        let sidecar = Sidecar::new(USERNAME, data_file.user_id, tweet, 2, media, file_name);
+        let text = serde_json::to_string_pretty(&sidecar).unwrap();
+        assert_golden("30_12.png.json", &text);
+    }
+}
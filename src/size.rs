@@ -0,0 +1,28 @@
+use anyhow::{anyhow, Context};
+use std::str::FromStr;
+
+/// A number of bytes, parsed from a human friendly value such as `512K`, `2M` or `50G`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number = f64::from_str(number).with_context(|| format!("Invalid size: {s}"))?;
+        let multiplier: u64 = match unit.trim().to_ascii_uppercase().trim_end_matches("IB") {
+            "" | "B" => 1,
+            "K" | "KB" => 1 << 10,
+            "M" | "MB" => 1 << 20,
+            "G" | "GB" => 1 << 30,
+            "T" | "TB" => 1 << 40,
+            _ => return Err(anyhow!("Unknown size unit: {unit}")),
+        };
+        Ok(ByteSize((number * multiplier as f64) as u64))
+    }
+}
@@ -0,0 +1,103 @@
+//! Sends a summary of each synced account to a Telegram chat (`--telegram-chat-id`), to keep an
+//! eye on unattended `watch`/`run` deployments
+
+use reqwest::Client;
+use std::time::Duration;
+
+const API_URL: &str = "https://api.telegram.org";
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Telegram<'a> {
+    pub token: &'a str,
+    pub chat_id: &'a str,
+}
+
+/// The outcome of syncing one account
+pub struct Summary<'a> {
+    pub username: &'a str,
+    pub new_tweets: usize,
+    pub downloaded: usize,
+    pub error: Option<&'a str>,
+}
+
+impl Summary<'_> {
+    /// The message, `None` if there is nothing new to report
+    fn message(&self) -> Option<String> {
+        if self.new_tweets == 0 && self.downloaded == 0 && self.error.is_none() {
+            return None;
+        }
+        let plural = |count: usize, noun: &str| match count {
+            1 => format!("1 {noun}"),
+            count => format!("{count} {noun}s"),
+        };
+        let mut message = format!(
+            "@{}: {}, {}",
+            self.username,
+            plural(self.new_tweets, "new tweet"),
+            plural(self.downloaded, "new file")
+        );
+        if let Some(error) = self.error {
+            message.push_str(&format!("\nFailed: {error}"));
+        }
+        Some(message)
+    }
+}
+
+impl Telegram<'_> {
+    /// Failures are only logged, they don't fail the sync
+    pub async fn send(&self, client: &Client, summary: &Summary<'_>) {
+        let text = match summary.message() {
+            Some(text) => text,
+            None => return,
+        };
+        let body = serde_json::json!({ "chat_id": self.chat_id, "text": text });
+        let result = client
+            .post(format!("{API_URL}/bot{}/sendMessage", self.token))
+            .timeout(TIMEOUT)
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .send()
+            .await;
+        // The URL contains the token, so it's left out of the errors
+        match result {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+                log::warn!(
+                    "Unable to send Telegram message ({}): {}",
+                    status,
+                    body.trim()
+                );
+            }
+            Err(e) => log::warn!("Unable to send Telegram message: {}", e.without_url()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_summaries() {
+        let summary = |new_tweets, downloaded, error| Summary {
+            username: "alice",
+            new_tweets,
+            downloaded,
+            error,
+        };
+        assert_eq!(summary(0, 0, None).message(), None);
+        assert_eq!(
+            summary(1, 3, None).message().as_deref(),
+            Some("@alice: 1 new tweet, 3 new files")
+        );
+        assert_eq!(
+            summary(0, 0, Some("Unable to find user"))
+                .message()
+                .as_deref(),
+            Some("@alice: 0 new tweets, 0 new files\nFailed: Unable to find user")
+        );
+    }
+}
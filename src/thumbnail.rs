@@ -0,0 +1,241 @@
+//! A content addressed cache of resized images, stored within the output directory so that it can
+//! be shared by everything that needs thumbnails of the archived media
+
+use crate::image_index::{ImageAttributes, ImageIndex};
+use crate::model::{list_user_dirs, DataFile};
+use crate::{CacheArgs, CacheBuildArgs, CacheCommands};
+use anyhow::{bail, Context};
+use image::imageops::FilterType;
+use image::{GenericImageView, ImageOutputFormat};
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+
+pub const CACHE_DIR: &str = ".thumbs";
+
+const THUMBNAIL_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "webp"];
+
+pub struct ThumbnailCache {
+    dir: PathBuf,
+}
+
+/// Limits used when evicting thumbnails from the cache
+pub struct EvictionPolicy {
+    /// Evict the least recently used thumbnails until the cache is at most this size
+    pub max_size: Option<u64>,
+    /// Evict thumbnails that haven't been used within this duration
+    pub max_age: Option<Duration>,
+}
+
+pub struct Thumbnail {
+    pub path: PathBuf,
+    /// SHA-256 of the source image
+    pub hash: String,
+    /// Only set when the thumbnail was newly generated (and so the source image was decoded)
+    pub attributes: Option<ImageAttributes>,
+}
+
+#[derive(Debug, Default)]
+pub struct PruneResult {
+    pub removed: usize,
+    pub freed: u64,
+    pub remaining: u64,
+}
+
+impl ThumbnailCache {
+    /// Uses the default cache location within an output directory
+    pub fn for_output_dir(dir: &Path) -> Self {
+        Self {
+            dir: dir.join(CACHE_DIR),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Whether a file is an image that thumbnails can be generated for
+    pub fn supports(path: &Path) -> bool {
+        path.extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| THUMBNAIL_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+    }
+
+    /// Returns the path of a thumbnail (fitting within `width` x `width`) of the source image,
+    /// generating it if it isn't already cached
+    pub async fn get_or_create(&self, source: &Path, width: u32) -> anyhow::Result<Thumbnail> {
+        let bytes = fs::read(source)
+            .await
+            .with_context(|| format!("Unable to read {}", source.display()))?;
+        let hash = hex::encode(Sha256::digest(&bytes));
+        // Shard into sub folders to avoid a single huge directory
+        let path = self
+            .dir
+            .join(&hash[..2])
+            .join(format!("{hash}-{width}.jpg"));
+        if path.exists() {
+            // Record the access for the least recently used eviction
+            let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+            file.set_modified(SystemTime::now()).ok();
+            return Ok(Thumbnail {
+                path,
+                hash,
+                attributes: None,
+            });
+        }
+        let output = path.clone();
+        let key = hash.clone();
+        let attributes = tokio::task::spawn_blocking(move || {
+            let image = image::load_from_memory(&bytes).context("Unable to decode image")?;
+            let thumbnail = image.resize(width, width, FilterType::Triangle);
+            let attributes = ImageAttributes::analyze(key, image.dimensions(), &thumbnail);
+            std::fs::create_dir_all(output.parent().unwrap())?;
+            // Write to a temporary file first so a partial thumbnail is never served
+            let temp = tempfile::NamedTempFile::new_in(output.parent().unwrap())?;
+            thumbnail
+                .to_rgb8()
+                .write_to(
+                    &mut BufWriter::new(File::create(temp.path())?),
+                    ImageOutputFormat::Jpeg(80),
+                )
+                .context("Unable to encode thumbnail")?;
+            temp.persist(&output)?;
+            Ok::<_, anyhow::Error>(attributes)
+        })
+        .await??;
+        Ok(Thumbnail {
+            path,
+            hash,
+            attributes: Some(attributes),
+        })
+    }
+
+    pub async fn prune(&self, policy: &EvictionPolicy) -> anyhow::Result<PruneResult> {
+        let mut entries = Vec::new();
+        if self.dir.exists() {
+            let mut shards = fs::read_dir(&self.dir).await?;
+            while let Some(shard) = shards.next_entry().await? {
+                if !shard.file_type().await?.is_dir() {
+                    continue;
+                }
+                let mut files = fs::read_dir(shard.path()).await?;
+                while let Some(file) = files.next_entry().await? {
+                    let metadata = file.metadata().await?;
+                    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                    entries.push((file.path(), metadata.len(), modified));
+                }
+            }
+        }
+        // Most recently used first
+        entries.sort_by_key(|e| Reverse(e.2));
+        let now = SystemTime::now();
+        let mut result = PruneResult::default();
+        for (path, size, modified) in entries {
+            let too_old = policy
+                .max_age
+                .is_some_and(|age| now.duration_since(modified).unwrap_or_default() > age);
+            let too_big = policy
+                .max_size
+                .is_some_and(|max| result.remaining + size > max);
+            if too_old || too_big {
+                fs::remove_file(&path).await?;
+                result.removed += 1;
+                result.freed += size;
+            } else {
+                result.remaining += size;
+            }
+        }
+        Ok(result)
+    }
+}
+
+pub async fn cache(args: CacheArgs) -> anyhow::Result<()> {
+    match args.command {
+        CacheCommands::Build(args) => build(args).await,
+        CacheCommands::Prune(args) => {
+            let cache = ThumbnailCache::for_output_dir(&args.dir);
+            let policy = EvictionPolicy {
+                max_size: args.max_size.map(|s| s.0),
+                max_age: args
+                    .max_age_days
+                    .map(|d| Duration::from_secs(d * 24 * 60 * 60)),
+            };
+            let result = cache.prune(&policy).await?;
+            log::info!(
+                "Removed {} thumbnails ({} bytes), {} bytes remaining",
+                result.removed,
+                result.freed,
+                result.remaining
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Generates thumbnails for all downloaded images, so they are ready before they are needed, and
+/// records their visual attributes in the image index
+async fn build(args: CacheBuildArgs) -> anyhow::Result<()> {
+    if crate::encryption::enabled() {
+        // The thumbnails would be an unencrypted copy of the archive
+        bail!("Thumbnails aren't supported for encrypted archives")
+    }
+    let cache = ThumbnailCache::for_output_dir(&args.dir);
+    let mut index = ImageIndex::load(cache.dir()).await?;
+    let mut seen = HashSet::new();
+    let mut count = 0;
+    for user_dir in list_user_dirs(&args.dir).await? {
+        let username = user_dir.file_name().unwrap().to_string_lossy().into_owned();
+        let data_file = match DataFile::load_unchecked(&user_dir).await? {
+            Some(d) => d,
+            None => continue,
+        };
+        let files = data_file
+            .tweets
+            .iter()
+            .flat_map(|t| t.media.iter())
+            .filter_map(|m| m.file_name.as_ref())
+            .map(|f| (format!("{username}/{f}"), user_dir.join(f)))
+            .filter(|(_, p)| ThumbnailCache::supports(p) && p.exists());
+        for (key, file) in files {
+            seen.insert(key.clone());
+            match index_thumbnail(&cache, &mut index, key, &file, args.width).await {
+                Ok(_) => count += 1,
+                Err(e) => log::warn!("Unable to create thumbnail for {}: {:#}", file.display(), e),
+            }
+        }
+    }
+    // Forget about images that have been deleted
+    index.images.retain(|k, _| seen.contains(k));
+    index.save(cache.dir()).await?;
+    log::info!("{} thumbnails are cached", count);
+    Ok(())
+}
+
+async fn index_thumbnail(
+    cache: &ThumbnailCache,
+    index: &mut ImageIndex,
+    key: String,
+    file: &Path,
+    width: u32,
+) -> anyhow::Result<()> {
+    let thumbnail = cache.get_or_create(file, width).await?;
+    let attributes = match thumbnail.attributes {
+        Some(attributes) => attributes,
+        // Already indexed, and the image hasn't changed
+        None if index
+            .images
+            .get(&key)
+            .is_some_and(|a| a.hash == thumbnail.hash) =>
+        {
+            return Ok(())
+        }
+        None => ImageAttributes::from_thumbnail(thumbnail.hash, file, &thumbnail.path).await?,
+    };
+    index.images.insert(key, attributes);
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use anyhow::anyhow;
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::str::FromStr;
+
+/// Tweet timestamps are always stored in UTC (exactly as fetched), this setting only controls how
+/// they are interpreted when organizing or filtering by calendar date
+#[derive(Debug, Clone, Copy)]
+pub enum TimeZoneSetting {
+    Local,
+    Named(Tz),
+    Fixed(FixedOffset),
+}
+
+impl TimeZoneSetting {
+    /// The calendar date a timestamp falls on
+    pub fn date(&self, timestamp: i64) -> NaiveDate {
+        self.datetime(timestamp).date()
+    }
+
+    /// The local date and time of a timestamp
+    pub fn datetime(&self, timestamp: i64) -> NaiveDateTime {
+        let utc = Utc.timestamp(timestamp, 0);
+        match self {
+            TimeZoneSetting::Local => utc.with_timezone(&Local).naive_local(),
+            TimeZoneSetting::Named(tz) => utc.with_timezone(tz).naive_local(),
+            TimeZoneSetting::Fixed(offset) => utc.with_timezone(offset).naive_local(),
+        }
+    }
+
+    /// The timestamp of the first instant of a calendar date
+    pub fn start_of_day(&self, date: NaiveDate) -> i64 {
+        let midnight = date.and_hms(0, 0, 0);
+        // Midnight may not exist, or be ambiguous, in time zones with daylight saving
+        let start = match self {
+            TimeZoneSetting::Local => Local.from_local_datetime(&midnight).earliest().map(to_ts),
+            TimeZoneSetting::Named(tz) => tz.from_local_datetime(&midnight).earliest().map(to_ts),
+            TimeZoneSetting::Fixed(offset) => {
+                offset.from_local_datetime(&midnight).earliest().map(to_ts)
+            }
+        };
+        start.unwrap_or_else(|| Utc.from_utc_datetime(&midnight).timestamp())
+    }
+}
+
+fn to_ts<T: TimeZone>(dt: DateTime<T>) -> i64 {
+    dt.timestamp()
+}
+
+impl FromStr for TimeZoneSetting {
+    type Err = anyhow::Error;
+
+    /// Accepts `local`, an IANA name (e.g. `Europe/London`), or a fixed offset (e.g. `+05:30`)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("local") {
+            return Ok(TimeZoneSetting::Local);
+        }
+        if let Ok(tz) = Tz::from_str(s) {
+            return Ok(TimeZoneSetting::Named(tz));
+        }
+        if let Ok(dt) = DateTime::parse_from_str(&format!("2000-01-01T00:00:00{s}"), "%FT%T%:z") {
+            return Ok(TimeZoneSetting::Fixed(*dt.offset()));
+        }
+        Err(anyhow!("Unknown time zone: {s}"))
+    }
+}
@@ -0,0 +1,129 @@
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use std::path::Path;
+use tokio::fs;
+
+const COOKIE_DOMAINS: &[&str] = &["twitter.com", "x.com"];
+
+/// Credentials loaded from the auth file, each backend supports a different set of these:
+///
+/// | Backend  | Bearer | OAuth1 | Cookies |
+/// |----------|--------|--------|---------|
+/// | v1       | ✅     | ✅     | ❌      |
+/// | v2       | ✅     | ❌     | ❌      |
+/// | graphql  | ❌     | ❌     | ✅      |
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Authentication {
+    /// App only authentication
+    Bearer { bearer_token: String },
+    /// User context authentication
+    OAuth1 {
+        consumer_key: String,
+        consumer_secret: String,
+        access_token: String,
+        access_token_secret: String,
+    },
+    /// The session cookies of a logged in web browser
+    Cookies { auth_token: String, ct0: String },
+}
+
+// A cookie as exported by browser extensions such as "Cookie-Editor"
+#[derive(Deserialize)]
+struct ExportedCookie {
+    domain: String,
+    name: String,
+    value: String,
+}
+
+impl Authentication {
+    /// Loads an `auth.json` file, a Netscape format `cookies.txt`, or a JSON cookie export
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .await
+            .context("Unable to read auth file")?;
+        Self::parse(&text)
+    }
+
+    /// Parses the contents of an auth file, in any of the formats supported by [`Self::load`]
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let trimmed = text.trim_start();
+        if trimmed.starts_with('{') {
+            serde_json::from_str::<Self>(text).context("Unable to deserialize auth file")
+        } else if trimmed.starts_with('[') {
+            let cookies = serde_json::from_str::<Vec<ExportedCookie>>(text)
+                .context("Unable to deserialize cookie export")?;
+            Self::from_cookies(
+                cookies
+                    .into_iter()
+                    .map(|c| (c.domain, c.name, c.value))
+                    .collect(),
+            )
+        } else {
+            Self::from_cookies(parse_netscape_cookies(text))
+        }
+    }
+
+    fn from_cookies(cookies: Vec<(String, String, String)>) -> anyhow::Result<Self> {
+        let find = |name: &str| {
+            cookies
+                .iter()
+                .find(|(domain, n, _)| n == name && is_twitter_domain(domain))
+                .map(|(_, _, value)| value.clone())
+                .ok_or_else(|| anyhow!("The cookies do not contain `{name}` for twitter.com"))
+        };
+        Ok(Self::Cookies {
+            auth_token: find("auth_token")?,
+            ct0: find("ct0")?,
+        })
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Authentication::Bearer { .. } => "a bearer token",
+            Authentication::OAuth1 { .. } => "OAuth1 credentials",
+            Authentication::Cookies { .. } => "cookies",
+        }
+    }
+
+    /// The error for when a backend doesn't support this kind of authentication
+    pub fn unsupported(&self, backend: &str, required: &str) -> anyhow::Error {
+        anyhow!(
+            "The {backend} backend requires {required}, but the auth file contains {}",
+            self.kind()
+        )
+    }
+
+    pub fn bearer_token(&self, backend: &str) -> anyhow::Result<&str> {
+        match self {
+            Authentication::Bearer { bearer_token } => Ok(bearer_token),
+            _ => Err(self.unsupported(backend, "a bearer token")),
+        }
+    }
+}
+
+fn is_twitter_domain(domain: &str) -> bool {
+    let domain = domain.trim_start_matches('.');
+    COOKIE_DOMAINS
+        .iter()
+        .any(|d| domain == *d || domain.ends_with(&format!(".{d}")))
+}
+
+/// Each line is: domain, include subdomains, path, secure, expiry, name, value (tab separated)
+fn parse_netscape_cookies(text: &str) -> Vec<(String, String, String)> {
+    text.lines()
+        .map(|l| l.strip_prefix("#HttpOnly_").unwrap_or(l))
+        .filter(|l| !l.starts_with('#'))
+        .filter_map(|l| {
+            let fields = l.split('\t').collect::<Vec<_>>();
+            match fields.as_slice() {
+                [domain, _, _, _, _, name, value] => Some((
+                    domain.to_string(),
+                    name.to_string(),
+                    value.trim().to_string(),
+                )),
+                _ => None,
+            }
+        })
+        .collect()
+}
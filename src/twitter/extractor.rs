@@ -0,0 +1,366 @@
+//! A backend declared in a JSON config file, for alternative mirrors and API shapes that aren't
+//! built in. The config gives the URLs to request and JSON pointers to the fields, e.g.
+//!
+//! ```json
+//! {
+//!   "name": "my-mirror",
+//!   "base_url": "https://mirror.example.com/api/",
+//!   "headers": { "Authorization": "Bearer ..." },
+//!   "user": { "url": "users/{username}", "id": "/data/id" },
+//!   "timeline": {
+//!     "url": "users/{user_id}/tweets",
+//!     "cursor_param": "cursor",
+//!     "tweets": "/data",
+//!     "next_cursor": "/meta/next_cursor",
+//!     "tweet": { "id": "/id", "timestamp": "/created_at", "text": "/text", "media": "/media" },
+//!     "media": { "id": "/id", "type": "/type", "url": "/url" }
+//!   }
+//! }
+//! ```
+//!
+//! The user mapping can also have `username`, `name`, `bio`, `avatar_url` and `banner_url`
+//! pointers, for the viewer, and a `by_id_url` (using the `username` pointer), for
+//! `--follow-renames`, and the tweet
+//! mapping `source`, `like_count`, `retweet_count` and `possibly_sensitive` pointers, for `--exclude-source`,
+//! `--min-likes` and `--skip-sensitive`, and `in_reply_to` and `quoted_tweet_id` pointers. The media
+//! mapping can have `preview_url` and `alt_text` pointers.
+//! `{username}` and `{user_id}` are substituted into the URLs. Ids can be numbers or strings,
+//! timestamps can be unix seconds, RFC 3339, or the API v1.1 date format.
+
+use crate::model::{Media, MediaType, Profile, Tweet};
+use crate::proxy;
+use crate::twitter::{limit_reached, source_name, TwitterClient, UserError, LEGACY_DATE_FORMAT};
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use chrono::DateTime;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+
+const TIMEOUT_SEC: u64 = 20;
+
+#[derive(Error, Debug)]
+#[error("{name} response was not successful: {code}")]
+struct StatusError {
+    name: String,
+    code: u16,
+}
+
+#[derive(Deserialize, Debug)]
+struct ExtractorConfig {
+    name: String,
+    base_url: Url,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    user: UserMapping,
+    timeline: TimelineMapping,
+}
+
+#[derive(Deserialize, Debug)]
+struct UserMapping {
+    url: String,
+    id: String,
+    name: Option<String>,
+    avatar_url: Option<String>,
+    banner_url: Option<String>,
+    bio: Option<String>,
+    /// Looks accounts up by id (with `{user_id}`), for `--follow-renames`
+    by_id_url: Option<String>,
+    /// The account's handle, in the responses of both `url` and `by_id_url`
+    username: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TimelineMapping {
+    url: String,
+    /// Query parameter the cursor for the next page is sent as
+    cursor_param: Option<String>,
+    tweets: String,
+    next_cursor: Option<String>,
+    tweet: TweetMapping,
+    media: MediaMapping,
+}
+
+#[derive(Deserialize, Debug)]
+struct TweetMapping {
+    id: String,
+    timestamp: String,
+    text: String,
+    media: Option<String>,
+    conversation_id: Option<String>,
+    in_reply_to: Option<String>,
+    quoted_tweet_id: Option<String>,
+    /// The app the tweet was posted with, as its name or a link to it
+    source: Option<String>,
+    like_count: Option<String>,
+    retweet_count: Option<String>,
+    /// A boolean
+    possibly_sensitive: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MediaMapping {
+    id: String,
+    r#type: String,
+    url: String,
+    /// The still image shown before a video is played
+    preview_url: Option<String>,
+    alt_text: Option<String>,
+}
+
+pub struct ExtractorClient {
+    client: Client,
+    config: ExtractorConfig,
+}
+
+impl ExtractorClient {
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .context("Unable to read extractor config")?;
+        let config = serde_json::from_str::<ExtractorConfig>(&text)
+            .context("Unable to deserialize extractor config")?;
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            headers.insert(HeaderName::from_str(name)?, HeaderValue::from_str(value)?);
+        }
+        log::info!("Using extractor: {}", config.name);
+        Ok(Self {
+            client: proxy::api(Client::builder())
+                .default_headers(headers)
+                .timeout(Duration::from_secs(TIMEOUT_SEC))
+                .build()?,
+            config,
+        })
+    }
+
+    async fn get_json(&self, path: &str, query: &[(&str, &str)]) -> anyhow::Result<Value> {
+        let url = self.config.base_url.join(path)?;
+        let response = self.client.get(url).query(query).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(StatusError {
+                name: self.config.name.clone(),
+                code: status.as_u16(),
+            }
+            .into());
+        }
+        let text = response.text().await.context("Bad response text")?;
+        serde_json::from_str(&text).context("Invalid JSON")
+    }
+
+    fn convert_tweet(&self, value: &Value) -> anyhow::Result<Tweet> {
+        let mapping = &self.config.timeline.tweet;
+        let media = match mapping.media.as_ref().and_then(|p| value.pointer(p)) {
+            Some(Value::Array(media)) => media
+                .iter()
+                .map(|m| self.convert_media(m))
+                .collect::<anyhow::Result<_>>()?,
+            _ => Vec::new(),
+        };
+        Ok(Tweet {
+            id: id(value, &mapping.id)?,
+            timestamp: timestamp(value, &mapping.timestamp)?,
+            text: string(value, &mapping.text)?,
+            media,
+            conversation_id: mapping
+                .conversation_id
+                .as_ref()
+                .map(|p| id(value, p))
+                .transpose()?,
+            // Usually null when the tweet isn't a reply or quote
+            in_reply_to: mapping.in_reply_to.as_ref().and_then(|p| id(value, p).ok()),
+            quoted_tweet_id: mapping
+                .quoted_tweet_id
+                .as_ref()
+                .and_then(|p| id(value, p).ok()),
+            poll: None,
+            card: None,
+            place: None,
+            coordinates: None,
+            batch: None,
+            source: mapping
+                .source
+                .as_ref()
+                .and_then(|p| string(value, p).ok())
+                .and_then(|s| source_name(&s)),
+            like_count: mapping.like_count.as_ref().and_then(|p| id(value, p).ok()),
+            retweet_count: mapping
+                .retweet_count
+                .as_ref()
+                .and_then(|p| id(value, p).ok()),
+            possibly_sensitive: mapping
+                .possibly_sensitive
+                .as_ref()
+                .and_then(|p| value.pointer(p)?.as_bool()),
+            unavailable_since: None,
+        })
+    }
+
+    fn convert_media(&self, value: &Value) -> anyhow::Result<Media> {
+        let mapping = &self.config.timeline.media;
+        let r#type = match string(value, &mapping.r#type)?.as_str() {
+            "photo" | "image" => MediaType::Photo,
+            "video" => MediaType::Video,
+            "gif" | "animated_gif" => MediaType::Gif,
+            other => bail!("Unknown media type: {other}"),
+        };
+        let url = string(value, &mapping.url).ok();
+        let url = url.map(|u| self.config.base_url.join(&u)).transpose()?;
+        let mut media = Media::new(id(value, &mapping.id)?, r#type, url);
+        if let Some(pointer) = &mapping.preview_url {
+            let preview_url = string(value, pointer).ok();
+            media.preview_url = preview_url
+                .map(|u| self.config.base_url.join(&u))
+                .transpose()?;
+        }
+        media.alt_text = mapping
+            .alt_text
+            .as_ref()
+            .and_then(|p| value.pointer(p)?.as_str())
+            .filter(|t| !t.is_empty())
+            .map(String::from);
+        Ok(media)
+    }
+}
+
+fn field<'a>(value: &'a Value, pointer: &str) -> anyhow::Result<&'a Value> {
+    value
+        .pointer(pointer)
+        .filter(|v| !v.is_null())
+        .ok_or_else(|| anyhow!("Missing field: {pointer}"))
+}
+
+fn string(value: &Value, pointer: &str) -> anyhow::Result<String> {
+    match field(value, pointer)? {
+        Value::String(s) => Ok(s.clone()),
+        other => Ok(other.to_string()),
+    }
+}
+
+fn id(value: &Value, pointer: &str) -> anyhow::Result<u64> {
+    match field(value, pointer)? {
+        Value::Number(n) => n.as_u64().context("Invalid id"),
+        Value::String(s) => s.parse().context("Couldn't parse id"),
+        _ => bail!("Invalid id: {pointer}"),
+    }
+}
+
+fn timestamp(value: &Value, pointer: &str) -> anyhow::Result<i64> {
+    match field(value, pointer)? {
+        Value::Number(n) => n.as_i64().context("Invalid timestamp"),
+        Value::String(s) => DateTime::parse_from_rfc3339(s)
+            .or_else(|_| DateTime::parse_from_str(s, LEGACY_DATE_FORMAT))
+            .map(|d| d.timestamp())
+            .with_context(|| format!("Couldn't parse date: {s}")),
+        _ => bail!("Invalid timestamp: {pointer}"),
+    }
+}
+
+#[async_trait]
+impl TwitterClient for ExtractorClient {
+    fn name(&self) -> &'static str {
+        "extractor"
+    }
+
+    async fn get_user(&self, username: &str) -> anyhow::Result<Profile> {
+        let mapping = &self.config.user;
+        let path = mapping.url.replace("{username}", username);
+        let response = match self.get_json(&path, &[]).await {
+            Err(e) if e.downcast_ref::<StatusError>().map(|e| e.code) == Some(404) => {
+                return Err(UserError::NotFound(username.to_string()).into())
+            }
+            response => response?,
+        };
+        let optional = |pointer: &Option<String>| {
+            pointer
+                .as_ref()
+                .and_then(|pointer| string(&response, pointer).ok())
+        };
+        Ok(Profile {
+            username: optional(&mapping.username),
+            name: optional(&mapping.name),
+            bio: optional(&mapping.bio),
+            avatar_url: optional(&mapping.avatar_url)
+                .map(|u| self.config.base_url.join(&u))
+                .transpose()?,
+            banner_url: optional(&mapping.banner_url)
+                .map(|u| self.config.base_url.join(&u))
+                .transpose()?,
+            ..Profile::new(id(&response, &mapping.id)?)
+        })
+    }
+
+    async fn get_username(&self, user_id: u64) -> anyhow::Result<String> {
+        let mapping = &self.config.user;
+        let (url, username) = match (&mapping.by_id_url, &mapping.username) {
+            (Some(url), Some(username)) => (url, username),
+            _ => bail!("Looking up accounts by id needs `by_id_url` and `username` pointers"),
+        };
+        let path = url.replace("{user_id}", &user_id.to_string());
+        let response = self.get_json(&path, &[]).await?;
+        string(&response, username)
+    }
+
+    async fn get_all_tweets_for_user(
+        &self,
+        username: &str,
+        user_id: u64,
+        since_id: Option<u64>,
+        _until_id: Option<u64>,
+        limit: Option<usize>,
+    ) -> anyhow::Result<Vec<Tweet>> {
+        let mapping = &self.config.timeline;
+        let path = mapping
+            .url
+            .replace("{username}", username)
+            .replace("{user_id}", &user_id.to_string());
+        let mut cursor: Option<String> = None;
+        let mut results = Vec::new();
+        loop {
+            let query = match (&mapping.cursor_param, &cursor) {
+                (Some(param), Some(cursor)) => vec![(param.as_str(), cursor.as_str())],
+                _ => vec![],
+            };
+            let response = self.get_json(&path, &query).await?;
+            let page = match response.pointer(&mapping.tweets) {
+                Some(Value::Array(tweets)) => tweets,
+                _ => bail!("Missing tweets: {}", mapping.tweets),
+            };
+            let mut finished = page.is_empty();
+            for tweet in page {
+                let tweet = self.convert_tweet(tweet)?;
+                if since_id.is_some_and(|since| tweet.id <= since) {
+                    finished = true;
+                    continue;
+                }
+                results.push(tweet);
+            }
+            if limit_reached(limit, results.len()) {
+                results.truncate(limit.unwrap_or(usize::MAX));
+                break;
+            }
+            let next = mapping
+                .next_cursor
+                .as_ref()
+                .and_then(|p| response.pointer(p))
+                .and_then(|c| match c {
+                    Value::String(s) if !s.is_empty() => Some(s.clone()),
+                    Value::Number(n) => Some(n.to_string()),
+                    _ => None,
+                });
+            // Guard against mirrors that ignore the cursor
+            if finished || next.is_none() || next == cursor {
+                break;
+            }
+            cursor = next;
+        }
+        Ok(results)
+    }
+}
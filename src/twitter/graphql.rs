@@ -0,0 +1,1067 @@
+//! Uses the GraphQL API of the Twitter web client, authenticated with the cookies of a logged in
+//! browser session. Unlike API v2 this returns full video variants.
+
+use crate::model::{
+    Card, Coordinates, Media, MediaType, Place, Poll, PollOption, Profile, Tweet, VideoQuality,
+    VideoVariant,
+};
+use crate::proxy;
+use crate::twitter::{
+    avatar_url, banner_url, limit_reached, rate_limit_reset, source_name, Account, Authentication,
+    Connections, RateLimit, TwitterClient, UserError, LEGACY_DATE_FORMAT, WEB_BEARER_TOKEN,
+};
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use chrono::DateTime;
+use regex::Regex;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, COOKIE};
+use reqwest::{Client, StatusCode, Url};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+const TIMEOUT_SEC: u64 = 10;
+
+// These identify the persisted GraphQL queries, they change occasionally as the web client updates
+const USER_BY_SCREEN_NAME: &str = "G3KGOASz96M-Qu0nwmGXNg/UserByScreenName";
+const USER_TWEETS: &str = "H8OOoI-5ZE4NxgRr8lfyWg/UserTweets";
+const USER_MEDIA: &str = "2tLOJWwGuCTytDrGBg8VwQ/UserMedia";
+const FOLLOWERS: &str = "rRXFSG5vR6drKr5M37YOTw/Followers";
+const FOLLOWING: &str = "iSicc7LrzWGBgDPL0tM_TQ/Following";
+const AUDIO_SPACE_BY_ID: &str = "xjTKygiBMpX44KU8ywLohQ/AudioSpaceById";
+
+const PAGE_SIZE: u64 = 100;
+
+// Number of times to retry a request after being rate limited
+const MAX_RETRIES: usize = 3;
+
+pub struct TwitterClientGraphql {
+    client: Client,
+    rate_limit: RateLimit,
+    /// Whether to look up the recordings of linked Spaces, which takes two more requests each
+    spaces: bool,
+}
+
+// The `legacy` tweet object has the same shape as in API v1.1
+#[derive(Deserialize)]
+struct LegacyTweet {
+    id_str: String,
+    full_text: String,
+    created_at: String,
+    conversation_id_str: Option<String>,
+    in_reply_to_status_id_str: Option<String>,
+    quoted_status_id_str: Option<String>,
+    source: Option<String>,
+    favorite_count: Option<u64>,
+    retweet_count: Option<u64>,
+    possibly_sensitive: Option<bool>,
+    retweeted_status_result: Option<Value>,
+    extended_entities: Option<LegacyEntities>,
+    coordinates: Option<LegacyPoint>,
+    place: Option<LegacyPlace>,
+}
+
+#[derive(Deserialize)]
+struct LegacyPoint {
+    /// `[longitude, latitude]`
+    coordinates: (f64, f64),
+}
+
+#[derive(Deserialize)]
+struct LegacyPlace {
+    full_name: String,
+    country: Option<String>,
+    bounding_box: Option<LegacyPolygon>,
+}
+
+#[derive(Deserialize)]
+struct LegacyPolygon {
+    coordinates: Vec<Vec<(f64, f64)>>,
+}
+
+#[derive(Deserialize)]
+struct LegacyEntities {
+    #[serde(default)]
+    media: Vec<LegacyMedia>,
+}
+
+#[derive(Deserialize)]
+struct LegacyMedia {
+    id_str: String,
+    r#type: String,
+    media_url_https: String,
+    ext_alt_text: Option<String>,
+    video_info: Option<LegacyVideoInfo>,
+}
+
+#[derive(Deserialize)]
+struct LegacyVideoInfo {
+    #[serde(default)]
+    variants: Vec<LegacyVideoVariant>,
+}
+
+#[derive(Deserialize)]
+struct LegacyVideoVariant {
+    bitrate: Option<u64>,
+    url: String,
+}
+
+impl TwitterClientGraphql {
+    pub fn new(auth: &Authentication) -> anyhow::Result<Self> {
+        let (auth_token, ct0) = match auth {
+            Authentication::Cookies { auth_token, ct0 } => (auth_token, ct0),
+            _ => return Err(auth.unsupported("graphql", "cookies")),
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {WEB_BEARER_TOKEN}"))?,
+        );
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_str(&format!("auth_token={auth_token}; ct0={ct0}"))?,
+        );
+        headers.insert("x-csrf-token", HeaderValue::from_str(ct0)?);
+        headers.insert(
+            "x-twitter-auth-type",
+            HeaderValue::from_static("OAuth2Session"),
+        );
+        headers.insert("x-twitter-active-user", HeaderValue::from_static("yes"));
+        Ok(Self {
+            client: proxy::api(Client::builder())
+                .default_headers(headers)
+                .timeout(Duration::from_secs(TIMEOUT_SEC))
+                .build()?,
+            rate_limit: RateLimit::default(),
+            spaces: false,
+        })
+    }
+
+    /// Also records the Spaces that tweets link to as audio media (for `--audio`)
+    pub fn with_spaces(mut self, spaces: bool) -> Self {
+        self.spaces = spaces;
+        self
+    }
+
+    async fn query(&self, operation: &str, variables: Value) -> anyhow::Result<Value> {
+        let url = Url::from_str(&format!("https://twitter.com/i/api/graphql/{operation}")).unwrap();
+        let query = [
+            ("variables", variables.to_string()),
+            ("features", features().to_string()),
+        ];
+        let value = self.get(url, &query).await?;
+        if value.get("data").is_none() {
+            bail!(value.to_string())
+        }
+        Ok(value)
+    }
+
+    /// Sends a request, waiting for rate limits as needed
+    async fn get(&self, url: Url, query: &[(&str, String)]) -> anyhow::Result<Value> {
+        for _ in 0..MAX_RETRIES {
+            self.rate_limit.wait().await;
+            let response = self.client.get(url.clone()).query(query).send().await?;
+            let status = response.status();
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                let wait = rate_limit_reset(&response).unwrap_or(Duration::from_secs(60));
+                self.rate_limit.limited(wait);
+                continue;
+            }
+            let text = response.text().await.context("Bad response text")?;
+            if !status.is_success() {
+                let code = status.as_u16();
+                bail!("Response was not successful: {code}\n{text}")
+            }
+            return serde_json::from_str::<Value>(&text).context("Invalid JSON");
+        }
+        bail!("Request failed after {MAX_RETRIES} attempts")
+    }
+
+    /// The recording of a Space, as an audio item. `None` if it wasn't recorded (or the recording
+    /// has expired).
+    async fn space_media(&self, space_id: &str) -> anyhow::Result<Option<Media>> {
+        let variables = json!({
+            "id": space_id,
+            "isMetatagsQuery": false,
+            "withReplays": true,
+            "withListeners": false,
+        });
+        let response = self.query(AUDIO_SPACE_BY_ID, variables).await?;
+        let metadata = response
+            .pointer("/data/audioSpace/metadata")
+            .context("Unexpected Space response")?;
+        let media_key = match replay_media_key(metadata)? {
+            Some(media_key) => media_key,
+            None => return Ok(None),
+        };
+        let url = Url::from_str(&format!(
+            "https://twitter.com/i/api/1.1/live_video_stream/status/{media_key}"
+        ))
+        .unwrap();
+        let stream = self.get(url, &[("client", "web".to_string())]).await?;
+        let location = stream
+            .pointer("/source/location")
+            .and_then(Value::as_str)
+            .context("Missing Space playlist")?;
+        convert_space(metadata, media_key, location).map(Some)
+    }
+
+    /// Returns the tweets on a single page of the timeline, and the cursor for the next page
+    async fn get_timeline_page(
+        &self,
+        operation: &str,
+        user_id: u64,
+        cursor: Option<&str>,
+    ) -> anyhow::Result<(Vec<Tweet>, Option<String>)> {
+        let mut variables = json!({
+            "userId": user_id.to_string(),
+            "count": PAGE_SIZE,
+            "includePromotedContent": false,
+            "withQuickPromoteEligibilityTweetFields": false,
+            "withVoice": true,
+            "withV2Timeline": true,
+        });
+        if let Some(cursor) = cursor {
+            variables["cursor"] = json!(cursor);
+        }
+        let response = self.query(operation, variables).await?;
+        let (tweets, next) = timeline_page(&response)?;
+        let mut converted = Vec::new();
+        for (mut tweet, mut space_ids) in tweets {
+            if !self.spaces {
+                space_ids.clear();
+            }
+            for space_id in space_ids {
+                match self.space_media(&space_id).await {
+                    Ok(Some(media)) => tweet.media.push(media),
+                    Ok(None) => {}
+                    Err(e) => log::warn!("Unable to find the recording of Space {space_id}: {e:#}"),
+                }
+            }
+            converted.push(tweet);
+        }
+        Ok((converted, next))
+    }
+
+    /// Pages through a timeline (newest first), until reaching `since_id` or the `limit`
+    async fn get_timeline(
+        &self,
+        operation: &str,
+        user_id: u64,
+        since_id: Option<u64>,
+        limit: Option<usize>,
+    ) -> anyhow::Result<Vec<Tweet>> {
+        let mut cursor: Option<String> = None;
+        let mut results = Vec::new();
+        loop {
+            let (page, next) = self
+                .get_timeline_page(operation, user_id, cursor.as_deref())
+                .await?;
+            // The web client keeps returning a bottom cursor even once the timeline is exhausted
+            let mut finished = page.is_empty() || next.is_none();
+            for tweet in page {
+                if since_id.is_some_and(|since| tweet.id <= since) {
+                    finished = true;
+                    continue;
+                }
+                results.push(tweet);
+            }
+            if limit_reached(limit, results.len()) {
+                results.truncate(limit.unwrap_or(usize::MAX));
+                break;
+            }
+            if finished {
+                break;
+            }
+            cursor = next;
+        }
+        Ok(results)
+    }
+}
+
+#[async_trait]
+impl TwitterClient for TwitterClientGraphql {
+    fn name(&self) -> &'static str {
+        "graphql"
+    }
+
+    async fn get_user(&self, username: &str) -> anyhow::Result<Profile> {
+        let variables = json!({
+            "screen_name": username,
+            "withSafetyModeUserFields": true,
+        });
+        let response = self.query(USER_BY_SCREEN_NAME, variables).await?;
+        convert_user(username, &response)
+    }
+
+    async fn get_all_tweets_for_user(
+        &self,
+        _username: &str,
+        user_id: u64,
+        since_id: Option<u64>,
+        _until_id: Option<u64>,
+        limit: Option<usize>,
+    ) -> anyhow::Result<Vec<Tweet>> {
+        self.get_timeline(USER_TWEETS, user_id, since_id, limit)
+            .await
+    }
+
+    async fn get_connections(
+        &self,
+        user_id: u64,
+        connections: Connections,
+    ) -> anyhow::Result<Vec<Account>> {
+        let operation = match connections {
+            Connections::Followers => FOLLOWERS,
+            Connections::Following => FOLLOWING,
+        };
+        let mut accounts = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut variables = json!({
+                "userId": user_id.to_string(),
+                "count": PAGE_SIZE,
+                "includePromotedContent": false,
+            });
+            if let Some(cursor) = &cursor {
+                variables["cursor"] = json!(cursor);
+            }
+            let response = self
+                .query(operation, variables)
+                .await
+                .with_context(|| format!("Unable to list {}", connections.name()))?;
+            let (mut page, next) = connections_page(&response)?;
+            let found = !page.is_empty();
+            accounts.append(&mut page);
+            // As with the timelines, the bottom cursor is returned even once the list is exhausted
+            match next {
+                Some(next) if found => cursor = Some(next),
+                _ => break,
+            }
+        }
+        Ok(accounts)
+    }
+
+    async fn get_media_tweets_for_user(
+        &self,
+        _username: &str,
+        user_id: u64,
+        since_id: Option<u64>,
+        _until_id: Option<u64>,
+        limit: Option<usize>,
+    ) -> anyhow::Result<Vec<Tweet>> {
+        self.get_timeline(USER_MEDIA, user_id, since_id, limit)
+            .await
+    }
+}
+
+/// The account of a UserByScreenName response, or why it can't be shown
+fn convert_user(username: &str, response: &Value) -> anyhow::Result<Profile> {
+    let user = response
+        .pointer("/data/user/result")
+        .ok_or_else(|| UserError::NotFound(username.to_string()))?;
+    if user.pointer("/__typename").and_then(Value::as_str) == Some("UserUnavailable") {
+        return Err(match user.pointer("/reason").and_then(Value::as_str) {
+            Some("Suspended") => UserError::Suspended(username.to_string()).into(),
+            reason => anyhow!(
+                "{} is unavailable ({})",
+                username,
+                reason.unwrap_or("unknown")
+            ),
+        });
+    }
+    let flag = |pointer: &str| user.pointer(pointer).and_then(Value::as_bool) == Some(true);
+    // The logged in account can read the protected accounts it follows
+    if flag("/legacy/protected") && !flag("/legacy/following") {
+        return Err(UserError::Protected(username.to_string()).into());
+    }
+    let id = user
+        .pointer("/rest_id")
+        .and_then(Value::as_str)
+        .context("Unable to find username")?;
+    let legacy = |pointer: &str| user.pointer(pointer).and_then(Value::as_str);
+    Ok(Profile {
+        username: legacy("/legacy/screen_name").map(String::from),
+        name: legacy("/legacy/name").map(String::from),
+        bio: legacy("/legacy/description")
+            .filter(|d| !d.is_empty())
+            .map(String::from),
+        avatar_url: legacy("/legacy/profile_image_url_https").and_then(avatar_url),
+        banner_url: legacy("/legacy/profile_banner_url").and_then(banner_url),
+        ..Profile::new(id.parse().context("Couldn't parse user id")?)
+    })
+}
+
+/// The accounts on a page of a Followers or Following response, and the cursor for the next page
+fn connections_page(response: &Value) -> anyhow::Result<(Vec<Account>, Option<String>)> {
+    let instructions = response
+        .pointer("/data/user/result/timeline/timeline/instructions")
+        .and_then(Value::as_array)
+        .context("Unexpected timeline response")?;
+    let mut accounts = Vec::new();
+    let mut next = None;
+    for entry in instructions
+        .iter()
+        .filter(|i| i["type"] == "TimelineAddEntries")
+        .filter_map(|i| i["entries"].as_array())
+        .flatten()
+    {
+        let entry_id = entry["entryId"].as_str().unwrap_or_default();
+        if entry_id.starts_with("cursor-bottom") {
+            next = entry
+                .pointer("/content/value")
+                .and_then(Value::as_str)
+                .map(String::from);
+            continue;
+        }
+        let user = match entry.pointer("/content/itemContent/user_results/result") {
+            Some(user) if entry_id.starts_with("user-") => user,
+            _ => continue,
+        };
+        let field = |pointer: &str| user.pointer(pointer).and_then(Value::as_str);
+        let (id, username) = match (field("/rest_id"), field("/legacy/screen_name")) {
+            (Some(id), Some(username)) => (id, username),
+            _ => continue,
+        };
+        accounts.push(Account {
+            user_id: id.parse().context("Couldn't parse user id")?,
+            username: username.to_string(),
+            name: field("/legacy/name").map(String::from),
+        });
+    }
+    Ok((accounts, next))
+}
+
+/// A tweet, with the ids of the Spaces it links to
+type TimelineTweet = (Tweet, Vec<String>);
+
+/// The tweets on a page of a UserTweets or UserMedia response, and the cursor for the next page
+fn timeline_page(response: &Value) -> anyhow::Result<(Vec<TimelineTweet>, Option<String>)> {
+    let instructions = response
+        .pointer("/data/user/result/timeline_v2/timeline/instructions")
+        .and_then(Value::as_array)
+        .context("Unexpected timeline response")?;
+    let mut tweets = Vec::new();
+    let mut next = None;
+    let mut add = |result: Option<&Value>| -> anyhow::Result<()> {
+        if let Some(result) = result {
+            if let Some(tweet) = convert_tweet(result)? {
+                tweets.push((tweet, space_ids(result)));
+            }
+        }
+        Ok(())
+    };
+    // Pinned tweets are in a separate `TimelinePinEntry` instruction, and so are skipped
+    for instruction in instructions {
+        if instruction["type"] == "TimelineAddToModule" {
+            // The following pages of the media grid
+            for item in instruction["moduleItems"].as_array().into_iter().flatten() {
+                add(item.pointer("/item/itemContent/tweet_results/result"))?;
+            }
+            continue;
+        }
+        if instruction["type"] != "TimelineAddEntries" {
+            continue;
+        }
+        for entry in instruction["entries"].as_array().into_iter().flatten() {
+            let entry_id = entry["entryId"].as_str().unwrap_or_default();
+            if entry_id.starts_with("cursor-bottom") {
+                next = entry.pointer("/content/value").and_then(Value::as_str);
+            } else if entry_id.starts_with("tweet-") {
+                add(entry.pointer("/content/itemContent/tweet_results/result"))?;
+            } else if entry_id.starts_with("profile-grid-") {
+                // The first page of the media grid
+                for item in entry
+                    .pointer("/content/items")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                {
+                    add(item.pointer("/item/itemContent/tweet_results/result"))?;
+                }
+            }
+        }
+    }
+    Ok((tweets, next.map(String::from)))
+}
+
+/// The media key of a Space's recording, from the `metadata` of an AudioSpaceById response.
+/// `None` if it wasn't recorded (or the recording has expired).
+fn replay_media_key(metadata: &Value) -> anyhow::Result<Option<&str>> {
+    if metadata["is_space_available_for_replay"].as_bool() != Some(true) {
+        return Ok(None);
+    }
+    metadata["media_key"]
+        .as_str()
+        .context("Missing Space media key")
+        .map(Some)
+}
+
+fn convert_space(metadata: &Value, media_key: &str, location: &str) -> anyhow::Result<Media> {
+    // Media keys are `<type>_<id>`
+    let id = media_key
+        .rsplit('_')
+        .next()
+        .and_then(|id| id.parse().ok())
+        .context("Couldn't parse Space media key")?;
+    let mut media = Media::new(id, MediaType::Audio, Some(Url::from_str(location)?));
+    media.title = metadata["title"].as_str().map(String::from);
+    // The times are in milliseconds, sometimes as strings
+    let time = |field: &str| match &metadata[field] {
+        Value::String(time) => time.parse::<u64>().ok(),
+        time => time.as_u64(),
+    };
+    media.duration_ms = match (time("started_at"), time("ended_at")) {
+        (Some(started), Some(ended)) => ended.checked_sub(started),
+        _ => None,
+    };
+    Ok(media)
+}
+
+/// The ids of the Spaces linked from a `tweet_results.result` object
+fn space_ids(result: &Value) -> Vec<String> {
+    static SPACE_URL: OnceLock<Regex> = OnceLock::new();
+    let space = SPACE_URL.get_or_init(|| Regex::new(r"/i/spaces/([A-Za-z0-9]+)").unwrap());
+    let result = match result["__typename"].as_str() {
+        Some("TweetWithVisibilityResults") => &result["tweet"],
+        _ => result,
+    };
+    let urls = result
+        .pointer("/legacy/entities/urls")
+        .and_then(Value::as_array);
+    let mut ids = Vec::new();
+    for url in urls.into_iter().flatten() {
+        let captures = url["expanded_url"].as_str().and_then(|u| space.captures(u));
+        if let Some(captures) = captures {
+            if !ids.contains(&captures[1].to_string()) {
+                ids.push(captures[1].to_string());
+            }
+        }
+    }
+    ids
+}
+
+/// Converts a `tweet_results.result` object, returning None for retweets and unavailable tweets
+fn convert_tweet(result: &Value) -> anyhow::Result<Option<Tweet>> {
+    let result = match result["__typename"].as_str() {
+        Some("Tweet") => result,
+        Some("TweetWithVisibilityResults") => &result["tweet"],
+        _ => return Ok(None),
+    };
+    let legacy = serde_json::from_value::<LegacyTweet>(result["legacy"].clone())
+        .context("Unable to deserialize tweet")?;
+    // The API clients exclude retweets
+    if legacy.retweeted_status_result.is_some() {
+        return Ok(None);
+    }
+    let media = match legacy.extended_entities {
+        None => Vec::new(),
+        Some(entities) => entities
+            .media
+            .into_iter()
+            .map(Media::try_from)
+            .collect::<Result<_, _>>()?,
+    };
+    Ok(Some(Tweet {
+        id: legacy.id_str.parse().context("Couldn't parse tweet id")?,
+        timestamp: DateTime::parse_from_str(&legacy.created_at, LEGACY_DATE_FORMAT)
+            .context("Couldn't parse tweet date")?
+            .timestamp(),
+        text: legacy.full_text,
+        media,
+        conversation_id: legacy
+            .conversation_id_str
+            .map(|id| id.parse())
+            .transpose()
+            .context("Couldn't parse conversation id")?,
+        in_reply_to: legacy
+            .in_reply_to_status_id_str
+            .and_then(|id| id.parse().ok()),
+        quoted_tweet_id: legacy.quoted_status_id_str.and_then(|id| id.parse().ok()),
+        poll: poll(&result["card"]),
+        card: card(&result["card"]),
+        place: legacy.place.map(|place| Place {
+            bounding_box: place
+                .bounding_box
+                .and_then(|b| Place::bounding_box(b.coordinates.first()?)),
+            full_name: place.full_name,
+            country: place.country.filter(|c| !c.is_empty()),
+        }),
+        coordinates: legacy.coordinates.map(|point| Coordinates {
+            longitude: point.coordinates.0,
+            latitude: point.coordinates.1,
+        }),
+        batch: None,
+        source: result["source"]
+            .as_str()
+            .or(legacy.source.as_deref())
+            .and_then(source_name),
+        like_count: legacy.favorite_count,
+        retweet_count: legacy.retweet_count,
+        possibly_sensitive: legacy.possibly_sensitive,
+        unavailable_since: None,
+    }))
+}
+
+/// Looks up a value of a card, cards list them as `{"key": ..., "value": {"string_value": ...}}`
+fn binding_value<'a>(card: &'a Value, key: &str) -> Option<&'a Value> {
+    card.pointer("/legacy/binding_values")?
+        .as_array()?
+        .iter()
+        .find(|binding| binding["key"] == key)
+        .map(|binding| &binding["value"])
+}
+
+/// Polls are cards named `poll<N>choice_text_only` (or `..._image`), with up to four choices
+fn poll(card: &Value) -> Option<Poll> {
+    let name = card.pointer("/legacy/name")?.as_str()?;
+    if !name.starts_with("poll") {
+        return None;
+    }
+    let string = |key: &str| binding_value(card, key)?["string_value"].as_str();
+    let options = (1..=4)
+        .map_while(|n| {
+            Some(PollOption {
+                label: string(&format!("choice{n}_label"))?.to_string(),
+                votes: string(&format!("choice{n}_count")).and_then(|c| c.parse().ok()),
+            })
+        })
+        .collect::<Vec<_>>();
+    if options.is_empty() {
+        return None;
+    }
+    let ends_at = string("end_datetime_utc")
+        .and_then(|d| DateTime::parse_from_rfc3339(d).ok())
+        .map(|d| d.timestamp());
+    Some(Poll { options, ends_at })
+}
+
+/// Link previews are the `summary` and `summary_large_image` cards
+fn card(card: &Value) -> Option<Card> {
+    let name = card.pointer("/legacy/name")?.as_str()?;
+    if !name.starts_with("summary") {
+        return None;
+    }
+    let string = |key: &str| binding_value(card, key)?["string_value"].as_str();
+    let url = |value: Option<&str>| value.and_then(|u| Url::parse(u).ok());
+    let thumbnail = ["thumbnail_image_original", "summary_photo_image_original"]
+        .iter()
+        .find_map(|key| {
+            binding_value(card, key)?
+                .pointer("/image_value/url")?
+                .as_str()
+        });
+    Some(Card {
+        url: url(string("card_url").or_else(|| card.pointer("/legacy/url")?.as_str())),
+        title: string("title").map(String::from),
+        description: string("description")
+            .filter(|d| !d.is_empty())
+            .map(String::from),
+        thumbnail_url: url(thumbnail),
+    })
+}
+
+impl TryFrom<LegacyMedia> for Media {
+    type Error = anyhow::Error;
+
+    fn try_from(media: LegacyMedia) -> anyhow::Result<Self> {
+        let id = media.id_str.parse().context("Couldn't parse media id")?;
+        let mut converted = match media.r#type.as_str() {
+            "photo" => Media::new(
+                id,
+                MediaType::Photo,
+                Some(Url::from_str(&media.media_url_https)?),
+            ),
+            "video" => video_media(id, &media, MediaType::Video)?,
+            "animated_gif" => video_media(id, &media, MediaType::Gif)?,
+            other => bail!("Unknown media type: {other}"),
+        };
+        converted.alt_text = media.ext_alt_text.filter(|t| !t.is_empty());
+        Ok(converted)
+    }
+}
+
+fn video_media(id: u64, media: &LegacyMedia, r#type: MediaType) -> anyhow::Result<Media> {
+    let info = media.video_info.as_ref().context("Missing video info")?;
+    let variants = info
+        .variants
+        .iter()
+        .map(|v| {
+            Ok(VideoVariant {
+                url: Url::from_str(&v.url)?,
+                bitrate: v.bitrate,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let best = VideoQuality::Best
+        .select(&variants)
+        .context("Missing video variant")?;
+    // For videos this is the thumbnail
+    let preview_url = Url::from_str(&media.media_url_https)?;
+    let mut media = Media::new(id, r#type, Some(best.url.clone()));
+    media.variants = variants;
+    media.preview_url = Some(preview_url);
+    Ok(media)
+}
+
+// The web client requires these feature switches to be sent with each query
+fn features() -> Value {
+    json!({
+        "responsive_web_graphql_exclude_directive_enabled": true,
+        "verified_phone_label_enabled": false,
+        "creator_subscriptions_tweet_preview_api_enabled": true,
+        "responsive_web_graphql_timeline_navigation_enabled": true,
+        "responsive_web_graphql_skip_user_profile_image_extensions_enabled": false,
+        "tweetypie_unmention_optimization_enabled": true,
+        "responsive_web_edit_tweet_api_enabled": true,
+        "graphql_is_translatable_rweb_tweet_is_translatable_enabled": true,
+        "view_counts_everywhere_api_enabled": true,
+        "longform_notetweets_consumption_enabled": true,
+        "responsive_web_twitter_article_tweet_consumption_enabled": false,
+        "tweet_awards_web_tipping_enabled": false,
+        "freedom_of_speech_not_reach_fetch_enabled": true,
+        "standardized_nudges_misinfo": true,
+        "tweet_with_visibility_results_prefer_gql_limited_actions_policy_enabled": true,
+        "longform_notetweets_rich_text_read_enabled": true,
+        "longform_notetweets_inline_media_enabled": true,
+        "responsive_web_media_download_video_enabled": false,
+        "responsive_web_enhance_cards_enabled": false,
+        "hidden_profile_likes_enabled": false,
+        "hidden_profile_subscriptions_enabled": false,
+        "highlights_tweets_tab_ui_enabled": true,
+        "subscriptions_verification_info_verified_since_enabled": true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `tweet_results.result` object, as returned by UserTweets and UserMedia
+    fn tweet_result(id: u64, photo: bool) -> Value {
+        let mut result = json!({
+            "__typename": "Tweet",
+            "rest_id": id.to_string(),
+            "legacy": {
+                "id_str": id.to_string(),
+                "full_text": format!("Tweet {id}"),
+                "created_at": "Thu Mar 04 00:00:00 +0000 2021",
+                "conversation_id_str": id.to_string(),
+            },
+        });
+        if photo {
+            result["legacy"]["extended_entities"] = json!({
+                "media": [{
+                    "id_str": "11",
+                    "type": "photo",
+                    "media_url_https": "https://pbs.twimg.com/media/photo.jpg",
+                    "ext_alt_text": "A cat",
+                }]
+            });
+        }
+        result
+    }
+
+    fn item(result: Value) -> Value {
+        json!({ "item": { "itemContent": { "tweet_results": { "result": result } } } })
+    }
+
+    fn timeline(instructions: Value) -> Value {
+        json!({ "data": { "user": { "result": { "timeline_v2": { "timeline": {
+            "instructions": instructions
+        } } } } } })
+    }
+
+    fn ids(page: &[TimelineTweet]) -> Vec<u64> {
+        page.iter().map(|(tweet, _)| tweet.id).collect()
+    }
+
+    fn user_error(result: Value) -> Option<UserError> {
+        let response = json!({ "data": { "user": { "result": result } } });
+        convert_user("a", &response)
+            .err()?
+            .downcast::<UserError>()
+            .ok()
+    }
+
+    #[test]
+    fn classifies_user_errors() {
+        let user =
+            |legacy: Value| json!({ "__typename": "User", "rest_id": "1", "legacy": legacy });
+        assert_eq!(user_error(user(json!({ "screen_name": "a" }))), None);
+        assert_eq!(
+            user_error(user(json!({ "protected": true }))),
+            Some(UserError::Protected("a".to_string()))
+        );
+        // Unless the logged in account follows it
+        assert_eq!(
+            user_error(user(json!({ "protected": true, "following": true }))),
+            None
+        );
+        assert_eq!(
+            user_error(json!({ "__typename": "UserUnavailable", "reason": "Suspended" })),
+            Some(UserError::Suspended("a".to_string()))
+        );
+        // Other reasons aren't classified, the error says why
+        let response = json!({ "data": { "user": { "result": {
+            "__typename": "UserUnavailable",
+            "reason": "Deactivated",
+        } } } });
+        let error = convert_user("a", &response).unwrap_err();
+        assert!(error.downcast_ref::<UserError>().is_none());
+        assert_eq!(error.to_string(), "a is unavailable (Deactivated)");
+        // Accounts that don't exist have no result
+        let error = convert_user("a", &json!({ "data": { "user": {} } })).unwrap_err();
+        assert_eq!(
+            error.downcast::<UserError>().unwrap(),
+            UserError::NotFound("a".to_string())
+        );
+    }
+
+    /// A tweet's `card` object, with its values as strings
+    fn card_object(name: &str, values: &[(&str, &str)]) -> Value {
+        let bindings = values
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": { "string_value": value } }))
+            .collect::<Vec<_>>();
+        json!({ "legacy": { "name": name, "url": "https://t.co/abc", "binding_values": bindings } })
+    }
+
+    #[test]
+    fn parses_polls() {
+        let card = card_object(
+            "poll3choice_text_only",
+            &[
+                ("choice1_label", "Yes"),
+                ("choice1_count", "12"),
+                ("choice2_label", "No"),
+                ("choice2_count", "3"),
+                ("choice3_label", "Maybe"),
+                ("end_datetime_utc", "2021-03-05T00:00:00Z"),
+            ],
+        );
+        let parsed = poll(&card).unwrap();
+        assert_eq!(
+            parsed.options,
+            [
+                PollOption {
+                    label: "Yes".to_string(),
+                    votes: Some(12),
+                },
+                PollOption {
+                    label: "No".to_string(),
+                    votes: Some(3),
+                },
+                PollOption {
+                    label: "Maybe".to_string(),
+                    votes: None,
+                },
+            ]
+        );
+        assert_eq!(parsed.ends_at, Some(1614902400));
+        assert_eq!(super::card(&card), None);
+        // A poll without any choices isn't one
+        assert_eq!(poll(&card_object("poll2choice_text_only", &[])), None);
+    }
+
+    #[test]
+    fn parses_link_previews() {
+        let mut summary = card_object(
+            "summary_large_image",
+            &[
+                ("card_url", "https://example.com/post"),
+                ("title", "A post"),
+                ("description", ""),
+            ],
+        );
+        summary["legacy"]["binding_values"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!({
+                "key": "summary_photo_image_original",
+                "value": { "image_value": { "url": "https://pbs.twimg.com/card_img/1.jpg" } },
+            }));
+        assert_eq!(
+            card(&summary),
+            Some(Card {
+                url: Some(Url::parse("https://example.com/post").unwrap()),
+                title: Some("A post".to_string()),
+                description: None,
+                thumbnail_url: Some(Url::parse("https://pbs.twimg.com/card_img/1.jpg").unwrap()),
+            })
+        );
+        assert_eq!(poll(&summary), None);
+
+        // Without a `card_url`, the card's own (t.co) link is used
+        let summary = card_object("summary", &[("title", "A post")]);
+        let url = card(&summary).unwrap().url.unwrap();
+        assert_eq!(url.as_str(), "https://t.co/abc");
+        assert_eq!(card(&card_object("player", &[("title", "A video")])), None);
+    }
+
+    #[test]
+    fn parses_connections() {
+        let user = |id: &str, name: &str| {
+            json!({
+                "entryId": format!("user-{id}"),
+                "content": { "itemContent": { "user_results": { "result": {
+                    "rest_id": id,
+                    "legacy": { "screen_name": name, "name": name.to_uppercase() },
+                } } } },
+            })
+        };
+        let response = json!({ "data": { "user": { "result": { "timeline": { "timeline": {
+            "instructions": [
+                { "type": "TimelineClearCache" },
+                { "type": "TimelineAddEntries", "entries": [
+                    user("1", "alice"),
+                    // e.g. a suspended account, without its details
+                    { "entryId": "user-2", "content": { "itemContent": {
+                        "user_results": { "result": { "__typename": "UserUnavailable" } }
+                    } } },
+                    user("3", "carol"),
+                    { "entryId": "cursor-top-1", "content": { "value": "top" } },
+                    { "entryId": "cursor-bottom-1", "content": { "value": "bottom" } },
+                ] },
+            ]
+        } } } } } });
+        let (accounts, next) = connections_page(&response).unwrap();
+        assert_eq!(
+            accounts,
+            [
+                Account {
+                    user_id: 1,
+                    username: "alice".to_string(),
+                    name: Some("ALICE".to_string()),
+                },
+                Account {
+                    user_id: 3,
+                    username: "carol".to_string(),
+                    name: Some("CAROL".to_string()),
+                },
+            ]
+        );
+        assert_eq!(next.as_deref(), Some("bottom"));
+        assert!(connections_page(&json!({ "data": {} })).is_err());
+    }
+
+    #[test]
+    fn parses_user_tweets() {
+        let mut retweet = tweet_result(4, false);
+        retweet["legacy"]["retweeted_status_result"] = json!({});
+        let response = timeline(json!([
+            {
+                "type": "TimelinePinEntry",
+                "entry": { "entryId": "tweet-1", "content": { "itemContent": {
+                    "tweet_results": { "result": tweet_result(1, false) }
+                } } },
+            },
+            {
+                "type": "TimelineAddEntries",
+                "entries": [
+                    { "entryId": "tweet-3", "content": { "itemContent": {
+                        "tweet_results": { "result": tweet_result(3, true) }
+                    } } },
+                    { "entryId": "tweet-4", "content": { "itemContent": {
+                        "tweet_results": { "result": retweet }
+                    } } },
+                    { "entryId": "tweet-2", "content": { "itemContent": {
+                        "tweet_results": { "result": {
+                            "__typename": "TweetWithVisibilityResults",
+                            "tweet": tweet_result(2, false),
+                        } }
+                    } } },
+                    { "entryId": "cursor-top-1", "content": { "value": "top" } },
+                    { "entryId": "cursor-bottom-1", "content": { "value": "bottom" } },
+                ],
+            },
+        ]));
+        let (page, next) = timeline_page(&response).unwrap();
+        assert_eq!(ids(&page), [3, 2]);
+        assert_eq!(next.as_deref(), Some("bottom"));
+        let media = &page[0].0.media[0];
+        assert_eq!(media.r#type, MediaType::Photo);
+        assert_eq!(media.alt_text.as_deref(), Some("A cat"));
+        assert_eq!(page[0].0.timestamp, 1614816000);
+    }
+
+    #[test]
+    fn parses_user_media_grid() {
+        // The first page has the grid as an entry, the following pages add to it
+        let first = timeline(json!([{
+            "type": "TimelineAddEntries",
+            "entries": [
+                { "entryId": "profile-grid-0", "content": { "items": [
+                    item(tweet_result(6, true)),
+                    item(tweet_result(5, true)),
+                ] } },
+                { "entryId": "cursor-bottom-0", "content": { "value": "page-2" } },
+            ],
+        }]));
+        let (page, next) = timeline_page(&first).unwrap();
+        assert_eq!(ids(&page), [6, 5]);
+        assert_eq!(next.as_deref(), Some("page-2"));
+
+        let following = timeline(json!([
+            {
+                "type": "TimelineAddToModule",
+                "moduleItems": [item(tweet_result(4, true))],
+            },
+            {
+                "type": "TimelineAddEntries",
+                "entries": [{ "entryId": "cursor-bottom-1", "content": { "value": "page-3" } }],
+            },
+        ]));
+        let (page, next) = timeline_page(&following).unwrap();
+        assert_eq!(ids(&page), [4]);
+        assert_eq!(next.as_deref(), Some("page-3"));
+
+        assert!(timeline_page(&json!({ "data": {} })).is_err());
+    }
+
+    #[test]
+    fn finds_space_ids() {
+        let url = |url: &str| json!({ "expanded_url": url });
+        let result = json!({
+            "__typename": "TweetWithVisibilityResults",
+            "tweet": {
+                "legacy": {
+                    "entities": {
+                        "urls": [
+                            url("https://twitter.com/i/spaces/1eaKbrPAqbwKX"),
+                            url("https://example.com/i/spaces"),
+                            url("https://twitter.com/i/spaces/1eaKbrPAqbwKX/peek"),
+                            url("https://x.com/i/spaces/1OdKrBnaEPXKX"),
+                        ]
+                    }
+                }
+            }
+        });
+        assert_eq!(space_ids(&result), ["1eaKbrPAqbwKX", "1OdKrBnaEPXKX"]);
+        assert!(space_ids(&json!({ "__typename": "Tweet", "legacy": {} })).is_empty());
+    }
+
+    #[test]
+    fn converts_spaces() {
+        let metadata = json!({
+            "media_key": "28_1620000000000000000",
+            "title": "Cartoons",
+            "is_space_available_for_replay": true,
+            "started_at": 1614816000000u64,
+            "ended_at": "1614819600000",
+        });
+        let media_key = replay_media_key(&metadata).unwrap().unwrap();
+        let media = convert_space(&metadata, media_key, "https://example.com/space.m3u8").unwrap();
+        assert_eq!(media.id, 1620000000000000000);
+        assert_eq!(media.r#type, MediaType::Audio);
+        assert_eq!(media.title.as_deref(), Some("Cartoons"));
+        assert_eq!(media.duration_ms, Some(3600000));
+
+        let expired = json!({ "media_key": "28_1", "is_space_available_for_replay": false });
+        assert!(replay_media_key(&expired).unwrap().is_none());
+        let missing = json!({ "is_space_available_for_replay": true });
+        assert!(replay_media_key(&missing).is_err());
+    }
+}
@@ -0,0 +1,293 @@
+//! Uses the guest token flow of the Twitter web client, allowing public accounts to be
+//! downloaded without any API credentials
+
+use crate::model::{Profile, Tweet};
+use crate::proxy;
+use crate::twitter::{
+    avatar_url, banner_url, limit_reached, rate_limit_reset, RateLimit, TweetAuthor, TwitterClient,
+    UserError, WEB_BEARER_TOKEN,
+};
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use egg_mode::error::TwitterErrors;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::{Client, RequestBuilder, Response, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const TIMEOUT_SEC: u64 = 10;
+
+// Guest tokens expire after a few hours, refresh well before then
+const GUEST_TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+// Number of times to retry a request after being rate limited, or the token being rejected
+const MAX_RETRIES: usize = 3;
+
+const PAGE_SIZE: &str = "200";
+
+// The most ids `statuses/lookup` accepts at once
+const LOOKUP_BATCH_SIZE: usize = 100;
+
+pub struct TwitterClientGuest {
+    client: Client,
+    token: Mutex<Option<GuestToken>>,
+    rate_limit: RateLimit,
+}
+
+struct GuestToken {
+    value: String,
+    created: Instant,
+}
+
+#[derive(Deserialize)]
+struct ActivateResponse {
+    guest_token: String,
+}
+
+impl TwitterClientGuest {
+    pub fn new() -> anyhow::Result<Self> {
+        let mut headers = HeaderMap::new();
+        let value = HeaderValue::from_str(&format!("Bearer {WEB_BEARER_TOKEN}"))?;
+        headers.insert(AUTHORIZATION, value);
+        Ok(Self {
+            client: proxy::api(Client::builder())
+                .default_headers(headers)
+                .timeout(Duration::from_secs(TIMEOUT_SEC))
+                .build()?,
+            token: Mutex::new(None),
+            rate_limit: RateLimit::default(),
+        })
+    }
+
+    async fn guest_token(&self, refresh: bool) -> anyhow::Result<String> {
+        let mut token = self.token.lock().await;
+        let expired = token
+            .as_ref()
+            .is_none_or(|t| t.created.elapsed() > GUEST_TOKEN_LIFETIME);
+        if refresh || expired {
+            log::debug!("Activating new guest token");
+            let response = self
+                .client
+                .post("https://api.twitter.com/1.1/guest/activate.json")
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                let code = response.status().as_u16();
+                bail!("Unable to activate guest token: {code}")
+            }
+            let text = response.text().await.context("Bad response text")?;
+            let activated = serde_json::from_str::<ActivateResponse>(&text)
+                .context("Unable to deserialize guest token")?;
+            *token = Some(GuestToken {
+                value: activated.guest_token,
+                created: Instant::now(),
+            });
+        }
+        Ok(token.as_ref().unwrap().value.clone())
+    }
+
+    /// Sends a request, refreshing the guest token and waiting for rate limits as needed
+    async fn send<T: DeserializeOwned>(
+        &self,
+        request: impl Fn() -> RequestBuilder,
+    ) -> anyhow::Result<T> {
+        let mut refresh = false;
+        for _ in 0..MAX_RETRIES {
+            self.rate_limit.wait().await;
+            let token = self.guest_token(refresh).await?;
+            let response = request().header("x-guest-token", token).send().await?;
+            match response.status() {
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                    let text = response.text().await.unwrap_or_default();
+                    // Suspended accounts are also forbidden, which a new token won't change
+                    if let Ok(errors) = serde_json::from_str::<TwitterErrors>(&text) {
+                        if errors
+                            .errors
+                            .iter()
+                            .any(|e| UserError::is_legacy_code(e.code))
+                        {
+                            return Err(errors).context("Response was not successful");
+                        }
+                    }
+                    // The token has been exhausted or invalidated, a new one has its own quota
+                    refresh = true;
+                }
+                StatusCode::TOO_MANY_REQUESTS => {
+                    let wait = rate_limit_reset(&response).unwrap_or(Duration::from_secs(60));
+                    self.rate_limit.limited(wait);
+                    refresh = true;
+                }
+                status if status.is_success() => {
+                    // Proactively switch tokens rather than getting rate limited
+                    if rate_limit_remaining(&response) == Some(0) {
+                        *self.token.lock().await = None;
+                    }
+                    let text = response.text().await.context("Bad response text")?;
+                    return serde_json::from_str(&text).context("Unable to deserialize response");
+                }
+                status => {
+                    let code = status.as_u16();
+                    let text = response.text().await.unwrap_or_default();
+                    if let Ok(errors) = serde_json::from_str::<TwitterErrors>(&text) {
+                        return Err(errors)
+                            .with_context(|| format!("Response was not successful: {code}"));
+                    }
+                    bail!("Response was not successful: {code}\n{text}")
+                }
+            }
+        }
+        bail!("Request failed after {MAX_RETRIES} attempts")
+    }
+
+    async fn lookup(&self, ids: &[u64]) -> anyhow::Result<Vec<egg_mode::tweet::Tweet>> {
+        let url = Url::from_str("https://api.twitter.com/1.1/statuses/lookup.json").unwrap();
+        let mut tweets = Vec::new();
+        for batch in ids.chunks(LOOKUP_BATCH_SIZE) {
+            let ids = batch
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            let mut page: Vec<egg_mode::tweet::Tweet> = self
+                .send(|| {
+                    self.client
+                        .get(url.clone())
+                        .query(&[("id", ids.as_str()), ("tweet_mode", "extended")])
+                })
+                .await
+                .context("Unable to lookup tweets")?;
+            tweets.append(&mut page);
+        }
+        Ok(tweets)
+    }
+}
+
+fn rate_limit_remaining(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get("x-rate-limit-remaining")?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+#[async_trait]
+impl TwitterClient for TwitterClientGuest {
+    fn name(&self) -> &'static str {
+        "guest"
+    }
+
+    async fn get_user(&self, username: &str) -> anyhow::Result<Profile> {
+        let url = Url::from_str("https://api.twitter.com/1.1/users/show.json").unwrap();
+        let user: egg_mode::user::TwitterUser = self
+            .send(|| {
+                self.client
+                    .get(url.clone())
+                    .query(&[("screen_name", username)])
+            })
+            .await
+            .map_err(|e| match e.downcast_ref::<TwitterErrors>() {
+                Some(errors) => {
+                    let codes = errors.errors.iter().map(|e| e.code);
+                    UserError::from_legacy_codes(username, codes).map_or(e, Into::into)
+                }
+                None => e,
+            })
+            .context("Unable to find username")?;
+        // Guests can only read public accounts
+        if user.protected {
+            return Err(UserError::Protected(username.to_string()).into());
+        }
+        Ok(Profile {
+            username: Some(user.screen_name),
+            name: Some(user.name),
+            bio: user.description.filter(|d| !d.is_empty()),
+            avatar_url: avatar_url(&user.profile_image_url_https),
+            banner_url: user.profile_banner_url.as_deref().and_then(banner_url),
+            ..Profile::new(user.id)
+        })
+    }
+
+    async fn get_username(&self, user_id: u64) -> anyhow::Result<String> {
+        let url = Url::from_str("https://api.twitter.com/1.1/users/show.json").unwrap();
+        let user_id = user_id.to_string();
+        let user: egg_mode::user::TwitterUser = self
+            .send(|| self.client.get(url.clone()).query(&[("user_id", &user_id)]))
+            .await
+            .context("Unable to find user")?;
+        Ok(user.screen_name)
+    }
+
+    async fn get_all_tweets_for_user(
+        &self,
+        _username: &str,
+        user_id: u64,
+        since_id: Option<u64>,
+        until_id: Option<u64>,
+        limit: Option<usize>,
+    ) -> anyhow::Result<Vec<Tweet>> {
+        let url = Url::from_str("https://api.twitter.com/1.1/statuses/user_timeline.json").unwrap();
+        // `max_id` is inclusive
+        let mut max_id = until_id.map(|id| id - 1);
+        let mut tweets = Vec::new();
+        loop {
+            let mut query = vec![
+                ("user_id", user_id.to_string()),
+                ("count", PAGE_SIZE.to_string()),
+                ("tweet_mode", "extended".to_string()),
+                ("include_rts", "false".to_string()),
+            ];
+            if let Some(since_id) = since_id {
+                query.push(("since_id", since_id.to_string()));
+            }
+            if let Some(max_id) = max_id {
+                query.push(("max_id", max_id.to_string()));
+            }
+            let page: Vec<egg_mode::tweet::Tweet> = self
+                .send(|| self.client.get(url.clone()).query(&query))
+                .await
+                .context("Unable to fetch tweets")?;
+            // `max_id` is inclusive, so continue from just below the oldest tweet
+            match page.iter().map(|t| t.id).min() {
+                Some(oldest) => max_id = Some(oldest - 1),
+                None => break,
+            }
+            for tweet in page {
+                tweets.push(Tweet::try_from(tweet)?);
+            }
+            if limit_reached(limit, tweets.len()) {
+                tweets.truncate(limit.unwrap_or(usize::MAX));
+                break;
+            }
+        }
+        Ok(tweets)
+    }
+
+    async fn lookup_tweets(&self, ids: &[u64]) -> anyhow::Result<Vec<Tweet>> {
+        self.lookup(ids)
+            .await?
+            .into_iter()
+            .map(Tweet::try_from)
+            .collect()
+    }
+
+    async fn lookup_tweets_with_authors(
+        &self,
+        ids: &[u64],
+    ) -> anyhow::Result<Vec<(TweetAuthor, Tweet)>> {
+        let mut tweets = Vec::new();
+        for mut tweet in self.lookup(ids).await? {
+            let user = tweet.user.take().context("Missing tweet author")?;
+            let author = TweetAuthor {
+                user_id: user.id,
+                username: user.screen_name,
+            };
+            tweets.push((author, Tweet::try_from(tweet)?));
+        }
+        Ok(tweets)
+    }
+}
@@ -1,22 +1,308 @@
+//! The backends tweets can be fetched from, each implementing [`TwitterClient`]
+
+mod auth;
+pub mod extractor;
+pub mod graphql;
+pub mod guest;
+pub mod nitter;
 pub mod v1;
 pub mod v2;
 
-use crate::model::Tweet;
+pub use crate::twitter::auth::Authentication;
+
+use crate::model::{Profile, Tweet};
+use anyhow::anyhow;
 use async_trait::async_trait;
-use serde::Deserialize;
+use reqwest::Response;
+use serde::Serialize;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::Instant;
+use url::Url;
+
+/// The public bearer token embedded in the Twitter web client
+pub const WEB_BEARER_TOKEN: &str = "AAAAAAAAAAAAAAAAAAAAANRILgAAAAAAnNwIzUejRCOuH5E6I8xnZz4puTs%3D1Zv7ttfk8LF81IUq16cHjhLTvJu4FA33AGWWjCpTnA";
+
+/// Date format used by API v1.1 (and the web client/archives that share its tweet format)
+/// e.g. "Wed Oct 10 20:19:24 +0000 2018"
+pub const LEGACY_DATE_FORMAT: &str = "%a %b %d %H:%M:%S %z %Y";
+
+/// How long to wait until a rate limit resets, according to the response headers
+pub fn rate_limit_reset(response: &Response) -> Option<Duration> {
+    let reset: i64 = response
+        .headers()
+        .get("x-rate-limit-reset")?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+    Some(until_reset(reset))
+}
+
+/// How long to wait until a rate limit resets at `reset` (a Unix timestamp)
+pub fn until_reset(reset: i64) -> Duration {
+    let wait = reset - chrono::Utc::now().timestamp();
+    Duration::from_secs(wait.clamp(1, 15 * 60) as u64)
+}
+
+/// Shared by a client's requests, so that once one is rate limited the others (e.g. those of the
+/// accounts synced concurrently with `--account-concurrency`) also wait for the reset, rather than
+/// each being rate limited in turn
+#[derive(Debug, Default)]
+pub struct RateLimit {
+    until: std::sync::Mutex<Option<Instant>>,
+}
+
+impl RateLimit {
+    /// Waits until the rate limit has reset, if a request has been rate limited
+    pub async fn wait(&self) {
+        let until = *self.until.lock().unwrap();
+        if let Some(until) = until {
+            tokio::time::sleep_until(until).await;
+        }
+    }
+
+    /// Pauses the requests for `wait`, after one was rate limited
+    pub fn limited(&self, wait: Duration) {
+        log::warn!("Rate limited, waiting {} seconds", wait.as_secs());
+        let mut until = self.until.lock().unwrap();
+        let reset = Instant::now() + wait;
+        *until = Some(until.map_or(reset, |until| until.max(reset)));
+    }
+}
+
+/// Why an account couldn't be looked up, returned (within the `anyhow::Error`) by
+/// [`TwitterClient::get_user`] where the backend can tell
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum UserError {
+    #[error("{0} doesn't exist (or has been renamed)")]
+    NotFound(String),
+    #[error("{0} has been suspended")]
+    Suspended(String),
+    #[error("{0} is protected, its tweets can only be seen by its followers")]
+    Protected(String),
+}
+
+/// Error codes returned by API v1.1 (and guest access)
+/// https://developer.twitter.com/en/support/twitter-api/error-troubleshooting
+const NOT_FOUND_CODE: i32 = 50;
+const SUSPENDED_CODE: i32 = 63;
+
+impl UserError {
+    /// Whether an API v1.1 error code is about the account being looked up
+    pub fn is_legacy_code(code: i32) -> bool {
+        matches!(code, NOT_FOUND_CODE | SUSPENDED_CODE)
+    }
+
+    /// Classifies the error codes of an API v1.1 response
+    pub fn from_legacy_codes(username: &str, codes: impl IntoIterator<Item = i32>) -> Option<Self> {
+        codes.into_iter().find_map(|code| match code {
+            NOT_FOUND_CODE => Some(UserError::NotFound(username.to_string())),
+            SUSPENDED_CODE => Some(UserError::Suspended(username.to_string())),
+            _ => None,
+        })
+    }
+}
+
+/// Banner URLs are of a folder of sizes, e.g. `.../profile_banners/<id>/<time>`, this requests the
+/// largest (1500x500)
+pub fn banner_url(url: &str) -> Option<Url> {
+    Url::parse(&format!("{}/1500x500", url.trim_end_matches('/'))).ok()
+}
+
+/// Avatar URLs are for a 48x48 version by default, e.g. `.../profile_images/<id>/<name>_normal.jpg`,
+/// this requests the original (as uploaded) instead
+pub fn avatar_url(url: &str) -> Option<Url> {
+    let url = match url.rsplit_once("_normal.") {
+        Some((start, ext)) => format!("{start}.{ext}"),
+        None => url.to_string(),
+    };
+    Url::parse(&url).ok()
+}
+
+/// The app's name from a tweet's `source`, which is given as a link to the app, e.g.
+/// `<a href="https://dlvr.it" rel="nofollow">dlvr.it</a>`
+pub fn source_name(source: &str) -> Option<String> {
+    let name = match source.split_once('>') {
+        Some((_, rest)) => rest.split('<').next().unwrap_or_default(),
+        None => source,
+    };
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Whether enough tweets have been fetched for `limit`, so that the older pages can be skipped
+pub fn limit_reached(limit: Option<usize>, fetched: usize) -> bool {
+    limit.is_some_and(|limit| fetched >= limit)
+}
+
+/// Milliseconds since the Unix epoch at which tweet ids (snowflakes) start
+const SNOWFLAKE_EPOCH_MS: i64 = 1_288_834_974_657;
 
-#[derive(Deserialize)]
-pub struct Authentication {
-    pub bearer_token: String,
+/// The smallest id a tweet posted at `timestamp` can have, as the ids start with the time they
+/// were created at. `None` before the ids were time based (November 2010).
+pub fn first_id_at(timestamp: i64) -> Option<u64> {
+    let since_epoch = timestamp.checked_mul(1000)? - SNOWFLAKE_EPOCH_MS;
+    (since_epoch > 0).then_some((since_epoch as u64) << 22)
 }
 
+/// The account a looked up tweet was posted by
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TweetAuthor {
+    pub user_id: u64,
+    pub username: String,
+}
+
+/// Which of an account's connections to list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connections {
+    Followers,
+    Following,
+}
+
+impl Connections {
+    /// Also the name of the files they are written to
+    pub fn name(self) -> &'static str {
+        match self {
+            Connections::Followers => "followers",
+            Connections::Following => "following",
+        }
+    }
+}
+
+/// An account in a followers or following list
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub user_id: u64,
+    pub username: String,
+    pub name: Option<String>,
+}
+
+/// Fetches accounts and their tweets from one of the backends (the Twitter APIs, guest access,
+/// Nitter or a configured extractor)
 #[async_trait]
-pub trait TwitterClient {
-    async fn get_id_for_username(&self, username: &str) -> anyhow::Result<u64>;
+pub trait TwitterClient: Send + Sync {
+    /// Identifies the backend, e.g. for recording the provenance of tweets
+    fn name(&self) -> &'static str;
+
+    /// Looks up the account, including its display name and avatar where they are available. Fails
+    /// with a [`UserError`] when the account doesn't exist, is suspended or is protected (and
+    /// can't be read).
+    async fn get_user(&self, username: &str) -> anyhow::Result<Profile>;
 
+    /// The account's current username, for following renames
+    async fn get_username(&self, _user_id: u64) -> anyhow::Result<String> {
+        Err(anyhow!(
+            "Looking up accounts by id is not supported by this API version"
+        ))
+    }
+
+    /// All the account's available tweets, or only those newer than `since_id`. The backends that
+    /// can start paging from an id (v1.1, v2 and guest access) also leave out the tweets from
+    /// `until_id` on, the others return them too. With a `limit`, only (about) that many of the
+    /// newest tweets, without fetching the older pages.
     async fn get_all_tweets_for_user(
         &self,
+        username: &str,
         user_id: u64,
         since_id: Option<u64>,
+        until_id: Option<u64>,
+        limit: Option<usize>,
     ) -> anyhow::Result<Vec<Tweet>>;
+
+    /// Like [`get_all_tweets_for_user`](Self::get_all_tweets_for_user), but only the tweets with
+    /// media (as on the profile's Media tab), which reaches further back than the full timeline on
+    /// the backends that have one. The others return the full timeline.
+    async fn get_media_tweets_for_user(
+        &self,
+        username: &str,
+        user_id: u64,
+        since_id: Option<u64>,
+        until_id: Option<u64>,
+        limit: Option<usize>,
+    ) -> anyhow::Result<Vec<Tweet>> {
+        self.get_all_tweets_for_user(username, user_id, since_id, until_id, limit)
+            .await
+    }
+
+    /// Returns all tweets by `user_id` that belong to the given conversation (thread)
+    async fn get_conversation_tweets(
+        &self,
+        _conversation_id: u64,
+        _user_id: u64,
+    ) -> anyhow::Result<Vec<Tweet>> {
+        Err(anyhow!(
+            "Downloading threads is not supported by this API version"
+        ))
+    }
+
+    /// Fetches tweets by id, tweets which couldn't be found are omitted
+    async fn lookup_tweets(&self, _ids: &[u64]) -> anyhow::Result<Vec<Tweet>> {
+        Err(anyhow!(
+            "Looking up tweets is not supported by this API version"
+        ))
+    }
+
+    /// The accounts following `user_id`, or that it follows (for `friends`)
+    async fn get_connections(
+        &self,
+        _user_id: u64,
+        _connections: Connections,
+    ) -> anyhow::Result<Vec<Account>> {
+        Err(anyhow!(
+            "Listing followers is not supported by this API version"
+        ))
+    }
+
+    /// Fetches tweets by id along with who posted them (for `--tweet-list`), tweets which couldn't
+    /// be found are omitted
+    async fn lookup_tweets_with_authors(
+        &self,
+        _ids: &[u64],
+    ) -> anyhow::Result<Vec<(TweetAuthor, Tweet)>> {
+        Err(anyhow!(
+            "Looking up tweets is not supported by this API version"
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_legacy_codes() {
+        // e.g. "Invalid or expired token" alongside the account's error
+        assert_eq!(
+            UserError::from_legacy_codes("a", [89, 63]),
+            Some(UserError::Suspended("a".to_string()))
+        );
+        assert_eq!(
+            UserError::from_legacy_codes("a", [50]),
+            Some(UserError::NotFound("a".to_string()))
+        );
+        assert_eq!(UserError::from_legacy_codes("a", [88]), None);
+        assert_eq!(UserError::from_legacy_codes("a", []), None);
+        assert!(UserError::is_legacy_code(50));
+        assert!(!UserError::is_legacy_code(88));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_waits_for_the_latest_reset() {
+        let rate_limit = RateLimit::default();
+        let start = Instant::now();
+        rate_limit.wait().await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        rate_limit.limited(Duration::from_millis(200));
+        // A shorter limit doesn't bring the reset forward
+        rate_limit.limited(Duration::from_millis(50));
+        rate_limit.wait().await;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        // Once reset, the requests carry on straight away
+        let reset = Instant::now();
+        rate_limit.wait().await;
+        assert!(reset.elapsed() < Duration::from_millis(50));
+    }
 }
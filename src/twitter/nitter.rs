@@ -0,0 +1,533 @@
+//! Scrapes the HTML timeline of a Nitter instance, for when API access is unavailable
+
+use crate::model::{Card, Media, MediaType, Poll, PollOption, Profile, Tweet};
+use crate::proxy;
+use crate::twitter::{limit_reached, TwitterClient, UserError};
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use percent_encoding::percent_decode_str;
+use reqwest::{Client, StatusCode, Url};
+use scraper::{ElementRef, Html, Selector};
+use std::str::FromStr;
+use std::time::Duration;
+
+const TIMEOUT_SEC: u64 = 20;
+
+// e.g. "Oct 10, 2018 · 8:19 PM UTC"
+const NITTER_DATE_FORMAT: &str = "%b %d, %Y · %I:%M %p UTC";
+
+pub struct NitterClient {
+    client: Client,
+    base_url: Url,
+}
+
+impl NitterClient {
+    pub fn new(base_url: Url) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: proxy::api(Client::builder())
+                .timeout(Duration::from_secs(TIMEOUT_SEC))
+                .build()?,
+            base_url,
+        })
+    }
+
+    async fn get_html(&self, path: &str, query: &[(&str, &str)]) -> anyhow::Result<Html> {
+        let (status, html) = self.get_page(path, query).await?;
+        if !status.is_success() {
+            let code = status.as_u16();
+            bail!("Nitter response was not successful: {code}")
+        }
+        Ok(html)
+    }
+
+    /// Also returns the pages of unsuccessful responses, which explain the error
+    async fn get_page(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> anyhow::Result<(StatusCode, Html)> {
+        let url = self.base_url.join(path)?;
+        let response = self.client.get(url).query(query).send().await?;
+        let status = response.status();
+        let text = response.text().await.context("Bad response text")?;
+        Ok((status, Html::parse_document(&text)))
+    }
+
+    /// Returns the tweets on a single timeline page, and the cursor for the next page
+    async fn get_timeline_page(
+        &self,
+        username: &str,
+        cursor: Option<&str>,
+    ) -> anyhow::Result<(Vec<(Tweet, bool)>, Option<String>)> {
+        let query = cursor.map(|c| vec![("cursor", c)]).unwrap_or_default();
+        let html = self.get_html(username, &query).await?;
+        let items = Selector::parse(".timeline > .timeline-item").unwrap();
+        let retweet = Selector::parse(".retweet-header").unwrap();
+        let pinned = Selector::parse(".pinned").unwrap();
+        let mut tweets = Vec::new();
+        for item in html.select(&items) {
+            // The API clients exclude retweets, so we should too
+            if item.select(&retweet).next().is_some() {
+                continue;
+            }
+            let is_pinned = item.select(&pinned).next().is_some();
+            tweets.push((parse_tweet(item)?, is_pinned));
+        }
+        let show_more = Selector::parse(".timeline > .show-more:not(.timeline-item) a").unwrap();
+        let next = html
+            .select(&show_more)
+            .last()
+            .and_then(|a| a.value().attr("href"))
+            .and_then(|href| href.split("cursor=").nth(1))
+            .map(|c| percent_decode_str(c).decode_utf8_lossy().into_owned());
+        Ok((tweets, next))
+    }
+
+    /// The account's profile page, or why it can't be shown
+    fn parse_profile(
+        &self,
+        username: &str,
+        status: StatusCode,
+        html: &Html,
+    ) -> anyhow::Result<Profile> {
+        // e.g. `User "name" not found` or `User "name" has been suspended`
+        let error = Selector::parse(".error-panel").unwrap();
+        if let Some(panel) = html.select(&error).next() {
+            let message = panel.text().collect::<String>().to_lowercase();
+            if message.contains("suspended") {
+                return Err(UserError::Suspended(username.to_string()).into());
+            } else if message.contains("not found") {
+                return Err(UserError::NotFound(username.to_string()).into());
+            }
+        }
+        if !status.is_success() {
+            let code = status.as_u16();
+            bail!("Nitter response was not successful: {code}")
+        }
+        // Nitter can only show public accounts
+        let protected = Selector::parse(".timeline-protected").unwrap();
+        if html.select(&protected).next().is_some() {
+            return Err(UserError::Protected(username.to_string()).into());
+        }
+        // Nitter doesn't display user ids, however they are contained within the banner URL
+        // e.g. `/pic/https%3A%2F%2Fpbs.twimg.com%2Fprofile_banners%2F783214%2F1646075315%2F1500x500`
+        let banner = Selector::parse(".profile-banner a").unwrap();
+        let banner_href = html
+            .select(&banner)
+            .next()
+            .and_then(|a| a.value().attr("href"))
+            .context("Unable to determine user id (account has no profile banner)")?;
+        let href = percent_decode_str(banner_href).decode_utf8_lossy();
+        let id = href
+            .split("profile_banners/")
+            .nth(1)
+            .and_then(|s| s.split('/').next())
+            .context("Unexpected profile banner format")?;
+        let name = Selector::parse(".profile-card-fullname").unwrap();
+        let handle = Selector::parse(".profile-card-username").unwrap();
+        let bio = Selector::parse(".profile-bio").unwrap();
+        let avatar = Selector::parse("a.profile-card-avatar").unwrap();
+        let text = |selector: &Selector| {
+            html.select(selector)
+                .next()
+                .map(|e| e.text().collect::<String>().trim().to_string())
+                .filter(|t| !t.is_empty())
+        };
+        Ok(Profile {
+            // e.g. "@name"
+            username: text(&handle).map(|h| h.trim_start_matches('@').to_string()),
+            name: text(&name),
+            bio: text(&bio),
+            // The avatar is proxied by the instance
+            avatar_url: html
+                .select(&avatar)
+                .next()
+                .and_then(|a| a.value().attr("href"))
+                .and_then(|href| self.base_url.join(href).ok()),
+            // Also proxied
+            banner_url: self.base_url.join(banner_href).ok(),
+            ..Profile::new(id.parse().context("Couldn't parse user id")?)
+        })
+    }
+}
+
+#[async_trait]
+impl TwitterClient for NitterClient {
+    fn name(&self) -> &'static str {
+        "nitter"
+    }
+
+    async fn get_user(&self, username: &str) -> anyhow::Result<Profile> {
+        let (status, html) = self.get_page(username, &[]).await?;
+        self.parse_profile(username, status, &html)
+    }
+
+    async fn get_all_tweets_for_user(
+        &self,
+        username: &str,
+        _user_id: u64,
+        since_id: Option<u64>,
+        _until_id: Option<u64>,
+        limit: Option<usize>,
+    ) -> anyhow::Result<Vec<Tweet>> {
+        let mut cursor: Option<String> = None;
+        let mut results = Vec::new();
+        loop {
+            let (page, next) = self.get_timeline_page(username, cursor.as_deref()).await?;
+            // Nitter returns an empty page once the end of the timeline is reached
+            let mut finished = page.is_empty() || next.is_none() || next == cursor;
+            for (tweet, is_pinned) in page {
+                if since_id.is_some_and(|since| tweet.id <= since) {
+                    // Pinned tweets may be older than the rest of the timeline
+                    finished |= !is_pinned;
+                    continue;
+                }
+                results.push(tweet);
+            }
+            if limit_reached(limit, results.len()) {
+                results.truncate(limit.unwrap_or(usize::MAX));
+                break;
+            }
+            if finished {
+                break;
+            }
+            cursor = next;
+        }
+        Ok(results)
+    }
+}
+
+fn parse_tweet(item: ElementRef) -> anyhow::Result<Tweet> {
+    let link = Selector::parse(".tweet-link").unwrap();
+    let date = Selector::parse(".tweet-date a").unwrap();
+    let content = Selector::parse(".tweet-content").unwrap();
+
+    // e.g. `/username/status/1234#m`
+    let href = item
+        .select(&link)
+        .next()
+        .and_then(|a| a.value().attr("href"))
+        .context("Missing tweet link")?;
+    let id = href
+        .split("/status/")
+        .nth(1)
+        .and_then(|s| s.split(|c: char| !c.is_ascii_digit()).next())
+        .context("Unexpected tweet link format")?
+        .parse()
+        .context("Couldn't parse tweet id")?;
+    let date = item
+        .select(&date)
+        .next()
+        .and_then(|a| a.value().attr("title"))
+        .context("Missing tweet date")?;
+    let timestamp = NaiveDateTime::parse_from_str(date, NITTER_DATE_FORMAT)
+        .context("Couldn't parse tweet date")?
+        .timestamp();
+    let text = item
+        .select(&content)
+        .next()
+        .map(|c| c.text().collect::<String>())
+        .unwrap_or_default();
+
+    Ok(Tweet {
+        id,
+        timestamp,
+        text,
+        media: parse_media(item),
+        conversation_id: None,
+        // Nitter only names the accounts replied to
+        in_reply_to: None,
+        quoted_tweet_id: quoted_tweet_id(item),
+        poll: poll(item),
+        card: card(item),
+        place: None,
+        coordinates: None,
+        batch: None,
+        source: None,
+        like_count: stat(item, "icon-heart"),
+        retweet_count: stat(item, "icon-retweet"),
+        possibly_sensitive: None,
+        unavailable_since: None,
+    })
+}
+
+/// Nitter shows the share of the votes of each option, rather than their counts
+fn poll(item: ElementRef) -> Option<Poll> {
+    let option = Selector::parse(".poll .poll-choice-option").unwrap();
+    let options = item
+        .select(&option)
+        .map(|o| PollOption {
+            label: o.text().collect::<String>().trim().to_string(),
+            votes: None,
+        })
+        .collect::<Vec<_>>();
+    if options.is_empty() {
+        return None;
+    }
+    Some(Poll {
+        options,
+        ends_at: None,
+    })
+}
+
+/// The link preview, its image is proxied like the media
+fn card(item: ElementRef) -> Option<Card> {
+    let container = Selector::parse(".card a.card-container").unwrap();
+    let title = Selector::parse(".card-title").unwrap();
+    let description = Selector::parse(".card-description").unwrap();
+    let image = Selector::parse(".card-image img").unwrap();
+    let container = item.select(&container).next()?;
+    let text = |selector: &Selector| {
+        let text = container
+            .select(selector)
+            .next()?
+            .text()
+            .collect::<String>();
+        Some(text.trim().to_string()).filter(|t| !t.is_empty())
+    };
+    let thumbnail = container
+        .select(&image)
+        .next()
+        .and_then(|img| img.value().attr("src"));
+    Some(Card {
+        url: container
+            .value()
+            .attr("href")
+            .and_then(|h| Url::parse(h).ok()),
+        title: text(&title),
+        description: text(&description),
+        thumbnail_url: thumbnail.and_then(decode_proxied_url),
+    })
+}
+
+/// The tweet quoted within the item, from its link, e.g. `/name/status/123#m`
+fn quoted_tweet_id(item: ElementRef) -> Option<u64> {
+    let link = Selector::parse(".quote a.quote-link").unwrap();
+    let href = item.select(&link).next()?.value().attr("href")?;
+    href.split("/status/")
+        .nth(1)?
+        .split(|c: char| !c.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// A count from the stats below the tweet, e.g. `1,234` next to its `icon` (empty when zero)
+fn stat(item: ElementRef, icon: &str) -> Option<u64> {
+    let selector = Selector::parse(&format!(".tweet-stats .{icon}")).unwrap();
+    let container = ElementRef::wrap(item.select(&selector).next()?.parent()?)?;
+    let text = container.text().collect::<String>().replace(',', "");
+    match text.trim() {
+        "" => Some(0),
+        count => count.parse().ok(),
+    }
+}
+
+fn parse_media(item: ElementRef) -> Vec<Media> {
+    let images = Selector::parse(".attachments .still-image").unwrap();
+    let gifs = Selector::parse(".attachments video.gif source").unwrap();
+    let videos = Selector::parse(".attachments video:not(.gif)").unwrap();
+    let mut media = Vec::new();
+    for image in item.select(&images) {
+        if let Some(url) = image.value().attr("href").and_then(decode_proxied_url) {
+            media.push(Media::new(media_id(&url), MediaType::Photo, Some(url)));
+        }
+    }
+    for gif in item.select(&gifs) {
+        if let Some(url) = gif.value().attr("src").and_then(decode_proxied_url) {
+            media.push(Media::new(media_id(&url), MediaType::Gif, Some(url)));
+        }
+    }
+    for video in item.select(&videos) {
+        let url = video
+            .value()
+            .attr("data-url")
+            .or_else(|| video.value().attr("src"))
+            .and_then(decode_proxied_url);
+        if let Some(url) = url {
+            // HLS playlists can't be downloaded directly
+            let mp4 = url.path().ends_with(".mp4").then(|| url.clone());
+            let mut item = Media::new(media_id(&url), MediaType::Video, mp4);
+            item.preview_url = video.value().attr("poster").and_then(decode_proxied_url);
+            media.push(item);
+        }
+    }
+    media
+}
+
+/// Converts a Nitter proxy path (e.g. `/pic/orig/media%2FABC.jpg`) back into the original URL
+fn decode_proxied_url(path: &str) -> Option<Url> {
+    let path = path
+        .strip_prefix("/pic/orig/")
+        .or_else(|| path.strip_prefix("/pic/"))
+        .or_else(|| {
+            path.strip_prefix("/video/")
+                .and_then(|p| p.split_once('/'))
+                .map(|p| p.1)
+        })?;
+    let decoded = percent_decode_str(path).decode_utf8().ok()?;
+    let decoded = if decoded.starts_with("http") {
+        decoded.into_owned()
+    } else if decoded.starts_with("media/") || decoded.starts_with("tweet_video_thumb/") {
+        format!("https://pbs.twimg.com/{decoded}")
+    } else {
+        format!("https://{decoded}")
+    };
+    Url::from_str(&decoded).ok()
+}
+
+/// Nitter doesn't expose media ids, so where possible extract it from the URL, e.g.
+/// `video.twimg.com/ext_tw_video/<id>/...`, otherwise derive a stable id from the file name
+fn media_id(url: &Url) -> u64 {
+    let segments = url
+        .path_segments()
+        .map(|s| s.collect::<Vec<_>>())
+        .unwrap_or_default();
+    if let ["ext_tw_video" | "amplify_video", id, ..] = segments.as_slice() {
+        if let Ok(id) = id.parse() {
+            return id;
+        }
+    }
+    // FNV-1a
+    url.path().bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses a timeline item, as its element
+    fn with_item<T>(html: &str, f: impl FnOnce(ElementRef) -> T) -> T {
+        let html = Html::parse_fragment(&format!("<div class=\"timeline-item\">{html}</div>"));
+        let selector = Selector::parse(".timeline-item").unwrap();
+        f(html.select(&selector).next().unwrap())
+    }
+
+    fn parse_profile(status: StatusCode, body: &str) -> anyhow::Result<Profile> {
+        let client = NitterClient::new(Url::parse("https://nitter.example/").unwrap()).unwrap();
+        client.parse_profile("a", status, &Html::parse_document(body))
+    }
+
+    fn user_error(status: StatusCode, body: &str) -> Option<UserError> {
+        parse_profile(status, body)
+            .err()?
+            .downcast::<UserError>()
+            .ok()
+    }
+
+    #[test]
+    fn classifies_user_errors() {
+        assert_eq!(
+            user_error(
+                StatusCode::NOT_FOUND,
+                r#"<div class="error-panel"><span>User "a" not found</span></div>"#
+            ),
+            Some(UserError::NotFound("a".to_string()))
+        );
+        assert_eq!(
+            user_error(
+                StatusCode::NOT_FOUND,
+                r#"<div class="error-panel"><span>User "a" has been suspended</span></div>"#
+            ),
+            Some(UserError::Suspended("a".to_string()))
+        );
+        assert_eq!(
+            user_error(
+                StatusCode::OK,
+                r#"<div class="timeline-container"><div class="timeline-protected">
+                    <h2>This account's tweets are protected.</h2>
+                </div></div>"#
+            ),
+            Some(UserError::Protected("a".to_string()))
+        );
+        // e.g. the instance being rate limited
+        let error = parse_profile(
+            StatusCode::TOO_MANY_REQUESTS,
+            r#"<div class="error-panel"><span>Instance has been rate limited.</span></div>"#,
+        )
+        .unwrap_err();
+        assert!(error.downcast_ref::<UserError>().is_none());
+
+        let profile = parse_profile(
+            StatusCode::OK,
+            r#"<div class="profile-banner">
+                <a href="/pic/https%3A%2F%2Fpbs.twimg.com%2Fprofile_banners%2F783214%2F1646075315%2F1500x500"></a>
+            </div>
+            <a class="profile-card-fullname" href="/a">Alice</a>
+            <a class="profile-card-username" href="/a">@a</a>"#,
+        )
+        .unwrap();
+        assert_eq!(profile.user_id, 783214);
+        assert_eq!(profile.username.as_deref(), Some("a"));
+        assert_eq!(profile.name.as_deref(), Some("Alice"));
+    }
+
+    #[test]
+    fn parses_polls() {
+        let html = r#"<div class="poll">
+            <div class="poll-meter leader">
+                <span class="poll-choice-value">80%</span>
+                <span class="poll-choice-option"> Yes </span>
+            </div>
+            <div class="poll-meter">
+                <span class="poll-choice-value">20%</span>
+                <span class="poll-choice-option">No</span>
+            </div>
+            <span class="poll-info">15 votes • Final results</span>
+        </div>"#;
+        assert_eq!(
+            with_item(html, poll),
+            Some(Poll {
+                options: vec![
+                    PollOption {
+                        label: "Yes".to_string(),
+                        votes: None,
+                    },
+                    PollOption {
+                        label: "No".to_string(),
+                        votes: None,
+                    },
+                ],
+                ends_at: None,
+            })
+        );
+        assert_eq!(
+            with_item("<div class=\"tweet-content\">Hi</div>", poll),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_link_previews() {
+        let html = r#"<div class="card large">
+            <a class="card-container" href="https://example.com/post">
+                <div class="card-image-container"><div class="card-image">
+                    <img src="/pic/pbs.twimg.com%2Fcard_img%2F1%2Fabc%3Fformat%3Djpg" alt="">
+                </div></div>
+                <div class="card-content-container"><div class="card-content">
+                    <h2 class="card-title"> A post </h2>
+                    <p class="card-description"></p>
+                    <span class="card-destination">example.com</span>
+                </div></div>
+            </a>
+        </div>"#;
+        assert_eq!(
+            with_item(html, card),
+            Some(Card {
+                url: Some(Url::parse("https://example.com/post").unwrap()),
+                title: Some("A post".to_string()),
+                description: None,
+                thumbnail_url: Some(
+                    Url::parse("https://pbs.twimg.com/card_img/1/abc?format=jpg").unwrap()
+                ),
+            })
+        );
+        assert_eq!(
+            with_item("<div class=\"tweet-content\">Hi</div>", card),
+            None
+        );
+    }
+}
@@ -1,58 +1,204 @@
-use crate::model::{Media, MediaType, Tweet};
-use crate::twitter::{Authentication, TwitterClient};
+use crate::model::{
+    Coordinates, Media, MediaType, Place, Profile, Tweet, VideoQuality, VideoVariant,
+};
+use crate::twitter::{
+    avatar_url, banner_url, limit_reached, until_reset, Account, Authentication, Connections,
+    RateLimit, TweetAuthor, TwitterClient, UserError,
+};
 use anyhow::Context;
 use async_trait::async_trait;
 use egg_mode::entities::MediaEntity;
-use egg_mode::Token;
+use egg_mode::{KeyPair, Token};
+use futures::StreamExt;
 use std::str::FromStr;
 use url::Url;
 
+// https://developer.twitter.com/en/docs/twitter-api/v1/tweets/post-and-engage/api-reference/get-statuses-lookup
+const LOOKUP_BATCH_SIZE: usize = 100;
+
+// The most accounts `followers/list` and `friends/list` return per page
+const CONNECTIONS_PAGE_SIZE: i32 = 200;
+
 pub struct TwitterClientV1 {
     token: Token,
+    rate_limit: RateLimit,
 }
 
 impl TwitterClientV1 {
-    pub fn new(auth: &Authentication) -> Self {
-        Self {
-            token: Token::Bearer(auth.bearer_token.clone()),
+    pub fn new(auth: &Authentication) -> anyhow::Result<Self> {
+        let token = match auth {
+            Authentication::Bearer { bearer_token } => Token::Bearer(bearer_token.clone()),
+            Authentication::OAuth1 {
+                consumer_key,
+                consumer_secret,
+                access_token,
+                access_token_secret,
+            } => Token::Access {
+                consumer: KeyPair::new(consumer_key.clone(), consumer_secret.clone()),
+                access: KeyPair::new(access_token.clone(), access_token_secret.clone()),
+            },
+            Authentication::Cookies { .. } => {
+                return Err(auth.unsupported("v1.1", "a bearer token or OAuth1 credentials"))
+            }
+        };
+        Ok(Self {
+            token,
+            rate_limit: RateLimit::default(),
+        })
+    }
+
+    async fn lookup(&self, ids: &[u64]) -> anyhow::Result<Vec<egg_mode::tweet::Tweet>> {
+        let mut tweets = Vec::new();
+        for batch in ids.chunks(LOOKUP_BATCH_SIZE) {
+            let response = egg_mode::tweet::lookup(batch.iter().copied(), &self.token)
+                .await
+                .context("Unable to lookup tweets")?;
+            tweets.extend(response.response);
         }
+        Ok(tweets)
     }
 }
 
 #[async_trait]
 impl TwitterClient for TwitterClientV1 {
-    async fn get_id_for_username(&self, username: &str) -> anyhow::Result<u64> {
-        let user = egg_mode::user::show(username.to_string(), &self.token)
+    fn name(&self) -> &'static str {
+        "v1.1"
+    }
+
+    async fn get_user(&self, username: &str) -> anyhow::Result<Profile> {
+        let user = match egg_mode::user::show(username.to_string(), &self.token).await {
+            Ok(user) => user.response,
+            Err(egg_mode::error::Error::TwitterError(_, errors)) => {
+                let codes = errors.errors.iter().map(|e| e.code);
+                match UserError::from_legacy_codes(username, codes) {
+                    Some(e) => return Err(e.into()),
+                    None => return Err(errors).context("Unable to find username"),
+                }
+            }
+            Err(e) => return Err(e).context("Unable to find username"),
+        };
+        // App-only authentication can't read any protected accounts, a user's credentials can read
+        // those that it follows
+        if user.protected && matches!(self.token, Token::Bearer(_)) {
+            return Err(UserError::Protected(username.to_string()).into());
+        }
+        Ok(Profile {
+            username: Some(user.screen_name),
+            name: Some(user.name),
+            bio: user.description.filter(|d| !d.is_empty()),
+            avatar_url: avatar_url(&user.profile_image_url_https),
+            banner_url: user.profile_banner_url.as_deref().and_then(banner_url),
+            ..Profile::new(user.id)
+        })
+    }
+
+    async fn get_username(&self, user_id: u64) -> anyhow::Result<String> {
+        let user = egg_mode::user::show(user_id, &self.token)
             .await
-            .context("Unable to find username")?;
-        Ok(user.response.id)
+            .context("Unable to find user")?
+            .response;
+        Ok(user.screen_name)
+    }
+
+    async fn get_connections(
+        &self,
+        user_id: u64,
+        connections: Connections,
+    ) -> anyhow::Result<Vec<Account>> {
+        let mut pages = match connections {
+            Connections::Followers => egg_mode::user::followers_of(user_id, &self.token),
+            Connections::Following => egg_mode::user::friends_of(user_id, &self.token),
+        }
+        .with_page_size(CONNECTIONS_PAGE_SIZE);
+        let mut accounts = Vec::new();
+        loop {
+            self.rate_limit.wait().await;
+            let user = match pages.next().await {
+                Some(user) => user,
+                None => break,
+            };
+            let user = match user {
+                // The cursor stays on the page that was rate limited, which is requested again
+                Err(egg_mode::error::Error::RateLimit(reset)) => {
+                    self.rate_limit.limited(until_reset(reset.into()));
+                    continue;
+                }
+                user => {
+                    user.with_context(|| format!("Unable to list {}", connections.name()))?
+                        .response
+                }
+            };
+            accounts.push(Account {
+                user_id: user.id,
+                username: user.screen_name,
+                name: Some(user.name),
+            });
+        }
+        Ok(accounts)
     }
 
     async fn get_all_tweets_for_user(
         &self,
+        _username: &str,
         user_id: u64,
         since_id: Option<u64>,
+        until_id: Option<u64>,
+        limit: Option<usize>,
     ) -> anyhow::Result<Vec<Tweet>> {
-        let mut timeline =
+        let timeline =
             egg_mode::tweet::user_timeline(user_id, true, false, &self.token).with_page_size(200);
+        // Each page continues from below the oldest tweet so far
+        let mut max_id = until_id.map(|id| id - 1);
         let mut tweets = Vec::new();
         loop {
-            let (t2, mut new) = timeline
-                .older(since_id)
-                .await
-                .context("Unable to fetch tweets")?;
-            timeline = t2;
-            if new.is_empty() {
+            self.rate_limit.wait().await;
+            let mut new = match timeline.call(since_id, max_id).await {
+                // The page that was rate limited is requested again
+                Err(egg_mode::error::Error::RateLimit(reset)) => {
+                    self.rate_limit.limited(until_reset(reset.into()));
+                    continue;
+                }
+                page => page.context("Unable to fetch tweets")?.response,
+            };
+            match new.iter().map(|t| t.id).min() {
+                Some(oldest) => max_id = Some(oldest - 1),
+                None => break,
+            }
+            tweets.append(&mut new);
+            if limit_reached(limit, tweets.len()) {
                 break;
-            } else {
-                tweets.append(&mut new);
             }
         }
         Ok(tweets
             .into_iter()
+            .take(limit.unwrap_or(usize::MAX))
             .map(Tweet::try_from)
             .collect::<Result<_, _>>()?)
     }
+
+    async fn lookup_tweets(&self, ids: &[u64]) -> anyhow::Result<Vec<Tweet>> {
+        self.lookup(ids)
+            .await?
+            .into_iter()
+            .map(Tweet::try_from)
+            .collect()
+    }
+
+    async fn lookup_tweets_with_authors(
+        &self,
+        ids: &[u64],
+    ) -> anyhow::Result<Vec<(TweetAuthor, Tweet)>> {
+        let mut tweets = Vec::new();
+        for mut tweet in self.lookup(ids).await? {
+            let user = tweet.user.take().context("Missing tweet author")?;
+            let author = TweetAuthor {
+                user_id: user.id,
+                username: user.screen_name,
+            };
+            tweets.push((author, Tweet::try_from(tweet)?));
+        }
+        Ok(tweets)
+    }
 }
 
 impl TryFrom<egg_mode::tweet::Tweet> for Tweet {
@@ -72,6 +218,27 @@ impl TryFrom<egg_mode::tweet::Tweet> for Tweet {
             timestamp: tweet.created_at.timestamp(),
             text: tweet.text,
             media,
+            conversation_id: None,
+            in_reply_to: tweet.in_reply_to_status_id,
+            quoted_tweet_id: tweet.quoted_status_id,
+            poll: None,
+            card: None,
+            place: tweet.place.map(|place| Place {
+                bounding_box: Place::bounding_box(&place.bounding_box),
+                full_name: place.full_name,
+                country: Some(place.country).filter(|c| !c.is_empty()),
+            }),
+            // Despite egg-mode's docs these are in GeoJSON's order
+            coordinates: tweet.coordinates.map(|(longitude, latitude)| Coordinates {
+                longitude,
+                latitude,
+            }),
+            batch: None,
+            source: tweet.source.map(|s| s.name),
+            like_count: u64::try_from(tweet.favorite_count).ok(),
+            retweet_count: u64::try_from(tweet.retweet_count).ok(),
+            possibly_sensitive: tweet.possibly_sensitive,
+            unavailable_since: None,
         })
     }
 }
@@ -80,29 +247,38 @@ impl TryFrom<MediaEntity> for Media {
     type Error = anyhow::Error;
 
     fn try_from(entity: MediaEntity) -> anyhow::Result<Self> {
-        Ok(match entity.media_type {
+        let mut media = match entity.media_type {
             egg_mode::entities::MediaType::Photo => Media::new(
                 entity.id,
                 MediaType::Photo,
                 Some(Url::from_str(&entity.media_url_https)?),
             ),
-            egg_mode::entities::MediaType::Video => {
-                Media::new(entity.id, MediaType::Video, Some(get_video_url(&entity)?))
-            }
-            egg_mode::entities::MediaType::Gif => {
-                Media::new(entity.id, MediaType::Gif, Some(get_video_url(&entity)?))
-            }
-        })
+            egg_mode::entities::MediaType::Video => video_media(&entity, MediaType::Video)?,
+            egg_mode::entities::MediaType::Gif => video_media(&entity, MediaType::Gif)?,
+        };
+        media.alt_text = entity.ext_alt_text.filter(|t| !t.is_empty());
+        Ok(media)
     }
 }
 
-fn get_video_url(entity: &MediaEntity) -> anyhow::Result<Url> {
+fn video_media(entity: &MediaEntity, r#type: MediaType) -> anyhow::Result<Media> {
     let info = entity.video_info.as_ref().context("Missing video info")?;
-    let best_variant = info
+    let variants = info
         .variants
         .iter()
-        .filter(|v| v.bitrate.is_some())
-        .max_by_key(|v| v.bitrate.unwrap())
+        .map(|v| {
+            Ok(VideoVariant {
+                url: Url::from_str(&v.url)?,
+                bitrate: v.bitrate.map(|b| b as u64),
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let best = VideoQuality::Best
+        .select(&variants)
         .context("Missing video variant")?;
-    Ok(Url::from_str(&best_variant.url)?)
+    let mut media = Media::new(entity.id, r#type, Some(best.url.clone()));
+    media.variants = variants;
+    // For videos this is the thumbnail
+    media.preview_url = Some(Url::from_str(&entity.media_url_https)?);
+    Ok(media)
 }
@@ -1,23 +1,33 @@
 //! There doesn't yet seem to be a good Rust client that uses API V2
 
-use crate::model::{Media, MediaType, Tweet};
-use crate::twitter::{Authentication, TwitterClient};
+use crate::model::{Card, Coordinates, Media, MediaType, Place, Poll, PollOption, Profile, Tweet};
+use crate::proxy;
+use crate::twitter::{
+    avatar_url, limit_reached, rate_limit_reset, Account, Authentication, Connections, RateLimit,
+    TweetAuthor, TwitterClient, UserError,
+};
 use anyhow::{bail, Context};
 use async_trait::async_trait;
 use chrono::DateTime;
 use maplit::hashmap;
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
-use reqwest::{Client, Response, Url};
+use reqwest::{Client, RequestBuilder, Response, StatusCode, Url};
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
 const TIMEOUT_SEC: u64 = 10;
 
+// https://developer.twitter.com/en/docs/twitter-api/tweets/lookup/api-reference/get-tweets
+const LOOKUP_BATCH_SIZE: usize = 100;
+
 #[derive(Clone)]
 pub struct TwitterClientV2 {
     client: Client,
+    rate_limit: Arc<RateLimit>,
 }
 
 #[derive(Deserialize)]
@@ -31,14 +41,68 @@ enum TwitterResponse<T> {
     },
 }
 
+/// Accounts that can't be found (or are suspended) are reported as errors alongside a missing
+/// `data`, so this is matched rather than `TwitterResponse::Error`
 #[derive(Deserialize)]
-struct ByUsernameResponse {
-    data: ByUsernameData,
+struct UserResponse {
+    data: Option<UserData>,
+    #[serde(default)]
+    errors: Vec<UserResponseError>,
 }
 
 #[derive(Deserialize)]
-struct ByUsernameData {
+struct UserData {
     id: String,
+    username: String,
+    name: Option<String>,
+    description: Option<String>,
+    profile_image_url: Option<String>,
+    #[serde(default)]
+    protected: bool,
+}
+
+#[derive(Deserialize)]
+struct UsersResponse {
+    #[serde(default)]
+    data: Vec<UserData>,
+    meta: GetTweetsMeta,
+}
+
+#[derive(Deserialize)]
+struct UserResponseError {
+    #[serde(default)]
+    detail: String,
+}
+
+impl UsersResponse {
+    /// The accounts on the page, and the token for the next page
+    fn accounts(self) -> anyhow::Result<(Vec<Account>, Option<String>)> {
+        let accounts = self
+            .data
+            .into_iter()
+            .map(|user| {
+                Ok(Account {
+                    user_id: user.id.parse().context("Couldn't parse user id")?,
+                    username: user.username,
+                    name: user.name,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok((accounts, self.meta.next_token))
+    }
+}
+
+impl UserResponse {
+    fn user(self, username: &str) -> anyhow::Result<UserData> {
+        match self.data {
+            Some(user) => Ok(user),
+            // e.g. "User has been suspended: [name]."
+            None if self.errors.iter().any(|e| e.detail.contains("suspended")) => {
+                Err(UserError::Suspended(username.to_string()).into())
+            }
+            None => Err(UserError::NotFound(username.to_string()).into()),
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -49,30 +113,146 @@ struct GetTweetsResponse {
     meta: GetTweetsMeta,
 }
 
+/// Tweets which couldn't be found are reported as errors, alongside those that were
+#[derive(Deserialize)]
+struct LookupResponse {
+    #[serde(default)]
+    data: Vec<GetTweetsTweet>,
+    includes: Option<GetTweetsIncludes>,
+}
+
 #[derive(Deserialize)]
 pub struct GetTweetsTweet {
     id: String,
+    author_id: Option<String>,
     text: String,
     created_at: String,
+    conversation_id: Option<String>,
+    source: Option<String>,
+    public_metrics: Option<GetTweetsPublicMetrics>,
+    possibly_sensitive: Option<bool>,
     #[serde(default)]
     attachments: GetTweetsTweetAttachment,
+    #[serde(default)]
+    referenced_tweets: Vec<GetTweetsReferencedTweet>,
+    #[serde(default)]
+    entities: GetTweetsEntities,
+    geo: Option<GetTweetsGeo>,
+}
+
+#[derive(Deserialize)]
+pub struct GetTweetsGeo {
+    place_id: Option<String>,
+    coordinates: Option<GetTweetsPoint>,
+}
+
+#[derive(Deserialize)]
+pub struct GetTweetsPoint {
+    /// `[longitude, latitude]`
+    coordinates: (f64, f64),
+}
+
+#[derive(Deserialize, Default)]
+pub struct GetTweetsEntities {
+    #[serde(default)]
+    urls: Vec<GetTweetsUrl>,
+}
+
+/// Links which Twitter unfurled also have their preview's title, description and images
+#[derive(Deserialize)]
+pub struct GetTweetsUrl {
+    expanded_url: Option<String>,
+    unwound_url: Option<String>,
+    title: Option<String>,
+    description: Option<String>,
+    #[serde(default)]
+    images: Vec<GetTweetsUrlImage>,
+}
+
+#[derive(Deserialize)]
+pub struct GetTweetsUrlImage {
+    url: String,
+}
+
+#[derive(Deserialize)]
+pub struct GetTweetsReferencedTweet {
+    /// `replied_to`, `quoted` or `retweeted`
+    r#type: String,
+    id: String,
+}
+
+impl GetTweetsTweet {
+    fn referenced(&self, kind: &str) -> Option<u64> {
+        self.referenced_tweets
+            .iter()
+            .find(|r| r.r#type == kind)
+            .and_then(|r| r.id.parse().ok())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GetTweetsPublicMetrics {
+    like_count: u64,
+    retweet_count: u64,
 }
 
 #[derive(Deserialize, Default)]
 pub struct GetTweetsTweetAttachment {
     #[serde(default)]
     media_keys: Vec<String>,
+    #[serde(default)]
+    poll_ids: Vec<String>,
 }
 
 #[derive(Deserialize, Default)]
 struct GetTweetsIncludes {
     #[serde(default)]
     media: Vec<GetTweetsMedia>,
+    #[serde(default)]
+    users: Vec<GetTweetsUser>,
+    #[serde(default)]
+    polls: Vec<GetTweetsPoll>,
+    #[serde(default)]
+    places: Vec<GetTweetsPlace>,
+}
+
+#[derive(Deserialize)]
+struct GetTweetsPlace {
+    id: String,
+    full_name: String,
+    country: Option<String>,
+    geo: Option<GetTweetsPlaceGeo>,
+}
+
+#[derive(Deserialize)]
+struct GetTweetsPlaceGeo {
+    /// `[west, south, east, north]`
+    bbox: [f64; 4],
+}
+
+#[derive(Deserialize)]
+struct GetTweetsPoll {
+    id: String,
+    options: Vec<GetTweetsPollOption>,
+    end_datetime: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GetTweetsPollOption {
+    label: String,
+    votes: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct GetTweetsUser {
+    id: String,
+    username: String,
 }
 
 #[derive(Deserialize)]
 struct GetTweetsMedia {
     media_key: String,
+    alt_text: Option<String>,
     #[serde(flatten)]
     variant: GetTweetsMediaVariant,
 }
@@ -81,11 +261,11 @@ struct GetTweetsMedia {
 #[serde(tag = "type")]
 enum GetTweetsMediaVariant {
     #[serde(rename = "video")]
-    Video,
+    Video { preview_image_url: Option<String> },
     #[serde(rename = "photo")]
     Photo { url: String },
     #[serde(rename = "animated_gif")]
-    Gif,
+    Gif { preview_image_url: Option<String> },
 }
 
 #[derive(Deserialize)]
@@ -121,73 +301,259 @@ async fn deserialize_response<T: DeserializeOwned>(response: Response) -> anyhow
 impl TwitterClientV2 {
     pub fn new(auth: &Authentication) -> anyhow::Result<Self> {
         let mut headers = HeaderMap::new();
-        let value = format!("Bearer {}", auth.bearer_token);
+        let value = format!("Bearer {}", auth.bearer_token("v2")?);
         let value = HeaderValue::from_str(&value)?;
         headers.insert(AUTHORIZATION, value);
         Ok(Self {
-            client: Client::builder()
+            client: proxy::api(Client::builder())
                 .default_headers(headers)
                 .timeout(Duration::from_secs(TIMEOUT_SEC))
                 .build()?,
+            rate_limit: Arc::default(),
         })
     }
 
+    /// Sends the request, waiting for the rate limit to reset (and sending it again) when it has
+    /// been hit
+    async fn send(&self, request: RequestBuilder) -> anyhow::Result<Response> {
+        loop {
+            self.rate_limit.wait().await;
+            let response = request
+                .try_clone()
+                .context("Unable to repeat request")?
+                .send()
+                .await?;
+            if response.status() != StatusCode::TOO_MANY_REQUESTS {
+                return Ok(response);
+            }
+            let wait = rate_limit_reset(&response).unwrap_or(Duration::from_secs(60));
+            self.rate_limit.limited(wait);
+        }
+    }
+
     // https://developer.twitter.com/en/docs/twitter-api/tweets/timelines/api-reference/get-users-id-tweets
     async fn get_tweets_for_user(
         &self,
         user_id: u64,
         since_id: Option<u64>,
+        until_id: Option<u64>,
         pagination_token: Option<String>,
     ) -> anyhow::Result<(Vec<Tweet>, Option<String>)> {
         let url =
             Url::from_str(&format!("https://api.twitter.com/2/users/{user_id}/tweets")).unwrap();
-        let mut query = hashmap! {
-            "exclude" => "retweets".to_string(),
-            "max_results" => "100".to_string(),
-            // Including `preview_image_url` ensures we do at least get video Ids
-            "media.fields" => "url,type,media_key,preview_image_url".to_string(),
-            "tweet.fields" => "created_at".to_string(),
-            "expansions" => "attachments.media_keys".to_string(),
-        };
+        let mut query = tweet_fields_query();
+        query.insert("exclude", "retweets".to_string());
         if let Some(since_id) = since_id {
             query.insert("since_id", since_id.to_string());
         }
+        if let Some(until_id) = until_id {
+            query.insert("until_id", until_id.to_string());
+        }
         if let Some(pagination_token) = pagination_token {
             query.insert("pagination_token", pagination_token);
         }
-        let response = self.client.get(url).query(&query).send().await?;
+        self.get_tweets_page(url, &query).await
+    }
+
+    // https://developer.twitter.com/en/docs/twitter-api/tweets/search/api-reference/get-tweets-search-recent
+    // Only searches the last 7 days, the full archive search needs academic access
+    async fn search_tweets(
+        &self,
+        search: &str,
+        next_token: Option<String>,
+    ) -> anyhow::Result<(Vec<Tweet>, Option<String>)> {
+        let url = Url::from_str("https://api.twitter.com/2/tweets/search/recent").unwrap();
+        let mut query = tweet_fields_query();
+        query.insert("query", search.to_string());
+        if let Some(next_token) = next_token {
+            query.insert("next_token", next_token);
+        }
+        self.get_tweets_page(url, &query).await
+    }
+
+    async fn get_tweets_page(
+        &self,
+        url: Url,
+        query: &HashMap<&str, String>,
+    ) -> anyhow::Result<(Vec<Tweet>, Option<String>)> {
+        let response = self.send(self.client.get(url).query(query)).await?;
         let response = deserialize_response::<GetTweetsResponse>(response).await?;
-        let media = response
-            .includes
-            .map(|i| i.media)
-            .unwrap_or_else(Default::default);
-        let tweets = convert_tweets(response.data, media)?;
+        let includes = response.includes.unwrap_or_default();
+        let tweets = convert_tweets(response.data, &includes)?;
         Ok((tweets, response.meta.next_token))
     }
+
+    /// Looks up the tweets 100 at a time, along with their authors (if they were included)
+    async fn lookup(&self, ids: &[u64]) -> anyhow::Result<Vec<(Option<TweetAuthor>, Tweet)>> {
+        let url = Url::from_str("https://api.twitter.com/2/tweets").unwrap();
+        let mut query = tweet_fields_query();
+        query.remove("max_results");
+        query.insert(
+            "expansions",
+            "attachments.media_keys,attachments.poll_ids,geo.place_id,author_id".to_string(),
+        );
+        let mut results = Vec::new();
+        for batch in ids.chunks(LOOKUP_BATCH_SIZE) {
+            let ids = batch
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            query.insert("ids", ids);
+            let response = self
+                .send(self.client.get(url.clone()).query(&query))
+                .await?;
+            let response = deserialize_response::<LookupResponse>(response)
+                .await
+                .context("Unable to lookup tweets")?;
+            let includes = response.includes.unwrap_or_default();
+            let authors = response
+                .data
+                .iter()
+                .map(|tweet| {
+                    let user = includes
+                        .users
+                        .iter()
+                        .find(|u| Some(&u.id) == tweet.author_id.as_ref())?;
+                    Some(TweetAuthor {
+                        user_id: user.id.parse().ok()?,
+                        username: user.username.clone(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            let tweets = convert_tweets(response.data, &includes)?;
+            results.extend(authors.into_iter().zip(tweets));
+        }
+        Ok(results)
+    }
+}
+
+fn tweet_fields_query() -> HashMap<&'static str, String> {
+    hashmap! {
+        "max_results" => "100".to_string(),
+        // Including `preview_image_url` ensures we do at least get video Ids
+        "media.fields" => "url,type,media_key,preview_image_url,alt_text".to_string(),
+        "tweet.fields" => "created_at,conversation_id,source,public_metrics,possibly_sensitive,referenced_tweets,entities,geo".to_string(),
+        "poll.fields" => "options,end_datetime".to_string(),
+        "place.fields" => "full_name,country,geo".to_string(),
+        "expansions" => "attachments.media_keys,attachments.poll_ids,geo.place_id".to_string(),
+    }
 }
 
 #[async_trait]
 impl TwitterClient for TwitterClientV2 {
-    async fn get_id_for_username(&self, username: &str) -> anyhow::Result<u64> {
+    fn name(&self) -> &'static str {
+        "v2"
+    }
+
+    async fn get_user(&self, username: &str) -> anyhow::Result<Profile> {
         let url = Url::from_str("https://api.twitter.com/2/users/by/username/").unwrap();
         let url = url.join(username).unwrap();
-        let response = self.client.get(url).send().await?;
-        let response = deserialize_response::<ByUsernameResponse>(response).await?;
-        Ok(response.data.id.parse().context("Couldn't parse user id")?)
+        let request = self
+            .client
+            .get(url)
+            .query(&[("user.fields", "description,profile_image_url,protected")]);
+        let response = self.send(request).await?;
+        let user = deserialize_response::<UserResponse>(response)
+            .await?
+            .user(username)?;
+        // The bearer token is app-only, which can't read protected accounts
+        if user.protected {
+            return Err(UserError::Protected(username.to_string()).into());
+        }
+        Ok(Profile {
+            username: Some(user.username),
+            name: user.name,
+            bio: user.description.filter(|d| !d.is_empty()),
+            avatar_url: user.profile_image_url.as_deref().and_then(avatar_url),
+            ..Profile::new(user.id.parse().context("Couldn't parse user id")?)
+        })
+    }
+
+    async fn get_username(&self, user_id: u64) -> anyhow::Result<String> {
+        let url = Url::from_str("https://api.twitter.com/2/users/").unwrap();
+        let url = url.join(&user_id.to_string()).unwrap();
+        let response = self.send(self.client.get(url)).await?;
+        let user = deserialize_response::<UserResponse>(response)
+            .await?
+            .user(&user_id.to_string())?;
+        Ok(user.username)
+    }
+
+    // https://developer.twitter.com/en/docs/twitter-api/users/follows/api-reference/get-users-id-followers
+    async fn get_connections(
+        &self,
+        user_id: u64,
+        connections: Connections,
+    ) -> anyhow::Result<Vec<Account>> {
+        let url = Url::from_str(&format!(
+            "https://api.twitter.com/2/users/{user_id}/{}",
+            connections.name()
+        ))
+        .unwrap();
+        let mut accounts = Vec::new();
+        let mut next_token: Option<String> = None;
+        loop {
+            let mut query = vec![("max_results", "1000".to_string())];
+            if let Some(next_token) = &next_token {
+                query.push(("pagination_token", next_token.clone()));
+            }
+            // Only 15 pages can be requested every 15 minutes, so larger accounts are rate limited
+            let response = self
+                .send(self.client.get(url.clone()).query(&query))
+                .await?;
+            let page = deserialize_response::<UsersResponse>(response)
+                .await
+                .with_context(|| format!("Unable to list {}", connections.name()))?;
+            let (mut page, next) = page.accounts()?;
+            accounts.append(&mut page);
+            match next {
+                Some(next) => next_token = Some(next),
+                None => break,
+            }
+        }
+        Ok(accounts)
     }
 
     async fn get_all_tweets_for_user(
         &self,
+        _username: &str,
         user_id: u64,
         since_id: Option<u64>,
+        until_id: Option<u64>,
+        limit: Option<usize>,
     ) -> anyhow::Result<Vec<Tweet>> {
         let mut next_token = None;
         let mut results = Vec::new();
         loop {
             let (mut page, next) = self
-                .get_tweets_for_user(user_id, since_id, next_token.clone())
+                .get_tweets_for_user(user_id, since_id, until_id, next_token.clone())
                 .await?;
             results.append(&mut page);
+            if limit_reached(limit, results.len()) {
+                results.truncate(limit.unwrap_or(usize::MAX));
+                break;
+            }
+            if next.is_none() {
+                break;
+            } else {
+                next_token = next;
+            }
+        }
+        Ok(results)
+    }
+
+    async fn get_conversation_tweets(
+        &self,
+        conversation_id: u64,
+        user_id: u64,
+    ) -> anyhow::Result<Vec<Tweet>> {
+        let search = conversation_search(conversation_id, user_id);
+        let mut next_token = None;
+        let mut results = Vec::new();
+        loop {
+            let (mut page, next) = self.search_tweets(&search, next_token.clone()).await?;
+            results.append(&mut page);
             if next.is_none() {
                 break;
             } else {
@@ -196,25 +562,87 @@ impl TwitterClient for TwitterClientV2 {
         }
         Ok(results)
     }
+
+    async fn lookup_tweets(&self, ids: &[u64]) -> anyhow::Result<Vec<Tweet>> {
+        let tweets = self.lookup(ids).await?;
+        Ok(tweets.into_iter().map(|(_, tweet)| tweet).collect())
+    }
+
+    async fn lookup_tweets_with_authors(
+        &self,
+        ids: &[u64],
+    ) -> anyhow::Result<Vec<(TweetAuthor, Tweet)>> {
+        self.lookup(ids)
+            .await?
+            .into_iter()
+            .map(|(author, tweet)| Ok((author.context("Missing tweet author")?, tweet)))
+            .collect()
+    }
+}
+
+/// The user's tweets in a conversation, as a search query
+fn conversation_search(conversation_id: u64, user_id: u64) -> String {
+    format!("conversation_id:{conversation_id} from:{user_id}")
 }
 
 fn convert_tweets(
     tweets: Vec<GetTweetsTweet>,
-    media: Vec<GetTweetsMedia>,
+    includes: &GetTweetsIncludes,
 ) -> anyhow::Result<Vec<Tweet>> {
     tweets
         .into_iter()
         .map(|tweet| {
+            let in_reply_to = tweet.referenced("replied_to");
+            let quoted_tweet_id = tweet.referenced("quoted");
+            let poll = tweet
+                .attachments
+                .poll_ids
+                .first()
+                .and_then(|id| includes.polls.iter().find(|p| p.id == *id))
+                .map(GetTweetsPoll::convert);
+            let card = tweet.entities.card();
+            let geo = tweet.geo.as_ref();
+            let place = geo
+                .and_then(|g| g.place_id.as_ref())
+                .and_then(|id| includes.places.iter().find(|p| p.id == *id))
+                .map(|place| Place {
+                    full_name: place.full_name.clone(),
+                    country: place.country.clone(),
+                    bounding_box: place.geo.as_ref().map(|g| g.bbox),
+                });
+            let coordinates = geo
+                .and_then(|g| g.coordinates.as_ref())
+                .map(|point| Coordinates {
+                    longitude: point.coordinates.0,
+                    latitude: point.coordinates.1,
+                });
             Ok(Tweet {
                 id: u64::from_str(&tweet.id)?,
                 timestamp: DateTime::parse_from_rfc3339(&tweet.created_at)?.timestamp(),
                 text: tweet.text,
+                conversation_id: tweet
+                    .conversation_id
+                    .map(|id| u64::from_str(&id))
+                    .transpose()?,
+                in_reply_to,
+                quoted_tweet_id,
+                poll,
+                card,
+                place,
+                coordinates,
+                batch: None,
+                source: tweet.source,
+                like_count: tweet.public_metrics.as_ref().map(|m| m.like_count),
+                retweet_count: tweet.public_metrics.as_ref().map(|m| m.retweet_count),
+                possibly_sensitive: tweet.possibly_sensitive,
+                unavailable_since: None,
                 media: tweet
                     .attachments
                     .media_keys
                     .into_iter()
                     .map(|key| {
-                        let m = media
+                        let m = includes
+                            .media
                             .iter()
                             .find(|m| m.media_key == key)
                             .context("Missing media item")?;
@@ -226,14 +654,52 @@ fn convert_tweets(
         .collect::<anyhow::Result<_>>()
 }
 
+impl GetTweetsEntities {
+    /// Twitter previews the last link in a tweet
+    fn card(&self) -> Option<Card> {
+        let link = self.urls.iter().rev().find(|u| u.title.is_some())?;
+        let url = link.unwound_url.as_ref().or(link.expanded_url.as_ref());
+        Some(Card {
+            url: url.and_then(|u| Url::parse(u).ok()),
+            title: link.title.clone(),
+            description: link.description.clone().filter(|d| !d.is_empty()),
+            thumbnail_url: link.images.first().and_then(|i| Url::parse(&i.url).ok()),
+        })
+    }
+}
+
+impl GetTweetsPoll {
+    fn convert(&self) -> Poll {
+        Poll {
+            options: self
+                .options
+                .iter()
+                .map(|o| PollOption {
+                    label: o.label.clone(),
+                    votes: o.votes,
+                })
+                .collect(),
+            ends_at: self
+                .end_datetime
+                .as_ref()
+                .and_then(|d| DateTime::parse_from_rfc3339(d).ok())
+                .map(|d| d.timestamp()),
+        }
+    }
+}
+
 impl GetTweetsMedia {
     fn convert(&self) -> anyhow::Result<Media> {
         // There doesn't seem to be a way to get the Video URLs at the moment :(
         // https://stackoverflow.com/questions/66211050/twitter-api-v2-video-url
-        let (url, r#type) = match &self.variant {
-            GetTweetsMediaVariant::Video => (None, MediaType::Video),
-            GetTweetsMediaVariant::Photo { url } => (Some(url.to_string()), MediaType::Photo),
-            GetTweetsMediaVariant::Gif => (None, MediaType::Gif),
+        let (url, preview_url, r#type) = match &self.variant {
+            GetTweetsMediaVariant::Video { preview_image_url } => {
+                (None, preview_image_url, MediaType::Video)
+            }
+            GetTweetsMediaVariant::Photo { url } => (Some(url), &None, MediaType::Photo),
+            GetTweetsMediaVariant::Gif { preview_image_url } => {
+                (None, preview_image_url, MediaType::Gif)
+            }
         };
         let pos = self
             .media_key
@@ -243,8 +709,195 @@ impl GetTweetsMedia {
             .parse()
             .context("Unable to parse media key")?;
         let url = url
-            .map(|url| Url::from_str(&url))
+            .map(|url| Url::from_str(url))
             .map_or(Ok(None), |url| url.map(Some))?;
-        Ok(Media::new(*id, r#type, url))
+        let mut media = Media::new(*id, r#type, url);
+        media.preview_url = preview_url
+            .as_deref()
+            .map(Url::from_str)
+            .transpose()
+            .context("Unable to parse preview URL")?;
+        media.alt_text = self.alt_text.clone().filter(|t| !t.is_empty());
+        Ok(media)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_error(json: &str) -> Option<UserError> {
+        let response: UserResponse = serde_json::from_str(json).unwrap();
+        let error = response.user("a").err()?;
+        Some(error.downcast::<UserError>().unwrap())
+    }
+
+    #[test]
+    fn classifies_user_errors() {
+        assert_eq!(
+            user_error(r#"{ "data": { "id": "1", "username": "a", "protected": true } }"#),
+            None
+        );
+        assert_eq!(
+            user_error(
+                r#"{ "errors": [{
+                    "title": "Forbidden",
+                    "detail": "User has been suspended: [a].",
+                    "type": "https://api.twitter.com/2/problems/resource-not-found"
+                }] }"#
+            ),
+            Some(UserError::Suspended("a".to_string()))
+        );
+        assert_eq!(
+            user_error(
+                r#"{ "errors": [{
+                    "title": "Not Found Error",
+                    "detail": "Could not find user with username: [a].",
+                    "type": "https://api.twitter.com/2/problems/resource-not-found"
+                }] }"#
+            ),
+            Some(UserError::NotFound("a".to_string()))
+        );
+    }
+
+    #[test]
+    fn parses_polls() {
+        let poll: GetTweetsPoll = serde_json::from_str(
+            r#"{
+                "id": "1",
+                "options": [
+                    { "position": 1, "label": "Yes", "votes": 12 },
+                    { "position": 2, "label": "No", "votes": 3 }
+                ],
+                "end_datetime": "2021-03-05T00:00:00.000Z",
+                "voting_status": "closed"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            poll.convert(),
+            Poll {
+                options: vec![
+                    PollOption {
+                        label: "Yes".to_string(),
+                        votes: Some(12),
+                    },
+                    PollOption {
+                        label: "No".to_string(),
+                        votes: Some(3),
+                    },
+                ],
+                ends_at: Some(1614902400),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_link_previews() {
+        let entities: GetTweetsEntities = serde_json::from_str(
+            r#"{
+                "urls": [
+                    {
+                        "expanded_url": "https://example.com/first",
+                        "title": "The first link"
+                    },
+                    {
+                        "expanded_url": "https://bit.ly/abc",
+                        "unwound_url": "https://example.com/post",
+                        "title": "A post",
+                        "description": "",
+                        "images": [
+                            { "url": "https://pbs.twimg.com/news_img/1.jpg", "width": 800 },
+                            { "url": "https://pbs.twimg.com/news_img/1_small.jpg", "width": 150 }
+                        ]
+                    },
+                    { "expanded_url": "https://twitter.com/a/status/1/photo/1" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            entities.card(),
+            Some(Card {
+                url: Some(Url::parse("https://example.com/post").unwrap()),
+                title: Some("A post".to_string()),
+                description: None,
+                thumbnail_url: Some(Url::parse("https://pbs.twimg.com/news_img/1.jpg").unwrap()),
+            })
+        );
+        // Links that weren't unfurled have no preview
+        let entities: GetTweetsEntities =
+            serde_json::from_str(r#"{ "urls": [{ "expanded_url": "https://example.com" }] }"#)
+                .unwrap();
+        assert_eq!(entities.card(), None);
+    }
+
+    #[test]
+    fn parses_conversation_searches() {
+        assert_eq!(conversation_search(30, 1), "conversation_id:30 from:1");
+        let page: GetTweetsResponse = serde_json::from_str(
+            r#"{
+                "data": [
+                    {
+                        "id": "32",
+                        "text": "2/2",
+                        "created_at": "2021-03-04T00:01:00.000Z",
+                        "conversation_id": "30",
+                        "referenced_tweets": [{ "type": "replied_to", "id": "31" }]
+                    }
+                ],
+                "meta": { "result_count": 1, "next_token": "next" }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(page.meta.next_token.as_deref(), Some("next"));
+        let tweets = convert_tweets(page.data, &GetTweetsIncludes::default()).unwrap();
+        assert_eq!(tweets.len(), 1);
+        assert_eq!(tweets[0].id, 32);
+        assert_eq!(tweets[0].conversation_id, Some(30));
+        assert_eq!(tweets[0].in_reply_to, Some(31));
+        // The last page has no token
+        let page: GetTweetsResponse =
+            serde_json::from_str(r#"{ "meta": { "result_count": 0 } }"#).unwrap();
+        assert!(page.data.is_empty());
+        assert_eq!(page.meta.next_token, None);
+    }
+
+    #[test]
+    fn parses_connections() {
+        let page: UsersResponse = serde_json::from_str(
+            r#"{
+                "data": [
+                    { "id": "1", "username": "alice", "name": "Alice" },
+                    { "id": "2", "username": "bob" }
+                ],
+                "meta": { "result_count": 2, "next_token": "next" }
+            }"#,
+        )
+        .unwrap();
+        let (accounts, next) = page.accounts().unwrap();
+        assert_eq!(
+            accounts,
+            [
+                Account {
+                    user_id: 1,
+                    username: "alice".to_string(),
+                    name: Some("Alice".to_string()),
+                },
+                Account {
+                    user_id: 2,
+                    username: "bob".to_string(),
+                    name: None,
+                },
+            ]
+        );
+        assert_eq!(next.as_deref(), Some("next"));
+
+        // The last page has no data (when the list is empty) and no token
+        let page: UsersResponse =
+            serde_json::from_str(r#"{ "meta": { "result_count": 0 } }"#).unwrap();
+        let (accounts, next) = page.accounts().unwrap();
+        assert!(accounts.is_empty());
+        assert_eq!(next, None);
     }
 }
@@ -0,0 +1,142 @@
+//! Checks the downloaded files against the SHA-256 hashes recorded when they were downloaded
+
+use crate::download::{connection_pool, download_media, trash};
+use crate::lock;
+use crate::messages::{self, Message};
+use crate::model::{list_user_dirs, DataFile};
+use crate::VerifyArgs;
+use anyhow::{bail, Context};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio_util::sync::CancellationToken;
+
+#[derive(Default)]
+struct Report {
+    verified: usize,
+    recorded: usize,
+    missing: usize,
+    corrupted: usize,
+}
+
+pub async fn verify(args: VerifyArgs, cancel: &CancellationToken) -> anyhow::Result<()> {
+    if !args.dir.is_dir() {
+        bail!("expected a directory")
+    }
+    let connection_pool = connection_pool();
+    let mut problems = 0;
+    for user_dir in list_user_dirs(&args.dir).await? {
+        let username = user_dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let _lock = lock::lock(&user_dir, false).await?;
+        let mut data_file = DataFile::load_unchecked(&user_dir)
+            .await?
+            .context("Missing data file")?;
+        let report = verify_account(&user_dir, &mut data_file, args.redownload)
+            .await
+            .with_context(|| format!("Unable to verify {username}"))?;
+        log::info!(
+            "{}",
+            messages::format(
+                Message::Verified,
+                [
+                    ("username", (&username).into()),
+                    ("verified", report.verified.into()),
+                    ("recorded", report.recorded.into()),
+                    ("missing", report.missing.into()),
+                    ("corrupted", report.corrupted.into()),
+                ]
+            )
+        );
+        data_file.save(&user_dir).await?;
+        if args.redownload && report.missing + report.corrupted > 0 {
+            download_media(
+                &username,
+                &user_dir,
+                &mut data_file,
+                &args.media,
+                &connection_pool,
+                cancel,
+            )
+            .await?;
+        } else {
+            problems += report.missing + report.corrupted;
+        }
+    }
+    if problems > 0 {
+        bail!("{} files are missing or corrupted", problems)
+    }
+    Ok(())
+}
+
+/// With `redownload` the missing and corrupted files are removed from the data file, so that
+/// they will be downloaded again
+async fn verify_account(
+    user_dir: &Path,
+    data_file: &mut DataFile,
+    redownload: bool,
+) -> anyhow::Result<Report> {
+    let mut report = Report::default();
+    for media in data_file.tweets.iter_mut().flat_map(|t| &mut t.media) {
+        let (file_name, path) = match &media.file_name {
+            Some(file_name) => (file_name, user_dir.join(file_name)),
+            None => continue,
+        };
+        if !path.exists() {
+            log::warn!("Missing: {}", path.display());
+            report.missing += 1;
+        } else {
+            let hash = hash_file(&path)
+                .await
+                .with_context(|| format!("Unable to read {}", path.display()))?;
+            match &media.sha256 {
+                Some(expected) if expected == &hash => {
+                    report.verified += 1;
+                    continue;
+                }
+                Some(_) => {
+                    log::warn!("Corrupted: {}", path.display());
+                    report.corrupted += 1;
+                    if redownload {
+                        trash(user_dir, file_name, Some(media.id), media.sha256.clone()).await?;
+                    }
+                }
+                // Downloaded before hashes were recorded
+                None => {
+                    media.sha256 = Some(hash);
+                    report.recorded += 1;
+                    continue;
+                }
+            }
+        }
+        if redownload {
+            media.file_name = None;
+            media.sha256 = None;
+        }
+    }
+    Ok(report)
+}
+
+/// Returns the SHA-256 hash (hex) of a file, or of its plaintext if it is encrypted
+pub async fn hash_file(path: &Path) -> anyhow::Result<String> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut first = true;
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if first && crate::encryption::is_encrypted(&buffer[..read]) {
+            let plaintext = crate::encryption::read(path).await?;
+            return Ok(hex::encode(Sha256::digest(plaintext)));
+        }
+        first = false;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
@@ -0,0 +1,158 @@
+//! Runs continuously (e.g. as a systemd service), re-checking each account on a schedule so that
+//! only new media is downloaded each time
+
+use crate::cancel::{cancellable, Cancelled};
+use crate::download::{
+    connection_pool, download_account, new_trash_folder, parse_usernames, reset_disk_usage, setup,
+};
+use crate::lock;
+use crate::messages::{self, Message};
+use crate::WatchArgs;
+use anyhow::{bail, Context};
+use chrono::Utc;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+use tokio::fs;
+use tokio_util::sync::CancellationToken;
+
+const STATE_FILE: &str = ".watch.json";
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct WatchState {
+    accounts: BTreeMap<String, AccountState>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct AccountState {
+    /// When the account was last checked (successfully or not)
+    last_checked: Option<i64>,
+    last_success: Option<i64>,
+    /// Scheduled when the previous check completes, so the schedule survives restarts
+    next_check: Option<i64>,
+    last_error: Option<String>,
+}
+
+impl WatchState {
+    async fn load(out: &Path) -> anyhow::Result<Self> {
+        let path = out.join(STATE_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let text = fs::read_to_string(&path)
+            .await
+            .context("Unable to read watch state")?;
+        serde_json::from_str(&text).context("Unable to deserialize watch state")
+    }
+
+    async fn save(&self, out: &Path) -> anyhow::Result<()> {
+        let text = serde_json::to_string_pretty(&self).unwrap();
+        fs::write(out.join(STATE_FILE), &text)
+            .await
+            .context("Unable to write watch state")
+    }
+}
+
+pub async fn watch(args: WatchArgs, cancel: &CancellationToken) -> anyhow::Result<()> {
+    let every: Duration = args.every.into();
+    let jitter: Duration = args.jitter.map(Into::into).unwrap_or(every / 10);
+    let download = &args.download;
+    if download.tweet_list.is_some() {
+        bail!("`--tweet-list` can only be used with `download`");
+    }
+    let client = setup(download).await?;
+    let connection_pool = connection_pool();
+    // Only one process watches each output folder, as they'd each update the watch state
+    let _lock = cancellable(cancel, lock::lock(&download.out, download.wait_lock)).await?;
+    // Fails straight away when the accounts are misconfigured, later errors are only logged
+    parse_usernames(download).await?;
+    let mut state = WatchState::load(&download.out).await?;
+    let every_text = humantime::format_duration(every).to_string();
+    log::info!(
+        "{}",
+        messages::format(Message::CheckingEvery, [("every", (&every_text).into())])
+    );
+
+    loop {
+        // The list is re-read each time so that accounts can be added without a restart
+        let usernames = match parse_usernames(download).await {
+            Ok(usernames) => usernames,
+            // e.g. the list is being rewritten, it's checked again after a while
+            Err(e) => {
+                log::warn!("Unable to read the accounts to watch: {:#}", e);
+                idle(every, cancel).await?;
+                continue;
+            }
+        };
+        let next = usernames
+            .iter()
+            .map(|u| {
+                let due = state.accounts.get(u).and_then(|a| a.next_check);
+                (u, due.unwrap_or_default())
+            })
+            .min_by_key(|(_, due)| *due);
+        let (username, due) = match next {
+            Some(next) => next,
+            None => {
+                log::warn!("No accounts to watch");
+                idle(every, cancel).await?;
+                continue;
+            }
+        };
+        let wait = due - Utc::now().timestamp();
+        if wait > 0 {
+            log::info!(
+                "{}",
+                messages::format(
+                    Message::NextCheck,
+                    [("username", username.into()), ("seconds", wait.into())]
+                )
+            );
+            idle(Duration::from_secs(wait as u64), cancel).await?;
+        }
+
+        reset_disk_usage();
+        new_trash_folder();
+        let result = download_account(
+            username,
+            download,
+            client.as_ref(),
+            &connection_pool,
+            cancel,
+        )
+        .await;
+        // The account will be checked again at the next start, what was downloaded has been saved
+        if let Err(e) = &result {
+            if e.is::<Cancelled>() {
+                return result;
+            }
+        }
+        let now = Utc::now().timestamp();
+        let account = state.accounts.entry(username.clone()).or_default();
+        account.last_checked = Some(now);
+        match result {
+            Ok(()) => {
+                account.last_success = Some(now);
+                account.last_error = None;
+            }
+            // Keep watching the other accounts, this one will be retried at its next check
+            Err(e) => {
+                log::warn!("Error downloading tweets for {}: {:#}", username, e);
+                account.last_error = Some(format!("{:#}", e));
+            }
+        }
+        let delay = every + rand::thread_rng().gen_range(Duration::ZERO..=jitter);
+        account.next_check = Some(now + delay.as_secs() as i64);
+        state.save(&download.out).await?;
+    }
+}
+
+/// Sleeps until the next check, unless cancelled first
+async fn idle(duration: Duration, cancel: &CancellationToken) -> anyhow::Result<()> {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => Ok(()),
+        _ = cancel.cancelled() => Err(Cancelled.into()),
+    }
+}
@@ -0,0 +1,110 @@
+//! The Internet Archive's Wayback Machine, for recovering media from deleted tweets (often the only
+//! place it survives), and for capturing the tweets themselves as an off-site backup
+
+use crate::model::DataFile;
+use anyhow::{bail, Context};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use std::path::Path;
+use std::time::Duration;
+use url::Url;
+
+const AVAILABILITY_API: &str = "https://archive.org/wayback/available";
+const SAVE_URL: &str = "https://web.archive.org/save/";
+/// Captures can take a while, as the Wayback Machine loads the page
+const SAVE_TIMEOUT_SEC: u64 = 120;
+
+#[derive(Deserialize, Debug)]
+struct Availability {
+    archived_snapshots: Snapshots,
+}
+
+#[derive(Deserialize, Debug)]
+struct Snapshots {
+    closest: Option<Snapshot>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Snapshot {
+    available: bool,
+    status: String,
+    timestamp: String,
+}
+
+/// Returns the URL of the raw content of the closest archived copy of `url`, if there is one
+pub async fn find_snapshot(client: &Client, url: &Url) -> anyhow::Result<Option<Url>> {
+    let text = client
+        .get(AVAILABILITY_API)
+        .query(&[("url", url.as_str())])
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let availability = serde_json::from_str::<Availability>(&text)
+        .context("Unable to deserialize Wayback Machine response")?;
+    match availability.archived_snapshots.closest {
+        Some(snapshot) if snapshot.available && snapshot.status == "200" => {
+            // The `id_` suffix requests the original content, rather than the rewritten page
+            let raw = format!(
+                "https://web.archive.org/web/{}id_/{}",
+                snapshot.timestamp, url
+            );
+            Ok(Some(Url::parse(&raw)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Asks the Wayback Machine to capture each tweet that hasn't been submitted yet, newest first.
+/// Failures are logged rather than returned, the tweets will be submitted on the next sync.
+pub async fn submit_tweets(
+    username: &str,
+    user_dir: &Path,
+    data_file: &mut DataFile,
+    client: &Client,
+) -> anyhow::Result<()> {
+    let pending = data_file
+        .tweets
+        .iter()
+        .rev()
+        .map(|t| t.id)
+        .filter(|id| !data_file.wayback_submitted.contains(id))
+        .collect::<Vec<_>>();
+    let mut submitted = 0;
+    for id in pending {
+        let url = format!("https://twitter.com/{username}/status/{id}");
+        match submit(client, &url).await {
+            Ok(()) => {
+                data_file.wayback_submitted.insert(id);
+                submitted += 1;
+            }
+            Err(e) => {
+                log::warn!("Unable to submit {} to the Wayback Machine: {:#}", url, e);
+                break;
+            }
+        }
+    }
+    data_file.save(user_dir).await?;
+    if submitted > 0 {
+        log::info!(
+            "Submitted {} tweets from {} to the Wayback Machine",
+            submitted,
+            username
+        );
+    }
+    Ok(())
+}
+
+async fn submit(client: &Client, url: &str) -> anyhow::Result<()> {
+    let response = client
+        .get(format!("{SAVE_URL}{url}"))
+        .timeout(Duration::from_secs(SAVE_TIMEOUT_SEC))
+        .send()
+        .await?;
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        StatusCode::TOO_MANY_REQUESTS => bail!("Rate limited, try again later"),
+        status => bail!("Received unsuccessful response code: {}", status.as_u16()),
+    }
+}
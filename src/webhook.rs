@@ -0,0 +1,39 @@
+//! Notifies another service (`--webhook-url`) of each downloaded file and of each synced account,
+//! by POSTing the same JSON events as `--json`
+
+use crate::events::Event;
+use reqwest::Client;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use url::Url;
+
+/// So that an unresponsive webhook doesn't hold up the downloads for long
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Failures are only logged, the downloads carry on regardless
+pub async fn post(client: &Client, url: &Url, event: &Event<'_>) {
+    send(client.clone(), url.clone(), body(event)).await
+}
+
+/// Like `post`, without waiting for the webhook to answer (until the handle is awaited)
+pub fn post_in_background(client: &Client, url: &Url, event: &Event<'_>) -> JoinHandle<()> {
+    tokio::spawn(send(client.clone(), url.clone(), body(event)))
+}
+
+fn body(event: &Event<'_>) -> Vec<u8> {
+    serde_json::to_vec(event).expect("Serializable event")
+}
+
+async fn send(client: Client, url: Url, body: Vec<u8>) {
+    let result = client
+        .post(url.clone())
+        .timeout(TIMEOUT)
+        .header("Content-Type", "application/json")
+        .body(body)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    if let Err(e) = result {
+        log::warn!("Unable to notify webhook {}: {}", url, e);
+    }
+}
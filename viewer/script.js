@@ -4,19 +4,30 @@ $( document ).ready(function() {
     const PAGE_CHOICE = $("#page-choice");
     const FORM = $("#form")
     const POSTS_DIV = $("#posts");
+    const ACCOUNTS_DIV = $("#accounts");
     const PAGE_SIZE = 100;
     const TYPE = $("#type")
     const RESULTS = $("#results")
     const SORT = $("#sort")
+    const FROM = $("#from")
+    const TO = $("#to")
+    const DOWNLOADS_DIV = $("#downloads");
+    const ARCHIVE = $("#archive");
+    const THREAD = $("#thread");
 
-    let ALL_TWEETS = [];
-    let FILTERED_TWEETS = [];
+    let LATEST_REQUEST = 0;
+    // The conversation id (as a string) of the thread being shown, if one is
+    let CONVERSATION = null;
 
     FORM.trigger("reset");
     FORM.submit(function( event ) {
         event.preventDefault();
     });
 
+    $.get("/settings").then(function(settings) {
+        NSFW_BLUR_THRESHOLD = settings.nsfw_threshold;
+    });
+
     $.get("/list").then(
         function(list) {
             if (list.length < 1) {
@@ -27,8 +38,12 @@ $( document ).ready(function() {
             placeholder.setAttribute('disabled', true);
             placeholder.setAttribute('selected', true);
             BLOG_CHOICE.append(placeholder);
-            list.forEach((d) => BLOG_CHOICE.append(new Option(d,d)));
+            list.forEach((account) => {
+                const label = account.name ? `${account.name} (@${account.username})` : account.username;
+                BLOG_CHOICE.append(new Option(label, account.username));
+            });
             BLOG_CHOICE.attr('disabled' , false);
+            render_accounts(list);
         },
         function (e) {
             throw new Error("Get /list failed")
@@ -37,34 +52,58 @@ $( document ).ready(function() {
         alert(e);
     })
 
-    BLOG_CHOICE.change(function() {
-        const blog = $(this).val();
-        const base = `/dir/${blog}`;
-        const url = `${base}/tweets.json`
-        $.get(url).then(
-            (res) => {
-                ALL_TWEETS = res.tweets.map((tweet) => Tweet.deserialize(tweet, base))
-                apply_filters();
-                update_page_choice();
-                render_posts();
-            },
-            function (e) {
-                throw new Error(`Get ${url} failed`)
+    // A grid of the accounts' avatars and names, choosing one selects it in the form
+    function render_accounts(list) {
+        ACCOUNTS_DIV.empty();
+        list.forEach((account) => {
+            const card = $("<div>", {class: "account"});
+            if (account.avatar) {
+                card.append($("<img>", {src: `/dir/${account.avatar}`, alt: ""}));
             }
-        ).catch((e) => {
-            alert(e);
-        })
+            card.append($("<div>", {class: "account-name"}).text(account.name || account.username));
+            card.append($("<div>", {class: "account-username"}).text(`@${account.username}`));
+            card.click(() => BLOG_CHOICE.val(account.username).change());
+            ACCOUNTS_DIV.append(card);
+        });
+    }
+
+    BLOG_CHOICE.change(function() {
+        ARCHIVE.attr("href", `/api/${encodeURIComponent(BLOG_CHOICE.val())}/archive.zip`);
+        ARCHIVE.attr("hidden", false);
+        show_thread(null);
     });
 
-    PAGE_CHOICE.change(function() {
-        apply_filters();
-        render_posts();
+    // Shows just the tweets of a thread, oldest first, or all the tweets again with `null`
+    function show_thread(conversation) {
+        CONVERSATION = conversation;
+        THREAD.attr("hidden", conversation === null);
+        if (conversation !== null) {
+            SORT.val("Oldest");
+        }
+        refresh();
+    }
+
+    POSTS_DIV.on("click", ".thread-link", function(e) {
+        e.preventDefault();
+        // Not `.data()`, which would convert the id to a number
+        show_thread($(this).attr("data-conversation"));
     });
 
+    $("#thread-clear").click(function(e) {
+        e.preventDefault();
+        show_thread(null);
+    });
+
+    PAGE_CHOICE.change(function() { load_page(false) });
+
     TYPE.change(function() { refresh() });
 
     SORT.change(function() { refresh() });
 
+    FROM.change(function() { refresh() });
+
+    TO.change(function() { refresh() });
+
     SEARCH.on("input", function(e) {
         clearTimeout(this.thread);
         this.thread = setTimeout(function() {
@@ -72,41 +111,124 @@ $( document ).ready(function() {
         }, 300);
     });
 
+    // The progress of downloads running alongside the viewer (`download`, `watch` or `run`)
+    const DOWNLOADS = new Map();
+    const events = new EventSource("/api/events");
+    events.onmessage = function(message) {
+        const event = JSON.parse(message.data);
+        const key = `${event.username}/${event.path}`;
+        switch (event.event) {
+            case "download_progress": {
+                let row = DOWNLOADS.get(key);
+                if (!row) {
+                    row = $("<div>", {class: "download"});
+                    row.data("media_id", event.media_id);
+                    DOWNLOADS.set(key, row);
+                    DOWNLOADS_DIV.append(row);
+                }
+                const total = event.total_bytes ? ` / ${format_bytes(event.total_bytes)}` : "";
+                row.text(`@${event.username}: ${event.path} ${format_bytes(event.bytes)}${total}`);
+                break;
+            }
+            case "download_completed":
+                remove_download(key);
+                break;
+            case "download_failed":
+                for (const [key, row] of DOWNLOADS) {
+                    if (key.startsWith(`${event.username}/`) && row.data("media_id") === event.media_id) {
+                        remove_download(key);
+                    }
+                }
+                break;
+            case "account_finished":
+                // Show the newly downloaded media
+                if (event.downloaded > 0 && BLOG_CHOICE.val() === event.username) {
+                    load_page(true);
+                }
+                break;
+        }
+        DOWNLOADS_DIV.attr("hidden", DOWNLOADS.size === 0);
+    };
+
+    function remove_download(key) {
+        const row = DOWNLOADS.get(key);
+        if (row) {
+            row.remove();
+            DOWNLOADS.delete(key);
+        }
+    }
+
+    function format_bytes(bytes) {
+        if (bytes < 1024 * 1024) {
+            return `${(bytes / 1024).toFixed(1)} KiB`;
+        }
+        return `${(bytes / 1024 / 1024).toFixed(1)} MiB`;
+    }
+
+    // Back to the first page, as the number of pages may have changed
     function refresh() {
-        apply_filters();
-        update_page_choice();
-        render_posts();
-    }
-
-    // Filters by search, type and sorting
-    function apply_filters() {
-        const search = SEARCH[0].value;
-        const type = TYPE[0].value;
-        const sort = SORT[0].value;
-        FILTERED_TWEETS = ALL_TWEETS.filter((p) => {
-            return type === "All" || p.matches_type(type)
-        })
-        FILTERED_TWEETS = FILTERED_TWEETS.filter((p) => {
-            return search.length === 0 || p.matches_search(search)
-        })
-        FILTERED_TWEETS.sort(function (a, b) {
-            if (sort === "Oldest") {
-                return a.id - b.id;
-            } else {
-                return b.id - a.id;
+        PAGE_CHOICE.val("1");
+        load_page(true);
+    }
+
+    // The tweets are filtered, sorted and paginated by the server, only the page shown is fetched
+    function load_page(pages_changed) {
+        const blog = BLOG_CHOICE.val();
+        if (!blog) {
+            return;
+        }
+        const base = `/dir/${blog}`;
+        const thumbs = `/thumb/${blog}`;
+        const params = {
+            page: PAGE_CHOICE[0].value || "1",
+            per_page: PAGE_SIZE,
+            sort: SORT[0].value.toLowerCase(),
+        };
+        if (TYPE[0].value !== "All") {
+            params.type = TYPE[0].value;
+        }
+        if (SEARCH[0].value.length > 0) {
+            params.q = SEARCH[0].value;
+        }
+        if (FROM[0].value) {
+            params.from = FROM[0].value;
+        }
+        if (TO[0].value) {
+            params.to = TO[0].value;
+        }
+        if (CONVERSATION !== null) {
+            params.conversation = CONVERSATION;
+        }
+        const url = `/api/${encodeURIComponent(blog)}/tweets?${$.param(params)}`;
+        // Responses to earlier requests that arrive late are ignored
+        const request = ++LATEST_REQUEST;
+        $.get(url).then(
+            (res) => {
+                if (request !== LATEST_REQUEST) {
+                    return;
+                }
+                if (pages_changed) {
+                    update_page_choice(res.pages);
+                }
+                const tweets = res.tweets.map((tweet) => Tweet.deserialize(tweet, base, thumbs));
+                render_posts(res.total, tweets);
+            },
+            function (e) {
+                throw new Error(`Get ${url} failed`)
             }
-        });
+        ).catch((e) => {
+            alert(e);
+        })
     }
 
-    function update_page_choice() {
+    function update_page_choice(pages) {
         PAGE_CHOICE.empty()
-        if (FILTERED_TWEETS.length < 1) {
+        if (pages < 1) {
             const placeholder = new Option("1", "1");
             placeholder.setAttribute('disabled', true);
             PAGE_CHOICE.append(placeholder)
             PAGE_CHOICE.attr('disabled' , true);
         } else {
-            const pages = Math.ceil(FILTERED_TWEETS.length / PAGE_SIZE)
             for (let i = 1; i <= pages; i++) {
                 PAGE_CHOICE.append(new Option(i.toString(), i.toString()));
             }
@@ -114,13 +236,9 @@ $( document ).ready(function() {
         }
     }
 
-    function render_posts() {
+    function render_posts(total, tweets) {
         POSTS_DIV.empty();
-        RESULTS.text(`Results: ${FILTERED_TWEETS.length}`)
-        const page_number = parseInt(PAGE_CHOICE[0].value) - 1;
-        const start = page_number * PAGE_SIZE
-        const stop = (page_number + 1) * PAGE_SIZE
-        const tweets = FILTERED_TWEETS.slice(start, stop);
+        RESULTS.text(`Results: ${total}`)
         for (const tweet of tweets) {
             const render = tweet.render();
             POSTS_DIV.append(`<div class='post' id="${tweet.id}">${render}</div>`)
@@ -129,67 +247,164 @@ $( document ).ready(function() {
 
 });
 
+// The ids are used as strings (`id_str` etc.), as they are too large to be represented exactly as numbers
 class Tweet {
     id;
     date;
     text
     media;
+    unavailable;
+    conversation;
+    in_reply_to;
+    quoted;
+    poll;
+    card;
 
-    constructor(id, date, text, media) {
+    constructor(id, date, text, media, unavailable, conversation, in_reply_to, quoted, poll, card) {
         this.id = id
         this.date = date
         this.text = text;
         this.media = media
+        this.unavailable = unavailable;
+        this.conversation = conversation;
+        this.in_reply_to = in_reply_to;
+        this.quoted = quoted;
+        this.poll = poll;
+        this.card = card;
     }
 
-    static deserialize(object, base) {
+    static deserialize(object, base, thumbs) {
         const date = new Date(object.timestamp * 1000).toLocaleString();
-        const media = object.media.map((m) => Media.deserialize(m, base))
-        return new Tweet(object.id, date, object.text, media)
-    }
-
-    matches_search(search) {
-        return this.text.toLowerCase().includes(search.toLowerCase())
-    }
-
-    matches_type(type) {
-        return this.media.some((m) => m.type === type)
+        const media = object.media.map((m) => Media.deserialize(m, base, thumbs, object.possibly_sensitive))
+        return new Tweet(object.id_str, date, object.text, media, object.unavailable_since != null,
+            object.conversation_id_str, object.in_reply_to_str, object.quoted_tweet_id_str, object.poll || null,
+            object.card || null)
     }
 
     render() {
         const medias = this.media.map((m) => m.render());
+        const lost = this.unavailable ? [`<p class="lost">This tweet appears to have been deleted</p>`] : [];
+        // Links to the tweet if it is on the page, which it may not be (e.g. replies to other accounts)
+        const context = [];
+        if (this.in_reply_to) {
+            context.push(`<p class="context">Replying to <a href="#${this.in_reply_to}">${this.in_reply_to}</a></p>`);
+        }
+        if (this.quoted) {
+            context.push(`<p class="context">Quoting <a href="#${this.quoted}">${this.quoted}</a></p>`);
+        }
+        const in_thread = this.conversation && (this.conversation !== this.id || this.in_reply_to);
+        const thread = in_thread
+            ? [`<p class="context"><a href="#" class="thread-link" data-conversation="${this.conversation}">Show thread</a></p>`]
+            : [];
         return [
             `<p>${this.date}</p>`,
+            ...lost,
+            ...context,
             `<p>${this.text}</p>`,
             ...medias,
+            ...this.render_poll(),
+            ...this.render_card(),
+            ...thread,
         ].join("\n")
     }
 
+    // The votes are as of when the tweet was last fetched, not every backend reports them
+    render_poll() {
+        if (!this.poll) {
+            return [];
+        }
+        const options = this.poll.options.map((o) => {
+            const votes = o.votes != null ? ` (${o.votes} votes)` : "";
+            return `<li>${escape(o.label)}${votes}</li>`;
+        });
+        return [`<ul class="poll">${options.join("")}</ul>`];
+    }
+
+    render_card() {
+        if (!this.card) {
+            return [];
+        }
+        const title = this.card.title ? `<strong>${escape(this.card.title)}</strong>` : "";
+        const description = this.card.description ? `<br>${escape(this.card.description)}` : "";
+        const href = this.card.url ? escape(this.card.url) : "#";
+        return [`<a class="card" href="${href}" target="_blank">${title}${description}</a>`];
+    }
+
+}
+
+// Media with a NSFW classifier score at or above this (or in tweets flagged as possibly sensitive) are blurred until
+// clicked, replaced by the server's `--nsfw-threshold` once its settings have loaded
+let NSFW_BLUR_THRESHOLD = 0.8;
+
+// Photos are shown as thumbnails of at most this width/height, linking to the full image
+const THUMBNAIL_WIDTH = 320;
+
+// Escaped for text or an attribute
+function escape(text) {
+    return text.replace(/&/g, "&amp;").replace(/"/g, "&quot;").replace(/</g, "&lt;");
 }
 
 class Media {
     type;
     url;
+    thumbnail;
+    preview;
+    ocr_text;
+    nsfw;
+    unavailable;
+    alt_text;
+    title;
 
-    constructor(type, url) {
+    constructor(type, url, thumbnail, preview, ocr_text, nsfw, unavailable, alt_text, title) {
         this.type = type;
         this.url = url;
+        this.thumbnail = thumbnail;
+        this.preview = preview;
+        this.ocr_text = ocr_text;
+        this.nsfw = nsfw;
+        this.unavailable = unavailable;
+        this.alt_text = alt_text;
+        this.title = title;
     }
 
-    static deserialize(object, base) {
+    static deserialize(object, base, thumbs, sensitive) {
         const filename = object.file_name;
         const url = filename === null ? null : `${base}/${filename}`;
-        return new Media(object.type, url);
+        const thumbnail = filename === null ? null : `${thumbs}/${filename}?w=${THUMBNAIL_WIDTH}`;
+        const preview = object.preview_file_name ? `${base}/${object.preview_file_name}` : null;
+        const nsfw = sensitive === true || (object.nsfw_score != null && object.nsfw_score >= NSFW_BLUR_THRESHOLD);
+        return new Media(object.type, url, thumbnail, preview, object.ocr_text || "", nsfw, object.unavailable_since != null,
+            object.alt_text || "", object.title || "");
     }
 
     render() {
-        if (!this.url) {
+        if (!this.url && this.unavailable) {
+            return `<p class="lost">${this.type} lost, it was no longer available when downloaded</p>`;
+        } else if (!this.url) {
             return `<p>${this.type} not downloaded</p>`;
+        } else if ((this.type === "video" || this.type === "gif") && this.preview) {
+            // Nothing is loaded until played, the preview is shown instead
+            return `<video controls preload="none" poster="${this.preview}"><source src="${this.url}"></video>`;
         } else if (this.type === "video" || this.type === "gif") {
             return `<video controls preload="metadata"><source src="${this.url}"></video>`;
+        } else if (this.type === "audio") {
+            const title = this.title ? `<figcaption>${escape(this.title)}</figcaption>` : "";
+            return `<figure class="space">${title}<audio controls preload="none"><source src="${this.url}"></audio></figure>`;
+        } else if (this.type === "photo" && this.nsfw) {
+            return `<img class="nsfw" src="${this.thumbnail}" alt="${this.alt()}" loading="lazy" onerror="${this.fallback()}" onclick="this.classList.remove('nsfw')">`;
         } else if (this.type === "photo") {
-            return `<img src="${this.url}" alt="">`;
+            return `<a href="${this.url}" target="_blank"><img src="${this.thumbnail}" alt="${this.alt()}" title="${this.alt()}" loading="lazy" onerror="${this.fallback()}"></a>`;
         }
     }
 
+    // Escaped for an attribute
+    alt() {
+        return escape(this.alt_text);
+    }
+
+    // Thumbnails aren't available for encrypted archives, so the full image is shown instead
+    fallback() {
+        return `this.onerror=null; this.src='${this.url}'`;
+    }
+
 }